@@ -1,14 +1,13 @@
 // Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
 // This script is sort of a joke, and toggles the state of all devices found.
 
-extern crate time;
 extern crate wemo;
 
 use std::env;
 use std::thread;
-use time::Duration;
 use wemo::DeviceSearch;
 use wemo::Switch;
+use wemo::time::Duration;
 
 #[derive(Clone, Copy)]
 enum Command { On, Off, Toggle }
@@ -1,24 +1,25 @@
 // Copyright (c) 2015 Brandon Thomas <bt@brand.io>
-extern crate time;
 extern crate wemo;
 
-use time::Duration;
-use wemo::DeviceSearch;
-use wemo::Switch;
+use wemo::time::Duration;
 
 pub fn main() {
-  let mut search = DeviceSearch::new();
-  let results = search.search(5_000);
+  let snapshot = wemo::snapshot(Duration::seconds(5));
 
-  for device in results.values() {
-    let device = Switch::from_dynamic_ip_and_port(device.ip_address,
-        device.port);
+  for device in snapshot.devices {
+    let name = format!("{}:{}", device.device.ip_address, device.device.port);
 
-    match device.get_state_with_retry(Duration::seconds(3)) {
-      Err(_) => { println!("Could not get the state."); },
+    match device.state {
+      Err(_) => { println!("Could not get the state of {}.", name); },
       Ok(state) => {
-        println!("Device {} turned on: {}", device.name(), state.is_on());
+        println!("Device {} turned on: {}", name, state.state.is_on());
+
+        if let Some(insight) = state.insight {
+          println!("  Current power: {} mW", insight.current_power);
+        }
       },
     }
   }
+
+  println!("Took {} ms.", snapshot.elapsed.num_milliseconds());
 }
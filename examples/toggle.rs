@@ -1,12 +1,10 @@
 // Copyright (c) 2015 Brandon Thomas <bt@brand.io>
 extern crate wemo;
-extern crate time;
 
 use std::env;
 use std::net::IpAddr;
 use std::str::FromStr;
-use time::Duration;
-use wemo::Switch;
+use wemo::prelude::*;
 
 pub fn main() {
   let ip_address = match env::args().nth(1) {
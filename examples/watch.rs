@@ -1,6 +1,5 @@
 // Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
 extern crate wemo;
-extern crate time;
 
 use wemo::DeviceSearch;
 use wemo::subscriptions::Notification;
@@ -26,7 +25,25 @@ pub fn main() {
       match notification.notification_type {
         NotificationType::State { state } => {
           println!("State update from {}: {}", host, state);
-        }
+        },
+        NotificationType::LoadRemoved => {
+          println!("Load removed from {}", host);
+        },
+        NotificationType::LoadRestored => {
+          println!("Load restored to {}", host);
+        },
+        NotificationType::Brightness { level } => {
+          println!("Brightness update from {}: {}%", host, level);
+        },
+        NotificationType::Bulb { device_id, change } => {
+          println!("Bulb {} on {} changed: {:?}", device_id, host, change);
+        },
+        NotificationType::Sensor { triggered } => {
+          println!("Sensor update from {}: triggered = {}", host, triggered);
+        },
+        NotificationType::Relay { on } => {
+          println!("Relay update from {}: on = {}", host, on);
+        },
       }
     }).unwrap();
   }
@@ -0,0 +1,40 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Base64, for the WebSocket handshake and onboarding's encrypted password.
+
+/// Standard base64, with padding.
+pub fn encode(data: &[u8]) -> String {
+  const ALPHABET: &'static [u8; 64] =
+      b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+  let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+  for chunk in data.chunks(3) {
+    let bits = (chunk[0] as u32) << 16
+        | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+        | *chunk.get(2).unwrap_or(&0) as u32;
+
+    for i in 0..4 {
+      if i <= chunk.len() {
+        let index = (bits >> (18 - 6 * i)) & 0x3f;
+        encoded.push(ALPHABET[index as usize] as char);
+      } else {
+        encoded.push('=');
+      }
+    }
+  }
+  encoded
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_encode() {
+    assert_eq!("", encode(b""));
+    assert_eq!("Zg==", encode(b"f"));
+    assert_eq!("Zm8=", encode(b"fo"));
+    assert_eq!("Zm9v", encode(b"foo"));
+    assert_eq!("Zm9vYmFy", encode(b"foobar"));
+  }
+}
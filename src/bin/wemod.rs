@@ -0,0 +1,402 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! `wemod`: a long-running daemon that discovers devices, keeps their
+//! locations and states current, and serves a small local HTTP/JSON control
+//! API:
+//!
+//! * `GET /devices`: every known device, with its last known state.
+//! * `GET /devices/<serial>`: one device, with its current state.
+//! * `POST /devices/<serial>/on`, `/off`, `/toggle`: change its state.
+//!
+//! Devices are encoded with `wemo::json::device`.
+//!
+//! Requests must name the daemon by address or as `localhost` in their `Host`
+//! header, and browsers' cross-origin requests are refused, so web pages
+//! can't reach the API. `POST`s must also be sent with `Content-Type:
+//! application/json`, and, when started with `--token`, `Authorization:
+//! Bearer <token>`.
+//!
+//! Usage: `wemod [--listen 127.0.0.1:8095] [--callback-port 3000]
+//! [--rediscover-secs 300] [--search-range 192.168.20.0/24]...
+//! [--no-multicast] [--advertise HOST:PORT] [--location-cache PATH]
+//! [--token TOKEN]`
+//!
+//! `--search-range` also searches the given addresses directly, for devices
+//! on other subnets, and `--no-multicast` searches only those. `--advertise`
+//! asks devices to call back on another address, eg. a port forwarded to the
+//! callback port. `--location-cache` remembers where devices were found
+//! between runs.
+
+extern crate iron;
+extern crate serde_json;
+extern crate wemo;
+
+use iron::prelude::*;
+use iron::status;
+use serde_json::Value;
+use serde_json::builder::{ArrayBuilder, ObjectBuilder};
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::process;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration as StdDuration;
+use wemo::registry::Registry;
+use wemo::subscriptions::{Notification, NotificationType, Subscriptions};
+use wemo::time::Duration;
+use wemo::config::{global_config, set_global_config};
+use wemo::json;
+use wemo::locations::LocationStore;
+use wemo::{DeviceSearch, Ipv4Range, RelocationWorker};
+
+const SEARCH_MS: u64 = 3_000;
+
+struct Options {
+  listen: String,
+  callback_port: u16,
+  rediscover_secs: u64,
+  search_ranges: Vec<Ipv4Range>,
+  multicast_search: bool,
+  advertise: Option<(String, u16)>,
+  location_cache: Option<String>,
+  token: Option<String>,
+}
+
+fn parse_options() -> Options {
+  let mut options = Options {
+    listen: "127.0.0.1:8095".to_string(),
+    callback_port: 3000,
+    rediscover_secs: 300,
+    search_ranges: Vec::new(),
+    multicast_search: true,
+    advertise: None,
+    location_cache: None,
+    token: None,
+  };
+
+  let args = env::args().skip(1).collect::<Vec<_>>();
+  let mut i = 0;
+  while i < args.len() {
+    if args[i] == "--no-multicast" {
+      options.multicast_search = false;
+      i += 1;
+      continue;
+    }
+
+    let value = args.get(i + 1).cloned().unwrap_or_else(|| usage());
+    match args[i].as_str() {
+      "--listen" => { options.listen = value; },
+      "--callback-port" => {
+        options.callback_port = value.parse().unwrap_or_else(|_| usage());
+      },
+      "--rediscover-secs" => {
+        options.rediscover_secs = value.parse().unwrap_or_else(|_| usage());
+      },
+      "--search-range" => {
+        let range = value.parse().unwrap_or_else(|_| usage());
+        options.search_ranges.push(range);
+      },
+      "--advertise" => {
+        let mut parts = value.rsplitn(2, ':');
+        let port = parts.next().and_then(|port| port.parse().ok());
+        match (parts.next(), port) {
+          (Some(host), Some(port)) => {
+            options.advertise = Some((host.to_string(), port));
+          },
+          _ => { usage(); },
+        }
+      },
+      "--location-cache" => { options.location_cache = Some(value); },
+      "--token" => { options.token = Some(value); },
+      _ => { usage(); },
+    }
+    i += 2;
+  }
+
+  options
+}
+
+fn usage() -> ! {
+  eprintln!("Usage: wemod [--listen ADDRESS] [--callback-port PORT] \
+      [--rediscover-secs SECONDS] [--search-range RANGE]... \
+      [--no-multicast] [--advertise HOST:PORT] [--location-cache PATH] \
+      [--token TOKEN]");
+  process::exit(2);
+}
+
+/// Tracks which device each subscription belongs to.
+type SubscriptionKeys = Arc<RwLock<HashMap<String, String>>>;
+
+/// Search for devices, adding new ones to the registry and subscribing to
+/// them. Devices found somewhere else than they were subscribed at are
+/// subscribed to again.
+fn discover(registry: &Arc<Registry>, subscriptions: &Subscriptions,
+            keys: &SubscriptionKeys) {
+  let mut search = DeviceSearch::new();
+  let results = search.search(SEARCH_MS).clone();
+
+  for (serial_number, response) in results.iter() {
+    let location = format!("{}:{}", response.ip_address, response.port);
+    let subscribed = keys.read()
+        .ok()
+        .and_then(|keys| {
+          keys.iter()
+              .find(|&(_, serial)| serial == serial_number)
+              .map(|(location, _)| location.clone())
+        });
+
+    match subscribed {
+      Some(ref previous) if *previous == location => { continue; },
+      Some(previous) => {
+        println!("{} moved from {} to {}", serial_number, previous,
+            location);
+        let _r = subscriptions.unsubscribe(&previous);
+        if let Ok(mut keys) = keys.write() {
+          keys.remove(&previous);
+        }
+      },
+      None => { println!("Found {} at {}", serial_number, location); },
+    }
+
+    if let Ok(mut keys) = keys.write() {
+      keys.insert(location.clone(), serial_number.clone());
+    }
+
+    let push_registry = registry.clone();
+    let push_keys = keys.clone();
+    let result = subscriptions.subscribe(&location, move |n: Notification| {
+      let serial_number = push_keys.read()
+          .ok()
+          .and_then(|keys| keys.get(&n.subscription_key).cloned());
+
+      let switch = serial_number.and_then(|s| push_registry.get(&s));
+      if let Some(switch) = switch {
+        match n.notification_type {
+          NotificationType::State { state } => { switch.record_push(state); },
+          _ => {},
+        }
+      }
+    });
+
+    if let Err(e) = result {
+      eprintln!("Couldn't subscribe to {}: {:?}", location, e);
+      // Try again on the next search.
+      if let Ok(mut keys) = keys.write() {
+        keys.remove(&location);
+      }
+    }
+  }
+
+  if let Err(e) = registry.insert_search_results(&results) {
+    eprintln!("Couldn't add devices: {:?}", e);
+  }
+}
+
+fn respond(status: status::Status, body: Value) -> IronResult<Response> {
+  let mut response = Response::with((status, body.to_string()));
+  response.headers.set_raw("Content-Type",
+      vec![b"application/json".to_vec()]);
+  Ok(response)
+}
+
+fn error(status: status::Status, message: &str) -> IronResult<Response> {
+  respond(status, ObjectBuilder::new().insert("error", message).build())
+}
+
+fn header(request: &Request, name: &str) -> Option<String> {
+  request.headers.get_raw(name)
+      .and_then(|values| values.first())
+      .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+/// Whether a `Host` header names an address or `localhost`, rather than a
+/// domain that could be pointed here by another site, ie. DNS rebinding.
+fn is_local_host(host: &str) -> bool {
+  let name = if let Some(bracketed) = host.strip_prefix('[') {
+    bracketed.split(']').next().unwrap_or("")
+  } else {
+    host.split(':').next().unwrap_or("")
+  };
+  name.eq_ignore_ascii_case("localhost") || name.parse::<IpAddr>().is_ok()
+}
+
+/// Refuse requests that could come from a web page rather than a local
+/// client, and unauthorized changes.
+fn check_request(token: Option<&str>, request: &Request)
+    -> Result<(), IronResult<Response>> {
+  let host = match header(request, "Host") {
+    Some(ref host) if is_local_host(host) => host.clone(),
+    _ => { return Err(error(status::Forbidden, "Unexpected Host")); },
+  };
+
+  if let Some(origin) = header(request, "Origin") {
+    if origin != format!("http://{}", host) {
+      return Err(error(status::Forbidden, "Cross-origin request"));
+    }
+  }
+
+  if request.method != iron::method::Post {
+    return Ok(());
+  }
+
+  // Browsers can't send this cross-origin without a preflight, which isn't
+  // answered.
+  let json = header(request, "Content-Type")
+      .map(|content_type| content_type.starts_with("application/json"))
+      .unwrap_or(false);
+  if !json {
+    return Err(error(status::UnsupportedMediaType,
+        "Expected Content-Type: application/json"));
+  }
+
+  if let Some(token) = token {
+    if header(request, "Authorization") != Some(format!("Bearer {}", token)) {
+      return Err(error(status::Unauthorized, "Missing or wrong token"));
+    }
+  }
+  Ok(())
+}
+
+fn handle(registry: &Registry, token: Option<&str>, request: &mut Request)
+    -> IronResult<Response> {
+  if let Err(response) = check_request(token, request) {
+    return response;
+  }
+
+  let path = request.url.path().into_iter()
+      .filter(|segment| !segment.is_empty())
+      .map(|segment| segment.to_string())
+      .collect::<Vec<_>>();
+  let path = path.iter().map(|segment| segment.as_str()).collect::<Vec<_>>();
+
+  match (&request.method, &path[..]) {
+    (&iron::method::Get, &["devices"]) => {
+      let mut devices = registry.devices();
+      devices.sort_by_key(|switch| switch.serial_number.clone());
+
+      let mut array = ArrayBuilder::new();
+      for switch in devices.iter() {
+        let state = switch.cached_state_report().map(|report| report.state);
+        array = array.push(json::device(switch, state.as_ref()));
+      }
+      respond(status::Ok, array.build())
+    },
+    (&iron::method::Get, &["devices", serial_number]) => {
+      let switch = match registry.get(serial_number) {
+        None => { return error(status::NotFound, "Unknown device"); },
+        Some(switch) => { switch },
+      };
+
+      match switch.get_state_default() {
+        Err(e) => error(status::BadGateway, &format!("{:?}", e)),
+        Ok(state) => {
+          respond(status::Ok, json::device(&switch, Some(&state)))
+        },
+      }
+    },
+    (&iron::method::Post, &["devices", serial_number, action]) => {
+      let switch = match registry.get(serial_number) {
+        None => { return error(status::NotFound, "Unknown device"); },
+        Some(switch) => { switch },
+      };
+
+      let result = match action {
+        "on" => switch.turn_on_default(),
+        "off" => switch.turn_off_default(),
+        "toggle" => switch.toggle_default(),
+        _ => { return error(status::NotFound, "Unknown action"); },
+      };
+
+      match result {
+        Err(e) => error(status::BadGateway, &format!("{:?}", e)),
+        Ok(state) => {
+          respond(status::Ok, json::device(&switch, Some(&state)))
+        },
+      }
+    },
+    _ => error(status::NotFound, "Not found"),
+  }
+}
+
+pub fn main() {
+  let options = parse_options();
+
+  let mut config = global_config();
+  config.search_ranges = options.search_ranges.clone();
+  config.multicast_search = options.multicast_search;
+  set_global_config(config);
+
+  let mut registry = Registry::new();
+  if let Some(ref path) = options.location_cache {
+    match LocationStore::open(path) {
+      Err(e) => { eprintln!("Not caching locations in {}: {:?}", path, e); },
+      Ok(locations) => { registry = registry.with_locations(locations); },
+    }
+  }
+  let registry = Arc::new(registry);
+  let keys: SubscriptionKeys = Arc::new(RwLock::new(HashMap::new()));
+
+  registry.on_online(|switch| println!("{} is back online", switch.name()));
+  registry.on_offline(|switch| println!("{} went offline", switch.name()));
+
+  let renewal_registry = registry.clone();
+  let mut subscriptions = Subscriptions::new(options.callback_port, 600)
+      .with_renewal_callback(move |key, renewed| {
+        renewal_registry.handle_renewal(key, renewed);
+      });
+  if let Some((ref host, port)) = options.advertise {
+    subscriptions = subscriptions.with_advertised_address(host, port);
+  }
+  if let Err(e) = subscriptions.start_server() {
+    eprintln!("Couldn't start the callback server: {:?}", e);
+    process::exit(1);
+  }
+
+  println!("Searching for devices...");
+  discover(&registry, &subscriptions, &keys);
+
+  // Optional: another process may hold the SSDP port.
+  let _listener = Registry::listen(registry.clone())
+      .map_err(|e| eprintln!("Not listening for announcements: {:?}", e));
+
+  // NB: Devices found later are covered once the workers are restarted.
+  let mut _relocation = RelocationWorker::start(registry.devices(),
+      Duration::seconds(10), Duration::seconds(5));
+  let mut _liveness = Registry::monitor(registry.clone(),
+      Duration::seconds(60), Duration::seconds(2));
+
+  let api_registry = registry.clone();
+  let token = options.token.clone();
+  let server = Iron::new(move |request: &mut Request| {
+    handle(&api_registry, token.as_deref(), request)
+  }).http(options.listen.as_str());
+
+  let _server = match server {
+    Err(e) => {
+      eprintln!("Couldn't listen on {}: {:?}", options.listen, e);
+      process::exit(1);
+    },
+    Ok(server) => { server },
+  };
+
+  println!("Serving the control API on {}", options.listen);
+
+  loop {
+    thread::sleep(StdDuration::from_secs(options.rediscover_secs));
+
+    let known = registry.devices().len();
+    discover(&registry, &subscriptions, &keys);
+
+    if let Err(e) = registry.save_locations() {
+      eprintln!("Couldn't save device locations: {:?}", e);
+    }
+
+    if registry.devices().len() != known {
+      _relocation = RelocationWorker::start(registry.devices(),
+          Duration::seconds(10), Duration::seconds(5));
+      _liveness = Registry::monitor(registry.clone(),
+          Duration::seconds(60), Duration::seconds(2));
+    }
+  }
+}
@@ -0,0 +1,161 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Operations on many devices at once.
+
+#[cfg(feature = "discovery")] use deadline::Deadline;
+use device::SerialNumber;
+use device::state::{BinaryState, WemoState};
+use device::switch::{Switch, WemoResult};
+use error::WemoError;
+#[cfg(feature = "discovery")] use net::search::DeviceSearch;
+use net::ssdp::SsdpResponse;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use threads;
+#[cfg(feature = "discovery")] use time::PreciseTime;
+use time::Duration;
+
+/// The most requests sent at once, each on its own thread. Past this, devices
+/// wait for a thread to come free, so a bulk call on more devices can take
+/// a multiple of its per-device timeout.
+pub const MAX_CONCURRENT_REQUESTS: usize = 16;
+
+/// Every device found on the network along with its state. See `snapshot`.
+#[derive(Debug)]
+pub struct Snapshot {
+  /// The devices found, ordered by serial number.
+  pub devices: Vec<DeviceSnapshot>,
+
+  /// How long discovery and fetching state took altogether.
+  pub elapsed: Duration,
+}
+
+/// A single device in a `Snapshot`.
+#[derive(Debug)]
+pub struct DeviceSnapshot {
+  /// Where the device was found.
+  pub device: SsdpResponse,
+
+  /// The device's state, including power usage if it's an Insight.
+  pub state: Result<BinaryState, WemoError>,
+}
+
+/// Discover all devices on the network, then concurrently fetch their
+/// states. Half of the timeout is spent on discovery and the rest on fetching
+/// state, which every device shares, so devices still waiting for a thread
+/// when it runs out report a timeout. Needs the `discovery` feature.
+#[cfg(feature = "discovery")]
+pub fn snapshot(timeout: Duration) -> Snapshot {
+  let start = PreciseTime::now();
+  let search_timeout = timeout / 2;
+
+  let mut search = DeviceSearch::new();
+  let mut found = search.search(search_timeout.num_milliseconds() as u64)
+      .values()
+      .cloned()
+      .collect::<Vec<_>>();
+
+  found.sort_by(|a, b| a.serial_number.cmp(&b.serial_number));
+
+  let elapsed = start.to(PreciseTime::now());
+  let state_timeout = if elapsed < timeout {
+    timeout - elapsed
+  } else {
+    Duration::zero()
+  };
+
+  let deadline = Deadline::after(state_timeout);
+  let states = threads::map_bounded(&found, MAX_CONCURRENT_REQUESTS,
+      |device| {
+        let mut switch = Switch::from_dynamic_ip_and_port(device.ip_address,
+            device.port);
+        switch.serial_number = Some(device.serial_number.clone());
+        switch.get_binary_state(deadline.remaining())
+      });
+
+  let devices = found.into_iter()
+      .zip(states)
+      .map(|(device, state)| {
+        DeviceSnapshot {
+          device: device,
+          state: state.unwrap_or(Err(WemoError::WemoError)),
+        }
+      })
+      .collect();
+
+  Snapshot {
+    devices: devices,
+    elapsed: start.to(PreciseTime::now()),
+  }
+}
+
+/// Get the state of every device concurrently, up to
+/// `MAX_CONCURRENT_REQUESTS` at a time, so the whole call takes about as long
+/// as the slowest device rather than the sum of them all. Results are keyed
+/// by serial number, or by `Switch::name()` for devices without a known
+/// serial number.
+pub fn get_states<S>(switches: &[S], timeout: Duration)
+    -> HashMap<SerialNumber, WemoResult> where S: Borrow<Switch> + Sync {
+  for_each(switches, |switch| switch.get_state(timeout))
+}
+
+/// Set the state of every device concurrently, like `get_states`.
+pub fn set_states<S>(switches: &[S], state: WemoState, timeout: Duration)
+    -> HashMap<SerialNumber, WemoResult> where S: Borrow<Switch> + Sync {
+  for_each(switches, |switch| switch.set_state(state.clone(), timeout))
+}
+
+/// Set the state of every device concurrently, like `set_states`, using each
+/// device's default timeout and retry policy.
+pub fn set_states_default<S>(switches: &[S], state: WemoState)
+    -> HashMap<SerialNumber, WemoResult> where S: Borrow<Switch> + Sync {
+  for_each(switches, |switch| switch.set_state_default(state.clone()))
+}
+
+/// Run the request against every device, `MAX_CONCURRENT_REQUESTS` at a
+/// time.
+fn for_each<S, F>(switches: &[S], request: F)
+    -> HashMap<SerialNumber, WemoResult>
+    where S: Borrow<Switch> + Sync, F: Fn(&Switch) -> WemoResult + Sync {
+  let results = threads::map_bounded(switches, MAX_CONCURRENT_REQUESTS,
+      |switch| request(switch.borrow()));
+
+  switches.iter()
+      .zip(results)
+      .filter_map(|(switch, result)| {
+        // NB: A panicked request has no result to report.
+        result.map(|result| (device_key(switch.borrow()), result))
+      })
+      .collect()
+}
+
+/// The key a device's results are reported under.
+fn device_key(switch: &Switch) -> SerialNumber {
+  match switch.serial_number {
+    Some(ref serial_number) => serial_number.clone(),
+    None => switch.name(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::IpAddr;
+  use std::str::FromStr;
+  use super::*;
+
+  #[test]
+  fn test_get_states_keys() {
+    // Nothing listens on port 1, so every request fails quickly.
+    let ip = IpAddr::from_str("127.0.0.1").unwrap();
+    let mut with_serial = Switch::from_static_ip_and_port(ip, 1);
+    with_serial.serial_number = Some("ABC123".to_string());
+    let without_serial = Switch::from_static_ip_and_port(ip, 1);
+
+    let results = get_states(&[with_serial, without_serial],
+        Duration::milliseconds(500));
+
+    assert_eq!(2, results.len());
+    assert!(results.get("ABC123").unwrap().is_err());
+    assert!(results.get("127.0.0.1:1").unwrap().is_err());
+  }
+}
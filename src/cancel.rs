@@ -0,0 +1,45 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Cancels in-flight operations that were handed a clone of the token, eg.
+/// when a user presses "cancel" or the application is shutting down. Clones
+/// share the same cancellation state.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+  cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+  /// CTOR.
+  pub fn new() -> CancellationToken {
+    CancellationToken::default()
+  }
+
+  /// Cancel all operations using this token. Operations end promptly with
+  /// `WemoError::Cancelled` rather than waiting out their timeouts.
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::SeqCst);
+  }
+
+  /// Whether the token has been cancelled.
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::SeqCst)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_clones_share_state() {
+    let token = CancellationToken::new();
+    let clone = token.clone();
+
+    assert!(!clone.is_cancelled());
+    token.cancel();
+    assert!(clone.is_cancelled());
+  }
+}
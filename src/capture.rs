@@ -0,0 +1,151 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! A debugging aid that records, verbatim, every SOAP request and response
+//! and every GENA subscription request and event. This helps when diagnosing
+//! new firmware without a packet sniffer. Install a sink with `set_capture`.
+//! Nothing is recorded by default.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Result as IoResult, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// What a captured message is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CaptureKind {
+  /// A SOAP request sent to a device.
+  SoapRequest,
+  /// A SOAP response received from a device.
+  SoapResponse,
+  /// A GENA SUBSCRIBE request sent to a device.
+  GenaSubscribe,
+  /// A GENA UNSUBSCRIBE request sent to a device.
+  GenaUnsubscribe,
+  /// The body of a GENA NOTIFY event received from a device.
+  GenaNotify,
+}
+
+impl CaptureKind {
+  fn label(&self) -> &'static str {
+    match *self {
+      CaptureKind::SoapRequest => "soap-request",
+      CaptureKind::SoapResponse => "soap-response",
+      CaptureKind::GenaSubscribe => "gena-subscribe",
+      CaptureKind::GenaUnsubscribe => "gena-unsubscribe",
+      CaptureKind::GenaNotify => "gena-notify",
+    }
+  }
+}
+
+/// One message as it went over the wire.
+#[derive(Debug)]
+pub struct CapturedMessage<'a> {
+  pub kind: CaptureKind,
+  /// The device's address, when known.
+  pub peer: Option<SocketAddr>,
+  pub data: &'a [u8],
+}
+
+/// Receives captured messages, on whichever thread sent or received them.
+pub trait CaptureSink: Send + Sync {
+  fn capture(&self, message: &CapturedMessage);
+}
+
+impl<F> CaptureSink for F where F: Fn(&CapturedMessage) + Send + Sync {
+  fn capture(&self, message: &CapturedMessage) {
+    self(message)
+  }
+}
+
+/// Appends captured messages to a file, each preceded by a line naming its
+/// kind and peer.
+pub struct FileSink {
+  file: Mutex<File>,
+}
+
+impl FileSink {
+  /// Append to the file at `path`, creating it if needed.
+  pub fn create<P: AsRef<Path>>(path: P) -> IoResult<FileSink> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(FileSink { file: Mutex::new(file) })
+  }
+}
+
+impl CaptureSink for FileSink {
+  fn capture(&self, message: &CapturedMessage) {
+    let peer = message.peer
+        .map(|peer| peer.to_string())
+        .unwrap_or("unknown".to_string());
+
+    match self.file.lock() {
+      Err(_) => {}, // Ignore.
+      Ok(mut file) => {
+        let _r = writeln!(file, "--- {} {} ---", message.kind.label(), peer)
+            .and_then(|_| file.write_all(message.data))
+            .and_then(|_| file.write_all(b"\n"));
+      },
+    }
+  }
+}
+
+lazy_static! {
+  static ref GLOBAL_CAPTURE: RwLock<Option<Arc<CaptureSink>>> =
+      RwLock::new(None);
+}
+
+/// Send captured messages to `sink` from now on, replacing any previous sink.
+pub fn set_capture(sink: Arc<CaptureSink>) {
+  match GLOBAL_CAPTURE.write() {
+    Err(_) => {}, // Ignore. Shouldn't occur.
+    Ok(mut global) => { *global = Some(sink); },
+  }
+}
+
+/// Stop capturing messages.
+pub fn clear_capture() {
+  match GLOBAL_CAPTURE.write() {
+    Err(_) => {}, // Ignore. Shouldn't occur.
+    Ok(mut global) => { *global = None; },
+  }
+}
+
+/// Pass a message to the installed sink, if any.
+pub fn record(kind: CaptureKind, peer: Option<SocketAddr>, data: &[u8]) {
+  let sink = GLOBAL_CAPTURE.read()
+      .ok()
+      .and_then(|global| global.clone());
+
+  if let Some(sink) = sink {
+    sink.capture(&CapturedMessage {
+      kind: kind,
+      peer: peer,
+      data: data,
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+  use super::*;
+
+  #[test]
+  fn test_record() {
+    let captured = Arc::new(Mutex::new(Vec::new()));
+    let sink_captured = captured.clone();
+
+    set_capture(Arc::new(move |message: &CapturedMessage| {
+      // Other tests may capture messages concurrently.
+      if message.data == b"capture-test" {
+        sink_captured.lock().unwrap().push(message.kind);
+      }
+    }));
+
+    record(CaptureKind::GenaNotify, None, b"capture-test");
+    clear_capture();
+    record(CaptureKind::GenaNotify, None, b"capture-test");
+
+    assert_eq!(vec![CaptureKind::GenaNotify], *captured.lock().unwrap());
+  }
+}
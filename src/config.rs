@@ -0,0 +1,659 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Crate-wide defaults. `Switch` and `DeviceSearch` copy the global
+//! `WemoConfig` when they're constructed, so set it once at startup, before
+//! creating any devices.
+//!
+//! Devices can also be declared in a file and read with `load`, so
+//! deployments don't hard-code addresses in source.
+//!
+//! Devices on other subnets, eg. an IoT VLAN, can be found by listing their
+//! addresses in `search_ranges`, and `multicast_search` can be turned off
+//! where multicast isn't routed at all. See the README.
+
+use device::SerialNumber;
+use device::switch::Switch;
+use error::WemoError;
+use net::range::Ipv4Range;
+use net::soap::SOAP_ENCODING;
+use net::ssdp::UPNP_PORT;
+#[cfg(feature = "serde_json")] use serde_json;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::RwLock;
+use time::Duration;
+use toml::{self, Table, Value};
+
+/// Default Wemo API ports (HTTP), in the order they're tried.
+/// Wemo devices change ports occasionally by incrementing the port number.
+pub const DEFAULT_PORTS: [u16; 4] = [49153, 49152, 49154, 49155];
+
+/// The `User-Agent` sent unless configured otherwise.
+pub const DEFAULT_USER_AGENT: &'static str =
+    concat!("wemo.rs/", env!("CARGO_PKG_VERSION"));
+
+/// The URN of the service that gets and sets a device's state.
+pub const BASIC_EVENT_SERVICE: &'static str =
+    "urn:Belkin:service:basicevent:1";
+
+/// Defaults for device communication.
+#[derive(Clone, Debug)]
+pub struct WemoConfig {
+  /// Timeout used by the methods that don't take one, eg.
+  /// `Switch::turn_on_default`.
+  pub default_timeout: Duration,
+
+  /// How the methods that don't take a timeout handle failed requests.
+  pub retry_policy: RetryPolicy,
+
+  /// Ports to try, in order, when a device's port isn't known.
+  pub default_ports: Vec<u16>,
+
+  /// Sent as the `User-Agent` header of requests to devices, if set.
+  pub user_agent: Option<String>,
+
+  /// Extra headers sent with every request to devices, before any added to
+  /// an individual `Switch` or `Subscriptions`.
+  pub default_headers: Vec<(String, String)>,
+
+  /// Where SSDP searches are sent. The UPnP multicast group by default.
+  pub ssdp_address: SocketAddr,
+
+  /// Also send SSDP searches directly to every address in these ranges, to
+  /// find devices on other subnets that multicast doesn't reach, eg. an IoT
+  /// VLAN. They're sent to the port of `ssdp_address`.
+  pub search_ranges: Vec<Ipv4Range>,
+
+  /// Whether SSDP searches are multicast to `ssdp_address`. Turn this off on
+  /// networks that block or don't route multicast, to only search
+  /// `search_ranges`.
+  pub multicast_search: bool,
+
+  /// How strictly device responses are parsed.
+  pub parsing_mode: ParsingMode,
+
+  /// The service URN that `GetBinaryState` and `SetBinaryState` are sent
+  /// to, and that strict parsing expects their responses in.
+  pub basic_event_service: String,
+
+  /// The `encodingStyle` of SOAP envelopes sent to devices, if any. The
+  /// standard SOAP encoding by default.
+  pub soap_encoding_style: Option<String>,
+
+  /// Stop sending requests to devices that keep failing, if set.
+  pub circuit_breaker: Option<CircuitBreakerPolicy>,
+
+  /// If set, errors from responses that couldn't be parsed carry up to this
+  /// many bytes of the raw body as `WemoError::UnexpectedResponse`.
+  pub error_body_limit: Option<usize>,
+
+  /// Before changing the state of a dynamic-IP device at an address it
+  /// hasn't been identified at, check that its `setup.xml` reports the
+  /// expected UDN or serial number. See `Switch::verify_identity`.
+  pub verify_identity: bool,
+}
+
+/// How strictly device responses are parsed. No mode panics on malformed
+/// input.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ParsingMode {
+  /// Malformed values are errors.
+  #[default]
+  Standard,
+  /// Malformed values degrade where possible, eg. an unreadable state becomes
+  /// `WemoState::Unknown` and invalid UTF-8 in SSDP responses is replaced.
+  Lenient,
+  /// Like `Standard`, but SOAP responses must also have a well-formed
+  /// envelope containing the expected action response element and service
+  /// namespace. Useful for debugging firmware oddities or impostor devices.
+  Strict,
+}
+
+/// When to stop sending requests to a device that keeps failing. After
+/// `failure_threshold` consecutive failures, requests fail immediately with
+/// `WemoError::CircuitOpen` until `cool_down` has passed. Then one request is
+/// let through: if it succeeds requests resume, otherwise the cool-down
+/// starts again.
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerPolicy {
+  pub failure_threshold: u32,
+  pub cool_down: Duration,
+}
+
+/// How failed requests are retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+  /// Whether to relocate the device via SSDP and try again after a failed
+  /// request.
+  pub relocate_on_failure: bool,
+
+  /// The most time a first attempt gets before the device is relocated and
+  /// tried again, out of the caller's timeout. Raise it on congested WiFi;
+  /// lower it on a wired LAN to fail over sooner.
+  pub first_attempt_timeout: Duration,
+
+  /// After a relocation fails to find the device, fail further relocations
+  /// immediately for this long instead of searching again. An SSDP
+  /// announcement from the device ends the wait early. Zero searches every
+  /// time.
+  pub negative_cache_ttl: Duration,
+}
+
+impl Default for WemoConfig {
+  fn default() -> WemoConfig {
+    WemoConfig {
+      default_timeout: Duration::seconds(3),
+      retry_policy: RetryPolicy::default(),
+      default_ports: DEFAULT_PORTS.to_vec(),
+      user_agent: Some(DEFAULT_USER_AGENT.to_string()),
+      default_headers: Vec::new(),
+      ssdp_address: SocketAddr::new(
+          IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), UPNP_PORT),
+      search_ranges: Vec::new(),
+      multicast_search: true,
+      parsing_mode: ParsingMode::Standard,
+      basic_event_service: BASIC_EVENT_SERVICE.to_string(),
+      soap_encoding_style: Some(SOAP_ENCODING.to_string()),
+      circuit_breaker: None,
+      error_body_limit: None,
+      verify_identity: false,
+    }
+  }
+}
+
+impl Default for RetryPolicy {
+  fn default() -> RetryPolicy {
+    RetryPolicy {
+      relocate_on_failure: true,
+      first_attempt_timeout: Duration::milliseconds(300),
+      negative_cache_ttl: Duration::seconds(30),
+    }
+  }
+}
+
+impl WemoConfig {
+  /// The `User-Agent`, if any, followed by the default headers.
+  pub fn request_headers(&self) -> Vec<(String, String)> {
+    let mut headers = Vec::with_capacity(self.default_headers.len() + 1);
+    if let Some(ref user_agent) = self.user_agent {
+      headers.push(("User-Agent".to_string(), user_agent.clone()));
+    }
+    headers.extend(self.default_headers.iter().cloned());
+    headers
+  }
+
+  /// The port to use when a device's port isn't known.
+  pub fn default_port(&self) -> u16 {
+    self.default_ports.get(0).cloned().unwrap_or(DEFAULT_PORTS[0])
+  }
+}
+
+lazy_static! {
+  static ref GLOBAL_CONFIG: RwLock<WemoConfig> =
+      RwLock::new(WemoConfig::default());
+}
+
+/// Set the defaults inherited by devices and searches created after this call.
+pub fn set_global_config(config: WemoConfig) {
+  match GLOBAL_CONFIG.write() {
+    Err(_) => {}, // Ignore. Shouldn't occur.
+    Ok(mut global) => { *global = config; },
+  }
+}
+
+/// The defaults inherited by newly created devices and searches.
+pub fn global_config() -> WemoConfig {
+  GLOBAL_CONFIG.read()
+      .map(|config| config.clone())
+      .unwrap_or_default()
+}
+
+/// What kind of device a definition declares.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DeviceKind {
+  #[default]
+  Switch,
+  Insight,
+}
+
+impl DeviceKind {
+  fn from_name(name: &str) -> Option<DeviceKind> {
+    match name {
+      "switch" => Some(DeviceKind::Switch),
+      "insight" => Some(DeviceKind::Insight),
+      _ => None,
+    }
+  }
+
+  fn name(&self) -> &'static str {
+    match *self {
+      DeviceKind::Switch => "switch",
+      DeviceKind::Insight => "insight",
+    }
+  }
+}
+
+/// A device declared in a device file. In TOML:
+///
+/// ```toml
+/// [[devices]]
+/// name = "Porch light"
+/// serial_number = "221517K0101769"
+/// ip_address = "192.168.1.4"
+/// port = 49153             # Optional.
+/// kind = "switch"          # Optional: "switch" (default) or "insight".
+/// aliases = ["porch"]      # Optional.
+/// zones = ["downstairs"]   # Optional.
+/// ```
+///
+/// Or in JSON: `{"devices": [{"serial_number": "221517K0101769", ...}]}`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeviceDefinition {
+  pub name: Option<String>,
+  pub serial_number: SerialNumber,
+  /// Treated as static.
+  pub ip_address: IpAddr,
+  pub port: Option<u16>,
+  pub kind: DeviceKind,
+  pub aliases: Vec<String>,
+  /// Rooms or other groups the device belongs to.
+  pub zones: Vec<String>,
+}
+
+impl DeviceDefinition {
+  /// A device at the declared static address, with the declared serial
+  /// number.
+  pub fn to_switch(&self) -> Switch {
+    let mut switch = match self.port {
+      None => Switch::from_static_ip(self.ip_address),
+      Some(port) => Switch::from_static_ip_and_port(self.ip_address, port),
+    };
+    switch.serial_number = Some(self.serial_number.clone());
+    switch
+  }
+
+  fn from_table(table: &Table) -> Result<DeviceDefinition, String> {
+    for key in table.keys() {
+      match key.as_str() {
+        "name" | "serial_number" | "ip_address" | "port" | "kind"
+            | "aliases" | "zones" => {},
+        _ => { return Err(format!("unknown key '{}'", key)); },
+      }
+    }
+
+    let ip_address = required_string(table, "ip_address")?;
+    let ip_address = ip_address.parse()
+        .map_err(|_| format!("invalid ip_address '{}'", ip_address))?;
+
+    let port = match table.get("port") {
+      None => None,
+      Some(&Value::Integer(port)) if port > 0 && port <= 65535 => {
+        Some(port as u16)
+      },
+      Some(_) => {
+        return Err("port must be a number from 1 to 65535".to_string());
+      },
+    };
+
+    let kind = match optional_string(table, "kind")? {
+      None => DeviceKind::Switch,
+      Some(kind) => {
+        DeviceKind::from_name(&kind)
+            .ok_or_else(|| format!("unknown kind '{}'", kind))?
+      },
+    };
+
+    Ok(DeviceDefinition {
+      name: optional_string(table, "name")?,
+      serial_number: required_string(table, "serial_number")?,
+      ip_address: ip_address,
+      port: port,
+      kind: kind,
+      aliases: strings(table, "aliases")?,
+      zones: strings(table, "zones")?,
+    })
+  }
+}
+
+/// An optional array of strings.
+fn strings(table: &Table, key: &str) -> Result<Vec<String>, String> {
+  match table.get(key) {
+    None => Ok(Vec::new()),
+    Some(&Value::Array(ref values)) => {
+      values.iter()
+          .map(|value| match *value {
+            Value::String(ref string) => Ok(string.clone()),
+            _ => Err(format!("{} must be strings", key)),
+          })
+          .collect()
+    },
+    Some(_) => Err(format!("{} must be an array", key)),
+  }
+}
+
+fn optional_string(table: &Table, key: &str) -> Result<Option<String>, String> {
+  match table.get(key) {
+    None => Ok(None),
+    Some(&Value::String(ref value)) => Ok(Some(value.clone())),
+    Some(_) => Err(format!("{} must be a string", key)),
+  }
+}
+
+fn required_string(table: &Table, key: &str) -> Result<String, String> {
+  optional_string(table, key)?.ok_or_else(|| format!("missing {}", key))
+}
+
+/// Read the devices declared in a file. Files ending in `.json` are read as
+/// JSON, which needs the `serde_json` feature; anything else as TOML.
+pub fn load<P: AsRef<Path>>(path: P)
+    -> Result<Vec<DeviceDefinition>, WemoError> {
+  let path = path.as_ref();
+  let mut contents = String::new();
+  File::open(path)?.read_to_string(&mut contents)?;
+
+  match path.extension().and_then(|extension| extension.to_str()) {
+    Some("json") => parse_json(&contents),
+    _ => parse_toml(&contents),
+  }
+}
+
+/// Read the devices declared in a TOML document.
+pub fn parse_toml(input: &str) -> Result<Vec<DeviceDefinition>, WemoError> {
+  let tables = toml::parse(input).map_err(config_error)?;
+
+  let mut definitions = Vec::new();
+  for (i, &(ref name, ref table)) in tables.iter().enumerate() {
+    if name != "devices" {
+      return Err(config_error(format!("unknown table '{}'", name)));
+    }
+    definitions.push(DeviceDefinition::from_table(table)
+        .map_err(|reason| config_error(format!("device {}: {}", i + 1,
+            reason)))?);
+  }
+  Ok(definitions)
+}
+
+/// Read the devices declared in a JSON document.
+#[cfg(feature = "serde_json")]
+pub fn parse_json(input: &str) -> Result<Vec<DeviceDefinition>, WemoError> {
+  let document: serde_json::Value = serde_json::from_str(input)
+      .map_err(|e| config_error(e.to_string()))?;
+
+  let devices = document.as_object()
+      .and_then(|document| document.get("devices"))
+      .and_then(|devices| devices.as_array())
+      .ok_or_else(|| config_error("expected {\"devices\": [...]}"))?;
+
+  let mut definitions = Vec::new();
+  for (i, device) in devices.iter().enumerate() {
+    let table = device.as_object()
+        .ok_or_else(|| "expected an object".to_string())
+        .and_then(|object| {
+          object.iter()
+              .map(|(key, value)| Ok((key.clone(), from_json(key, value)?)))
+              .collect::<Result<Table, String>>()
+        })
+        .and_then(|table| DeviceDefinition::from_table(&table))
+        .map_err(|reason| config_error(format!("device {}: {}", i + 1,
+            reason)))?;
+    definitions.push(table);
+  }
+  Ok(definitions)
+}
+
+/// Always an error: JSON device files need the `serde_json` feature.
+#[cfg(not(feature = "serde_json"))]
+pub fn parse_json(_input: &str)
+    -> Result<Vec<DeviceDefinition>, WemoError> {
+  Err(config_error("JSON device files need the serde_json feature"))
+}
+
+#[cfg(feature = "serde_json")]
+fn from_json(key: &str, value: &serde_json::Value) -> Result<Value, String> {
+  match *value {
+    serde_json::Value::String(ref s) => Ok(Value::String(s.clone())),
+    serde_json::Value::Bool(b) => Ok(Value::Boolean(b)),
+    serde_json::Value::I64(n) => Ok(Value::Integer(n)),
+    serde_json::Value::U64(n) if n <= i64::MAX as u64 => {
+      Ok(Value::Integer(n as i64))
+    },
+    serde_json::Value::Array(ref values) => {
+      values.iter()
+          .map(|value| from_json(key, value))
+          .collect::<Result<Vec<_>, _>>()
+          .map(Value::Array)
+    },
+    _ => Err(format!("unsupported value for {}", key)),
+  }
+}
+
+/// Write device definitions to a file in the format `load` reads: JSON for
+/// files ending in `.json`, otherwise TOML.
+pub fn save<P: AsRef<Path>>(path: P, definitions: &[DeviceDefinition])
+    -> Result<(), WemoError> {
+  let path = path.as_ref();
+  let contents = match path.extension().and_then(|e| e.to_str()) {
+    Some("json") => to_json(definitions)?,
+    _ => to_toml(definitions),
+  };
+
+  File::create(path)?.write_all(contents.as_bytes())?;
+  Ok(())
+}
+
+/// Encode device definitions as TOML, as read by `parse_toml`.
+pub fn to_toml(definitions: &[DeviceDefinition]) -> String {
+  let mut toml = String::new();
+
+  for definition in definitions.iter() {
+    if !toml.is_empty() {
+      toml.push('\n');
+    }
+    toml.push_str("[[devices]]\n");
+    if let Some(ref name) = definition.name {
+      toml.push_str(&format!("name = {}\n", toml_string(name)));
+    }
+    toml.push_str(&format!("serial_number = {}\n",
+        toml_string(&definition.serial_number)));
+    toml.push_str(&format!("ip_address = \"{}\"\n", definition.ip_address));
+    if let Some(port) = definition.port {
+      toml.push_str(&format!("port = {}\n", port));
+    }
+    toml.push_str(&format!("kind = \"{}\"\n", definition.kind.name()));
+    for &(key, values) in [("aliases", &definition.aliases),
+        ("zones", &definition.zones)].iter() {
+      if !values.is_empty() {
+        let values = values.iter()
+            .map(|value| toml_string(value))
+            .collect::<Vec<_>>();
+        toml.push_str(&format!("{} = [{}]\n", key, values.join(", ")));
+      }
+    }
+  }
+
+  toml
+}
+
+fn toml_string(value: &str) -> String {
+  let mut string = String::with_capacity(value.len() + 2);
+  string.push('"');
+  for c in value.chars() {
+    match c {
+      '"' => string.push_str("\\\""),
+      '\\' => string.push_str("\\\\"),
+      '\n' => string.push_str("\\n"),
+      '\r' => string.push_str("\\r"),
+      '\t' => string.push_str("\\t"),
+      c => string.push(c),
+    }
+  }
+  string.push('"');
+  string
+}
+
+#[cfg(feature = "serde_json")]
+fn to_json(definitions: &[DeviceDefinition]) -> Result<String, WemoError> {
+  use serde_json::builder::{ArrayBuilder, ObjectBuilder};
+
+  let mut devices = ArrayBuilder::new();
+  for definition in definitions.iter() {
+    let mut device = ObjectBuilder::new();
+    if let Some(ref name) = definition.name {
+      device = device.insert("name", name);
+    }
+    device = device
+        .insert("serial_number", &definition.serial_number)
+        .insert("ip_address", definition.ip_address.to_string());
+    if let Some(port) = definition.port {
+      device = device.insert("port", port);
+    }
+    device = device
+        .insert("kind", definition.kind.name())
+        .insert("aliases", &definition.aliases)
+        .insert("zones", &definition.zones);
+    devices = devices.push(device.build());
+  }
+
+  Ok(ObjectBuilder::new().insert("devices", devices.build()).build()
+      .to_string())
+}
+
+#[cfg(not(feature = "serde_json"))]
+fn to_json(_definitions: &[DeviceDefinition]) -> Result<String, WemoError> {
+  Err(config_error("JSON device files need the serde_json feature"))
+}
+
+fn config_error<S: Into<String>>(reason: S) -> WemoError {
+  WemoError::ConfigError { reason: reason.into() }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_default_port() {
+    let mut config = WemoConfig::default();
+    assert_eq!(49153, config.default_port());
+
+    config.default_ports = vec![1234, 5678];
+    assert_eq!(1234, config.default_port());
+
+    config.default_ports = Vec::new();
+    assert_eq!(49153, config.default_port());
+  }
+
+  #[test]
+  fn test_request_headers() {
+    let mut config = WemoConfig::default();
+    assert_eq!(vec![("User-Agent".to_string(),
+        DEFAULT_USER_AGENT.to_string())], config.request_headers());
+
+    config.user_agent = None;
+    config.default_headers = vec![("Connection".to_string(),
+        "close".to_string())];
+    assert_eq!(config.default_headers, config.request_headers());
+  }
+
+  #[test]
+  fn test_parse_toml() {
+    let definitions = parse_toml(r#"
+      [[devices]]
+      name = "Porch light"
+      serial_number = "221517K0101769"
+      ip_address = "192.168.1.4"
+      port = 49153
+      aliases = ["porch", "front"]
+
+      [[devices]]
+      serial_number = "231442K1200494"
+      ip_address = "192.168.1.5"
+      kind = "insight"
+      zones = ["downstairs"]
+    "#).unwrap();
+
+    assert_eq!(vec![
+      DeviceDefinition {
+        name: Some("Porch light".to_string()),
+        serial_number: "221517K0101769".to_string(),
+        ip_address: "192.168.1.4".parse().unwrap(),
+        port: Some(49153),
+        kind: DeviceKind::Switch,
+        aliases: vec!["porch".to_string(), "front".to_string()],
+        zones: Vec::new(),
+      },
+      DeviceDefinition {
+        name: None,
+        serial_number: "231442K1200494".to_string(),
+        ip_address: "192.168.1.5".parse().unwrap(),
+        port: None,
+        kind: DeviceKind::Insight,
+        aliases: Vec::new(),
+        zones: vec!["downstairs".to_string()],
+      },
+    ], definitions);
+
+    let switch = definitions[0].to_switch();
+    assert_eq!(Some("221517K0101769".to_string()), switch.serial_number);
+    assert_eq!(Some(49153), switch.get_port());
+  }
+
+  #[test]
+  fn test_parse_toml_errors() {
+    fn reason(input: &str) -> String {
+      match parse_toml(input) {
+        Err(WemoError::ConfigError { reason }) => reason,
+        other => panic!("unexpected {:?}", other),
+      }
+    }
+
+    assert_eq!("device 1: missing serial_number",
+        reason("[[devices]]\nip_address = \"192.168.1.4\""));
+    assert_eq!("device 1: invalid ip_address 'porch'",
+        reason("[[devices]]\nserial_number = \"1\"\nip_address = \"porch\""));
+    assert_eq!("device 1: unknown kind 'toaster'",
+        reason(concat!("[[devices]]\nserial_number = \"1\"\n",
+            "ip_address = \"192.168.1.4\"\nkind = \"toaster\"")));
+    assert_eq!("unknown table 'device'", reason("[[device]]"));
+  }
+
+  #[test]
+  fn test_to_toml() {
+    let definitions = vec![
+      DeviceDefinition {
+        name: Some("Porch \"front\" light".to_string()),
+        serial_number: "221517K0101769".to_string(),
+        ip_address: "192.168.1.4".parse().unwrap(),
+        port: Some(49153),
+        kind: DeviceKind::Insight,
+        aliases: vec!["porch".to_string(), "front".to_string()],
+        zones: Vec::new(),
+      },
+      DeviceDefinition {
+        name: None,
+        serial_number: "231442K1200494".to_string(),
+        ip_address: "192.168.1.5".parse().unwrap(),
+        port: None,
+        kind: DeviceKind::Switch,
+        aliases: Vec::new(),
+        zones: vec!["upstairs".to_string(), "bedroom".to_string()],
+      },
+    ];
+
+    assert_eq!(definitions, parse_toml(&to_toml(&definitions)).unwrap());
+  }
+
+  #[cfg(feature = "serde_json")]
+  #[test]
+  fn test_parse_json() {
+    let definitions = parse_json(r#"{"devices": [{"name": "Porch light",
+        "serial_number": "221517K0101769", "ip_address": "192.168.1.4",
+        "port": 49153, "aliases": ["porch"]}]}"#).unwrap();
+
+    assert_eq!(1, definitions.len());
+    assert_eq!(Some(49153), definitions[0].port);
+    assert_eq!(vec!["porch".to_string()], definitions[0].aliases);
+  }
+}
@@ -0,0 +1,261 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Just enough cryptography to encrypt a WiFi password the way WeMo setup
+//! expects: AES-128-CBC with an OpenSSL `enc` style key, then base64. Only
+//! encryption is needed, and pulling in OpenSSL for one password isn't worth
+//! it, so this is written out here. It isn't constant-time and shouldn't be
+//! used for anything else.
+
+/// Encrypt `plaintext` like `openssl enc -aes-128-cbc -md md5 -S <salt>
+/// -iv <iv> -pass pass:<password>`, without the `Salted__` header. The key
+/// comes from OpenSSL's `EVP_BytesToKey` with MD5 and one round.
+pub fn openssl_aes128_cbc(plaintext: &[u8], password: &[u8], salt: &[u8],
+                          iv: &[u8; 16]) -> Vec<u8> {
+  let mut keying = Vec::with_capacity(password.len() + salt.len());
+  keying.extend_from_slice(password);
+  keying.extend_from_slice(salt);
+  let key = md5(&keying);
+
+  aes128_cbc_encrypt(plaintext, &key, iv)
+}
+
+/// AES-128 in CBC mode with PKCS#7 padding.
+fn aes128_cbc_encrypt(plaintext: &[u8], key: &[u8; 16], iv: &[u8; 16])
+    -> Vec<u8> {
+  let round_keys = expand_key(key);
+  let padding = 16 - plaintext.len() % 16;
+
+  let mut padded = plaintext.to_vec();
+  padded.resize(plaintext.len() + padding, padding as u8);
+
+  let mut previous = *iv;
+  let mut ciphertext = Vec::with_capacity(padded.len());
+  for chunk in padded.chunks(16) {
+    let mut block = [0; 16];
+    for i in 0..16 {
+      block[i] = chunk[i] ^ previous[i];
+    }
+    encrypt_block(&mut block, &round_keys);
+    ciphertext.extend_from_slice(&block);
+    previous = block;
+  }
+  ciphertext
+}
+
+const SBOX: [u8; 256] = [
+  0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b,
+  0xfe, 0xd7, 0xab, 0x76, 0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0,
+  0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0, 0xb7, 0xfd, 0x93, 0x26,
+  0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+  0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2,
+  0xeb, 0x27, 0xb2, 0x75, 0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0,
+  0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84, 0x53, 0xd1, 0x00, 0xed,
+  0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+  0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f,
+  0x50, 0x3c, 0x9f, 0xa8, 0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5,
+  0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2, 0xcd, 0x0c, 0x13, 0xec,
+  0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+  0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14,
+  0xde, 0x5e, 0x0b, 0xdb, 0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c,
+  0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79, 0xe7, 0xc8, 0x37, 0x6d,
+  0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+  0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f,
+  0x4b, 0xbd, 0x8b, 0x8a, 0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e,
+  0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e, 0xe1, 0xf8, 0x98, 0x11,
+  0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+  0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f,
+  0xb0, 0x54, 0xbb, 0x16,
+];
+
+/// The round constants for key expansion.
+const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b,
+    0x36];
+
+fn expand_key(key: &[u8; 16]) -> [[u8; 16]; 11] {
+  let mut round_keys = [[0; 16]; 11];
+  round_keys[0] = *key;
+
+  for round in 1..11 {
+    let previous = round_keys[round - 1];
+    let mut word = [previous[12], previous[13], previous[14], previous[15]];
+    word.rotate_left(1);
+    for byte in word.iter_mut() {
+      *byte = SBOX[*byte as usize];
+    }
+    word[0] ^= RCON[round - 1];
+
+    let mut next = [0; 16];
+    for i in 0..16 {
+      let left = if i < 4 { word[i] } else { next[i - 4] };
+      next[i] = previous[i] ^ left;
+    }
+    round_keys[round] = next;
+  }
+  round_keys
+}
+
+fn encrypt_block(block: &mut [u8; 16], round_keys: &[[u8; 16]; 11]) {
+  add_round_key(block, &round_keys[0]);
+  for (round, round_key) in round_keys.iter().enumerate().skip(1) {
+    for byte in block.iter_mut() {
+      *byte = SBOX[*byte as usize];
+    }
+    shift_rows(block);
+    if round < 10 {
+      mix_columns(block);
+    }
+    add_round_key(block, round_key);
+  }
+}
+
+fn add_round_key(block: &mut [u8; 16], round_key: &[u8; 16]) {
+  for i in 0..16 {
+    block[i] ^= round_key[i];
+  }
+}
+
+/// The block is in column-major order, so row `r` is every fourth byte from
+/// `r`.
+fn shift_rows(block: &mut [u8; 16]) {
+  let state = *block;
+  for column in 0..4 {
+    for row in 0..4 {
+      block[column * 4 + row] = state[((column + row) % 4) * 4 + row];
+    }
+  }
+}
+
+fn mix_columns(block: &mut [u8; 16]) {
+  for column in block.chunks_mut(4) {
+    let a = [column[0], column[1], column[2], column[3]];
+    let b = [double(a[0]), double(a[1]), double(a[2]), double(a[3])];
+    column[0] = b[0] ^ a[1] ^ b[1] ^ a[2] ^ a[3];
+    column[1] = a[0] ^ b[1] ^ a[2] ^ b[2] ^ a[3];
+    column[2] = a[0] ^ a[1] ^ b[2] ^ a[3] ^ b[3];
+    column[3] = a[0] ^ b[0] ^ a[1] ^ a[2] ^ b[3];
+  }
+}
+
+/// Multiply by two in AES's finite field.
+fn double(byte: u8) -> u8 {
+  let shifted = byte << 1;
+  if byte & 0x80 != 0 { shifted ^ 0x1b } else { shifted }
+}
+
+fn md5(data: &[u8]) -> [u8; 16] {
+  const SHIFTS: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+    5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20,
+    4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+    6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+  ];
+  const CONSTANTS: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee,
+    0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be,
+    0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa,
+    0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed,
+    0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c,
+    0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05,
+    0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039,
+    0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1,
+    0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+  ];
+
+  let mut message = data.to_vec();
+  message.push(0x80);
+  while message.len() % 64 != 56 {
+    message.push(0);
+  }
+  message.extend_from_slice(&((data.len() as u64) * 8).to_le_bytes());
+
+  let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+
+  for chunk in message.chunks(64) {
+    let words = (0..16)
+        .map(|i| u32::from_le_bytes([chunk[i * 4], chunk[i * 4 + 1],
+            chunk[i * 4 + 2], chunk[i * 4 + 3]]))
+        .collect::<Vec<_>>();
+
+    let [mut a, mut b, mut c, mut d] = state;
+    for i in 0..64 {
+      let (f, g) = match i / 16 {
+        0 => ((b & c) | (!b & d), i),
+        1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+        2 => (b ^ c ^ d, (3 * i + 5) % 16),
+        _ => (c ^ (b | !d), (7 * i) % 16),
+      };
+      let rotated = a.wrapping_add(f)
+          .wrapping_add(CONSTANTS[i])
+          .wrapping_add(words[g])
+          .rotate_left(SHIFTS[i]);
+      a = d;
+      d = c;
+      c = b;
+      b = b.wrapping_add(rotated);
+    }
+
+    state[0] = state[0].wrapping_add(a);
+    state[1] = state[1].wrapping_add(b);
+    state[2] = state[2].wrapping_add(c);
+    state[3] = state[3].wrapping_add(d);
+  }
+
+  let mut digest = [0; 16];
+  for (i, word) in state.iter().enumerate() {
+    digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+  }
+  digest
+}
+
+#[cfg(test)]
+mod tests {
+  use base64;
+  use super::*;
+
+  fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+  }
+
+  #[test]
+  fn test_md5() {
+    assert_eq!("d41d8cd98f00b204e9800998ecf8427e", hex(&md5(b"")));
+    assert_eq!("9e107d9d372bb6826bd81d3542a419d6",
+        hex(&md5(b"The quick brown fox jumps over the lazy dog")));
+  }
+
+  #[test]
+  fn test_aes128() {
+    // FIPS-197, appendix C.1.
+    let key = [0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09,
+        0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f];
+    let mut block = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
+        0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+    encrypt_block(&mut block, &expand_key(&key));
+    assert_eq!("69c4e0d86a7b0430d8cdb78070b4c55a", hex(&block));
+  }
+
+  #[test]
+  fn test_openssl_aes128_cbc() {
+    // From `openssl enc -aes-128-cbc -md md5 -S ... -iv ... -pass ...`.
+    let password = b"EC1A59221517K0101769F0A1B2";
+    let mut iv = [0; 16];
+    iv.copy_from_slice(&password[..16]);
+
+    let encrypt = |plaintext: &[u8]| {
+      let encrypted =
+          openssl_aes128_cbc(plaintext, password, &password[..8], &iv);
+      base64::encode(&encrypted)
+    };
+    assert_eq!("uXg0Ks1a5Vvhe+4Gv2X5gw==", encrypt(b"hunter22"));
+    assert_eq!("LRctuj4Z7uqbSFsv8cbXGBorMsGGwNmfw4VV2RJW/Is=",
+        encrypt(b"correct horse battery staple"));
+    assert_eq!("j+Wydn32YsNOR4hxbTLp9A==", encrypt(b""));
+  }
+}
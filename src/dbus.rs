@@ -0,0 +1,819 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Exposes a `Registry` over D-Bus for desktop integration, so scripts can
+//! control devices with eg. `gdbus` or `dbus-send` without linking Rust. The
+//! service owns the name `org.wemo` and serves the object `/org/wemo/Devices`
+//! with the interface `org.wemo.Devices1`:
+//!
+//! * `List() -> as`: the serial numbers of known devices.
+//! * `GetState(s serial) -> s`
+//! * `TurnOn(s serial) -> s`, `TurnOff(s serial) -> s`, `Toggle(s serial) -> s`
+//! * Signal `StateChanged(s serial, s state)`, sent with
+//!   `DbusService::emit_state_changed`.
+//!
+//! States are `WemoState::description` strings, eg. `"on"`. Only Unix socket
+//! bus addresses (`unix:path=...`) are supported.
+
+use device::state::WemoState;
+use device::switch::Switch;
+use error::WemoError;
+use registry::Registry;
+use std::env;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use threads;
+
+/// The well-known bus name requested by the service.
+pub const BUS_NAME: &'static str = "org.wemo";
+/// The object devices are served on.
+pub const OBJECT_PATH: &'static str = "/org/wemo/Devices";
+/// The interface devices are served with.
+pub const INTERFACE: &'static str = "org.wemo.Devices1";
+
+/// How often the service thread wakes to check whether it was stopped.
+const STOP_CHECK_MS: u64 = 200;
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+
+const NO_REPLY_EXPECTED: u8 = 0x1;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// Refuse messages larger than this, rather than allocating for them.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+const INTROSPECTION: &'static str = "\
+<!DOCTYPE node PUBLIC \"-//freedesktop//DTD D-BUS Object Introspection 1.0//EN\"
+ \"http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd\">
+<node>
+  <interface name=\"org.wemo.Devices1\">
+    <method name=\"List\"><arg type=\"as\" direction=\"out\"/></method>
+    <method name=\"GetState\">
+      <arg name=\"serial\" type=\"s\" direction=\"in\"/>
+      <arg name=\"state\" type=\"s\" direction=\"out\"/>
+    </method>
+    <method name=\"TurnOn\">
+      <arg name=\"serial\" type=\"s\" direction=\"in\"/>
+      <arg name=\"state\" type=\"s\" direction=\"out\"/>
+    </method>
+    <method name=\"TurnOff\">
+      <arg name=\"serial\" type=\"s\" direction=\"in\"/>
+      <arg name=\"state\" type=\"s\" direction=\"out\"/>
+    </method>
+    <method name=\"Toggle\">
+      <arg name=\"serial\" type=\"s\" direction=\"in\"/>
+      <arg name=\"state\" type=\"s\" direction=\"out\"/>
+    </method>
+    <signal name=\"StateChanged\">
+      <arg name=\"serial\" type=\"s\"/>
+      <arg name=\"state\" type=\"s\"/>
+    </signal>
+  </interface>
+  <interface name=\"org.freedesktop.DBus.Introspectable\">
+    <method name=\"Introspect\"><arg type=\"s\" direction=\"out\"/></method>
+  </interface>
+</node>
+";
+
+/// Serves a `Registry` on a D-Bus connection from a background thread, which
+/// stops when dropped.
+pub struct DbusService {
+  connection: Arc<Connection>,
+  stopped: Arc<AtomicBool>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl DbusService {
+  /// Serve on the session bus, from `DBUS_SESSION_BUS_ADDRESS`. Fails with
+  /// `WemoError::ConfigError` if it isn't set.
+  pub fn start(registry: Arc<Registry>) -> Result<DbusService, WemoError> {
+    let address = env::var("DBUS_SESSION_BUS_ADDRESS")
+        .map_err(|_| WemoError::ConfigError {
+          reason: "DBUS_SESSION_BUS_ADDRESS isn't set".to_string(),
+        })?;
+    DbusService::start_at(&address, registry)
+  }
+
+  /// Serve on the bus at `address`, eg.
+  /// `unix:path=/var/run/dbus/system_bus_socket` for the system bus. Only
+  /// `unix:path=` addresses are supported; others fail with
+  /// `WemoError::InvalidArgument`.
+  pub fn start_at(address: &str, registry: Arc<Registry>)
+      -> Result<DbusService, WemoError> {
+    let path = address.split(';')
+        .filter_map(|address| address.trim().split(',')
+            .find(|part| part.starts_with("unix:path="))
+            .map(|part| part["unix:path=".len()..].to_string()))
+        .next()
+        .ok_or_else(|| WemoError::InvalidArgument {
+          reason: format!("unsupported D-Bus address '{}', expected \
+              unix:path=", address),
+        })?;
+
+    let stream = UnixStream::connect(path)?;
+    let connection = Arc::new(Connection::open(stream)?);
+    connection.request_name(BUS_NAME)?;
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stop = stopped.clone();
+    let serving = connection.clone();
+
+    let handle = threads::spawn("wemo-dbus", move || {
+      while !stop.load(Ordering::SeqCst) {
+        let message = match serving.receive() {
+          Ok(message) => { message },
+          Err(WemoError::IoError { ref cause }) if is_timeout(cause) => {
+            continue;
+          },
+          Err(WemoError::BadResponseError) => { continue; }, // Unsupported.
+          Err(error) => {
+            error!(target: "wemo", thread = "wemo-dbus";
+                "D-Bus connection failed, stopping: {:?}", error);
+            return;
+          },
+        };
+
+        if message.message_type == METHOD_CALL {
+          let reply = dispatch(&registry, &message);
+          if message.flags & NO_REPLY_EXPECTED == 0 {
+            let _r = serving.send(reply);
+          }
+        }
+      }
+    });
+
+    Ok(DbusService {
+      connection: connection,
+      stopped: stopped,
+      handle: Some(handle),
+    })
+  }
+
+  /// Broadcast the `StateChanged` signal, eg. from a subscription callback.
+  pub fn emit_state_changed(&self, serial_number: &str, state: &WemoState)
+      -> Result<(), WemoError> {
+    let mut body = Writer::new();
+    body.string(serial_number);
+    body.string(state.description());
+
+    let mut signal = Message::new(SIGNAL);
+    signal.path = Some(OBJECT_PATH.to_string());
+    signal.interface = Some(INTERFACE.to_string());
+    signal.member = Some("StateChanged".to_string());
+    signal.signature = "ss".to_string();
+    signal.body = body.buf;
+
+    self.connection.send(signal).map(|_| ())
+  }
+
+  /// Stop serving.
+  pub fn stop(&mut self) {
+    self.stopped.store(true, Ordering::SeqCst);
+    if let Some(handle) = self.handle.take() {
+      let _r = handle.join();
+    }
+  }
+}
+
+impl Drop for DbusService {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
+
+/// Answer a method call.
+fn dispatch(registry: &Registry, call: &Message) -> Message {
+  let interface = call.interface.as_ref().map(|i| i.as_str());
+  let member = call.member.as_ref().map(|m| m.as_str()).unwrap_or("");
+
+  match (interface, member) {
+    (Some("org.freedesktop.DBus.Introspectable"), "Introspect")
+        | (None, "Introspect") => {
+      let mut body = Writer::new();
+      body.string(INTROSPECTION);
+      call.reply("s", body.buf)
+    },
+    (Some(INTERFACE), "List") | (None, "List") => {
+      let mut serials = registry.devices().iter()
+          .filter_map(|switch| switch.serial_number.clone())
+          .collect::<Vec<_>>();
+      serials.sort();
+
+      let mut body = Writer::new();
+      body.string_array(&serials);
+      call.reply("as", body.buf)
+    },
+    (Some(INTERFACE), action) | (None, action) => {
+      let serial = match Reader::new(&call.body).string() {
+        Some(ref serial) if call.signature == "s" => serial.clone(),
+        _ => {
+          return call.error("org.freedesktop.DBus.Error.InvalidArgs",
+              "Expected a serial number");
+        },
+      };
+
+      let switch = match registry.get(&serial) {
+        None => {
+          return call.error("org.wemo.Error.UnknownDevice",
+              &format!("No device with serial number {}", serial));
+        },
+        Some(switch) => { switch },
+      };
+
+      let result = match action {
+        "GetState" => switch.get_state_default(),
+        "TurnOn" => switch.turn_on_default(),
+        "TurnOff" => switch.turn_off_default(),
+        "Toggle" => switch.toggle_default(),
+        _ => {
+          return call.error("org.freedesktop.DBus.Error.UnknownMethod",
+              &format!("No method {}", action));
+        },
+      };
+
+      state_reply(call, &switch, result)
+    },
+    _ => {
+      call.error("org.freedesktop.DBus.Error.UnknownMethod",
+          &format!("No method {}", member))
+    },
+  }
+}
+
+fn state_reply(call: &Message, switch: &Switch,
+               result: Result<WemoState, WemoError>) -> Message {
+  match result {
+    Err(e) => {
+      call.error("org.wemo.Error.Failed",
+          &format!("Request to {} failed: {:?}", switch.name(), e))
+    },
+    Ok(state) => {
+      let mut body = Writer::new();
+      body.string(state.description());
+      call.reply("s", body.buf)
+    },
+  }
+}
+
+/// An authenticated connection to a message bus.
+struct Connection {
+  reader: Mutex<Inbox>,
+  writer: Mutex<UnixStream>,
+  next_serial: AtomicUsize,
+}
+
+impl Connection {
+  /// Authenticate as the current user and say hello to the bus.
+  fn open(stream: UnixStream) -> Result<Connection, WemoError> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = stream;
+    reader.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    writer.write_all(b"\0")?;
+    writer.write_all(format!("AUTH EXTERNAL {}\r\n",
+        hex(current_uid()?.to_string().as_bytes())).as_bytes())?;
+
+    let line = read_line(&mut reader)?;
+    if !line.starts_with("OK ") {
+      return Err(WemoError::BadResponseError);
+    }
+    writer.write_all(b"BEGIN\r\n")?;
+
+    let connection = Connection {
+      reader: Mutex::new(Inbox::new(reader)),
+      writer: Mutex::new(writer),
+      next_serial: AtomicUsize::new(1),
+    };
+
+    connection.call_bus("Hello", "", Vec::new())?;
+
+    connection.reader.lock()
+        .map_err(|_| WemoError::LockError)?
+        .stream
+        .set_read_timeout(Some(Duration::from_millis(STOP_CHECK_MS)))?;
+
+    Ok(connection)
+  }
+
+  fn request_name(&self, name: &str) -> Result<(), WemoError> {
+    let mut body = Writer::new();
+    body.string(name);
+    body.u32(0x4); // DBUS_NAME_FLAG_DO_NOT_QUEUE
+
+    let reply = self.call_bus("RequestName", "su", body.buf)?;
+
+    // 1 is DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER.
+    match Reader::new(&reply.body).u32() {
+      Some(1) => Ok(()),
+      _ => Err(WemoError::BadResponseError),
+    }
+  }
+
+  /// Call a method on the bus itself and wait for the reply.
+  fn call_bus(&self, member: &str, signature: &str, body: Vec<u8>)
+      -> Result<Message, WemoError> {
+    let mut call = Message::new(METHOD_CALL);
+    call.destination = Some("org.freedesktop.DBus".to_string());
+    call.path = Some("/org/freedesktop/DBus".to_string());
+    call.interface = Some("org.freedesktop.DBus".to_string());
+    call.member = Some(member.to_string());
+    call.signature = signature.to_string();
+    call.body = body;
+
+    let serial = self.send(call)?;
+
+    loop {
+      let message = self.receive()?;
+      if message.reply_serial != Some(serial) {
+        continue;
+      }
+      return match message.message_type {
+        METHOD_RETURN => Ok(message),
+        _ => Err(WemoError::BadResponseError),
+      };
+    }
+  }
+
+  /// Send a message, returning the serial it was sent with.
+  fn send(&self, mut message: Message) -> Result<u32, WemoError> {
+    message.serial = self.next_serial.fetch_add(1, Ordering::SeqCst) as u32;
+    let encoded = message.encode();
+
+    self.writer.lock()
+        .map_err(|_| WemoError::LockError)?
+        .write_all(&encoded)?;
+
+    Ok(message.serial)
+  }
+
+  fn receive(&self) -> Result<Message, WemoError> {
+    self.reader.lock()
+        .map_err(|_| WemoError::LockError)?
+        .receive()
+  }
+}
+
+/// The reading side of a connection. Bytes are kept across reads that time
+/// out partway through a message, so the stream stays in step.
+struct Inbox {
+  stream: UnixStream,
+  buffer: Vec<u8>,
+}
+
+impl Inbox {
+  fn new(stream: UnixStream) -> Inbox {
+    Inbox {
+      stream: stream,
+      buffer: Vec::new(),
+    }
+  }
+
+  /// The next message. A read timeout is returned as an `IoError`, and a
+  /// message that isn't supported is skipped and returned as
+  /// `BadResponseError`.
+  fn receive(&mut self) -> Result<Message, WemoError> {
+    let mut chunk = [0u8; 4096];
+    loop {
+      if let Some(result) = take_message(&mut self.buffer) {
+        return result;
+      }
+
+      match self.stream.read(&mut chunk)? {
+        0 => { return Err(io::Error::from(ErrorKind::UnexpectedEof).into()); },
+        amount => self.buffer.extend_from_slice(&chunk[..amount]),
+      }
+    }
+  }
+}
+
+/// Remove the first message from `buffer` once it has arrived in full.
+fn take_message(buffer: &mut Vec<u8>) -> Option<Result<Message, WemoError>> {
+  if buffer.len() < 16 {
+    return None;
+  }
+
+  let length = |bytes: &[u8]| {
+    let mut fixed = [0u8; 4];
+    fixed.copy_from_slice(bytes);
+    match buffer[0] {
+      b'l' => Some(u32::from_le_bytes(fixed) as usize),
+      b'B' => Some(u32::from_be_bytes(fixed) as usize),
+      _ => None,
+    }
+  };
+
+  let (body_length, fields_length) =
+      match (length(&buffer[4..8]), length(&buffer[12..16])) {
+        (Some(body_length), Some(fields_length)) => {
+          (body_length, fields_length)
+        },
+        _ => {
+          // Nothing to find the next message by.
+          return Some(Err(io::Error::from(ErrorKind::InvalidData).into()));
+        },
+      };
+
+  let total = align(16 + fields_length, 8) + body_length;
+  if total > MAX_MESSAGE_SIZE {
+    return Some(Err(io::Error::from(ErrorKind::InvalidData).into()));
+  }
+  if buffer.len() < total {
+    return None;
+  }
+
+  let encoded = buffer.drain(..total).collect::<Vec<u8>>();
+  if encoded[0] != b'l' {
+    return Some(Err(WemoError::BadResponseError));
+  }
+  Some(Message::decode(&encoded).ok_or(WemoError::BadResponseError))
+}
+
+/// A D-Bus message. Only little-endian messages with the header field and
+/// body types used by the service are supported.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Message {
+  message_type: u8,
+  flags: u8,
+  serial: u32,
+  path: Option<String>,
+  interface: Option<String>,
+  member: Option<String>,
+  error_name: Option<String>,
+  reply_serial: Option<u32>,
+  destination: Option<String>,
+  sender: Option<String>,
+  signature: String,
+  body: Vec<u8>,
+}
+
+impl Message {
+  fn new(message_type: u8) -> Message {
+    Message { message_type: message_type, ..Message::default() }
+  }
+
+  fn reply(&self, signature: &str, body: Vec<u8>) -> Message {
+    let mut reply = Message::new(METHOD_RETURN);
+    reply.reply_serial = Some(self.serial);
+    reply.destination = self.sender.clone();
+    reply.signature = signature.to_string();
+    reply.body = body;
+    reply
+  }
+
+  fn error(&self, error_name: &str, description: &str) -> Message {
+    let mut body = Writer::new();
+    body.string(description);
+
+    let mut error = self.reply("s", body.buf);
+    error.message_type = ERROR;
+    error.error_name = Some(error_name.to_string());
+    error
+  }
+
+  fn encode(&self) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.u8(b'l');
+    w.u8(self.message_type);
+    w.u8(self.flags);
+    w.u8(1); // Protocol version.
+    w.u32(self.body.len() as u32);
+    w.u32(self.serial);
+
+    let length_at = w.buf.len();
+    w.u32(0);
+    w.align(8);
+    let fields_start = w.buf.len();
+
+    let strings = [
+      (FIELD_PATH, "o", &self.path),
+      (FIELD_INTERFACE, "s", &self.interface),
+      (FIELD_MEMBER, "s", &self.member),
+      (FIELD_ERROR_NAME, "s", &self.error_name),
+      (FIELD_DESTINATION, "s", &self.destination),
+      (FIELD_SENDER, "s", &self.sender),
+    ];
+
+    for &(code, signature, value) in strings.iter() {
+      if let Some(ref value) = *value {
+        w.align(8);
+        w.u8(code);
+        w.signature(signature);
+        w.string(value);
+      }
+    }
+
+    if let Some(reply_serial) = self.reply_serial {
+      w.align(8);
+      w.u8(FIELD_REPLY_SERIAL);
+      w.signature("u");
+      w.u32(reply_serial);
+    }
+
+    if !self.signature.is_empty() {
+      w.align(8);
+      w.u8(FIELD_SIGNATURE);
+      w.signature("g");
+      w.signature(&self.signature);
+    }
+
+    let fields_length = (w.buf.len() - fields_start) as u32;
+    w.set_u32(length_at, fields_length);
+    w.align(8);
+    w.buf.extend_from_slice(&self.body);
+    w.buf
+  }
+
+  fn decode(encoded: &[u8]) -> Option<Message> {
+    let mut r = Reader::new(encoded);
+    if r.u8()? != b'l' {
+      return None;
+    }
+
+    let mut message = Message::new(r.u8()?);
+    message.flags = r.u8()?;
+    let _version = r.u8()?;
+    let body_length = r.u32()? as usize;
+    message.serial = r.u32()?;
+
+    let fields_length = r.u32()? as usize;
+    r.align(8);
+    let fields_end = r.pos + fields_length;
+
+    while r.pos < fields_end {
+      r.align(8);
+      let code = r.u8()?;
+      let signature = r.signature()?;
+
+      match (code, signature.as_str()) {
+        (FIELD_REPLY_SERIAL, "u") => { message.reply_serial = Some(r.u32()?); },
+        (FIELD_SIGNATURE, "g") => { message.signature = r.signature()?; },
+        (_, "s") | (_, "o") => {
+          let value = Some(r.string()?);
+          match code {
+            FIELD_PATH => { message.path = value; },
+            FIELD_INTERFACE => { message.interface = value; },
+            FIELD_MEMBER => { message.member = value; },
+            FIELD_ERROR_NAME => { message.error_name = value; },
+            FIELD_DESTINATION => { message.destination = value; },
+            FIELD_SENDER => { message.sender = value; },
+            _ => {}, // Unknown fields are ignored.
+          }
+        },
+        _ => { return None; },
+      }
+    }
+
+    r.align(8);
+    let body = encoded.get(r.pos..r.pos + body_length)?;
+    message.body = body.to_vec();
+    Some(message)
+  }
+}
+
+/// Marshals little-endian values, aligned relative to the start of the
+/// buffer, which must be the start of the message or of its body.
+struct Writer {
+  buf: Vec<u8>,
+}
+
+impl Writer {
+  fn new() -> Writer {
+    Writer { buf: Vec::new() }
+  }
+
+  fn align(&mut self, alignment: usize) {
+    let aligned = align(self.buf.len(), alignment);
+    self.buf.resize(aligned, 0);
+  }
+
+  fn u8(&mut self, value: u8) {
+    self.buf.push(value);
+  }
+
+  fn u32(&mut self, value: u32) {
+    self.align(4);
+    for shift in 0..4 {
+      self.buf.push((value >> (shift * 8)) as u8);
+    }
+  }
+
+  fn set_u32(&mut self, at: usize, value: u32) {
+    for shift in 0..4 {
+      self.buf[at + shift] = (value >> (shift * 8)) as u8;
+    }
+  }
+
+  fn string(&mut self, value: &str) {
+    self.u32(value.len() as u32);
+    self.buf.extend_from_slice(value.as_bytes());
+    self.buf.push(0);
+  }
+
+  fn signature(&mut self, value: &str) {
+    self.buf.push(value.len() as u8);
+    self.buf.extend_from_slice(value.as_bytes());
+    self.buf.push(0);
+  }
+
+  fn string_array(&mut self, values: &[String]) {
+    let length_at = self.buf.len();
+    self.u32(0);
+    let start = self.buf.len();
+    for value in values {
+      self.string(value);
+    }
+    let length = (self.buf.len() - start) as u32;
+    self.set_u32(align(length_at, 4), length);
+  }
+}
+
+/// Unmarshals what `Writer` writes.
+struct Reader<'a> {
+  buf: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn new(buf: &'a [u8]) -> Reader<'a> {
+    Reader { buf: buf, pos: 0 }
+  }
+
+  fn align(&mut self, alignment: usize) {
+    self.pos = align(self.pos, alignment);
+  }
+
+  fn u8(&mut self) -> Option<u8> {
+    let value = *self.buf.get(self.pos)?;
+    self.pos += 1;
+    Some(value)
+  }
+
+  fn u32(&mut self) -> Option<u32> {
+    self.align(4);
+    let bytes = self.buf.get(self.pos..self.pos + 4)?;
+    self.pos += 4;
+    Some(bytes.iter().rev().fold(0, |value, &b| value << 8 | b as u32))
+  }
+
+  fn string(&mut self) -> Option<String> {
+    let length = self.u32()? as usize;
+    let bytes = self.buf.get(self.pos..self.pos + length)?;
+    self.pos += length + 1;
+    String::from_utf8(bytes.to_vec()).ok()
+  }
+
+  fn signature(&mut self) -> Option<String> {
+    let length = self.u8()? as usize;
+    let bytes = self.buf.get(self.pos..self.pos + length)?;
+    self.pos += length + 1;
+    String::from_utf8(bytes.to_vec()).ok()
+  }
+}
+
+fn align(position: usize, alignment: usize) -> usize {
+  (position + alignment - 1) / alignment * alignment
+}
+
+/// The uid we're running as, for EXTERNAL authentication.
+fn current_uid() -> Result<u32, WemoError> {
+  Ok(fs::metadata("/proc/self")?.uid())
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Read one line of the authentication handshake.
+fn is_timeout(error: &io::Error) -> bool {
+  error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut
+}
+
+fn read_line(reader: &mut UnixStream) -> Result<String, WemoError> {
+  let mut line = Vec::new();
+  let mut byte = [0u8; 1];
+  while !line.ends_with(b"\r\n") {
+    reader.read_exact(&mut byte)?;
+    line.push(byte[0]);
+    if line.len() > 1024 {
+      return Err(WemoError::BadResponseError);
+    }
+  }
+  Ok(String::from_utf8_lossy(&line).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use registry::Registry;
+  use super::*;
+
+  fn call(member: &str, serial: Option<&str>) -> Message {
+    let mut call = Message::new(METHOD_CALL);
+    call.serial = 7;
+    call.path = Some(OBJECT_PATH.to_string());
+    call.interface = Some(INTERFACE.to_string());
+    call.member = Some(member.to_string());
+    call.sender = Some(":1.42".to_string());
+
+    if let Some(serial) = serial {
+      let mut body = Writer::new();
+      body.string(serial);
+      call.signature = "s".to_string();
+      call.body = body.buf;
+    }
+    call
+  }
+
+  #[test]
+  fn test_start_at_unsupported_address() {
+    let registry = Arc::new(Registry::new());
+    match DbusService::start_at("tcp:host=localhost,port=1234", registry) {
+      Err(WemoError::InvalidArgument { reason }) => {
+        assert!(reason.contains("tcp:host=localhost"));
+      },
+      Err(other) => panic!("unexpected {:?}", other),
+      Ok(_) => panic!("unexpected service"),
+    }
+  }
+
+  #[test]
+  fn test_encode_decode() {
+    let mut message = call("GetState", Some("221517K0101769"));
+    message.reply_serial = Some(3);
+
+    assert_eq!(Some(message.clone()), Message::decode(&message.encode()));
+  }
+
+  #[test]
+  fn test_inbox() {
+    let (mut peer, stream) = UnixStream::pair().unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    let mut inbox = Inbox::new(stream);
+
+    let message = call("GetState", Some("221517K0101769"));
+    let encoded = message.encode();
+
+    // A big-endian message is skipped whole.
+    let mut big_endian = encoded.clone();
+    big_endian[0] = b'B';
+    for range in [4..8, 12..16].iter() {
+      big_endian[range.clone()].reverse();
+    }
+    peer.write_all(&big_endian).unwrap();
+
+    // A read timing out partway through a message loses nothing.
+    peer.write_all(&encoded[..20]).unwrap();
+    match inbox.receive() {
+      Err(WemoError::BadResponseError) => {},
+      other => panic!("expected an unsupported message, got {:?}", other),
+    }
+    match inbox.receive() {
+      Err(WemoError::IoError { ref cause }) if is_timeout(cause) => {},
+      other => panic!("expected a timeout, got {:?}", other),
+    }
+    peer.write_all(&encoded[20..]).unwrap();
+    assert_eq!(message, inbox.receive().unwrap());
+
+    drop(peer);
+    match inbox.receive() {
+      Err(WemoError::IoError { ref cause }) => assert!(!is_timeout(cause)),
+      other => panic!("expected the connection to close, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_dispatch() {
+    let registry = Registry::new();
+
+    let reply = dispatch(&registry, &call("List", None));
+    assert_eq!(METHOD_RETURN, reply.message_type);
+    assert_eq!(Some(7), reply.reply_serial);
+    assert_eq!(Some(":1.42".to_string()), reply.destination);
+    assert_eq!("as", reply.signature);
+    assert_eq!(Some(0), Reader::new(&reply.body).u32());
+
+    let reply = dispatch(&registry, &call("TurnOn", Some("MISSING")));
+    assert_eq!(ERROR, reply.message_type);
+    assert_eq!(Some("org.wemo.Error.UnknownDevice".to_string()),
+        reply.error_name);
+
+    let reply = dispatch(&registry, &call("TurnOn", None));
+    assert_eq!(Some("org.freedesktop.DBus.Error.InvalidArgs".to_string()),
+        reply.error_name);
+  }
+}
@@ -0,0 +1,119 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use error::{TimeoutStage, WemoError};
+use std::time::{Duration as StdDuration, Instant};
+use time::Duration;
+
+/// The time by which a whole operation has to finish, shared by each of its
+/// steps, eg. a first attempt, a relocation, and a retry. Each step gets the
+/// time that's left instead of its own timeout, so the total never exceeds
+/// the caller's.
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline {
+  start: Instant,
+  end: Instant,
+}
+
+impl Deadline {
+  /// A deadline `timeout` from now.
+  pub fn after(timeout: Duration) -> Deadline {
+    let timeout = timeout.to_std().unwrap_or(StdDuration::from_secs(0));
+    let start = Instant::now();
+    Deadline {
+      start: start,
+      end: start + timeout,
+    }
+  }
+
+  /// The time left, or zero once the deadline has passed.
+  pub fn remaining(&self) -> Duration {
+    let remaining = self.end.saturating_duration_since(Instant::now());
+    Duration::from_std(remaining).unwrap_or(Duration::zero())
+  }
+
+  /// The time left, but no more than `limit`, eg. to keep a first attempt
+  /// short while leaving time for a retry.
+  pub fn capped(&self, limit: Duration) -> Duration {
+    self.remaining().min(limit)
+  }
+
+  pub fn is_expired(&self) -> bool {
+    self.remaining() <= Duration::zero()
+  }
+
+  /// The time since the deadline was set.
+  pub fn elapsed(&self) -> Duration {
+    Duration::from(self.start.elapsed())
+  }
+
+  /// The time left, or `WemoError::TimeoutError` in the `Request` stage if
+  /// there's none.
+  pub fn check(&self) -> Result<Duration, WemoError> {
+    self.check_for(TimeoutStage::Request)
+  }
+
+  /// The time left, or `WemoError::TimeoutError` in `stage` if there's none.
+  pub fn check_for(&self, stage: TimeoutStage) -> Result<Duration, WemoError> {
+    let remaining = self.remaining();
+    if remaining <= Duration::zero() {
+      Err(self.timed_out(stage))
+    } else {
+      Ok(remaining)
+    }
+  }
+
+  /// A `WemoError::TimeoutError` for running out of time in `stage`.
+  pub fn timed_out(&self, stage: TimeoutStage) -> WemoError {
+    WemoError::timeout(stage, self.elapsed())
+  }
+}
+
+/// A deadline at `end`, set now.
+impl From<Instant> for Deadline {
+  fn from(end: Instant) -> Deadline {
+    Deadline {
+      start: Instant::now(),
+      end: end,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+  use super::*;
+
+  #[test]
+  fn test_remaining() {
+    let deadline = Deadline::after(Duration::milliseconds(60));
+    assert!(deadline.remaining() <= Duration::milliseconds(60));
+    assert_eq!(Duration::milliseconds(10),
+        deadline.capped(Duration::milliseconds(10)));
+    assert!(deadline.check().is_ok());
+
+    thread::sleep(StdDuration::from_millis(80));
+    assert_eq!(Duration::zero(), deadline.remaining());
+    assert!(deadline.is_expired());
+    assert!(deadline.elapsed() >= Duration::milliseconds(80));
+    match deadline.check_for(TimeoutStage::Search) {
+      Err(WemoError::TimeoutError { stage, elapsed, retry_after }) => {
+        assert_eq!(TimeoutStage::Search, stage);
+        assert!(elapsed >= Duration::milliseconds(80));
+        assert_eq!(TimeoutStage::Search.retry_after(), retry_after);
+      },
+      other => panic!("unexpected {:?}", other),
+    }
+
+    assert!(Deadline::after(Duration::milliseconds(-5)).is_expired());
+  }
+
+  #[test]
+  fn test_from_instant() {
+    let deadline = Deadline::from(Instant::now() + StdDuration::from_secs(10));
+    assert!(deadline.remaining() > Duration::seconds(9));
+    assert!(deadline.remaining() <= Duration::seconds(10));
+    assert!(deadline.elapsed() < Duration::seconds(1));
+
+    assert!(Deadline::from(Instant::now()).check().is_err());
+  }
+}
@@ -0,0 +1,194 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use device::SerialNumber;
+use device::sampler::{InsightSample, InsightSampler};
+use device::switch::Switch;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration as StdDuration, SystemTime};
+use time::Duration;
+
+/// Which side of a `PowerAlert` threshold a device's power draw is on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PowerLevel {
+  High,
+  Low,
+}
+
+/// Fires a callback when an Insight's power draw crosses a threshold, eg. to
+/// tell when a washer has finished. Feed it samples with `observe`, or have
+/// it sample devices itself with `watch`. Each device is tracked separately,
+/// by serial number. A device's first sample only establishes its level;
+/// callbacks fire on changes after that.
+pub struct PowerAlert {
+  high_mw: i64,
+  low_mw: i64,
+  hold: StdDuration,
+  callback: Box<Fn(&InsightSample, PowerLevel) + Send + Sync>,
+  devices: Mutex<HashMap<Option<SerialNumber>, Tracker>>,
+}
+
+#[derive(Default)]
+struct Tracker {
+  level: Option<PowerLevel>,
+  /// A different level seen since, waiting out the hold duration.
+  pending: Option<(PowerLevel, SystemTime)>,
+}
+
+impl PowerAlert {
+  /// Fire when power draw rises to `threshold_mw` milliwatts or more, or
+  /// drops below it.
+  pub fn new<F>(threshold_mw: i64, callback: F) -> PowerAlert
+      where F: Fn(&InsightSample, PowerLevel) + Send + Sync + 'static {
+    PowerAlert {
+      high_mw: threshold_mw,
+      low_mw: threshold_mw,
+      hold: StdDuration::from_secs(0),
+      callback: Box::new(callback),
+      devices: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Only count power as low once it drops `margin_mw` below the threshold,
+  /// so readings hovering around the threshold don't fire repeatedly.
+  pub fn with_hysteresis(mut self, margin_mw: i64) -> PowerAlert {
+    self.low_mw = self.high_mw - margin_mw.max(0);
+    self
+  }
+
+  /// Only fire once the new level has held for this long, eg. two minutes
+  /// for a washer that pauses between cycles.
+  pub fn with_duration(mut self, hold: Duration) -> PowerAlert {
+    self.hold = hold.to_std().unwrap_or(StdDuration::from_secs(0));
+    self
+  }
+
+  /// Account for a new sample, firing the callback if the device's level
+  /// changed.
+  pub fn observe(&self, sample: &InsightSample) {
+    let power = sample.params.current_power;
+    let changed = match self.devices.lock() {
+      Err(_) => None, // Ignore.
+      Ok(mut devices) => {
+        let tracker = devices.entry(sample.serial_number.clone())
+            .or_insert_with(Tracker::default);
+        self.update(tracker, power, sample.taken_at)
+      },
+    };
+
+    if let Some(level) = changed {
+      (self.callback)(sample, level);
+    }
+  }
+
+  /// Sample the devices every `interval` and observe each sample until the
+  /// returned sampler is dropped. Failed samples are ignored.
+  pub fn watch(self, switches: Vec<Arc<Switch>>, interval: Duration,
+               timeout: Duration) -> InsightSampler {
+    InsightSampler::start(switches, interval, timeout,
+        move |_switch: &Switch, result| {
+          if let Ok(sample) = result {
+            self.observe(&sample);
+          }
+        })
+  }
+
+  /// Returns the new level if the change should fire.
+  fn update(&self, tracker: &mut Tracker, power: i64, taken_at: SystemTime)
+      -> Option<PowerLevel> {
+    let observed = if power >= self.high_mw {
+      PowerLevel::High
+    } else if power < self.low_mw {
+      PowerLevel::Low
+    } else {
+      // Between the thresholds: no change.
+      match tracker.level {
+        Some(level) => level,
+        None => PowerLevel::Low,
+      }
+    };
+
+    let current = match tracker.level {
+      None => {
+        tracker.level = Some(observed);
+        return None;
+      },
+      Some(current) => current,
+    };
+
+    if observed == current {
+      tracker.pending = None;
+      return None;
+    }
+
+    let since = match tracker.pending {
+      Some((level, since)) if level == observed => since,
+      _ => {
+        tracker.pending = Some((observed, taken_at));
+        taken_at
+      },
+    };
+
+    let held = taken_at.duration_since(since)
+        .unwrap_or(StdDuration::from_secs(0));
+    if held < self.hold {
+      return None;
+    }
+
+    tracker.level = Some(observed);
+    tracker.pending = None;
+    Some(observed)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use device::insight::InsightParams;
+  use device::state::WemoState;
+  use std::sync::mpsc::channel;
+  use std::time::UNIX_EPOCH;
+  use super::*;
+
+  fn sample(current_power: i64, secs: u64) -> InsightSample {
+    InsightSample {
+      serial_number: Some("231442K1200494".to_string()),
+      state: WemoState::On,
+      params: InsightParams::from_fields(
+          &["0", "0", "0", "0", "0", "0", &current_power.to_string(), "0",
+            "0"]).unwrap(),
+      taken_at: UNIX_EPOCH + StdDuration::from_secs(secs),
+    }
+  }
+
+  #[test]
+  fn test_hysteresis_and_duration() {
+    let (sender, receiver) = channel();
+    let sender = Mutex::new(sender);
+    let alert = PowerAlert::new(5_000, move |sample, level| {
+          let secs = sample.taken_at.duration_since(UNIX_EPOCH).unwrap();
+          let _r = sender.lock().unwrap().send((level, secs.as_secs()));
+        })
+        .with_hysteresis(1_000)
+        .with_duration(Duration::minutes(2));
+
+    // Running, then a short pause that doesn't last long enough.
+    for &(power, secs) in [(200_000, 0), (3_000, 60), (150_000, 120)].iter() {
+      alert.observe(&sample(power, secs));
+    }
+    // Hovering between the thresholds isn't low.
+    alert.observe(&sample(4_500, 180));
+    alert.observe(&sample(4_500, 400));
+    // Finished.
+    alert.observe(&sample(1_000, 500));
+    alert.observe(&sample(1_000, 560));
+    alert.observe(&sample(1_000, 620));
+    alert.observe(&sample(1_000, 680));
+    // Starts again.
+    alert.observe(&sample(150_000, 900));
+    alert.observe(&sample(150_000, 1020));
+
+    drop(alert);
+    assert_eq!(vec![(PowerLevel::Low, 620), (PowerLevel::High, 1020)],
+        receiver.iter().collect::<Vec<_>>());
+  }
+}
@@ -0,0 +1,259 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use device::SerialNumber;
+use device::sampler::{InsightSample, InsightSampler};
+use device::state::WemoState;
+use device::switch::Switch;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration as StdDuration, SystemTime};
+use time::Duration;
+
+/// What the appliance plugged into an Insight is doing, going by its power
+/// draw.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ApplianceState {
+  /// The Insight is off, or the appliance draws less than the standby
+  /// threshold.
+  Off,
+  /// Drawing power, but less than the running threshold, eg. a dryer's
+  /// display.
+  Standby,
+  Running,
+}
+
+/// An appliance's change from one state to another.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ApplianceEvent {
+  pub from: ApplianceState,
+  pub to: ApplianceState,
+  /// How long the appliance ran, when it stops running.
+  pub ran_for: Option<StdDuration>,
+}
+
+impl ApplianceEvent {
+  /// Whether the appliance started running, eg. woke from standby.
+  pub fn is_started(&self) -> bool {
+    self.to == ApplianceState::Running
+  }
+
+  /// Whether the appliance stopped running, eg. the dryer finished.
+  pub fn is_finished(&self) -> bool {
+    self.from == ApplianceState::Running
+  }
+}
+
+/// Tells when the appliance plugged into an Insight starts and finishes, eg.
+/// to announce that the dryer is done. Like `PowerAlert`, feed it samples
+/// with `observe` or have it sample devices itself with `watch`; each device
+/// is tracked separately, and its first sample only establishes its state.
+pub struct ApplianceMonitor {
+  running_mw: i64,
+  standby_mw: i64,
+  debounce: StdDuration,
+  callback: Box<Fn(&InsightSample, &ApplianceEvent) + Send + Sync>,
+  devices: Mutex<HashMap<Option<SerialNumber>, Tracker>>,
+}
+
+#[derive(Default)]
+struct Tracker {
+  state: Option<ApplianceState>,
+  /// A different state seen since, waiting out the debounce duration.
+  pending: Option<(ApplianceState, SystemTime)>,
+  running_since: Option<SystemTime>,
+}
+
+impl ApplianceMonitor {
+  /// Count the appliance as running while it draws `running_mw` milliwatts
+  /// or more.
+  pub fn new<F>(running_mw: i64, callback: F) -> ApplianceMonitor
+      where F: Fn(&InsightSample, &ApplianceEvent) + Send + Sync + 'static {
+    ApplianceMonitor {
+      running_mw: running_mw,
+      standby_mw: 0,
+      debounce: StdDuration::from_secs(0),
+      callback: Box::new(callback),
+      devices: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Count the appliance as off while it draws less than `standby_mw`
+  /// milliwatts, even if the Insight is on, eg. when it's been turned off at
+  /// its own switch. By default, it's only off when the Insight is.
+  pub fn with_standby_threshold(mut self, standby_mw: i64)
+      -> ApplianceMonitor {
+    self.standby_mw = standby_mw;
+    self
+  }
+
+  /// Only change state once the new one has held for this long, eg. five
+  /// minutes for a washer that pauses between cycles.
+  pub fn with_debounce(mut self, debounce: Duration) -> ApplianceMonitor {
+    self.debounce = debounce.to_std().unwrap_or(StdDuration::from_secs(0));
+    self
+  }
+
+  /// The device's current state, once it's been sampled.
+  pub fn state(&self, serial_number: Option<&SerialNumber>)
+      -> Option<ApplianceState> {
+    self.devices.lock()
+        .ok()
+        .and_then(|devices| {
+          devices.get(&serial_number.cloned())
+              .and_then(|tracker| tracker.state)
+        })
+  }
+
+  /// Account for a new sample, firing the callback if the appliance changed
+  /// state.
+  pub fn observe(&self, sample: &InsightSample) {
+    let observed = self.classify(sample);
+    let event = match self.devices.lock() {
+      Err(_) => None, // Ignore.
+      Ok(mut devices) => {
+        let tracker = devices.entry(sample.serial_number.clone())
+            .or_insert_with(Tracker::default);
+        self.update(tracker, observed, sample.taken_at)
+      },
+    };
+
+    if let Some(event) = event {
+      (self.callback)(sample, &event);
+    }
+  }
+
+  /// Sample the devices every `interval` and observe each sample until the
+  /// returned sampler is dropped. Failed samples are ignored.
+  pub fn watch(self, switches: Vec<Arc<Switch>>, interval: Duration,
+               timeout: Duration) -> InsightSampler {
+    InsightSampler::start(switches, interval, timeout,
+        move |_switch: &Switch, result| {
+          if let Ok(sample) = result {
+            self.observe(&sample);
+          }
+        })
+  }
+
+  fn classify(&self, sample: &InsightSample) -> ApplianceState {
+    let power = sample.params.current_power;
+    match sample.state {
+      WemoState::Off => ApplianceState::Off,
+      _ if power >= self.running_mw => ApplianceState::Running,
+      _ if power < self.standby_mw => ApplianceState::Off,
+      _ => ApplianceState::Standby,
+    }
+  }
+
+  /// Returns the event if the change should fire.
+  fn update(&self, tracker: &mut Tracker, observed: ApplianceState,
+            taken_at: SystemTime) -> Option<ApplianceEvent> {
+    let current = match tracker.state {
+      None => {
+        tracker.state = Some(observed);
+        if observed == ApplianceState::Running {
+          tracker.running_since = Some(taken_at);
+        }
+        return None;
+      },
+      Some(current) => current,
+    };
+
+    if observed == current {
+      tracker.pending = None;
+      return None;
+    }
+
+    let since = match tracker.pending {
+      Some((state, since)) if state == observed => since,
+      _ => {
+        tracker.pending = Some((observed, taken_at));
+        taken_at
+      },
+    };
+
+    let held = taken_at.duration_since(since)
+        .unwrap_or(StdDuration::from_secs(0));
+    if held < self.debounce {
+      return None;
+    }
+
+    let ran_for = if current == ApplianceState::Running {
+      tracker.running_since.take()
+          .and_then(|running_since| since.duration_since(running_since).ok())
+    } else {
+      None
+    };
+    if observed == ApplianceState::Running {
+      tracker.running_since = Some(since);
+    }
+
+    tracker.state = Some(observed);
+    tracker.pending = None;
+    Some(ApplianceEvent {
+      from: current,
+      to: observed,
+      ran_for: ran_for,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use device::insight::InsightParams;
+  use std::sync::mpsc::channel;
+  use std::time::UNIX_EPOCH;
+  use super::*;
+
+  fn sample(state: WemoState, current_power: i64, secs: u64) -> InsightSample {
+    InsightSample {
+      serial_number: Some("231442K1200494".to_string()),
+      state: state,
+      params: InsightParams::from_fields(
+          &["0", "0", "0", "0", "0", "0", &current_power.to_string(), "0",
+            "0"]).unwrap(),
+      taken_at: UNIX_EPOCH + StdDuration::from_secs(secs),
+    }
+  }
+
+  #[test]
+  fn test_dryer_cycle() {
+    let (sender, receiver) = channel();
+    let sender = Mutex::new(sender);
+    let monitor = ApplianceMonitor::new(50_000, move |_sample, event| {
+          let _r = sender.lock().unwrap().send(*event);
+        })
+        .with_standby_threshold(500)
+        .with_debounce(Duration::minutes(2));
+
+    let readings = [
+      (WemoState::OnWithoutLoad, 2_000, 0), // Standby.
+      (WemoState::On, 3_000_000, 60), // Started...
+      (WemoState::On, 3_000_000, 180), // ...and held.
+      (WemoState::On, 2_000, 1_000), // Paused, but not for long enough.
+      (WemoState::On, 3_000_000, 1_060),
+      (WemoState::On, 2_000, 3_060), // Finished...
+      (WemoState::On, 2_000, 3_200), // ...and held.
+      (WemoState::Off, 0, 4_000),
+      (WemoState::Off, 0, 4_200),
+    ];
+    for &(ref state, power, secs) in readings.iter() {
+      monitor.observe(&sample(state.clone(), power, secs));
+    }
+
+    assert_eq!(Some(ApplianceState::Off),
+        monitor.state(Some(&"231442K1200494".to_string())));
+
+    drop(monitor);
+    let events = receiver.iter().collect::<Vec<_>>();
+    assert_eq!(3, events.len());
+    assert!(events[0].is_started());
+    assert_eq!(ApplianceState::Standby, events[0].from);
+    assert!(events[1].is_finished());
+    assert_eq!(Some(StdDuration::from_secs(3_000)), events[1].ran_for);
+    assert_eq!(ApplianceEvent {
+      from: ApplianceState::Standby,
+      to: ApplianceState::Off,
+      ran_for: None,
+    }, events[2]);
+  }
+}
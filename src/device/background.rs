@@ -0,0 +1,146 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Running blocking calls from async applications without tying the crate to
+//! an executor. Each call runs on its own thread and wakes the task when it
+//! finishes, so the futures work the same under tokio, async-std, or any
+//! other runtime.
+
+use cancel::CancellationToken;
+use error::WemoError;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use threads;
+
+/// A `Future` for a blocking call running on a background thread, eg.
+/// `Background::spawn(move || switch.turn_on(timeout))`. Dropping it cancels
+/// the call's `CancellationToken`, if it takes one.
+pub struct Background<T> {
+  shared: Arc<Mutex<Shared<T>>>,
+  cancellation: CancellationToken,
+}
+
+struct Shared<T> {
+  result: Option<Result<T, WemoError>>,
+  waker: Option<Waker>,
+}
+
+impl<T: Send + 'static> Background<T> {
+  pub fn spawn<F>(call: F) -> Background<T>
+      where F: FnOnce() -> T + Send + 'static {
+    Background::spawn_cancellable(move |_| call())
+  }
+
+  /// Run a call that can be cancelled, eg. `Switch::turn_on_cancellable`.
+  pub fn spawn_cancellable<F>(call: F) -> Background<T>
+      where F: FnOnce(&CancellationToken) -> T + Send + 'static {
+    let shared = Arc::new(Mutex::new(Shared {
+      result: None,
+      waker: None,
+    }));
+
+    let cancellation = CancellationToken::new();
+    let call_shared = shared.clone();
+    let call_cancellation = cancellation.clone();
+
+    threads::spawn("wemo-background", move || {
+      // A panic still completes the future, rather than leaving it pending.
+      let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            call(&call_cancellation)
+          }))
+          .map_err(|cause| {
+            let message = threads::panic_message(&*cause);
+            error!(target: "wemo", thread = "wemo-background";
+                "Background call panicked: {}", message);
+            WemoError::Panicked { message: message }
+          });
+
+      if let Ok(mut shared) = call_shared.lock() {
+        shared.result = Some(result);
+        if let Some(waker) = shared.waker.take() {
+          waker.wake();
+        }
+      }
+    });
+
+    Background {
+      shared: shared,
+      cancellation: cancellation,
+    }
+  }
+
+  /// Cancel the call, if it takes a `CancellationToken`.
+  pub fn cancel(&self) {
+    self.cancellation.cancel();
+  }
+}
+
+impl<T> Future for Background<T> {
+  type Output = Result<T, WemoError>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context)
+      -> Poll<Result<T, WemoError>> {
+    let mut shared = match self.shared.lock() {
+      Err(_) => { return Poll::Ready(Err(WemoError::LockError)); },
+      Ok(shared) => { shared },
+    };
+
+    match shared.result.take() {
+      Some(result) => Poll::Ready(result),
+      None => {
+        shared.waker = Some(cx.waker().clone());
+        Poll::Pending
+      },
+    }
+  }
+}
+
+impl<T> Drop for Background<T> {
+  fn drop(&mut self) {
+    self.cancellation.cancel();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::task::Wake;
+  use std::thread::{self, Thread};
+  use super::*;
+
+  struct ThreadWaker(Thread);
+
+  impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+      self.0.unpark();
+    }
+  }
+
+  fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+      match future.as_mut().poll(&mut cx) {
+        Poll::Ready(output) => { return output; },
+        Poll::Pending => thread::park(),
+      }
+    }
+  }
+
+  #[test]
+  fn test_spawn() {
+    assert_eq!(2, block_on(Background::spawn(|| 1 + 1)).unwrap());
+
+    let panicked = block_on(Background::spawn(|| -> u32 {
+      panic!("renewal failed")
+    }));
+    match panicked {
+      Err(WemoError::Panicked { message }) => {
+        assert_eq!("renewal failed", message);
+      },
+      other => panic!("expected a panic, got {:?}", other),
+    }
+  }
+}
@@ -0,0 +1,175 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use config::CircuitBreakerPolicy;
+use std::sync::Mutex;
+use time::PreciseTime;
+
+/// Whether requests to a device are being let through.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CircuitState {
+  /// Requests are sent as normal.
+  Closed,
+  /// The device failed repeatedly, so requests fail immediately until the
+  /// cool-down passes.
+  Open,
+  /// The cool-down passed and a single probe request is being let through.
+  /// Its result closes or reopens the circuit.
+  HalfOpen,
+}
+
+struct Inner {
+  consecutive_failures: u32,
+  opened_at: Option<PreciseTime>,
+  probing: bool,
+}
+
+/// Counts consecutive request failures for one device.
+pub struct CircuitBreaker {
+  inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+  pub fn new() -> CircuitBreaker {
+    CircuitBreaker {
+      inner: Mutex::new(Inner {
+        consecutive_failures: 0,
+        opened_at: None,
+        probing: false,
+      }),
+    }
+  }
+
+  /// The state under `policy`, without claiming the probe.
+  pub fn state(&self, policy: &CircuitBreakerPolicy) -> CircuitState {
+    match self.inner.lock() {
+      Err(_) => CircuitState::Closed,
+      Ok(inner) => {
+        match inner.opened_at {
+          None => CircuitState::Closed,
+          Some(_) if inner.probing => CircuitState::HalfOpen,
+          Some(opened_at) => {
+            if opened_at.to(PreciseTime::now()) < policy.cool_down {
+              CircuitState::Open
+            } else {
+              CircuitState::HalfOpen
+            }
+          },
+        }
+      },
+    }
+  }
+
+  /// Whether a request may be sent. Once the cool-down has passed, the first
+  /// caller is let through as the probe and the rest are refused until it
+  /// finishes.
+  pub fn allow(&self, policy: &CircuitBreakerPolicy) -> bool {
+    match self.inner.lock() {
+      Err(_) => true,
+      Ok(mut inner) => {
+        match inner.opened_at {
+          None => true,
+          Some(_) if inner.probing => false,
+          Some(opened_at) => {
+            if opened_at.to(PreciseTime::now()) < policy.cool_down {
+              false
+            } else {
+              inner.probing = true;
+              true
+            }
+          },
+        }
+      },
+    }
+  }
+
+  pub fn record_success(&self) {
+    match self.inner.lock() {
+      Err(_) => {}, // Ignore.
+      Ok(mut inner) => {
+        inner.consecutive_failures = 0;
+        inner.opened_at = None;
+        inner.probing = false;
+      },
+    }
+  }
+
+  /// Give up the probe without a result, eg. when it was cancelled, so that
+  /// the next caller probes instead.
+  pub fn release_probe(&self) {
+    match self.inner.lock() {
+      Err(_) => {}, // Ignore.
+      Ok(mut inner) => { inner.probing = false; },
+    }
+  }
+
+  pub fn record_failure(&self, policy: &CircuitBreakerPolicy) {
+    match self.inner.lock() {
+      Err(_) => {}, // Ignore.
+      Ok(mut inner) => {
+        inner.consecutive_failures =
+            inner.consecutive_failures.saturating_add(1);
+        if inner.probing
+            || inner.consecutive_failures >= policy.failure_threshold {
+          inner.opened_at = Some(PreciseTime::now());
+        }
+        inner.probing = false;
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use config::CircuitBreakerPolicy;
+  use std::thread;
+  use std::time::Duration as StdDuration;
+  use time::Duration;
+  use super::*;
+
+  #[test]
+  fn test_opens_and_probes() {
+    let policy = CircuitBreakerPolicy {
+      failure_threshold: 2,
+      cool_down: Duration::milliseconds(20),
+    };
+    let breaker = CircuitBreaker::new();
+
+    breaker.record_failure(&policy);
+    assert!(breaker.allow(&policy));
+    breaker.record_failure(&policy);
+    assert_eq!(CircuitState::Open, breaker.state(&policy));
+    assert!(!breaker.allow(&policy));
+
+    thread::sleep(StdDuration::from_millis(30));
+    assert_eq!(CircuitState::HalfOpen, breaker.state(&policy));
+    assert!(breaker.allow(&policy));
+    assert!(!breaker.allow(&policy));
+
+    // A failed probe reopens the circuit straight away.
+    breaker.record_failure(&policy);
+    assert_eq!(CircuitState::Open, breaker.state(&policy));
+
+    thread::sleep(StdDuration::from_millis(30));
+    assert!(breaker.allow(&policy));
+    breaker.record_success();
+    assert_eq!(CircuitState::Closed, breaker.state(&policy));
+  }
+
+  #[test]
+  fn test_release_probe() {
+    let policy = CircuitBreakerPolicy {
+      failure_threshold: 1,
+      cool_down: Duration::milliseconds(20),
+    };
+    let breaker = CircuitBreaker::new();
+
+    breaker.record_failure(&policy);
+    thread::sleep(StdDuration::from_millis(30));
+    assert!(breaker.allow(&policy));
+    assert!(!breaker.allow(&policy));
+
+    // An abandoned probe doesn't hold the circuit half-open for good.
+    breaker.release_probe();
+    assert!(breaker.allow(&policy));
+  }
+}
@@ -0,0 +1,311 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use device::service::{Action, ServiceDescription};
+use device::switch::Switch;
+use error::WemoError;
+use std::collections::BTreeMap;
+use time::Duration;
+use xml::{self, find_tag_value};
+
+/// An action argument or result, typed by the UPnP data type of its state
+/// variable: `boolean`, the signed (`i1` to `i8`, `int`) and unsigned (`ui1`
+/// to `ui8`) integers, and text for everything else.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ArgumentValue {
+  Boolean(bool),
+  Integer(i64),
+  UnsignedInteger(u64),
+  String(String),
+}
+
+impl From<bool> for ArgumentValue {
+  fn from(value: bool) -> ArgumentValue {
+    ArgumentValue::Boolean(value)
+  }
+}
+
+impl From<i64> for ArgumentValue {
+  fn from(value: i64) -> ArgumentValue {
+    ArgumentValue::Integer(value)
+  }
+}
+
+impl From<u64> for ArgumentValue {
+  fn from(value: u64) -> ArgumentValue {
+    ArgumentValue::UnsignedInteger(value)
+  }
+}
+
+impl<'a> From<&'a str> for ArgumentValue {
+  fn from(value: &'a str) -> ArgumentValue {
+    ArgumentValue::String(value.to_string())
+  }
+}
+
+impl From<String> for ArgumentValue {
+  fn from(value: String) -> ArgumentValue {
+    ArgumentValue::String(value)
+  }
+}
+
+/// Action arguments or results, by name.
+pub type Arguments = BTreeMap<String, ArgumentValue>;
+
+/// Calls any action of one of a device's services by name. Arguments are
+/// checked against the service's description before anything is sent:
+/// every input must be given, with a value of its type, and nothing else.
+pub struct ServiceClient<'a> {
+  switch: &'a Switch,
+  description: ServiceDescription,
+}
+
+impl<'a> ServiceClient<'a> {
+  pub fn new(switch: &'a Switch, description: ServiceDescription)
+      -> ServiceClient<'a> {
+    ServiceClient {
+      switch: switch,
+      description: description,
+    }
+  }
+
+  /// Look up and describe the device's service with the given type or ID,
+  /// eg. `urn:Belkin:service:basicevent:1` or
+  /// `urn:Belkin:serviceId:basicevent1`.
+  pub fn connect(switch: &'a Switch, service: &str, timeout: Duration)
+      -> Result<ServiceClient<'a>, WemoError> {
+    let found = switch.list_services(timeout)?
+        .into_iter()
+        .find(|s| s.service_type == service || s.service_id == service)
+        .ok_or_else(|| invalid(format!("no service {}", service)))?;
+
+    let description = switch.describe_service(&found, timeout)?;
+    Ok(ServiceClient::new(switch, description))
+  }
+
+  pub fn description(&self) -> &ServiceDescription {
+    &self.description
+  }
+
+  /// Call the action, returning the output arguments the device sent back.
+  /// Results that don't parse as their type, eg. a Boolean `BinaryState`
+  /// carrying Insight fields, are returned as text.
+  pub fn call(&self, action: &str, arguments: &Arguments, timeout: Duration)
+      -> Result<Arguments, WemoError> {
+    let action = self.description.action(action)
+        .ok_or_else(|| invalid(format!("{} has no action {}",
+            self.description.service.service_id, action)))?;
+
+    let encoded = encode_arguments(action, arguments)?;
+    let body = self.switch.send_action(&self.description.service,
+        &action.name, &encoded, timeout)?;
+
+    Ok(decode_results(action, &body))
+  }
+}
+
+/// What an argument's values may be, from its UPnP data type.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+  Boolean,
+  Integer(i64, i64),
+  UnsignedInteger(u64),
+  String,
+}
+
+impl Kind {
+  fn of(data_type: Option<&str>) -> Kind {
+    let data_type = data_type.map(|t| t.to_ascii_lowercase());
+    match data_type.as_deref() {
+      Some("boolean") => Kind::Boolean,
+      Some("i1") => Kind::Integer(i8::MIN as i64, i8::MAX as i64),
+      Some("i2") => Kind::Integer(i16::MIN as i64, i16::MAX as i64),
+      Some("i4") | Some("int") => {
+        Kind::Integer(i32::MIN as i64, i32::MAX as i64)
+      },
+      Some("i8") => Kind::Integer(i64::MIN, i64::MAX),
+      Some("ui1") => Kind::UnsignedInteger(u8::MAX as u64),
+      Some("ui2") => Kind::UnsignedInteger(u16::MAX as u64),
+      Some("ui4") => Kind::UnsignedInteger(u32::MAX as u64),
+      Some("ui8") => Kind::UnsignedInteger(u64::MAX),
+      _ => Kind::String,
+    }
+  }
+
+  fn encode(&self, value: &ArgumentValue) -> Option<String> {
+    match (*self, value) {
+      (Kind::Boolean, &ArgumentValue::Boolean(value)) => {
+        Some(if value { "1" } else { "0" }.to_string())
+      },
+      (Kind::Integer(min, max), &ArgumentValue::Integer(value))
+          if value >= min && value <= max => Some(value.to_string()),
+      (Kind::Integer(_, max), &ArgumentValue::UnsignedInteger(value))
+          if value <= max as u64 => Some(value.to_string()),
+      (Kind::UnsignedInteger(max), &ArgumentValue::UnsignedInteger(value))
+          if value <= max => Some(value.to_string()),
+      (Kind::UnsignedInteger(max), &ArgumentValue::Integer(value))
+          if value >= 0 && value as u64 <= max => Some(value.to_string()),
+      (Kind::String, &ArgumentValue::String(ref value)) => {
+        Some(value.clone())
+      },
+      _ => None,
+    }
+  }
+
+  fn decode(&self, text: &str) -> ArgumentValue {
+    let text = xml::unescape(text);
+    let trimmed = text.trim();
+    let value = match *self {
+      Kind::Boolean => {
+        match trimmed.to_ascii_lowercase().as_str() {
+          "1" | "true" | "yes" => Some(ArgumentValue::Boolean(true)),
+          "0" | "false" | "no" => Some(ArgumentValue::Boolean(false)),
+          _ => None,
+        }
+      },
+      Kind::Integer(..) => trimmed.parse().ok().map(ArgumentValue::Integer),
+      Kind::UnsignedInteger(_) => {
+        trimmed.parse().ok().map(ArgumentValue::UnsignedInteger)
+      },
+      Kind::String => None,
+    };
+    value.unwrap_or(ArgumentValue::String(text))
+  }
+}
+
+/// Check the arguments against the action's inputs and encode them as text,
+/// in the order the service declares them.
+fn encode_arguments(action: &Action, arguments: &Arguments)
+    -> Result<Vec<(String, String)>, WemoError> {
+  let inputs = action.inputs();
+
+  for name in arguments.keys() {
+    if !inputs.iter().any(|input| &input.name == name) {
+      return Err(invalid(format!("{} has no argument {}", action.name, name)));
+    }
+  }
+
+  inputs.iter()
+      .map(|input| {
+        let value = arguments.get(&input.name)
+            .ok_or_else(|| invalid(format!("{} needs argument {}",
+                action.name, input.name)))?;
+
+        Kind::of(input.data_type.as_deref())
+            .encode(value)
+            .map(|text| (input.name.clone(), text))
+            .ok_or_else(|| invalid(format!("{:?} isn't a valid {} for {}",
+                value, input.data_type.as_deref().unwrap_or("string"),
+                input.name)))
+      })
+      .collect()
+}
+
+fn decode_results(action: &Action, body: &str) -> Arguments {
+  action.outputs().iter()
+      .filter_map(|output| {
+        find_tag_value(&output.name, body).map(|text| {
+          let value = Kind::of(output.data_type.as_deref()).decode(text);
+          (output.name.clone(), value)
+        })
+      })
+      .collect()
+}
+
+fn invalid(reason: String) -> WemoError {
+  WemoError::InvalidArgument { reason: reason }
+}
+
+#[cfg(test)]
+mod tests {
+  use device::service::{Argument, ArgumentDirection};
+  use device::state::WemoState;
+  use super::*;
+  use testing::{FakeDevice, timeout};
+
+  fn argument(name: &str, direction: ArgumentDirection, data_type: &str)
+      -> Argument {
+    Argument {
+      name: name.to_string(),
+      direction: direction,
+      related_state_variable: Some(name.to_string()),
+      data_type: Some(data_type.to_string()),
+    }
+  }
+
+  fn action() -> Action {
+    Action {
+      name: "SetRule".to_string(),
+      arguments: vec![
+        argument("Enabled", ArgumentDirection::In, "Boolean"),
+        argument("Level", ArgumentDirection::In, "ui1"),
+        argument("Label", ArgumentDirection::In, "string"),
+        argument("Result", ArgumentDirection::Out, "i4"),
+        argument("State", ArgumentDirection::Out, "Boolean"),
+      ],
+    }
+  }
+
+  fn arguments(level: ArgumentValue) -> Arguments {
+    let mut arguments = Arguments::new();
+    arguments.insert("Enabled".to_string(), true.into());
+    arguments.insert("Level".to_string(), level);
+    arguments.insert("Label".to_string(), "Lamp & fan".into());
+    arguments
+  }
+
+  #[test]
+  fn test_encode_arguments() {
+    assert_eq!(vec![
+      ("Enabled".to_string(), "1".to_string()),
+      ("Level".to_string(), "200".to_string()),
+      ("Label".to_string(), "Lamp & fan".to_string()),
+    ], encode_arguments(&action(), &arguments(200u64.into())).unwrap());
+
+    // Out of range for a ui1, or the wrong type.
+    assert!(encode_arguments(&action(), &arguments(256u64.into())).is_err());
+    assert!(encode_arguments(&action(), &arguments((-1i64).into())).is_err());
+    assert!(encode_arguments(&action(), &arguments("7".into())).is_err());
+
+    let mut missing = arguments(1u64.into());
+    missing.remove("Label");
+    assert!(encode_arguments(&action(), &missing).is_err());
+
+    let mut unknown = arguments(1u64.into());
+    unknown.insert("Colour".to_string(), "red".into());
+    assert!(encode_arguments(&action(), &unknown).is_err());
+  }
+
+  #[test]
+  fn test_decode_results() {
+    let body = "<u:SetRuleResponse><Result>-3</Result>\
+        <State>8|1479872570|0</State></u:SetRuleResponse>";
+
+    let results = decode_results(&action(), body);
+    assert_eq!(Some(&ArgumentValue::Integer(-3)), results.get("Result"));
+    assert_eq!(Some(&ArgumentValue::String("8|1479872570|0".to_string())),
+        results.get("State"));
+    assert_eq!(None, results.get("Enabled"));
+  }
+
+  #[test]
+  fn test_service_client() {
+    let device = FakeDevice::start_unique().unwrap();
+    let switch = device.switch();
+    let client = ServiceClient::connect(&switch,
+        "urn:Belkin:serviceId:basicevent1", timeout()).unwrap();
+
+    let mut arguments = Arguments::new();
+    arguments.insert("BinaryState".to_string(), true.into());
+    client.call("SetBinaryState", &arguments, timeout()).unwrap();
+    assert_eq!(WemoState::On, device.state());
+
+    let results = client.call("GetBinaryState", &Arguments::new(), timeout())
+        .unwrap();
+    assert_eq!(Some(&ArgumentValue::Boolean(true)), results.get("BinaryState"));
+
+    // Checked before sending.
+    assert!(client.call("GetBinaryState", &arguments, timeout()).is_err());
+    assert!(client.call("ReSetup", &Arguments::new(), timeout()).is_err());
+    assert_eq!(2, device.request_count());
+  }
+}
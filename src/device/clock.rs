@@ -0,0 +1,196 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Reading a device's clock. Devices run their schedules on their own
+//! clocks, kept by NTP, so one that can't reach a time server drifts and
+//! switches at the wrong times.
+
+use device::SerialNumber;
+use device::switch::Switch;
+use error::WemoError;
+use std::time::{Duration as StdDuration, SystemTime, UNIX_EPOCH};
+use time::Duration;
+use xml::find_tag_value;
+
+/// Where devices report their time.
+pub const TIME_SYNC_SERVICE: &'static str = "urn:Belkin:service:timesync:1";
+
+/// A device's clock, from `GetTime`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeviceTime {
+  /// The device's current time. Only whole seconds are reported.
+  pub utc: SystemTime,
+  /// The offset from UTC the device was set up with, as reported, eg.
+  /// `-8.0`.
+  pub time_zone: Option<String>,
+  /// Whether daylight saving time is in effect.
+  pub dst: Option<bool>,
+}
+
+impl DeviceTime {
+  /// Parse a `GetTimeResponse`.
+  pub fn parse(body: &str) -> Result<DeviceTime, WemoError> {
+    let field = |name: &str| {
+      find_tag_value(name, body)
+          .map(|value| value.trim())
+          .filter(|value| !value.is_empty())
+    };
+
+    let utc = field("UTC")
+        .and_then(|utc| utc.parse::<u64>().ok())
+        .ok_or(WemoError::ParsingError)?;
+
+    Ok(DeviceTime {
+      utc: UNIX_EPOCH + StdDuration::from_secs(utc),
+      time_zone: field("TimeZone").map(|zone| zone.to_string()),
+      dst: field("dst").map(|dst| dst != "0"),
+    })
+  }
+}
+
+/// How far a device's clock is from this host's.
+#[derive(Clone, Debug)]
+pub struct ClockDrift {
+  pub serial_number: Option<SerialNumber>,
+  pub device_time: DeviceTime,
+  /// This host's time when the device read its clock, taken as halfway
+  /// through the request.
+  pub host_time: SystemTime,
+  /// Positive when the device is ahead. Off by up to a second, since the
+  /// device only reports whole seconds.
+  pub drift: Duration,
+}
+
+impl ClockDrift {
+  /// Compare the device's time with the host's halfway between sending the
+  /// request and receiving the response.
+  pub fn measure(serial_number: Option<SerialNumber>, device_time: DeviceTime,
+                 sent_at: SystemTime, received_at: SystemTime) -> ClockDrift {
+    let round_trip = received_at.duration_since(sent_at)
+        .unwrap_or(StdDuration::from_secs(0));
+    let host_time = sent_at + round_trip / 2;
+
+    let drift = match device_time.utc.duration_since(host_time) {
+      Ok(ahead) => Duration::from(ahead),
+      Err(behind) => -Duration::from(behind.duration()),
+    };
+
+    ClockDrift {
+      serial_number: serial_number,
+      device_time: device_time,
+      host_time: host_time,
+      drift: drift,
+    }
+  }
+
+  /// Whether the clocks are more than `threshold` apart, either way.
+  pub fn exceeds(&self, threshold: Duration) -> bool {
+    self.drift.num_milliseconds().abs() > threshold.num_milliseconds().abs()
+  }
+}
+
+/// Fires a callback when a device's clock has drifted more than a threshold
+/// from this host's, eg. to warn that its schedules will run late.
+pub struct ClockAlert {
+  threshold: Duration,
+  callback: Box<Fn(&ClockDrift) + Send + Sync>,
+}
+
+impl ClockAlert {
+  pub fn new<F>(threshold: Duration, callback: F) -> ClockAlert
+      where F: Fn(&ClockDrift) + Send + Sync + 'static {
+    ClockAlert {
+      threshold: threshold,
+      callback: Box::new(callback),
+    }
+  }
+
+  /// Read the device's clock, firing the callback if it has drifted too far.
+  pub fn check(&self, switch: &Switch, timeout: Duration)
+      -> Result<ClockDrift, WemoError> {
+    let drift = switch.clock_drift(timeout)?;
+    self.observe(&drift);
+    Ok(drift)
+  }
+
+  /// Account for a measurement, firing the callback if it's over the
+  /// threshold.
+  pub fn observe(&self, drift: &ClockDrift) {
+    if !drift.exceeds(self.threshold) {
+      return;
+    }
+
+    warn!(target: "wemo", serial:? = drift.serial_number,
+        drift_ms = drift.drift.num_milliseconds();
+        "Device clock is off by {}s", drift.drift.num_seconds());
+    (self.callback)(drift);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Mutex;
+  use std::sync::mpsc::channel;
+  use super::*;
+
+  const RESPONSE: &'static str = "\
+      <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">\
+        <s:Body>\
+          <u:GetTimeResponse xmlns:u=\"urn:Belkin:service:timesync:1\">\
+            <UTC>1479872570</UTC>\
+            <TimeZone>-8.0</TimeZone>\
+            <dst>1</dst>\
+          </u:GetTimeResponse>\
+        </s:Body>\
+      </s:Envelope>";
+
+  fn at(secs: u64, millis: u64) -> SystemTime {
+    UNIX_EPOCH + StdDuration::from_millis(secs * 1_000 + millis)
+  }
+
+  #[test]
+  fn test_parse_device_time() {
+    let time = DeviceTime::parse(RESPONSE).unwrap();
+    assert_eq!(at(1479872570, 0), time.utc);
+    assert_eq!(Some("-8.0".to_string()), time.time_zone);
+    assert_eq!(Some(true), time.dst);
+
+    match DeviceTime::parse("<UTC></UTC>") {
+      Err(WemoError::ParsingError) => {},
+      other => panic!("expected a parsing error, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_clock_drift() {
+    let time = DeviceTime::parse(RESPONSE).unwrap();
+
+    // The device read its clock halfway through a 400ms round trip.
+    let drift = ClockDrift::measure(None, time.clone(),
+        at(1479872500, 800), at(1479872501, 200));
+    assert_eq!(at(1479872501, 0), drift.host_time);
+    assert_eq!(69, drift.drift.num_seconds());
+
+    let behind = ClockDrift::measure(None, time, at(1479872600, 0),
+        at(1479872600, 0));
+    assert_eq!(-30, behind.drift.num_seconds());
+    assert!(behind.exceeds(Duration::seconds(10)));
+    assert!(!behind.exceeds(Duration::seconds(30)));
+  }
+
+  #[test]
+  fn test_clock_alert() {
+    let (sender, receiver) = channel();
+    let sender = Mutex::new(sender);
+    let alert = ClockAlert::new(Duration::seconds(60), move |drift| {
+      let _r = sender.lock().unwrap().send(drift.drift.num_seconds());
+    });
+
+    let time = DeviceTime::parse(RESPONSE).unwrap();
+    for &secs in [1479872550, 1479872700].iter() {
+      alert.observe(&ClockDrift::measure(None, time.clone(), at(secs, 0),
+          at(secs, 0)));
+    }
+
+    assert_eq!(vec![-130], receiver.try_iter().collect::<Vec<_>>());
+  }
+}
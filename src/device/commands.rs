@@ -0,0 +1,249 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Serializing and coalescing rapid-fire state changes to one device.
+
+use device::state::WemoState;
+use device::switch::{Switch, WemoResult};
+use error::WemoError;
+use std::io;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration as StdDuration, Instant};
+use threads;
+use time::Duration;
+
+/// How long a command waits for later ones to coalesce with by default.
+const DEFAULT_WINDOW_MS: i64 = 200;
+
+/// An opt-in background thread that sends a device's state changes one at a
+/// time. Commands queued within the coalescing window of the first one are
+/// collapsed into the last, eg. on, off, on within 200ms is sent as just on,
+/// which protects the device and avoids flicker. Requests use the switch's
+/// configured default timeout and retry policy. The queue is stopped when
+/// dropped, after sending any pending command.
+pub struct CommandQueue {
+  shared: Arc<Shared>,
+  handle: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+  pending: Mutex<Pending>,
+  changed: Condvar,
+}
+
+struct Pending {
+  /// The state to send, and when the first command coalesced into it was
+  /// queued.
+  command: Option<(WemoState, Instant)>,
+  /// Everyone waiting on the pending command.
+  waiting: Vec<Sender<WemoResult>>,
+  stopped: bool,
+}
+
+impl CommandQueue {
+  /// Start a queue with the default 200ms coalescing window.
+  pub fn start(switch: Arc<Switch>) -> CommandQueue {
+    CommandQueue::start_with_window(switch,
+        Duration::milliseconds(DEFAULT_WINDOW_MS))
+  }
+
+  /// Start a queue that waits `window` after a command for later ones to
+  /// coalesce with. A zero window only serializes.
+  pub fn start_with_window(switch: Arc<Switch>, window: Duration)
+      -> CommandQueue {
+    let shared = Arc::new(Shared {
+      pending: Mutex::new(Pending {
+        command: None,
+        waiting: Vec::new(),
+        stopped: false,
+      }),
+      changed: Condvar::new(),
+    });
+
+    let worker_shared = shared.clone();
+    let window = window.to_std().unwrap_or(StdDuration::from_secs(0));
+
+    let handle = threads::spawn("wemo-commands", move || {
+      while let Some((state, waiting)) = worker_shared.next(window) {
+        debug!(target: "wemo", serial:? = switch.serial_number,
+            action = "command_queue", coalesced = waiting.len();
+            "Sending queued {} to {}", state, switch.name());
+
+        let result = switch.set_state_default(state);
+        if let Some((last, coalesced)) = waiting.split_last() {
+          for sender in coalesced {
+            let _r = sender.send(match result {
+              Ok(ref state) => Ok(state.clone()),
+              Err(ref error) => Err(duplicate(error)),
+            });
+          }
+          let _r = last.send(result);
+        }
+      }
+    });
+
+    CommandQueue {
+      shared: shared,
+      handle: Some(handle),
+    }
+  }
+
+  /// Queue a change to `state`, replacing any change still waiting to be
+  /// sent. The receiver gets the result of the command that's actually sent,
+  /// so a coalesced command reports the state the device ended up in.
+  pub fn set_state(&self, state: WemoState) -> Receiver<WemoResult> {
+    let (sender, receiver) = channel();
+
+    match self.shared.pending.lock() {
+      Err(_) => { let _r = sender.send(Err(WemoError::LockError)); },
+      Ok(mut pending) => {
+        if pending.stopped {
+          let _r = sender.send(Err(WemoError::Cancelled));
+          return receiver;
+        }
+        let queued_at = pending.command.as_ref()
+            .map(|&(_, queued_at)| queued_at)
+            .unwrap_or_else(Instant::now);
+        pending.command = Some((state, queued_at));
+        pending.waiting.push(sender);
+        self.shared.changed.notify_all();
+      },
+    }
+
+    receiver
+  }
+
+  pub fn turn_on(&self) -> Receiver<WemoResult> {
+    self.set_state(WemoState::On)
+  }
+
+  pub fn turn_off(&self) -> Receiver<WemoResult> {
+    self.set_state(WemoState::Off)
+  }
+
+  /// Stop the queue, waiting for any pending command to be sent.
+  pub fn stop(&mut self) {
+    if let Ok(mut pending) = self.shared.pending.lock() {
+      pending.stopped = true;
+      self.shared.changed.notify_all();
+    }
+    if let Some(handle) = self.handle.take() {
+      let _r = handle.join();
+    }
+  }
+}
+
+impl Drop for CommandQueue {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
+
+impl Shared {
+  /// Wait for a command and its coalescing window. Returns `None` once
+  /// stopped with nothing pending.
+  fn next(&self, window: StdDuration)
+      -> Option<(WemoState, Vec<Sender<WemoResult>>)> {
+    let mut pending = self.pending.lock().ok()?;
+
+    loop {
+      match pending.command.clone() {
+        None if pending.stopped => { return None; },
+        None => {
+          pending = self.changed.wait(pending).ok()?;
+        },
+        Some((state, queued_at)) => {
+          let elapsed = queued_at.elapsed();
+          if pending.stopped || elapsed >= window {
+            pending.command = None;
+            let waiting = pending.waiting.drain(..).collect();
+            return Some((state, waiting));
+          }
+          pending = self.changed.wait_timeout(pending, window - elapsed)
+              .ok()?
+              .0;
+        },
+      }
+    }
+  }
+}
+
+/// A copy of the error for each coalesced caller, since `WemoError` can't be
+/// cloned.
+fn duplicate(error: &WemoError) -> WemoError {
+  match *error {
+    WemoError::BadResponseError => WemoError::BadResponseError,
+    WemoError::IoError { ref cause } => WemoError::IoError {
+      cause: io::Error::new(cause.kind(), cause.to_string()),
+    },
+    WemoError::ParsingError => WemoError::ParsingError,
+    WemoError::InvalidEnvelope { ref reason } => WemoError::InvalidEnvelope {
+      reason: reason.clone(),
+    },
+    WemoError::UnexpectedResponse { ref cause, ref body } => {
+      WemoError::UnexpectedResponse {
+        cause: Box::new(duplicate(cause)),
+        body: body.clone(),
+      }
+    },
+    WemoError::TimeoutError { stage, elapsed, retry_after } => {
+      WemoError::TimeoutError {
+        stage: stage,
+        elapsed: elapsed,
+        retry_after: retry_after,
+      }
+    },
+    WemoError::CircuitOpen => WemoError::CircuitOpen,
+    WemoError::Cancelled => WemoError::Cancelled,
+    WemoError::WemoError => WemoError::WemoError,
+    WemoError::IronError => WemoError::IronError,
+    WemoError::LockError => WemoError::LockError,
+    WemoError::SubscriptionError => WemoError::SubscriptionError,
+    WemoError::NoLocalIp => WemoError::NoLocalIp,
+    WemoError::MissingSerialNumber => WemoError::MissingSerialNumber,
+    WemoError::MissingInsightParams => WemoError::MissingInsightParams,
+    WemoError::InvalidUrl { ref reason } => WemoError::InvalidUrl {
+      reason: reason.clone(),
+    },
+    WemoError::ConfigError { ref reason } => WemoError::ConfigError {
+      reason: reason.clone(),
+    },
+    WemoError::InvalidArgument { ref reason } => WemoError::InvalidArgument {
+      reason: reason.clone(),
+    },
+    WemoError::WrongDevice { ref expected, ref found } => {
+      WemoError::WrongDevice {
+        expected: expected.clone(),
+        found: found.clone(),
+      }
+    },
+    WemoError::Panicked { ref message } => WemoError::Panicked {
+      message: message.clone(),
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use testing::FakeDevice;
+
+  #[test]
+  fn test_command_queue() {
+    let device = FakeDevice::start_unique().unwrap();
+    let queue = CommandQueue::start(Arc::new(device.switch()));
+
+    let results = vec![queue.turn_on(), queue.turn_off(), queue.turn_on()];
+    for result in results {
+      assert_eq!(WemoState::On, result.recv().unwrap().unwrap());
+    }
+    assert_eq!(WemoState::On, device.state());
+    assert_eq!(1, device.request_count());
+
+    // Commands after the window are sent in turn.
+    assert_eq!(WemoState::Off, queue.turn_off().recv().unwrap().unwrap());
+    assert_eq!(WemoState::Off, device.state());
+    assert_eq!(2, device.request_count());
+  }
+}
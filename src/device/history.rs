@@ -0,0 +1,80 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use super::state::StateReport;
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+/// The most recent state transitions of a device, oldest first. Readings that
+/// don't change the state aren't kept.
+#[derive(Clone, Debug)]
+pub struct StateHistory {
+  capacity: usize,
+  transitions: VecDeque<StateReport>,
+}
+
+impl StateHistory {
+  /// Keep up to `capacity` transitions, forgetting the oldest first.
+  pub fn new(capacity: usize) -> StateHistory {
+    StateHistory {
+      capacity: capacity,
+      transitions: VecDeque::with_capacity(capacity),
+    }
+  }
+
+  /// Remember the reading if it's a transition.
+  pub fn record(&mut self, report: &StateReport) {
+    let unchanged = self.transitions.back()
+        .map(|last| last.state == report.state)
+        .unwrap_or(false);
+
+    if unchanged || self.capacity == 0 {
+      return;
+    }
+
+    if self.transitions.len() >= self.capacity {
+      self.transitions.pop_front();
+    }
+    self.transitions.push_back(report.clone());
+  }
+
+  /// Transitions at or after `since`, oldest first.
+  pub fn since(&self, since: SystemTime) -> Vec<StateReport> {
+    self.transitions.iter()
+        .filter(|report| report.fetched_at >= since)
+        .cloned()
+        .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use device::state::{StateSource, WemoState};
+  use std::time::{Duration as StdDuration, UNIX_EPOCH};
+  use super::*;
+  use time::Duration;
+
+  fn report(state: WemoState, secs: u64) -> StateReport {
+    let mut report = StateReport::new(state, Duration::zero(),
+        StateSource::Poll);
+    report.fetched_at = UNIX_EPOCH + StdDuration::from_secs(secs);
+    report
+  }
+
+  #[test]
+  fn test_record() {
+    let mut history = StateHistory::new(2);
+    history.record(&report(WemoState::Off, 1));
+    history.record(&report(WemoState::Off, 2));
+    history.record(&report(WemoState::On, 3));
+    history.record(&report(WemoState::Off, 4));
+
+    // Repeats are skipped and the oldest transition is forgotten.
+    let states = history.since(UNIX_EPOCH).into_iter()
+        .map(|report| report.state)
+        .collect::<Vec<_>>();
+    assert_eq!(vec![WemoState::On, WemoState::Off], states);
+
+    let since = UNIX_EPOCH + StdDuration::from_secs(4);
+    assert_eq!(vec![report(WemoState::Off, 4)], history.since(since));
+  }
+}
@@ -0,0 +1,151 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use time::Duration;
+
+/// The extended, pipe-delimited fields a WeMo Insight appends to its
+/// `BinaryState`, eg. `8|1479872570|0|0|432|1234|56|0|0|-123`. Regular
+/// switches don't report these.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InsightParams {
+  /// Unix timestamp of the last state change.
+  pub last_change: i64,
+
+  /// Seconds the device has been on since the last state change.
+  pub on_for: i64,
+
+  /// Seconds the device has been on today.
+  pub on_today: i64,
+
+  /// Seconds the device has been on over `time_period`.
+  pub on_total: i64,
+
+  /// The period (in seconds) the totals are computed over.
+  pub time_period: i64,
+
+  /// Undocumented. Believed to be the average power draw in watts.
+  pub average_power: i64,
+
+  /// Instantaneous power draw in milliwatts.
+  pub current_power: i64,
+
+  /// Energy used today in milliwatt-minutes.
+  pub today_energy: i64,
+
+  /// Energy used over `time_period` in milliwatt-minutes.
+  pub total_energy: i64,
+
+  /// The "on without load" power threshold in milliwatts. Only reported by
+  /// `GetInsightParams`, not by `GetBinaryState` or subscription events.
+  pub power_threshold: Option<i64>,
+}
+
+impl InsightParams {
+  /// Build from the fields that trail the state in a `BinaryState` value.
+  /// Returns None if there are too few fields or they aren't numeric.
+  pub fn from_fields(fields: &[&str]) -> Option<InsightParams> {
+    if fields.len() < 9 {
+      return None;
+    }
+
+    let mut values = Vec::with_capacity(fields.len());
+    for field in fields {
+      match field.trim().parse::<i64>() {
+        Ok(value) => values.push(value),
+        Err(_) => return None,
+      }
+    }
+
+    Some(InsightParams {
+      last_change: values[0],
+      on_for: values[1],
+      on_today: values[2],
+      on_total: values[3],
+      time_period: values[4],
+      average_power: values[5],
+      current_power: values[6],
+      today_energy: values[7],
+      total_energy: values[8],
+      power_threshold: values.get(9).cloned(),
+    })
+  }
+
+  /// How long the device has been on today.
+  pub fn time_on_today(&self) -> Duration {
+    Duration::seconds(self.on_today)
+  }
+
+  /// How long the device has been on over `time_period`.
+  pub fn time_on_total(&self) -> Duration {
+    Duration::seconds(self.on_total)
+  }
+
+  /// Energy used today, in kilowatt-hours.
+  pub fn energy_today_kwh(&self) -> f64 {
+    milliwatt_minutes_to_kwh(self.today_energy)
+  }
+
+  /// Energy used over `time_period`, in kilowatt-hours.
+  pub fn energy_total_kwh(&self) -> f64 {
+    milliwatt_minutes_to_kwh(self.total_energy)
+  }
+
+  /// The average power draw in watts while the device was on today, or None
+  /// if it hasn't been on.
+  pub fn average_power_today(&self) -> Option<f64> {
+    average_watts(self.today_energy, self.on_today)
+  }
+
+  /// The average power draw in watts while the device was on over
+  /// `time_period`, or None if it hasn't been on.
+  pub fn average_power_total(&self) -> Option<f64> {
+    average_watts(self.total_energy, self.on_total)
+  }
+
+  /// What today's energy cost at `rate` per kilowatt-hour, in whatever
+  /// currency the rate is in.
+  pub fn estimated_cost_today(&self, rate: f64) -> f64 {
+    self.energy_today_kwh() * rate
+  }
+
+  /// What the energy used over `time_period` cost at `rate` per
+  /// kilowatt-hour.
+  pub fn estimated_cost_total(&self, rate: f64) -> f64 {
+    self.energy_total_kwh() * rate
+  }
+}
+
+fn milliwatt_minutes_to_kwh(energy: i64) -> f64 {
+  energy as f64 / 60.0 / 1_000_000.0
+}
+
+fn average_watts(energy: i64, on_for: i64) -> Option<f64> {
+  if on_for <= 0 {
+    return None;
+  }
+  let minutes = on_for as f64 / 60.0;
+  Some(energy as f64 / minutes / 1_000.0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_summaries() {
+    // On for two hours today at 60W, and for ten hours in all at 30W.
+    let params = InsightParams::from_fields(&["1479872570", "7200", "7200",
+        "36000", "1209600", "30", "60000", "7200000", "18000000"]).unwrap();
+
+    assert_eq!(7200, params.time_on_today().num_seconds());
+    assert_eq!(36000, params.time_on_total().num_seconds());
+    assert!((params.energy_today_kwh() - 0.12).abs() < 1e-9);
+    assert!((params.energy_total_kwh() - 0.3).abs() < 1e-9);
+    assert!((params.average_power_today().unwrap() - 60.0).abs() < 1e-9);
+    assert!((params.average_power_total().unwrap() - 30.0).abs() < 1e-9);
+    assert!((params.estimated_cost_today(0.25) - 0.03).abs() < 1e-9);
+    assert!((params.estimated_cost_total(0.25) - 0.075).abs() < 1e-9);
+
+    let off = InsightParams { on_today: 0, ..params };
+    assert_eq!(None, off.average_power_today());
+  }
+}
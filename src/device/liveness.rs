@@ -0,0 +1,132 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use device::switch::Switch;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+use threads;
+use time::Duration;
+
+/// How often the monitor wakes to check whether it was stopped.
+const STOP_CHECK_MS: u64 = 100;
+
+/// How long to wait before pinging an unreachable device again, after each
+/// consecutive failure. The last wait repeats.
+const OFFLINE_BACKOFF_SECS: [u64; 4] = [30, 60, 300, 900];
+
+/// An opt-in background thread that pings devices periodically, keeping
+/// `Switch::is_reachable` current without the cost of `GetBinaryState`.
+/// Unreachable devices are pinged less often, backing off through
+/// `OFFLINE_BACKOFF_SECS`, until a ping or request reaches them again. The
+/// monitor is stopped when dropped.
+pub struct LivenessMonitor {
+  stopped: Arc<AtomicBool>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl LivenessMonitor {
+  /// Ping each device every `interval`, waiting up to `timeout` for each.
+  pub fn start(switches: Vec<Arc<Switch>>, interval: Duration,
+               timeout: Duration) -> LivenessMonitor {
+    LivenessMonitor::start_with(switches, interval, timeout, |_, _| {})
+  }
+
+  /// Like `start`, but also calls `on_ping` with each device and whether it
+  /// was reachable, eg. for `Registry::report_presence`.
+  pub fn start_with<F>(switches: Vec<Arc<Switch>>, interval: Duration,
+                       timeout: Duration, on_ping: F) -> LivenessMonitor
+                       where F: Fn(&Arc<Switch>, bool) + Send + 'static {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stop = stopped.clone();
+    let interval_ms = interval.num_milliseconds().max(0) as u64;
+
+    let handle = threads::spawn("wemo-liveness", move || {
+      // Consecutive failures and the next ping, by index into `switches`.
+      let mut offline: HashMap<usize, (usize, Instant)> = HashMap::new();
+
+      loop {
+        for (i, switch) in switches.iter().enumerate() {
+          if stop.load(Ordering::SeqCst) {
+            return;
+          }
+
+          // A successful request since the last ping ends the backoff.
+          if let Some(&(_, next_ping)) = offline.get(&i) {
+            if Instant::now() < next_ping && !switch.is_reachable() {
+              continue;
+            }
+          }
+
+          let reachable = switch.ping(timeout);
+          if reachable {
+            offline.remove(&i);
+          } else {
+            let failures = offline.get(&i).map(|&(n, _)| n + 1).unwrap_or(1);
+            let wait = offline_backoff(failures, interval_ms);
+            offline.insert(i, (failures, Instant::now() + wait));
+
+            debug!(target: "wemo", serial:? = switch.serial_number,
+                ip:? = switch.get_ip_address(), action = "ping",
+                failures = failures; "Device unreachable: {}, next ping in \
+                {}s", switch.name(), wait.as_secs());
+          }
+          on_ping(switch, reachable);
+        }
+
+        let mut slept_ms = 0;
+        while slept_ms < interval_ms {
+          if stop.load(Ordering::SeqCst) {
+            return;
+          }
+          thread::sleep(StdDuration::from_millis(STOP_CHECK_MS));
+          slept_ms += STOP_CHECK_MS;
+        }
+      }
+    });
+
+    LivenessMonitor {
+      stopped: stopped,
+      handle: Some(handle),
+    }
+  }
+
+  /// Stop the monitor, waiting for any ping in progress to finish.
+  pub fn stop(&mut self) {
+    self.stopped.store(true, Ordering::SeqCst);
+    if let Some(handle) = self.handle.take() {
+      let _r = handle.join();
+    }
+  }
+}
+
+impl Drop for LivenessMonitor {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
+
+/// How long to wait before pinging a device that failed `failures` pings in
+/// a row, but never less than the monitor's interval.
+fn offline_backoff(failures: usize, interval_ms: u64) -> StdDuration {
+  let step = failures.max(1).min(OFFLINE_BACKOFF_SECS.len()) - 1;
+  let backoff = StdDuration::from_secs(OFFLINE_BACKOFF_SECS[step]);
+  backoff.max(StdDuration::from_millis(interval_ms))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_offline_backoff() {
+    let secs = |failures| offline_backoff(failures, 10_000).as_secs();
+    assert_eq!(vec![30, 60, 300, 900, 900],
+        (1..6).map(secs).collect::<Vec<_>>());
+
+    // Never more often than the interval.
+    assert_eq!(120, offline_backoff(1, 120_000).as_secs());
+  }
+}
@@ -1,6 +1,23 @@
 // Copyright (c) 2015 Brandon Thomas <bt@brand.io>
 
+pub mod alert;
+pub mod appliance;
+#[cfg(feature = "async")] pub mod background;
+pub mod breaker;
+pub mod client;
+pub mod clock;
+pub mod commands;
+pub mod history;
+pub mod insight;
+pub mod liveness;
+#[cfg(feature = "discovery")] pub mod relocation;
+pub mod sampler;
+pub mod service;
 pub mod state;
 pub mod switch;
+#[cfg(feature = "async")] pub mod wait;
 
 pub type SerialNumber = String;
+
+/// UPnP unique device name, eg. `uuid:Socket-1_0-221517K0101769`.
+pub type Udn = String;
@@ -0,0 +1,88 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use device::switch::Switch;
+use net::search::DeviceSearch;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::thread;
+use std::time::Duration as StdDuration;
+use threads;
+use time::Duration;
+
+/// How often the worker wakes to check whether it was stopped.
+const STOP_CHECK_MS: u64 = 100;
+
+/// An opt-in background thread that relocates devices after requests to them
+/// fail. Relocation is slow, so doing it off the hot path means the next
+/// request usually goes straight to the corrected address. Devices are first
+/// probed on their candidate ports, then searched for via SSDP. The worker is
+/// stopped when dropped.
+pub struct RelocationWorker {
+  stopped: Arc<AtomicBool>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl RelocationWorker {
+  /// Check the devices every `interval`, spending up to `timeout` relocating
+  /// each device that needs it.
+  pub fn start(switches: Vec<Arc<Switch>>, interval: Duration,
+               timeout: Duration) -> RelocationWorker {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stop = stopped.clone();
+    let interval_ms = interval.num_milliseconds().max(0) as u64;
+
+    let handle = threads::spawn("wemo-relocation", move || {
+      // One socket is reused for every relocation.
+      let mut search = DeviceSearch::new();
+
+      loop {
+        let mut slept_ms = 0;
+        while slept_ms < interval_ms {
+          if stop.load(Ordering::SeqCst) {
+            return;
+          }
+          thread::sleep(StdDuration::from_millis(STOP_CHECK_MS));
+          slept_ms += STOP_CHECK_MS;
+        }
+
+        for switch in switches.iter() {
+          if stop.load(Ordering::SeqCst) {
+            return;
+          }
+
+          if !switch.needs_relocation() {
+            continue;
+          }
+
+          debug!(target: "wemo", serial:? = switch.serial_number,
+              ip:? = switch.get_ip_address(), action = "relocate";
+              "Relocating in background: {}", switch.name());
+
+          if switch.probe_ports(timeout / 2).is_none() {
+            let _r = switch.relocate_with(&mut search, timeout / 2);
+          }
+        }
+      }
+    });
+
+    RelocationWorker {
+      stopped: stopped,
+      handle: Some(handle),
+    }
+  }
+
+  /// Stop the worker, waiting for any relocation in progress to finish.
+  pub fn stop(&mut self) {
+    self.stopped.store(true, Ordering::SeqCst);
+    if let Some(handle) = self.handle.take() {
+      let _r = handle.join();
+    }
+  }
+}
+
+impl Drop for RelocationWorker {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
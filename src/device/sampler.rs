@@ -0,0 +1,163 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use device::SerialNumber;
+use device::insight::InsightParams;
+use device::state::WemoState;
+use device::switch::Switch;
+use error::WemoError;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::thread;
+use std::time::{Duration as StdDuration, SystemTime};
+use threads;
+use time::Duration;
+
+/// How often the sampler wakes to check whether it was stopped.
+const STOP_CHECK_MS: u64 = 100;
+
+/// One power reading from an Insight.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InsightSample {
+  pub serial_number: Option<SerialNumber>,
+  pub state: WemoState,
+  pub params: InsightParams,
+  pub taken_at: SystemTime,
+}
+
+/// An opt-in background thread that reads the power usage of Insight devices
+/// at an interval, eg. for energy dashboards. Requests that fail are retried
+/// once after relocating the device, if its retry policy allows. The sampler
+/// is stopped when dropped.
+pub struct InsightSampler {
+  stopped: Arc<AtomicBool>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl InsightSampler {
+  /// Sample each device every `interval`, waiting up to `timeout` for each.
+  /// The callback is invoked with every sample, or with the error if a
+  /// device couldn't be sampled.
+  pub fn start<F>(switches: Vec<Arc<Switch>>, interval: Duration,
+                  timeout: Duration, callback: F) -> InsightSampler
+      where F: Fn(&Switch, Result<InsightSample, WemoError>) + Send + 'static {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stop = stopped.clone();
+    let interval_ms = interval.num_milliseconds().max(0) as u64;
+
+    let handle = threads::spawn("wemo-sampler", move || {
+      loop {
+        for switch in switches.iter() {
+          if stop.load(Ordering::SeqCst) {
+            return;
+          }
+          callback(switch, sample(switch, timeout));
+        }
+
+        let mut slept_ms = 0;
+        while slept_ms < interval_ms {
+          if stop.load(Ordering::SeqCst) {
+            return;
+          }
+          thread::sleep(StdDuration::from_millis(STOP_CHECK_MS));
+          slept_ms += STOP_CHECK_MS;
+        }
+      }
+    });
+
+    InsightSampler {
+      stopped: stopped,
+      handle: Some(handle),
+    }
+  }
+
+  /// Stop sampling, waiting for any sample in progress to finish.
+  pub fn stop(&mut self) {
+    self.stopped.store(true, Ordering::SeqCst);
+    if let Some(handle) = self.handle.take() {
+      let _r = handle.join();
+    }
+  }
+}
+
+impl Drop for InsightSampler {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
+
+/// Read the device's power usage, relocating it and trying again on failure
+/// if its retry policy allows.
+pub fn sample(switch: &Switch, timeout: Duration)
+    -> Result<InsightSample, WemoError> {
+  let mut result = switch.get_binary_state(timeout);
+
+  let retry = match result {
+    Ok(_) | Err(WemoError::CircuitOpen) => false,
+    Err(_) => switch.config().retry_policy.relocate_on_failure,
+  };
+
+  if retry && relocate(switch, timeout) {
+    result = switch.get_binary_state(timeout);
+  }
+
+  let binary_state = result?;
+  let params = binary_state.insight.ok_or(WemoError::MissingInsightParams)?;
+
+  Ok(InsightSample {
+    serial_number: switch.serial_number.clone(),
+    state: binary_state.state,
+    params: params,
+    taken_at: SystemTime::now(),
+  })
+}
+
+/// Find the device again, by searching for it if discovery is built in and
+/// otherwise by probing its ports.
+#[cfg(feature = "discovery")]
+fn relocate(switch: &Switch, timeout: Duration) -> bool {
+  switch.relocate(timeout).is_some()
+}
+
+#[cfg(not(feature = "discovery"))]
+fn relocate(switch: &Switch, timeout: Duration) -> bool {
+  switch.probe(timeout).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+  use config::WemoConfig;
+  use std::net::IpAddr;
+  use std::str::FromStr;
+  use std::sync::mpsc::channel;
+  use super::*;
+  use testing::FakeDevice;
+
+  #[test]
+  fn test_sampler_reports_errors() {
+    // Nothing listens on port 1, so every request fails quickly.
+    let ip = IpAddr::from_str("127.0.0.1").unwrap();
+    let mut config = WemoConfig::default();
+    config.retry_policy.relocate_on_failure = false;
+    let switch = Switch::from_static_ip_and_port(ip, 1).with_config(config);
+
+    let (sender, receiver) = channel();
+    let mut sampler = InsightSampler::start(vec![Arc::new(switch)],
+        Duration::seconds(60), Duration::milliseconds(500),
+        move |_switch: &Switch, result| {
+          let _r = sender.send(result.is_err());
+        });
+
+    assert_eq!(Ok(true), receiver.recv());
+    sampler.stop();
+  }
+
+  #[test]
+  fn test_sample_requires_insight() {
+    let device = FakeDevice::start("221517K0101769").unwrap();
+    match sample(&device.switch(), Duration::seconds(2)) {
+      Err(WemoError::MissingInsightParams) => {},
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+}
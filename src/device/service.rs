@@ -0,0 +1,82 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+/// A UPnP service listed in a device's `setup.xml`, eg. `basicevent1`, which
+/// switches are controlled through. URLs are paths on the device, eg.
+/// `/upnp/control/basicevent1`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Service {
+  /// eg. `urn:Belkin:service:basicevent:1`.
+  pub service_type: String,
+
+  /// eg. `urn:Belkin:serviceId:basicevent1`.
+  pub service_id: String,
+
+  /// Where SOAP actions are POSTed.
+  pub control_url: String,
+
+  /// Where subscriptions are sent.
+  pub event_url: String,
+
+  /// Where the service description (SCPD) is served.
+  pub scpd_url: String,
+}
+
+/// A service's actions, read from its SCPD (service control protocol
+/// description), eg. `/eventservice.xml`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServiceDescription {
+  pub service: Service,
+  pub actions: Vec<Action>,
+}
+
+/// An action a service accepts, eg. `SetBinaryState`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Action {
+  pub name: String,
+  pub arguments: Vec<Argument>,
+}
+
+/// One of an action's arguments.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Argument {
+  pub name: String,
+  pub direction: ArgumentDirection,
+
+  /// The state variable describing the argument's values, eg. `BinaryState`.
+  pub related_state_variable: Option<String>,
+
+  /// The UPnP type of the related state variable, eg. `Boolean` or `string`,
+  /// if the SCPD declares one.
+  pub data_type: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArgumentDirection {
+  /// Sent with the request.
+  In,
+  /// Returned in the response.
+  Out,
+}
+
+impl ServiceDescription {
+  /// The action with the given name, if the service has it.
+  pub fn action(&self, name: &str) -> Option<&Action> {
+    self.actions.iter().find(|action| action.name == name)
+  }
+}
+
+impl Action {
+  /// The arguments sent with the request, in order.
+  pub fn inputs(&self) -> Vec<&Argument> {
+    self.arguments.iter()
+        .filter(|argument| argument.direction == ArgumentDirection::In)
+        .collect()
+  }
+
+  /// The arguments returned in the response, in order.
+  pub fn outputs(&self) -> Vec<&Argument> {
+    self.arguments.iter()
+        .filter(|argument| argument.direction == ArgumentDirection::Out)
+        .collect()
+  }
+}
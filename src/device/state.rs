@@ -1,6 +1,9 @@
 // Copyright (c) 2015 Brandon Thomas <bt@brand.io>
 
+use super::insight::InsightParams;
 use std::fmt;
+use std::time::SystemTime;
+use time::Duration;
 
 #[derive(Clone,Debug,Eq,PartialEq)]
 pub enum WemoState {
@@ -15,6 +18,78 @@ pub enum WemoState {
   Unknown(u16),
 }
 
+/// The full `BinaryState` reported by a device. Insight switches report extra
+/// power and usage fields alongside the on/off state.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct BinaryState {
+  /// The on/off state.
+  pub state: WemoState,
+  /// Present when the device is an Insight that reported extended fields.
+  pub insight: Option<InsightParams>,
+  /// The pipe-delimited fields after the state, as reported.
+  extended: Vec<String>,
+}
+
+impl BinaryState {
+  pub fn new(state: WemoState, insight: Option<InsightParams>) -> BinaryState {
+    BinaryState {
+      state: state,
+      insight: insight,
+      extended: Vec::new(),
+    }
+  }
+
+  /// Use these raw extended fields.
+  pub fn with_extended_fields(mut self, fields: Vec<String>) -> BinaryState {
+    self.extended = fields;
+    self
+  }
+
+  /// The unparsed pipe-delimited fields reported after the state, in order,
+  /// eg. `["1479872570", "0", ...]` for `8|1479872570|0|...`. Includes fields
+  /// `InsightParams` doesn't model, for firmware that reports more.
+  pub fn extended_fields(&self) -> &[String] {
+    &self.extended
+  }
+}
+
+/// Where a `StateReport` came from.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum StateSource {
+  /// Read from the device with `GetBinaryState`.
+  Poll,
+  /// Sent by the device in a subscription event.
+  Push,
+  /// Requested with a state change the device accepted.
+  Command,
+  /// The last reading remembered by the `Switch`, not a new one.
+  Cache,
+}
+
+/// A state reading along with how fresh and how trustworthy it is.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct StateReport {
+  pub state: WemoState,
+  /// When the reading was taken.
+  pub fetched_at: SystemTime,
+  /// How long the device took to respond. Zero if it wasn't asked.
+  pub latency: Duration,
+  pub source: StateSource,
+}
+
+impl StateReport {
+  /// A reading taken just now.
+  pub fn new(state: WemoState, latency: Duration, source: StateSource)
+      -> StateReport {
+    StateReport {
+      state: state,
+      fetched_at: SystemTime::now(),
+      latency: latency,
+      source: source,
+    }
+  }
+}
+
 impl fmt::Display for WemoState {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     let s = match *self {
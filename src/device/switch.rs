@@ -5,31 +5,78 @@
  */
 
 pub use time::Duration;
-pub use url::{Host, Url};
-use error::WemoError;
-use net::soap::{SoapClient, SoapRequest};
-use net::ssdp::{DeviceSearch, SsdpResponse};
+use cancel::CancellationToken;
+use config::{ParsingMode, WemoConfig, global_config};
+use deadline::Deadline;
+use error::{TimeoutStage, WemoError};
+use metrics;
+use net::cell::{AtomicIpAddr, AtomicPort};
+use net::http;
+use net::location::{DeviceLocation, SetupUrl};
+use net::ports::DevicePorts;
+use net::soap::{SoapClient, SoapEnvelope, SoapRequest};
+#[cfg(feature = "discovery")] use net::search::DeviceSearch;
+use net::ssdp::SsdpResponse;
+use parsing::{parse_binary_state_with, parse_scpd, parse_services};
+use parsing::{parse_udn, validate_envelope};
 use std::fmt::{Display, Error, Formatter};
+use std::hash::{Hash, Hasher};
 use std::net::IpAddr;
 use std::str::FromStr;
-use std::sync::RwLock;
-use super::SerialNumber;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration as StdDuration, Instant, SystemTime};
+#[cfg(feature = "subscriptions")]
+use subscriptions::{NotificationType, Subscriptions};
+use super::{SerialNumber, Udn};
+use super::breaker::{CircuitBreaker, CircuitState};
+use super::clock::{ClockDrift, DeviceTime, TIME_SYNC_SERVICE};
+use super::history::StateHistory;
+use super::service::{Service, ServiceDescription};
 use super::state::WemoState::{Off, On, OnWithoutLoad};
-use super::state::WemoState;
+use super::state::{BinaryState, StateReport, StateSource, WemoState};
+use threads;
 use time::PreciseTime;
-use url::ParseError;
 use xml::find_tag_value;
 
 pub type WemoResult = Result<WemoState, WemoError>;
 
-/// Default Wemo API port (HTTP).
-/// Wemo devices change ports occasionally by incrementing the port number.
-const DEFAULT_API_PORT: u16 = 49153;
+/// How often `wait_for_state` reads the state of a device that isn't
+/// push-fed.
+const WAIT_POLL_MS: i64 = 250;
 
-const FIRST_ATTEMPT_TIMEOUT: i64 = 300;
+/// How often `wait_for_state` checks the pushed state of a push-fed device.
+const WAIT_PUSH_CHECK_MS: i64 = 20;
+
+/// How long a toggle of a push-fed device waits for the device's event
+/// before reading the new state back instead.
+const TOGGLE_PUSH_CONFIRM_MS: i64 = 500;
+
+/// How long `wait_until_online` first waits between attempts. This doubles
+/// after each attempt, up to `WAIT_ONLINE_MAX_BACKOFF_MS`.
+const WAIT_ONLINE_MIN_BACKOFF_MS: i64 = 250;
+
+const WAIT_ONLINE_MAX_BACKOFF_MS: i64 = 5_000;
+
+/// The most time a request to a device that needs relocation spends probing
+/// its ports first, in case only the port changed.
+const PROBE_BEFORE_REQUEST_MS: i64 = 100;
+
+/// Log with the device's serial number and IP address attached as structured
+/// fields, so log aggregation can filter per device.
+macro_rules! log_device {
+  ($level:ident, $switch:expr, $($key:ident = $value:expr),+; $($arg:tt)+) => {
+    $level!(target: "wemo", serial:? = $switch.serial_number,
+        ip:? = $switch.get_ip_address(), $($key = $value),+; $($arg)+)
+  };
+}
 
 // A method of identifying a WeMo device on the network. When a WeMo device
 // goes offline, this is what we use to find it again.
+#[derive(Clone)]
 pub enum DeviceIdentifier {
   // A static IP address is the best way to find a device.
   StaticIp(IpAddr),
@@ -38,105 +85,130 @@ pub enum DeviceIdentifier {
   // TODO: DeviceName(String),
   // The WeMo serial number unique to the device.
   // TODO: SerialNumber(String),
+  // The UPnP UDN, eg. `uuid:Socket-1_0-221517K0101769`. Unlike the serial
+  // number, this is guaranteed unique and survives firmware changes.
+  Udn(Udn),
   // Transient value while this is unimplemented.
   Unimplemented, // TODO: Remove.
 }
 
-// TODO: Problems between internalized client, mutability, and clonability
-
-/// Represents a Wemo Switch device.
+/// Represents a Wemo Switch device. Clones are cheap handles to the same
+/// device: they share its location, cached state, and connection, so an
+/// update learned through one, eg. a new port, is seen by all of them.
+#[derive(Clone)]
 pub struct Switch {
   /// How we identify the device on the network. A static IP address is optimal.
   device_identifier: DeviceIdentifier,
 
+  // TODO: Make private. Only temporary.
+  /// The device's unique serial number.
+  pub serial_number: Option<SerialNumber>,
+
+  /// Extra HTTP headers sent with every SOAP request to the device.
+  headers: Vec<(String, String)>,
+
+  /// Defaults for timeouts, retries, ports, etc.
+  config: WemoConfig,
+
+  /// How long an identity check is trusted in paranoid mode, if enabled.
+  /// See `with_paranoid_mode`.
+  paranoid_ttl: Option<Duration>,
+
+  /// What's learned about the device as it's used, shared by every clone.
+  shared: Arc<SharedState>,
+}
+
+struct SharedState {
   /// Location of the device if a dynamic IP address is used.
-  dynamic_ip_address: RwLock<Option<IpAddr>>,
+  dynamic_ip_address: AtomicIpAddr,
 
   /// Last known port the device used.
   /// Wemo devices are notorious for occasionally changing ports, so we keep
   /// track of the last one we found it using to reduce failed requests and
-  /// retries.
-  port: RwLock<Option<u16>>,
+  /// retries. The candidates to fall back on come from the config.
+  port: AtomicPort,
 
-  // TODO: Make private. Only temporary.
-  /// The device's unique serial number.
-  pub serial_number: Option<SerialNumber>,
+  /// Set when a request fails to reach the device, so a `RelocationWorker`
+  /// can find it again before the next request.
+  needs_relocation: AtomicBool,
+
+  /// The most recent state reading.
+  last_report: RwLock<Option<StateReport>>,
+
+  /// Whether subscription events keep `last_report` current. Shared with
+  /// the subscription, which clears it when it lapses.
+  push_fed: Arc<AtomicBool>,
+
+  /// When the device at the current IP address was last known to be this
+  /// one. Cleared when the address changes.
+  identity_verified_at: RwLock<Option<Instant>>,
+
+  /// Recent state transitions, if kept. See `keep_history`.
+  history: Mutex<Option<StateHistory>>,
+
+  /// A connection opened ahead of time by `preconnect`, used by the next
+  /// request.
+  warm_client: Mutex<Option<SoapClient>>,
+
+  /// Whether to keep the preconnected connection open between requests.
+  keep_alive: AtomicBool,
+
+  /// Result of the latest liveness check or request.
+  reachable: AtomicBool,
+
+  /// When a relocation last failed to find the device, if it hasn't been
+  /// found since.
+  relocation_failed_at: RwLock<Option<PreciseTime>>,
+
+  /// Consecutive failures, for the configured circuit breaker.
+  breaker: CircuitBreaker,
 }
 
 /// Functions for WeMo Switch.
 impl Switch {
   /// Switch CTOR.
   #[deprecated(since="0.0.11")]
-  pub fn new(url: Url) -> Switch {
-    let mut maybe_ip_addr = None;
-
-    let host = url.host();
-    if host.is_some() {
-      maybe_ip_addr = match url.host().unwrap() {
-        Host::Domain(_) => None,
-        Host::Ipv4(ip) => Some(IpAddr::V4(ip)),
-        Host::Ipv6(ip) => Some(IpAddr::V6(ip)),
-      };
-    }
-
-    Switch {
-      // NB: Without an IP, we will never be able to talk to the device.
-      // This is acceptable since this CTOR is deprecated / going away.
-      dynamic_ip_address: RwLock::new(maybe_ip_addr),
-      port: RwLock::new(url.port()),
-      device_identifier: DeviceIdentifier::Unimplemented,
-      serial_number: None,
-    }
+  pub fn new(url: SetupUrl) -> Switch {
+    // NB: Without an IP, we will never be able to talk to the device.
+    // This is acceptable since this CTOR is deprecated / going away.
+    Switch::from_parts(DeviceIdentifier::Unimplemented, url.ip_address(),
+        url.port(), None)
   }
 
   /// Construct a device that lives behind a static IP address.
   /// We won't need to issue later SSDP searches to find or relocate the device.
   pub fn from_static_ip(ip_address: IpAddr) -> Switch {
-    Switch {
-      device_identifier: DeviceIdentifier::StaticIp(ip_address),
-      dynamic_ip_address: RwLock::new(None),
-      port: RwLock::new(None),
-      serial_number: None,
-    }
+    Switch::from_parts(DeviceIdentifier::StaticIp(ip_address), None, None,
+        None)
   }
 
   /// Also include port (ports are subject to change).
   pub fn from_static_ip_and_port(ip_address: IpAddr, port: u16) -> Switch {
-    Switch {
-      device_identifier: DeviceIdentifier::StaticIp(ip_address),
-      dynamic_ip_address: RwLock::new(None),
-      port: RwLock::new(Some(port)),
-      serial_number: None,
-    }
+    Switch::from_parts(DeviceIdentifier::StaticIp(ip_address), None,
+        Some(port), None)
   }
 
   /// Construct a device that lives behind a static IP address.
   /// We may need to relocate this device later if it changes IP by issuing SSDP
   /// searches.
   pub fn from_dynamic_ip(ip_address: IpAddr) -> Switch {
-    Switch {
-      device_identifier: DeviceIdentifier::Unimplemented, // TODO: Not permanent!
-      dynamic_ip_address: RwLock::new(Some(ip_address)),
-      port: RwLock::new(None),
-      serial_number: None,
-    }
+    // TODO: DeviceIdentifier::Unimplemented is not permanent!
+    Switch::from_parts(DeviceIdentifier::Unimplemented, Some(ip_address), None,
+        None)
   }
 
   /// Also include port (ports are subject to change).
   pub fn from_dynamic_ip_and_port(ip_address: IpAddr, port: u16) -> Switch {
-    Switch {
-      device_identifier: DeviceIdentifier::Unimplemented, // TODO: Not permanent!
-      dynamic_ip_address: RwLock::new(Some(ip_address)),
-      port: RwLock::new(Some(port)),
-      serial_number: None,
-    }
+    // TODO: DeviceIdentifier::Unimplemented is not permanent!
+    Switch::from_parts(DeviceIdentifier::Unimplemented, Some(ip_address),
+        Some(port), None)
   }
 
   /// Switch CTOR.
   #[allow(deprecated)]
   #[deprecated(since="0.0.11")]
-  pub fn from_url(url: &str) -> Result<Switch, ParseError> {
-    match Url::parse(url) {
+  pub fn from_url(url: &str) -> Result<Switch, WemoError> {
+    match SetupUrl::parse(url) {
       Ok(parsed_url) => { Ok(Switch::new(parsed_url)) },
       Err(e) => { Err(e) },
     }
@@ -147,400 +219,1595 @@ impl Switch {
   pub fn from_ip_and_port(ip_addr: &str, port: u16) -> Switch {
     // TODO: Unsafe. Going away, though!
     let ip_addr = IpAddr::from_str(ip_addr).unwrap();
-    Switch {
-      dynamic_ip_address: RwLock::new(Some(ip_addr)),
-      port: RwLock::new(Some(port)),
-      device_identifier: DeviceIdentifier::Unimplemented,
-      serial_number: None,
-    }
+    Switch::from_parts(DeviceIdentifier::Unimplemented, Some(ip_addr),
+        Some(port), None)
+  }
+
+  /// Switch CTOR. The device has no known location until it is found with
+  /// `relocate`.
+  pub fn from_udn(udn: &str) -> Switch {
+    Switch::from_parts(DeviceIdentifier::Udn(udn.to_string()), None, None,
+        None)
   }
 
   // TODO: TEST.
   /// Switch CTOR.
+  #[cfg(feature = "discovery")]
   fn from_search_result(search_result: &SsdpResponse) -> Switch {
+    let switch = Switch::from_parts(
+        DeviceIdentifier::Udn(search_result.udn.clone()),
+        Some(search_result.ip_address.clone()),
+        Some(search_result.port),
+        Some(search_result.serial_number.clone()));
+    // Identified by the USN it answered with.
+    switch.mark_identified();
+    switch
+  }
+
+  /// The CTOR all others use. Crate-wide defaults are copied from the global
+  /// `WemoConfig`.
+  fn from_parts(device_identifier: DeviceIdentifier,
+                dynamic_ip_address: Option<IpAddr>,
+                port: Option<u16>,
+                serial_number: Option<SerialNumber>) -> Switch {
+    let config = global_config();
+
     Switch {
-      dynamic_ip_address: RwLock::new(Some(search_result.ip_address.clone())),
-      port: RwLock::new(Some(search_result.port)),
-      device_identifier: DeviceIdentifier::Unimplemented,
-      serial_number: Some(search_result.serial_number.clone()),
+      device_identifier: device_identifier,
+      serial_number: serial_number,
+      headers: Vec::new(),
+      config: config,
+      paranoid_ttl: None,
+      shared: Arc::new(SharedState {
+        dynamic_ip_address: AtomicIpAddr::new(dynamic_ip_address),
+        port: AtomicPort::new(port),
+        needs_relocation: AtomicBool::new(false),
+        last_report: RwLock::new(None),
+        push_fed: Arc::new(AtomicBool::new(false)),
+        identity_verified_at: RwLock::new(None),
+        history: Mutex::new(None),
+        warm_client: Mutex::new(None),
+        keep_alive: AtomicBool::new(false),
+        reachable: AtomicBool::new(true),
+        relocation_failed_at: RwLock::new(None),
+        breaker: CircuitBreaker::new(),
+      }),
+    }
+  }
+
+  /// Use these settings instead of the global `WemoConfig`.
+  pub fn with_config(mut self, config: WemoConfig) -> Switch {
+    self.config = config;
+    self
+  }
+
+  /// The settings this device uses.
+  pub fn config(&self) -> &WemoConfig {
+    &self.config
+  }
+
+  /// Send an extra HTTP header with every SOAP request to the device. Useful
+  /// for working around firmware quirks, eg. `Connection: close`. Fails if
+  /// the header can't be sent as given, eg. its value holds a line break.
+  pub fn with_header(mut self, name: &str, value: &str)
+      -> Result<Switch, WemoError> {
+    http::check_header(name, value)?;
+    self.headers.push((name.to_string(), value.to_string()));
+    Ok(self)
+  }
+
+  /// Make sure the device is the expected one before every `SetBinaryState`,
+  /// static IP or not, re-checking once the last check is older than `ttl`.
+  /// For switches controlling loads where reaching the wrong device would be
+  /// dangerous. See `verify_identity`.
+  pub fn with_paranoid_mode(mut self, ttl: Duration) -> Switch {
+    self.paranoid_ttl = Some(ttl);
+    self
+  }
+
+  /// Get the current state with the configured default timeout and retry
+  /// policy.
+  pub fn get_state_default(&self) -> WemoResult {
+    let timeout = self.config.default_timeout;
+    if self.config.retry_policy.relocate_on_failure {
+      self.get_state_with_retry(timeout)
+    } else {
+      self.get_state(timeout)
+    }
+  }
+
+  /// Set the current state with the configured default timeout and retry
+  /// policy.
+  pub fn set_state_default(&self, state: WemoState) -> WemoResult {
+    let timeout = self.config.default_timeout;
+    if self.config.retry_policy.relocate_on_failure {
+      self.set_state_with_retry(state, timeout)
+    } else {
+      self.set_state(state, timeout)
+    }
+  }
+
+  /// Turn the device on with the configured default timeout and retry policy.
+  pub fn turn_on_default(&self) -> WemoResult {
+    log_device!(info, self, action = "turn_on"; "Turning on: {}", self.name());
+    self.set_state_default(On)
+  }
+
+  /// Turn the device off with the configured default timeout and retry
+  /// policy.
+  pub fn turn_off_default(&self) -> WemoResult {
+    log_device!(info, self, action = "turn_off"; "Turning off: {}", self.name());
+    self.set_state_default(Off)
+  }
+
+  /// Toggle the device with the configured default timeout and retry policy.
+  pub fn toggle_default(&self) -> WemoResult {
+    let timeout = self.config.default_timeout;
+    if self.config.retry_policy.relocate_on_failure {
+      self.toggle_with_retry(timeout)
+    } else {
+      self.toggle(timeout)
     }
   }
 
   /// Turn the device on.
   pub fn turn_on(&self, timeout: Duration) -> WemoResult {
-    info!(target: "wemo", "Turning on: {}", self.name());
+    log_device!(info, self, action = "turn_on"; "Turning on: {}", self.name());
     self.set_state(On, timeout)
   }
 
   /// Turn the device on.
   pub fn turn_on_with_retry(&self, timeout: Duration) -> WemoResult {
-    info!(target: "wemo", "Turning on with retry: {}", self.name());
+    log_device!(info, self, action = "turn_on"; "Turning on with retry: {}", self.name());
     self.set_state_with_retry(On, timeout)
   }
 
+  /// Turn the device on, unless cancelled first.
+  pub fn turn_on_cancellable(&self, timeout: Duration,
+                             cancellation: &CancellationToken) -> WemoResult {
+    log_device!(info, self, action = "turn_on"; "Turning on: {}", self.name());
+    self.send_set_state(On, timeout, Some(cancellation))
+  }
+
   /// Turn the device off.
   pub fn turn_off(&self, timeout: Duration) -> WemoResult {
-    info!(target: "wemo", "Turning off: {}", self.name());
+    log_device!(info, self, action = "turn_off"; "Turning off: {}", self.name());
     self.set_state(Off, timeout)
   }
 
+  /// Turn the device off, unless cancelled first.
+  pub fn turn_off_cancellable(&self, timeout: Duration,
+                              cancellation: &CancellationToken) -> WemoResult {
+    log_device!(info, self, action = "turn_off"; "Turning off: {}", self.name());
+    self.send_set_state(Off, timeout, Some(cancellation))
+  }
+
   /// Turn the device off.
   pub fn turn_off_with_retry(&self, timeout: Duration) -> WemoResult {
-    info!(target: "wemo", "Turning off with retry: {}", self.name());
+    log_device!(info, self, action = "turn_off"; "Turning off with retry: {}", self.name());
     self.set_state_with_retry(Off, timeout)
   }
 
-  /// Toggle the device on or off.
+  /// Toggle the device on or off. While the switch is push-fed, this takes
+  /// one round trip instead of two, as long as the device's event confirms
+  /// the change; see `set_push_fed`.
   pub fn toggle(&self, timeout: Duration) -> WemoResult {
-    let mut state: Option<WemoState> = None;
-    let mut error: Option<WemoError> = None;
-
-    let elapsed = Duration::span(|| {
-      match self.get_state(timeout) {
-        Ok(result) => {
-          state = Some(result);
-        },
-        Err(_) => {
-          error = Some(WemoError::BadResponseError); // TODO: Wrong error
-        },
+    let deadline = Deadline::after(timeout);
+    if let Some(cached) = self.pushed_state() {
+      if let Some(state) = self.toggle_pushed(cached, deadline)? {
+        return Ok(state);
       }
-    });
-
-    if error.is_some() {
-      return Err(error.unwrap());
-    } else if elapsed > timeout {
-      return Err(WemoError::TimeoutError);
     }
 
-    let remaining = timeout - elapsed;
+    let state = self.get_state(deadline.remaining())?;
 
-    match state {
-      Some(Off) => {
-        self.turn_on(remaining)
-      },
-      Some(On) => {
-        self.turn_off(remaining)
-      },
-      Some(OnWithoutLoad) => {
-        self.turn_off(remaining)
-      },
-      Some(_) | None => {
-        Err(WemoError::WemoError)
-      },
-    }
+    self.toggle_from(state, deadline, false)
   }
 
   /// Toggle the device on or off.
   pub fn toggle_with_retry(&self, timeout: Duration) -> WemoResult {
-    let mut state: Option<WemoState> = None;
-    let mut error: Option<WemoError> = None;
-
-    let elapsed = Duration::span(|| {
-      match self.get_state_with_retry(timeout) {
-        Ok(result) => {
-          state = Some(result);
-        },
+    let deadline = Deadline::after(timeout);
+    if let Some(cached) = self.pushed_state() {
+      match self.toggle_pushed(cached.clone(), deadline) {
+        Ok(Some(state)) => { return Ok(state); },
+        Ok(None) => {}, // Stale, so read it instead.
+        Err(WemoError::CircuitOpen) => { return Err(WemoError::CircuitOpen); },
         Err(_) => {
-          error = Some(WemoError::BadResponseError); // TODO: Wrong error
+          // The change may have gone through, so retry it rather than
+          // toggling again.
+          return self.set_state_by(toggled(&cached)?, deadline);
         },
       }
-    });
-
-    if error.is_some() {
-      return Err(error.unwrap());
-    } else if elapsed > timeout {
-      return Err(WemoError::TimeoutError);
     }
 
-    let remaining = timeout - elapsed;
+    let state = self.get_state_by(deadline)?;
 
-    match state {
-      Some(Off) => {
-        self.turn_on_with_retry(remaining)
-      },
-      Some(On) => {
-        self.turn_off_with_retry(remaining)
-      },
-      Some(OnWithoutLoad) => {
-        self.turn_off_with_retry(remaining)
-      },
-      Some(_) | None => {
-        Err(WemoError::WemoError)
-      },
+    self.toggle_from(state, deadline, true)
+  }
+
+  fn toggle_from(&self, state: WemoState, deadline: Deadline, retry: bool)
+      -> WemoResult {
+    let state = toggled(&state)?;
+
+    let remaining = deadline.check()?;
+    if retry {
+      self.set_state_by(state, deadline)
+    } else {
+      self.set_state(state, remaining)
     }
   }
 
   /// Get the current state of the device.
   pub fn get_state(&self, timeout: Duration) -> WemoResult {
-    let ip_address = self.get_ip_address().ok_or(WemoError::NoLocalIp)?;
-    let port = self.get_port().unwrap_or(DEFAULT_API_PORT);
+    self.get_binary_state(timeout).map(|binary_state| binary_state.state)
+  }
 
-    let mut client = match SoapClient::connect(ip_address, port) {
-      Some(c) => { c },
-      None => {
-        return Err(WemoError::BadResponseError); // TODO WRONG TYPE
-      },
-    };
+  /// Get the current state of the device before the deadline passes. Takes
+  /// a `Deadline`, or an `Instant`.
+  pub fn get_state_until<D: Into<Deadline>>(&self, deadline: D)
+      -> WemoResult {
+    self.get_state(deadline.into().check()?)
+  }
 
-    let xml_body = "\
-      <?xml version=\"1.0\" encoding=\"utf-8\"?>\
-        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"\
-            s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
-          <s:Body>\
-            <u:GetBinaryState xmlns:u=\"urn:Belkin:service:basicevent:1\">\
-              <BinaryState>1</BinaryState>\
-            </u:GetBinaryState>\
-          </s:Body>\
-        </s:Envelope>";
-
-    let request = SoapRequest {
-      request_path: "/upnp/control/basicevent1".to_string(),
-      soap_action: "urn:Belkin:service:basicevent:1#GetBinaryState".to_string(),
-      http_post_payload: xml_body.to_string(),
-    };
+  /// Set the current state of the device before the deadline passes.
+  pub fn set_state_until<D: Into<Deadline>>(&self, state: WemoState,
+                                            deadline: D) -> WemoResult {
+    self.set_state(state, deadline.into().check()?)
+  }
 
-    let response = client.post(request, timeout.num_milliseconds() as u64);
+  /// Turn the device on before the deadline passes.
+  pub fn turn_on_until<D: Into<Deadline>>(&self, deadline: D) -> WemoResult {
+    log_device!(info, self, action = "turn_on"; "Turning on: {}", self.name());
+    self.set_state_until(On, deadline)
+  }
 
-    // TODO: Stronger return error types
-    let body = match response {
-      Some(r) => { r },
-      None => {
-        return Err(WemoError::BadResponseError);
-      }
-    };
+  /// Turn the device off before the deadline passes.
+  pub fn turn_off_until<D: Into<Deadline>>(&self, deadline: D)
+      -> WemoResult {
+    log_device!(info, self, action = "turn_off"; "Turning off: {}", self.name());
+    self.set_state_until(Off, deadline)
+  }
 
-    // TODO: Error handle.
-    let state = find_tag_value("BinaryState", body.as_ref()).unwrap_or("");
-    match WemoState::from_i64(state.parse::<i64>().unwrap()) {
-      Some(result) => {
-        Ok(result)
-      },
-      None => {
-        Err(WemoError::WemoError)
-      }
+  /// Toggle the device on or off. Both reading and setting the state must
+  /// finish before the deadline passes.
+  pub fn toggle_until<D: Into<Deadline>>(&self, deadline: D) -> WemoResult {
+    let deadline = deadline.into();
+    match self.get_state_until(deadline)? {
+      Off => self.turn_on_until(deadline),
+      On | OnWithoutLoad => self.turn_off_until(deadline),
+      _ => Err(WemoError::WemoError),
     }
   }
 
-  /// Set the current state of the device.
-  pub fn set_state(&self, state: WemoState, timeout: Duration) -> WemoResult {
-    let ip_address = self.get_ip_address().ok_or(WemoError::NoLocalIp)?;
-    let port = self.get_port().unwrap_or(DEFAULT_API_PORT);
+  /// Get the current state of the device, unless cancelled first.
+  pub fn get_state_cancellable(&self, timeout: Duration,
+                               cancellation: &CancellationToken)
+      -> WemoResult {
+    self.send_get_binary_state(timeout, Some(cancellation))
+        .map(|binary_state| binary_state.state)
+  }
 
-    let mut client = match SoapClient::connect(ip_address, port) {
-      Some(c) => { c },
-      None => {
-        return Err(WemoError::BadResponseError); // TODO WRONG TYPE
-      },
-    };
+  /// Wait until the device reaches `state`, eg. after commanding a device
+  /// with a slow relay, or for another controller to act. Push-fed switches
+  /// watch their pushed state; others are read every `WAIT_POLL_MS`. Any on
+  /// state satisfies `On`. Fails with `WemoError::TimeoutError` if the state
+  /// isn't reached in time. See `WaitForState` for async applications.
+  pub fn wait_for_state(&self, state: WemoState, timeout: Duration)
+      -> WemoResult {
+    self.wait_for_state_cancellable(state, timeout, &CancellationToken::new())
+  }
 
-    let xml_body = format!("\
-      <?xml version=\"1.0\" encoding=\"utf-8\"?>\
-        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"\
-            s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
-          <s:Body>\
-            <u:SetBinaryState xmlns:u=\"urn:Belkin:service:basicevent:1\">\
-              <BinaryState>{}</BinaryState>\
-            </u:SetBinaryState>\
-          </s:Body>\
-        </s:Envelope>\
-      ", state.to_i8());
-
-    let request = SoapRequest {
-      request_path: "/upnp/control/basicevent1".to_string(),
-      soap_action: "urn:Belkin:service:basicevent:1#SetBinaryState".to_string(),
-      http_post_payload: xml_body.to_string(),
-    };
+  /// Wait until the device reaches `state`, unless cancelled first.
+  pub fn wait_for_state_cancellable(&self, state: WemoState,
+                                    timeout: Duration,
+                                    cancellation: &CancellationToken)
+      -> WemoResult {
+    let deadline = Deadline::after(timeout);
 
-    let response = client.post(request, timeout.num_milliseconds() as u64);
+    loop {
+      if cancellation.is_cancelled() {
+        return Err(WemoError::Cancelled);
+      }
 
-    match response {
-      Some(_) => { Ok(state)  }, // TODO: Check to ensure matches requested state
-      None => { Err(WemoError::BadResponseError) },
-    }
-  }
+      let pushed = self.pushed_state();
+      let pause = if pushed.is_some() {
+        WAIT_PUSH_CHECK_MS
+      } else {
+        WAIT_POLL_MS
+      };
 
-  // TODO: Make private.
-  pub fn get_state_with_retry(&self, timeout: Duration) -> WemoResult {
-    let mut start = PreciseTime::now();
+      let current = match pushed {
+        Some(current) => Some(current),
+        None => {
+          match self.send_get_binary_state(
+              deadline.check_for(TimeoutStage::Wait)?,
+                                           Some(cancellation)) {
+            Ok(binary_state) => Some(binary_state.state),
+            Err(WemoError::CircuitOpen) => {
+              return Err(WemoError::CircuitOpen);
+            },
+            Err(WemoError::Cancelled) => { return Err(WemoError::Cancelled); },
+            Err(_) => None, // Keep trying until the deadline.
+          }
+        },
+      };
 
-    // TODO: use the minimum of the timestamps
-    let result = self.get_state(Duration::milliseconds(FIRST_ATTEMPT_TIMEOUT));
+      if let Some(current) = current {
+        if current == state || (current.is_on() && state.is_on()) {
+          return Ok(current);
+        }
+      }
 
-    match result {
-      Ok(r) => { return Ok(r); },
-      Err(_) => {}, // TODO
+      let pause = deadline.capped(Duration::milliseconds(pause));
+      if pause <= Duration::zero() {
+        return Err(deadline.timed_out(TimeoutStage::Wait));
+      }
+      if let Ok(pause) = pause.to_std() {
+        thread::sleep(pause);
+      }
     }
+  }
 
-    let mut elapsed = start.to(PreciseTime::now());
-
-    if elapsed > timeout {
-      return Err(WemoError::TimeoutError);
-    }
+  /// Wait until the device responds, eg. in startup sequences where WeMos
+  /// boot slower than the host controlling them. After each failed read the
+  /// device is looked for on its other ports and via SSDP, backing off
+  /// between attempts. Returns the device's state once it responds.
+  pub fn wait_until_online(&self, timeout: Duration) -> WemoResult {
+    let deadline = Deadline::after(timeout);
+    let mut backoff = Duration::milliseconds(WAIT_ONLINE_MIN_BACKOFF_MS);
+
+    loop {
+      let pause = Deadline::after(backoff);
+
+      let attempt = deadline.check_for(TimeoutStage::Wait)?
+          .min(self.config.retry_policy.first_attempt_timeout);
+      if let Ok(binary_state) = self.send_get_binary_state(attempt, None) {
+        return Ok(binary_state.state);
+      }
 
-    let mut remaining = timeout - elapsed;
-    if remaining <= Duration::zero() {
-      return Err(WemoError::TimeoutError);
-    }
+      // A search during boot says nothing about the next one.
+      self.clear_negative_cache();
+      self.race_relocation(deadline.check_for(TimeoutStage::Wait)?
+          .min(backoff));
 
-    start = PreciseTime::now();
+      log_device!(debug, self, action = "wait_until_online";
+          "Device isn't online yet: {}", self.name());
 
-    let switch = match self.relocate(remaining) {
-      None => { return Err(WemoError::TimeoutError); }, // TODO: Wrong.
-      Some(s) => { s },
-    };
+      if let Ok(pause) = deadline.capped(pause.remaining()).to_std() {
+        thread::sleep(pause);
+      }
 
-    elapsed = start.to(PreciseTime::now());
-    if elapsed > remaining {
-      return Err(WemoError::TimeoutError);
+      backoff = (backoff * 2)
+          .min(Duration::milliseconds(WAIT_ONLINE_MAX_BACKOFF_MS));
     }
+  }
 
-    remaining = remaining - elapsed;
-    if remaining <= Duration::zero() {
-      return Err(WemoError::TimeoutError);
-    }
+  /// Get the current state of the device, including the extended power and
+  /// usage fields if the device is an Insight.
+  pub fn get_binary_state(&self, timeout: Duration)
+      -> Result<BinaryState, WemoError> {
+    self.send_get_binary_state(timeout, None)
+  }
 
-    switch.get_state(remaining)
+  /// Get the current state of the device along with how long it took to
+  /// respond.
+  pub fn get_state_report(&self, timeout: Duration)
+      -> Result<StateReport, WemoError> {
+    let start = PreciseTime::now();
+    let binary_state = self.send_get_binary_state(timeout, None)?;
+    let latency = start.to(PreciseTime::now());
+
+    let report = StateReport::new(binary_state.state, latency,
+        StateSource::Poll);
+    self.remember_report(report.clone());
+    Ok(report)
   }
 
-  // TODO: Make private
-  pub fn set_state_with_retry(&self, state: WemoState, timeout: Duration)
-      -> WemoResult {
-    let mut start = PreciseTime::now();
+  /// The most recent reading from `get_state_report`, `record_push`, or a
+  /// state change, if any, without contacting the device.
+  pub fn cached_state_report(&self) -> Option<StateReport> {
+    self.shared.last_report.read()
+        .ok()
+        .and_then(|report| report.clone())
+        .map(|mut report| {
+          report.source = StateSource::Cache;
+          report
+        })
+  }
 
-    // TODO: use the minimum of the timestamps
-    let result = self.set_state(state.clone(),
-        Duration::milliseconds(FIRST_ATTEMPT_TIMEOUT));
+  /// Mark whether subscription events keep this switch's state current, by
+  /// way of `record_push`. While they do, toggling trusts the last known
+  /// state instead of reading it first, and reads it after all if the
+  /// device refuses the change because that state was stale. Turn this off
+  /// when the subscription lapses.
+  pub fn set_push_fed(&self, push_fed: bool) {
+    self.shared.push_fed.store(push_fed, Ordering::SeqCst);
+  }
 
-    match result {
-      Ok(r) => { return Ok(r); },
-      Err(_) => {}, // TODO: Return type
+  pub fn is_push_fed(&self) -> bool {
+    self.shared.push_fed.load(Ordering::SeqCst)
+  }
+
+  /// The last known state, if pushes keep it current.
+  fn pushed_state(&self) -> Option<WemoState> {
+    if !self.is_push_fed() {
+      return None;
     }
 
-    let mut elapsed = start.to(PreciseTime::now());
+    self.shared.last_report.read()
+        .ok()
+        .and_then(|report| report.as_ref().map(|r| r.state.clone()))
+        .and_then(|state| match state {
+          WemoState::Unknown(_) => None,
+          state => Some(state),
+        })
+  }
 
-    if elapsed > timeout {
-      return Err(WemoError::TimeoutError);
+  /// Toggle from the push-fed state with a single `SetBinaryState`, then
+  /// wait for the device's event to confirm the new state, reading it back
+  /// if none comes. Returns `None`, having changed nothing, if the device
+  /// was already in the new state.
+  fn toggle_pushed(&self, cached: WemoState, deadline: Deadline)
+      -> Result<Option<WemoState>, WemoError> {
+    let state = toggled(&cached)?;
+    let (refused, _) = self.post_set_state(&state, deadline.check()?,
+        None)?;
+
+    if refused {
+      log_device!(debug, self, action = "toggle";
+          "Pushed state was stale: {}", self.name());
+      return Ok(None);
     }
 
-    let mut remaining = timeout - elapsed;
-    if remaining <= Duration::zero() {
-      return Err(WemoError::TimeoutError);
+    // NB: The pushed state stays the old one until the event arrives.
+    let confirm = deadline.capped(
+        Duration::milliseconds(TOGGLE_PUSH_CONFIRM_MS));
+    if let Ok(state) = self.wait_for_state(state, confirm) {
+      return Ok(Some(state));
     }
 
-    start = PreciseTime::now();
+    log_device!(debug, self, action = "toggle";
+        "No event confirmed the change, reading it back: {}", self.name());
+    let report = self.get_state_report(deadline.check()?)?;
+    Ok(Some(report.state))
+  }
 
-    let switch = match self.relocate(remaining) {
-      None => {
-        return Err(WemoError::TimeoutError); // TODO: Wrong err.
-      },
-      Some(s) => { s },
-    };
+  /// Remember a state the device sent in a subscription event.
+  pub fn record_push(&self, state: WemoState) -> StateReport {
+    let report = StateReport::new(state, Duration::zero(), StateSource::Push);
+    self.remember_report(report.clone());
+    report
+  }
 
-    elapsed = start.to(PreciseTime::now());
-    if elapsed > remaining {
-      return Err(WemoError::TimeoutError);
-    }
+  /// The state from `cached_state_report`, if any.
+  pub fn last_known_state(&self) -> Option<WemoState> {
+    self.cached_state_report().map(|report| report.state)
+  }
 
-    remaining = remaining - elapsed;
-    if remaining <= Duration::zero() {
-      return Err(WemoError::TimeoutError);
-    }
+  /// Subscribe to the device's events, keeping its last known state current
+  /// with `record_push` and marking it push-fed. This lasts until
+  /// `detach_subscriptions` or until `subscriptions` is dropped. While a
+  /// renewal of the subscription fails, the switch isn't push-fed.
+  #[cfg(feature = "subscriptions")]
+  pub fn attach_subscriptions(&self, subscriptions: &Subscriptions)
+      -> Result<(), WemoError> {
+    let host = self.subscription_host()?;
+    let switch = self.clone();
+    subscriptions.subscribe(&host, move |n| {
+      if let NotificationType::State { state } = n.notification_type {
+        switch.record_push(state);
+      }
+    })?;
 
-    switch.set_state(state.clone(), remaining)
+    log_device!(debug, self, action = "attach_subscriptions";
+        "Attached subscription: {}", self.name());
+    subscriptions.track_push_fed(&host, self.shared.push_fed.clone())
   }
 
-  /// Returns the static IP if the Wemo was configured with a static IP,
-  /// otherwise returns the last cached IP address (which may not be set).
-  pub fn get_ip_address(&self) -> Option<IpAddr> {
-    match self.device_identifier {
-      DeviceIdentifier::StaticIp(ip) => Some(ip.clone()),
-      _ => {
-        self.dynamic_ip_address.read()
-            .ok()
-            .and_then(|ip| ip.clone())
-      },
+  /// Cancel the subscription `attach_subscriptions` made. The last known
+  /// state is kept, but no longer trusted when toggling.
+  #[cfg(feature = "subscriptions")]
+  pub fn detach_subscriptions(&self, subscriptions: &Subscriptions)
+      -> Result<(), WemoError> {
+    self.set_push_fed(false);
+    subscriptions.unsubscribe(&self.subscription_host()?)
+  }
+
+  /// How `Subscriptions` refers to the device, eg. `192.168.1.4:49153`.
+  #[cfg(feature = "subscriptions")]
+  fn subscription_host(&self) -> Result<String, WemoError> {
+    let ip_address = self.get_ip_address().ok_or(WemoError::NoLocalIp)?;
+    Ok(SocketAddr::new(ip_address, self.get_ports().preferred()).to_string())
+  }
+
+  /// Keep the last `capacity` state transitions seen in readings, pushes,
+  /// and state changes, for `history`. Any transitions already kept are
+  /// forgotten.
+  pub fn keep_history(&self, capacity: usize) {
+    match self.shared.history.lock() {
+      Err(_) => {}, // Ignore.
+      Ok(mut history) => { *history = Some(StateHistory::new(capacity)); },
     }
   }
 
-  /// Get the currently known port. If we haven't manually set the port or
-  /// talked to the Wemo device yet, the port will not be set.
-  pub fn get_port(&self) -> Option<u16> {
-    self.port.read()
+  /// Kept state transitions at or after `since`, oldest first. Empty unless
+  /// `keep_history` was called.
+  pub fn history(&self, since: SystemTime) -> Vec<StateReport> {
+    self.shared.history.lock()
         .ok()
-        .and_then(|port| *port)
+        .and_then(|history| history.as_ref().map(|h| h.since(since)))
+        .unwrap_or_default()
   }
 
-  // TODO: Refactor this to not create a new 'Switch'. Use interior mutability
-  // and return a boolean if the device was found.
-  // rename pub fn locate(&self, Duration) -> bool, but recommend against use
-  /// Attempt to find the Switch on the network via SSDP.
-  /// Both the IP address and port will be updated if they changed. (The IP
-  /// address will not be updated if the device is configured to use a static
-  /// IP.)
-  pub fn relocate(&self, timeout: Duration) -> Option<Switch> {
-    let result = if self.serial_number.is_some() {
-      // Guaranteed to be the same device unless there is spoofing
-      // (or Belkin assigned duplicate serial numbers).
-      self.relocate_by_serial(timeout)
-    } else {
-      // Won't necessarily be the same device if DHCP has reassigned
-      // the address.
-      self.relocate_by_ip(timeout)
-    };
+  fn remember_report(&self, report: StateReport) {
+    match self.shared.history.lock() {
+      Err(_) => {}, // Ignore.
+      Ok(mut history) => {
+        if let Some(ref mut history) = *history {
+          history.record(&report);
+        }
+      },
+    }
 
-    // Update existing Switch state.
-    if result.is_some() {
-      self.update_location(&result.as_ref().unwrap());
+    match self.shared.last_report.write() {
+      Err(_) => {}, // Ignore.
+      Ok(mut last_report) => { *last_report = Some(report); },
     }
+  }
 
-    result
+  /// Set the current state of the device.
+  pub fn set_state(&self, state: WemoState, timeout: Duration) -> WemoResult {
+    self.send_set_state(state, timeout, None)
   }
 
-  fn relocate_by_serial(&self, timeout: Duration) -> Option<Switch> {
-    let serial = match self.serial_number {
-      None => { return None; },
-      Some(ref s) => { s },
+  /// Set the current state of the device, unless cancelled first.
+  pub fn set_state_cancellable(&self, state: WemoState, timeout: Duration,
+                               cancellation: &CancellationToken)
+      -> WemoResult {
+    self.send_set_state(state, timeout, Some(cancellation))
+  }
+
+  fn send_get_binary_state(&self, timeout: Duration,
+                           cancellation: Option<&CancellationToken>)
+      -> Result<BinaryState, WemoError> {
+    let request = self.get_state_request();
+
+    let start = PreciseTime::now();
+    let response = self.post(request, timeout, cancellation)?;
+    let latency_ms = start.to(PreciseTime::now()).num_milliseconds();
+
+    log_device!(debug, self, action = "get_state", latency_ms = latency_ms,
+        success = response.is_some(); "GetBinaryState: {}", self.name());
+
+    // TODO: Stronger return error types
+    let body = match response {
+      Some(r) => { r },
+      None => {
+        return Err(self.failure_error(cancellation));
+      }
     };
 
-    let mut search = DeviceSearch::new();
+    self.shared.needs_relocation.store(false, Ordering::SeqCst);
+    self.shared.reachable.store(true, Ordering::SeqCst);
+    self.check_envelope(&body, "GetBinaryStateResponse")
+        .and_then(|_| parse_binary_state_with(&body, self.config.parsing_mode))
+        .map_err(|error| self.attach_body(error, &body))
+  }
 
-    match search.search_for_serial(serial, timeout.num_milliseconds() as u64){
-      None => { None },
-      Some(result) => { Some(Switch::from_search_result(result)) },
+  fn send_set_state(&self, state: WemoState, timeout: Duration,
+                    cancellation: Option<&CancellationToken>) -> WemoResult {
+    let deadline = Deadline::after(timeout);
+    let (refused, latency) = self.post_set_state(&state, timeout,
+        cancellation)?;
+
+    if refused {
+      self.confirm_state(&state, deadline.remaining(), cancellation)?;
     }
+
+    self.remember_report(StateReport::new(state.clone(), latency,
+        StateSource::Command));
+    Ok(state) // TODO: Check to ensure matches requested state
   }
 
-  fn relocate_by_ip(&self, timeout: Duration) -> Option<Switch> {
-    let ip_address = match self.get_ip_address() {
-      None => { return None; },
-      Some(ip) => { ip },
-    };
+  /// Send `SetBinaryState`, returning whether the device refused it with
+  /// `Error`, and how long it took to respond.
+  fn post_set_state(&self, state: &WemoState, timeout: Duration,
+                    cancellation: Option<&CancellationToken>)
+      -> Result<(bool, Duration), WemoError> {
+    let deadline = Deadline::after(timeout);
+    self.check_identity(timeout)?;
+    let timeout = deadline.remaining();
 
-    let mut search = DeviceSearch::new();
+    let request = self.set_state_request(state);
 
-    match search.search_for_ip(&ip_address, timeout.num_milliseconds() as u64) {
-      None => { None },
-      Some(result) => { Some(Switch::from_search_result(result)) },
+    let start = PreciseTime::now();
+    let response = self.post(request, timeout, cancellation)?;
+    let latency = start.to(PreciseTime::now());
+    let latency_ms = latency.num_milliseconds();
+
+    log_device!(debug, self, action = "set_state", latency_ms = latency_ms,
+        success = response.is_some(); "SetBinaryState: {}", self.name());
+
+    match response {
+      None => { Err(self.failure_error(cancellation)) },
+      Some(body) => {
+        self.shared.needs_relocation.store(false, Ordering::SeqCst);
+        self.shared.reachable.store(true, Ordering::SeqCst);
+        self.check_envelope(&body, "SetBinaryStateResponse")
+            .map_err(|error| self.attach_body(error, &body))?;
+        Ok((is_error_response(&body), latency))
+      },
     }
   }
 
-  // TODO: Take an SsdpResponse instead.
-  // Update the IP and port from a search result using internal mutability.
-  fn update_location(&self, search_result: &Switch) {
-    match self.port.write() {
-      Err(_) => {}, // Ignore.
-      Ok(mut port) => { *port = search_result.get_port(); },
+  /// Devices answer `SetBinaryState` with `Error` when they're already in
+  /// the requested state, eg. when a retry follows a request that went
+  /// through but whose response was lost. Read the state back to tell that
+  /// apart from a real failure.
+  fn confirm_state(&self, state: &WemoState, timeout: Duration,
+                   cancellation: Option<&CancellationToken>)
+      -> Result<(), WemoError> {
+    if timeout <= Duration::zero() {
+      return Err(WemoError::WemoError);
     }
 
+    let current = self.send_get_binary_state(timeout, cancellation)?.state;
+    if current.is_on() != state.is_on() {
+      log_device!(warn, self, action = "set_state";
+          "{} refused to change to {}", self.name(), state.description());
+      return Err(WemoError::WemoError);
+    }
+
+    log_device!(debug, self, action = "set_state";
+        "{} was already {}", self.name(), state.description());
+    Ok(())
+  }
+
+  /// Call an action on one of the device's services, with its arguments
+  /// already encoded as text, and return the raw response body. See
+  /// `ServiceClient` for calls checked against the service's description.
+  pub fn send_action(&self, service: &Service, action: &str,
+                     arguments: &[(String, String)], timeout: Duration)
+      -> Result<String, WemoError> {
+    let request = self.action_request(service, action, arguments);
+
+    let start = PreciseTime::now();
+    let response = self.post(request, timeout, None)?;
+    let latency_ms = start.to(PreciseTime::now()).num_milliseconds();
+
+    log_device!(debug, self, action = action, latency_ms = latency_ms,
+        success = response.is_some(); "{}: {}", action, self.name());
+
+    let body = response.ok_or_else(|| self.failure_error(None))?;
+
+    self.shared.needs_relocation.store(false, Ordering::SeqCst);
+    self.shared.reachable.store(true, Ordering::SeqCst);
+    self.check_service_envelope(&body, &format!("{}Response", action),
+        &service.service_type)
+        .map_err(|error| self.attach_body(error, &body))?;
+    Ok(body)
+  }
+
+  /// In strict parsing mode, make sure the response is a well-formed
+  /// basicevent response.
+  fn check_envelope(&self, body: &str, action_response: &str)
+      -> Result<(), WemoError> {
+    self.check_service_envelope(body, action_response,
+        &self.config.basic_event_service)
+  }
+
+  fn check_service_envelope(&self, body: &str, action_response: &str,
+                            service_type: &str) -> Result<(), WemoError> {
+    match self.config.parsing_mode {
+      ParsingMode::Strict => {
+        let result = validate_envelope(body, action_response, service_type);
+        if let Err(WemoError::InvalidEnvelope { ref reason }) = result {
+          log_device!(warn, self, action = action_response;
+              "Invalid SOAP envelope from {}: {}", self.name(), reason);
+        }
+        result
+      },
+      _ => Ok(()),
+    }
+  }
+
+  /// Wrap a parsing error with the start of the response body, if configured.
+  fn attach_body(&self, error: WemoError, body: &str) -> WemoError {
+    let limit = match self.config.error_body_limit {
+      None => { return error; },
+      Some(limit) => { limit },
+    };
+
+    let mut end = body.len().min(limit);
+    while !body.is_char_boundary(end) {
+      end -= 1;
+    }
+
+    WemoError::UnexpectedResponse {
+      cause: Box::new(error),
+      body: body[..end].to_string(),
+    }
+  }
+
+  /// The error for a request that got no response. Unless it was cancelled,
+  /// the device may have moved, so it is flagged for relocation.
+  fn failure_error(&self, cancellation: Option<&CancellationToken>)
+      -> WemoError {
+    match cancellation {
+      Some(cancellation) if cancellation.is_cancelled() => WemoError::Cancelled,
+      _ => {
+        self.shared.needs_relocation.store(true, Ordering::SeqCst);
+        self.shared.reachable.store(false, Ordering::SeqCst);
+        // Whatever answers at the address next may be another device.
+        self.forget_identity();
+        WemoError::BadResponseError
+      },
+    }
+  }
+
+  /// Whether a recent request failed to reach the device, meaning it has
+  /// probably changed its port or IP address.
+  pub fn needs_relocation(&self) -> bool {
+    self.shared.needs_relocation.load(Ordering::SeqCst)
+  }
+
+  /// Check whether the device accepts connections at its last known
+  /// location. This only opens a TCP connection, so it's much lighter than
+  /// `get_state`. The result is remembered by `is_reachable`.
+  pub fn ping(&self, timeout: Duration) -> bool {
+    let reachable = match (self.get_ip_address(), timeout.to_std()) {
+      (Some(ip_address), Ok(timeout)) => {
+        let socket = SocketAddr::new(ip_address, self.get_ports().preferred());
+        TcpStream::connect_timeout(&socket, timeout).is_ok()
+      },
+      _ => false,
+    };
+
+    self.shared.reachable.store(reachable, Ordering::SeqCst);
+    reachable
+  }
+
+  /// Whether the latest `ping` or request reached the device. Devices are
+  /// assumed reachable until one fails.
+  pub fn is_reachable(&self) -> bool {
+    self.shared.reachable.load(Ordering::SeqCst)
+  }
+
+  /// Look for the device on each of its candidate ports at its last known IP
+  /// address, updating the port if it's found on one. This is much cheaper
+  /// than a full SSDP search.
+  pub fn probe_ports(&self, timeout: Duration) -> Option<u16> {
+    let ip_address = match self.get_ip_address() {
+      None => { return None; },
+      Some(ip) => { ip },
+    };
+
+    let port = sweep_ports(ip_address, self.get_ports().probe_order(),
+        timeout);
+    if port.is_some() {
+      self.set_location(Some(ip_address), port);
+    }
+    port
+  }
+
+  /// Check whether the device is up, and on which port, by connecting to all
+  /// of its candidate ports at once. On a LAN this takes well under 100ms,
+  /// so it's worth doing before committing to a full SOAP request or SSDP
+  /// search. Like `probe_ports`, but the result is also remembered by
+  /// `is_reachable`.
+  pub fn probe(&self, timeout: Duration) -> Option<u16> {
+    let port = self.probe_ports(timeout);
+    self.shared.reachable.store(port.is_some(), Ordering::SeqCst);
+    port
+  }
+
+  /// Perform several actions in order over a single keep-alive connection,
+  /// eg. `switch.batch(timeout, |b| { b.get_state(); b.set_state(On); })`.
+  /// Returns one result per action. Execution stops at the first action whose
+  /// request fails, since the connection can't be trusted after that.
+  pub fn batch<F>(&self, timeout: Duration, build: F)
+      -> Result<Vec<WemoResult>, WemoError> where F: FnOnce(&mut Batch) {
+    let mut batch = Batch { actions: Vec::new() };
+    build(&mut batch);
+
+    let start = PreciseTime::now();
+    let changes_state = batch.actions.iter().any(|action| match *action {
+      BatchAction::SetState(_) => true,
+      BatchAction::GetState => false,
+    });
+    if changes_state {
+      self.check_identity(timeout)?;
+    }
+
+    let mut client = self.connect()?;
+    let mut results = Vec::with_capacity(batch.actions.len());
+
+    for action in batch.actions {
+      let elapsed = start.to(PreciseTime::now());
+      if elapsed >= timeout {
+        results.push(Err(WemoError::timeout(TimeoutStage::Request,
+            elapsed)));
+        break;
+      }
+
+      let remaining = timeout - elapsed;
+
+      let request = match action {
+        BatchAction::GetState => self.get_state_request(),
+        BatchAction::SetState(ref state) => self.set_state_request(state),
+      }.header("Connection", "keep-alive");
+      let request = match request {
+        Err(e) => {
+          results.push(Err(e));
+          break;
+        },
+        Ok(request) => request,
+      };
+
+      let sent = PreciseTime::now();
+      let response = self.guarded(None, || {
+        Ok(client.post(request, remaining.num_milliseconds() as u64))
+      });
+      let body = match response {
+        Ok(Some(body)) => body,
+        Ok(None) => {
+          results.push(Err(self.failure_error(None)));
+          break;
+        },
+        Err(e) => {
+          results.push(Err(e));
+          break;
+        },
+      };
+      let latency = sent.to(PreciseTime::now());
+
+      self.shared.needs_relocation.store(false, Ordering::SeqCst);
+      self.shared.reachable.store(true, Ordering::SeqCst);
+      results.push(match action {
+        BatchAction::GetState => {
+          self.check_envelope(&body, "GetBinaryStateResponse")
+              .and_then(|_| {
+                parse_binary_state_with(&body, self.config.parsing_mode)
+              })
+              .map(|binary_state| binary_state.state)
+              .map_err(|error| self.attach_body(error, &body))
+        },
+        BatchAction::SetState(state) => {
+          let remaining = timeout - start.to(PreciseTime::now());
+          self.check_batch_set_state(state, &body, latency, remaining)
+        },
+      });
+    }
+
+    Ok(results)
+  }
+
+  /// Check a batched `SetBinaryState` response the way `send_set_state`
+  /// does, reading the state back if the device refused the change.
+  fn check_batch_set_state(&self, state: WemoState, body: &str,
+                           latency: Duration, remaining: Duration)
+      -> WemoResult {
+    self.check_envelope(body, "SetBinaryStateResponse")
+        .map_err(|error| self.attach_body(error, body))?;
+
+    if is_error_response(body) {
+      self.confirm_state(&state, remaining, None)?;
+    }
+
+    self.remember_report(StateReport::new(state.clone(), latency,
+        StateSource::Command));
+    Ok(state)
+  }
+
+  /// Open a connection and confirm the port ahead of time, so the next
+  /// request doesn't wait on connecting, eg. for switches tied to physical
+  /// buttons. With `keep_alive`, the connection is kept open and reused by
+  /// every request until it fails. Returns the confirmed port.
+  pub fn preconnect(&self, timeout: Duration, keep_alive: bool)
+      -> Result<u16, WemoError> {
+    let start = PreciseTime::now();
+    let port = self.probe_ports(timeout).ok_or_else(|| {
+      WemoError::timeout(TimeoutStage::Connect, start.to(PreciseTime::now()))
+    })?;
+    let mut client = self.connect()?;
+    if !client.open(timeout.num_milliseconds().max(0) as u64) {
+      return Err(WemoError::timeout(TimeoutStage::Connect,
+          start.to(PreciseTime::now())));
+    }
+
+    self.shared.keep_alive.store(keep_alive, Ordering::SeqCst);
+    self.store_client(client);
+    Ok(port)
+  }
+
+  /// Whether a preconnected connection is waiting to be used.
+  pub fn is_preconnected(&self) -> bool {
+    self.shared.warm_client.lock()
+        .map(|client| client.is_some())
+        .unwrap_or(false)
+  }
+
+  /// Send the request over the preconnected connection if there is one,
+  /// falling back to a new connection if that one has gone stale.
+  fn post(&self, request: SoapRequest, timeout: Duration,
+          cancellation: Option<&CancellationToken>)
+      -> Result<Option<String>, WemoError> {
+    self.guarded(cancellation,
+        || self.send_post(request, timeout, cancellation))
+  }
+
+  /// Send a request under the configured circuit breaker, if any, counting
+  /// its result.
+  fn guarded<F>(&self, cancellation: Option<&CancellationToken>, send: F)
+      -> Result<Option<String>, WemoError>
+      where F: FnOnce() -> Result<Option<String>, WemoError> {
+    let policy = match self.config.circuit_breaker {
+      None => { return send(); },
+      Some(ref policy) => { policy },
+    };
+
+    if !self.shared.breaker.allow(policy) {
+      log_device!(debug, self, action = "post";
+          "Circuit open, not sending request: {}", self.name());
+      return Err(WemoError::CircuitOpen);
+    }
+
+    let result = send();
+    match result {
+      Ok(Some(_)) => { self.shared.breaker.record_success(); },
+      _ => {
+        let cancelled = cancellation.map(|c| c.is_cancelled())
+            .unwrap_or(false);
+        if cancelled {
+          self.shared.breaker.release_probe();
+        } else {
+          self.shared.breaker.record_failure(policy);
+        }
+      },
+    }
+    result
+  }
+
+  /// Whether requests are currently being sent, under the configured circuit
+  /// breaker. Always `Closed` if there isn't one.
+  pub fn circuit_state(&self) -> CircuitState {
+    match self.config.circuit_breaker {
+      None => CircuitState::Closed,
+      Some(ref policy) => self.shared.breaker.state(policy),
+    }
+  }
+
+  fn send_post(&self, request: SoapRequest, timeout: Duration,
+               cancellation: Option<&CancellationToken>)
+      -> Result<Option<String>, WemoError> {
+    let keep_alive = self.shared.keep_alive.load(Ordering::SeqCst);
+    let request = if keep_alive {
+      request.header("Connection", "keep-alive")?
+    } else {
+      request
+    };
+
+    let deadline = Deadline::after(timeout);
+    let warm_client = self.shared.warm_client.lock()
+        .ok()
+        .and_then(|mut client| client.take());
+
+    if let Some(mut client) = warm_client {
+      let response = client.post_cancellable(request.clone(),
+          timeout.num_milliseconds() as u64, cancellation);
+
+      // A device closes idle connections, in which case nothing comes back
+      // and the request is sent again on a new one.
+      match response {
+        Some(ref body) if !body.is_empty() => {
+          if keep_alive {
+            self.store_client(client);
+          }
+          return Ok(response);
+        },
+        _ => {
+          log_device!(debug, self, action = "post";
+              "Reused connection was stale: {}", self.name());
+        },
+      }
+    }
+
+    if deadline.is_expired() {
+      return Ok(None);
+    }
+
+    // Catch a device that only changed port before the request times out
+    // on the old one.
+    if self.needs_relocation() {
+      let _r = self.probe(deadline.capped(
+          Duration::milliseconds(PROBE_BEFORE_REQUEST_MS)));
+      if deadline.is_expired() {
+        return Ok(None);
+      }
+    }
+
+    let mut client = self.connect()?;
+    let response = client.post_cancellable(request,
+        deadline.remaining().num_milliseconds() as u64, cancellation);
+
+    if keep_alive && response.is_some() {
+      self.store_client(client);
+    }
+
+    Ok(response)
+  }
+
+  fn store_client(&self, client: SoapClient) {
+    match self.shared.warm_client.lock() {
+      Err(_) => {}, // Ignore.
+      Ok(mut warm_client) => { *warm_client = Some(client); },
+    }
+  }
+
+  /// A SOAP client for the last known location of the device. It connects
+  /// on its first request.
+  fn connect(&self) -> Result<SoapClient, WemoError> {
+    let ip_address = self.get_ip_address().ok_or(WemoError::NoLocalIp)?;
+    let port = self.get_ports().preferred();
+
+    Ok(SoapClient::new(ip_address, port))
+  }
+
+  fn get_state_request(&self) -> SoapRequest {
+    let envelope = self.envelope(&self.config.basic_event_service,
+        "GetBinaryState").argument("BinaryState", "1");
+    self.soap_request(envelope)
+  }
+
+  fn set_state_request(&self, state: &WemoState) -> SoapRequest {
+    let envelope = self.envelope(&self.config.basic_event_service,
+        "SetBinaryState").argument("BinaryState", &state.to_i8().to_string());
+    self.soap_request(envelope)
+  }
+
+  /// An envelope in our configured encoding style.
+  fn envelope(&self, service_urn: &str, action: &str) -> SoapEnvelope {
+    SoapEnvelope::new(service_urn, action)
+        .encoding_style(self.config.soap_encoding_style.as_deref())
+  }
+
+  /// A basicevent request with our configured headers.
+  fn soap_request(&self, envelope: SoapEnvelope) -> SoapRequest {
+    self.control_request("/upnp/control/basicevent1", envelope)
+  }
+
+  fn action_request(&self, service: &Service, action: &str,
+                    arguments: &[(String, String)]) -> SoapRequest {
+    let mut envelope = self.envelope(&service.service_type, action);
+    envelope.arguments = arguments.to_vec();
+
+    let path = if service.control_url.starts_with('/') {
+      service.control_url.clone()
+    } else {
+      format!("/{}", service.control_url)
+    };
+
+    self.control_request(&path, envelope)
+  }
+
+  /// A request with our configured headers.
+  fn control_request(&self, path: &str, envelope: SoapEnvelope)
+      -> SoapRequest {
+    // The configured headers are checked when the request is sent.
+    let mut request = envelope.into_request(path);
+    request.headers.extend(self.config.request_headers());
+    request.headers.extend_from_slice(&self.headers);
+    request
+  }
+
+  // TODO: Make private.
+  pub fn get_state_with_retry(&self, timeout: Duration) -> WemoResult {
+    self.get_state_by(Deadline::after(timeout))
+  }
+
+  /// Set the state, relocating the device and trying again once if the first
+  /// attempt fails. A retry the device refuses because it's already in the
+  /// requested state, as when the first attempt's response was lost, counts
+  /// as success once a read confirms it.
+  // TODO: Make private
+  pub fn set_state_with_retry(&self, state: WemoState, timeout: Duration)
+      -> WemoResult {
+    self.set_state_by(state, Deadline::after(timeout))
+  }
+
+  /// Like `get_state_with_retry`, sharing the caller's deadline.
+  fn get_state_by(&self, deadline: Deadline) -> WemoResult {
+    let soap_action = self.get_state_request().soap_action;
+    self.retry_by(deadline, "get_state", &soap_action,
+        |timeout| self.get_state(timeout))
+  }
+
+  fn set_state_by(&self, state: WemoState, deadline: Deadline)
+      -> WemoResult {
+    let soap_action = self.set_state_request(&state).soap_action;
+    self.retry_by(deadline, "set_state", &soap_action,
+        |timeout| self.set_state(state.clone(), timeout))
+  }
+
+  /// Make a short first attempt, then relocate the device and make a second
+  /// with whatever time is left. Every step shares the one deadline.
+  fn retry_by<F>(&self, deadline: Deadline, action: &str, soap_action: &str,
+                 attempt: F) -> WemoResult
+      where F: Fn(Duration) -> WemoResult {
+    let first_timeout =
+        deadline.capped(self.config.retry_policy.first_attempt_timeout);
+
+    match attempt(first_timeout) {
+      Ok(r) => { return Ok(r); },
+      Err(WemoError::CircuitOpen) => { return Err(WemoError::CircuitOpen); },
+      Err(_) => {}, // TODO: Return type
+    }
+
+    let remaining = deadline.check()?;
+
+    log_device!(info, self, action = action, attempt = 2;
+        "Retrying after relocation: {}", self.name());
+    metrics::report(|metrics| metrics.on_retry(soap_action));
+
+    // NB: Relocation updates our own location, so retry with `self` to keep
+    // any configured headers.
+    if !self.race_relocation(remaining) {
+      return Err(deadline.timed_out(TimeoutStage::Search));
+    }
+
+    attempt(deadline.check()?)
+  }
+
+  /// Returns the static IP if the Wemo was configured with a static IP,
+  /// otherwise returns the last cached IP address (which may not be set).
+  pub fn get_ip_address(&self) -> Option<IpAddr> {
+    match self.device_identifier {
+      DeviceIdentifier::StaticIp(ip) => Some(ip.clone()),
+      _ => {
+        self.shared.dynamic_ip_address.load()
+      },
+    }
+  }
+
+  /// The last known IP address and port, if both are known.
+  pub fn location(&self) -> Option<DeviceLocation> {
+    match (self.get_ip_address(), self.get_port()) {
+      (Some(ip_address), Some(port)) => {
+        Some(DeviceLocation::new(ip_address, port))
+      },
+      _ => None,
+    }
+  }
+
+  /// The UDN if the device was found via SSDP or created with `from_udn`.
+  pub fn get_udn(&self) -> Option<Udn> {
+    match self.device_identifier {
+      DeviceIdentifier::Udn(ref udn) => Some(udn.clone()),
+      _ => None,
+    }
+  }
+
+  /// Read the UDN from the device's `setup.xml` at its last known location.
+  pub fn fetch_udn(&self, timeout: Duration) -> Result<Udn, WemoError> {
+    let xml = self.fetch_setup_xml(timeout)?;
+    parse_udn(&xml).map_err(|error| self.attach_body(error, &xml))
+  }
+
+  /// With `WemoConfig::verify_identity`, make sure a dynamic-IP device is
+  /// the expected one before changing its state, unless it's already been
+  /// identified at its current address. In paranoid mode every device is
+  /// checked, and identifications expire after the TTL.
+  fn check_identity(&self, timeout: Duration) -> Result<(), WemoError> {
+    let verified_at = self.shared.identity_verified_at.read()
+        .ok()
+        .and_then(|verified_at| *verified_at);
+
+    let needed = match (self.paranoid_ttl, verified_at) {
+      (Some(_), None) => true,
+      (Some(ttl), Some(verified_at)) => {
+        let ttl = ttl.to_std().unwrap_or(StdDuration::from_secs(0));
+        verified_at.elapsed() >= ttl
+      },
+      (None, Some(_)) => false,
+      (None, None) => {
+        match self.device_identifier {
+          DeviceIdentifier::StaticIp(_) => false,
+          _ => self.config.verify_identity,
+        }
+      },
+    };
+
+    if needed {
+      self.verify_identity(timeout)
+    } else {
+      Ok(())
+    }
+  }
+
+  fn mark_identified(&self) {
+    match self.shared.identity_verified_at.write() {
+      Err(_) => {}, // Ignore.
+      Ok(mut verified_at) => { *verified_at = Some(Instant::now()); },
+    }
+  }
+
+  fn forget_identity(&self) {
+    match self.shared.identity_verified_at.write() {
+      Err(_) => {}, // Ignore.
+      Ok(mut verified_at) => { *verified_at = None; },
+    }
+  }
+
+  /// Check that the device at the last known location reports the expected
+  /// UDN, or else serial number, in its `setup.xml`. Fails with
+  /// `WemoError::WrongDevice` if it's another device. Devices with neither
+  /// known can't be checked, so fail with `WemoError::InvalidArgument`
+  /// without sending anything.
+  pub fn verify_identity(&self, timeout: Duration) -> Result<(), WemoError> {
+    let (expected, by_udn) = match (self.get_udn(), &self.serial_number) {
+      (Some(udn), _) => (udn, true),
+      (None, Some(serial_number)) => (serial_number.clone(), false),
+      (None, None) => {
+        return Err(WemoError::InvalidArgument {
+          reason: "serial number unknown, cannot verify".to_string(),
+        });
+      },
+    };
+
+    let xml = self.fetch_setup_xml(timeout)?;
+
+    let found = if by_udn {
+      parse_udn(&xml).ok()
+    } else {
+      find_tag_value("serialNumber", &xml)
+          .map(|found| found.trim().to_string())
+    };
+
+    if found.as_ref() != Some(&expected) {
+      log_device!(warn, self, action = "verify_identity",
+          found = found.as_deref().unwrap_or("nothing");
+          "Wrong device at {}, expected {}", self.name(), expected);
+      return Err(WemoError::WrongDevice {
+        expected: expected,
+        found: found,
+      });
+    }
+
+    self.mark_identified();
+    Ok(())
+  }
+
+  /// Whether an SSDP response comes from this device, going by its UDN or
+  /// serial number.
+  fn is_identified_by(&self, response: &SsdpResponse) -> bool {
+    match (self.get_udn(), self.serial_number.as_ref()) {
+      (Some(udn), _) => udn == response.udn,
+      (None, Some(serial_number)) => *serial_number == response.serial_number,
+      (None, None) => false,
+    }
+  }
+
+  /// Read the UPnP services the device offers from its `setup.xml`, eg. to
+  /// find the control URL of a service other than `basicevent1`.
+  pub fn list_services(&self, timeout: Duration)
+      -> Result<Vec<Service>, WemoError> {
+    let xml = self.fetch_setup_xml(timeout)?;
+    parse_services(&xml).map_err(|error| self.attach_body(error, &xml))
+  }
+
+  /// Fetch and parse the service's SCPD to learn the actions it supports and
+  /// their arguments.
+  pub fn describe_service(&self, service: &Service, timeout: Duration)
+      -> Result<ServiceDescription, WemoError> {
+    let path = if service.scpd_url.starts_with('/') {
+      service.scpd_url.clone()
+    } else {
+      format!("/{}", service.scpd_url)
+    };
+
+    let xml = self.fetch_xml(&path, timeout)?;
+    let actions = parse_scpd(&xml)
+        .map_err(|error| self.attach_body(error, &xml))?;
+
+    Ok(ServiceDescription {
+      service: service.clone(),
+      actions: actions,
+    })
+  }
+
+  /// Describe every service the device offers.
+  pub fn describe_services(&self, timeout: Duration)
+      -> Result<Vec<ServiceDescription>, WemoError> {
+    self.list_services(timeout)?
+        .iter()
+        .map(|service| self.describe_service(service, timeout))
+        .collect()
+  }
+
+  /// Read the device's clock from its `timesync` service.
+  pub fn get_time(&self, timeout: Duration) -> Result<DeviceTime, WemoError> {
+    let deadline = Deadline::after(timeout);
+    let service = self.list_services(timeout)?
+        .into_iter()
+        .find(|service| service.service_type == TIME_SYNC_SERVICE)
+        .ok_or_else(|| WemoError::InvalidArgument {
+          reason: format!("{} has no service {}", self.name(),
+              TIME_SYNC_SERVICE),
+        })?;
+
+    let body = self.send_action(&service, "GetTime", &[],
+        deadline.check()?)?;
+    DeviceTime::parse(&body).map_err(|error| self.attach_body(error, &body))
+  }
+
+  /// How far the device's clock is from this host's. Drifting clocks make
+  /// on-device schedules run at the wrong times; see `ClockAlert`.
+  pub fn clock_drift(&self, timeout: Duration)
+      -> Result<ClockDrift, WemoError> {
+    let sent_at = SystemTime::now();
+    let device_time = self.get_time(timeout)?;
+    let received_at = SystemTime::now();
+
+    Ok(ClockDrift::measure(self.serial_number.clone(), device_time, sent_at,
+        received_at))
+  }
+
+  fn fetch_setup_xml(&self, timeout: Duration) -> Result<String, WemoError> {
+    self.fetch_xml("/setup.xml", timeout)
+  }
+
+  fn fetch_xml(&self, path: &str, timeout: Duration)
+      -> Result<String, WemoError> {
+    let ip_address = self.get_ip_address().ok_or(WemoError::NoLocalIp)?;
+    let port = self.get_ports().preferred();
+
+    let mut headers = self.config.request_headers();
+    headers.extend(self.headers.iter().cloned());
+
+    http::get(ip_address, port, path, &headers, timeout)
+  }
+
+  /// Get the currently known port. If we haven't manually set the port or
+  /// talked to the Wemo device yet, the port will not be set.
+  pub fn get_port(&self) -> Option<u16> {
+    self.shared.port.load()
+  }
+
+  /// The last known port along with the candidate ports the device may have
+  /// moved to.
+  pub fn get_ports(&self) -> DevicePorts {
+    DevicePorts::new(self.shared.port.load(),
+        self.config.default_ports.clone())
+  }
+
+  // TODO: Refactor this to not create a new 'Switch'. Use interior mutability
+  // and return a boolean if the device was found.
+  // rename pub fn locate(&self, Duration) -> bool, but recommend against use
+  /// Attempt to find the Switch on the network via SSDP.
+  /// Both the IP address and port will be updated if they changed. (The IP
+  /// address will not be updated if the device is configured to use a static
+  /// IP.) Needs the `discovery` feature.
+  #[cfg(feature = "discovery")]
+  pub fn relocate(&self, timeout: Duration) -> Option<Switch> {
+    let mut search = DeviceSearch::new().with_config(self.config.clone());
+    self.relocate_with(&mut search, timeout)
+  }
+
+  /// Probe the device's ports at its last known IP address while searching
+  /// for it via SSDP, taking whichever finds it first. The probe is quicker
+  /// when only the port changed; the search is needed when the IP did.
+  /// Without the `discovery` feature, only the ports are probed. Returns
+  /// whether the device was found.
+  fn race_relocation(&self, timeout: Duration) -> bool {
+    let cancellation = CancellationToken::new();
+    let (sender, receiver) = channel();
+
+    match self.get_ip_address() {
+      // Nothing to probe, so don't wait on it below.
+      None => { drop(sender); },
+      Some(ip_address) => {
+        let candidates = self.get_ports().probe_order();
+        let search_cancellation = cancellation.clone();
+        threads::spawn("wemo-port-sweep", move || {
+          let port = sweep_ports(ip_address, candidates, timeout);
+          if port.is_some() {
+            // The search can stop; the device was found.
+            search_cancellation.cancel();
+          }
+          let _r = sender.send(port);
+        });
+      },
+    }
+
+    let deadline = Deadline::after(timeout);
+
+    #[cfg(feature = "discovery")]
+    {
+      let mut search = DeviceSearch::new()
+          .with_config(self.config.clone())
+          .with_cancellation(cancellation);
+
+      if self.relocate_with(&mut search, timeout).is_some() {
+        return true;
+      }
+    }
+
+    // The search gave up, or was cancelled because the probe won.
+    let probed = deadline.remaining().to_std().ok()
+        .and_then(|remaining| receiver.recv_timeout(remaining).ok())
+        .and_then(|port| port);
+
+    match probed {
+      None => false,
+      Some(port) => {
+        log_device!(debug, self, action = "relocate", port = port;
+            "Found device by probing its ports: {}", self.name());
+        self.set_location(self.get_ip_address(), Some(port));
+        self.shared.needs_relocation.store(false, Ordering::SeqCst);
+        self.clear_negative_cache();
+        true
+      },
+    }
+  }
+
+  /// Like `relocate`, but reuses an existing search and its socket, which
+  /// saves setting up a new one for every relocation in long-running
+  /// programs. The search's previous results are cleared.
+  ///
+  /// If a relocation failed within the retry policy's `negative_cache_ttl`,
+  /// this returns `None` without searching.
+  #[cfg(feature = "discovery")]
+  pub fn relocate_with(&self, search: &mut DeviceSearch, timeout: Duration)
+      -> Option<Switch> {
+    if self.is_known_offline() {
+      log_device!(debug, self, action = "relocate";
+          "Skipping relocation of recently missing device: {}", self.name());
+      return None;
+    }
+
+    search.reset();
+
+    let result = if self.get_udn().is_some() {
+      // Guaranteed unique by UPnP.
+      self.relocate_by_udn(search, timeout)
+    } else if self.serial_number.is_some() {
+      // Guaranteed to be the same device unless there is spoofing
+      // (or Belkin assigned duplicate serial numbers).
+      self.relocate_by_serial(search, timeout)
+    } else {
+      // Won't necessarily be the same device if DHCP has reassigned
+      // the address.
+      self.relocate_by_ip(search, timeout)
+    };
+
+    // Update existing Switch state.
+    if result.is_some() {
+      self.update_location(&result.as_ref().unwrap());
+      if self.get_udn().is_some() || self.serial_number.is_some() {
+        self.mark_identified();
+      }
+      self.shared.needs_relocation.store(false, Ordering::SeqCst);
+      self.clear_negative_cache();
+    } else {
+      match self.shared.relocation_failed_at.write() {
+        Err(_) => {}, // Ignore.
+        Ok(mut failed_at) => { *failed_at = Some(PreciseTime::now()); },
+      }
+    }
+
+    result
+  }
+
+  /// Whether a relocation failed to find the device within the retry
+  /// policy's `negative_cache_ttl`, and the device hasn't been seen since.
+  pub fn is_known_offline(&self) -> bool {
+    let failed_at = match self.shared.relocation_failed_at.read() {
+      Err(_) => { return false; },
+      Ok(failed_at) => { *failed_at },
+    };
+
+    match failed_at {
+      None => false,
+      Some(failed_at) => {
+        failed_at.to(PreciseTime::now())
+            < self.config.retry_policy.negative_cache_ttl
+      },
+    }
+  }
+
+  /// Forget any failed relocation, so the next relocation searches again.
+  pub fn clear_negative_cache(&self) {
+    match self.shared.relocation_failed_at.write() {
+      Err(_) => {}, // Ignore.
+      Ok(mut failed_at) => { *failed_at = None; },
+    }
+  }
+
+  #[cfg(feature = "discovery")]
+  fn relocate_by_udn(&self, search: &mut DeviceSearch, timeout: Duration)
+      -> Option<Switch> {
+    let udn = match self.get_udn() {
+      None => { return None; },
+      Some(udn) => { udn },
+    };
+
+    match search.search_for_udn(&udn, timeout.num_milliseconds() as u64) {
+      None => { None },
+      Some(result) => { Some(Switch::from_search_result(result)) },
+    }
+  }
+
+  #[cfg(feature = "discovery")]
+  fn relocate_by_serial(&self, search: &mut DeviceSearch, timeout: Duration)
+      -> Option<Switch> {
+    let serial = match self.serial_number {
+      None => { return None; },
+      Some(ref s) => { s },
+    };
+
+    match search.search_for_serial(serial, timeout.num_milliseconds() as u64){
+      None => { None },
+      Some(result) => { Some(Switch::from_search_result(result)) },
+    }
+  }
+
+  #[cfg(feature = "discovery")]
+  fn relocate_by_ip(&self, search: &mut DeviceSearch, timeout: Duration)
+      -> Option<Switch> {
+    let ip_address = match self.get_ip_address() {
+      None => { return None; },
+      Some(ip) => { ip },
+    };
+
+    match search.search_for_ip(&ip_address, timeout.num_milliseconds() as u64) {
+      None => { None },
+      Some(result) => { Some(Switch::from_search_result(result)) },
+    }
+  }
+
+  // Update the IP and port from a search result using internal mutability.
+  #[cfg(any(test, feature = "discovery"))]
+  fn update_location(&self, search_result: &Switch) {
+    self.set_location(search_result.get_ip_address(),
+        search_result.get_port());
+  }
+
+  /// Update the cached IP address and port from an SSDP response or
+  /// announcement, eg. after the device's DHCP lease changed. (The IP address
+  /// will not be updated if the device is configured to use a static IP.)
+  pub fn update_from_ssdp(&self, response: &SsdpResponse) {
+    self.set_location(Some(response.ip_address), Some(response.port));
+    if self.is_identified_by(response) {
+      self.mark_identified();
+    }
+    self.shared.needs_relocation.store(false, Ordering::SeqCst);
+    self.clear_negative_cache();
+  }
+
+  /// Use a location learned earlier, eg. by a `LocationStore`, until the
+  /// device is found elsewhere. (The IP address will not be updated if the
+  /// device is configured to use a static IP.)
+  pub fn restore_location(&self, ip_address: IpAddr, port: u16) {
+    self.set_location(Some(ip_address), Some(port));
+  }
+
+  /// Any move, even to another port at the same IP address, may land on a
+  /// different device, so it has to be identified again.
+  fn set_location(&self, ip_address: Option<IpAddr>, port: Option<u16>) {
+    let mut moved = self.shared.port.swap(port) != port;
+
     match self.device_identifier {
       DeviceIdentifier::StaticIp(_) => {}, // No need to update.
       _ => {
-        match self.dynamic_ip_address.write() {
-          Err(_) => {}, // Ignore.
-          Ok(mut ip_addr) => { *ip_addr = search_result.get_ip_address(); },
+        if self.shared.dynamic_ip_address.swap(ip_address) != ip_address {
+          moved = true;
         }
       },
     }
+
+    if moved {
+      self.forget_identity();
+    }
   }
 
   /// Return the IP/port, name, or other identifier for logging.
@@ -558,21 +1825,200 @@ impl Switch {
   }
 }
 
+/// A sequence of actions to send to a device over one connection.
+/// See `Switch::batch`.
+pub struct Batch {
+  actions: Vec<BatchAction>,
+}
+
+enum BatchAction {
+  GetState,
+  SetState(WemoState),
+}
+
+impl Batch {
+  /// Queue a request for the current state of the device.
+  pub fn get_state(&mut self) -> &mut Batch {
+    self.actions.push(BatchAction::GetState);
+    self
+  }
+
+  /// Queue a request to set the state of the device.
+  pub fn set_state(&mut self, state: WemoState) -> &mut Batch {
+    self.actions.push(BatchAction::SetState(state));
+    self
+  }
+}
+
+/// The state toggling from `state` changes to.
+fn toggled(state: &WemoState) -> WemoResult {
+  match *state {
+    Off => Ok(On),
+    On | OnWithoutLoad => Ok(Off),
+    _ => Err(WemoError::WemoError),
+  }
+}
+
+/// Whether a `SetBinaryState` response reports `Error` instead of the new
+/// state.
+fn is_error_response(body: &str) -> bool {
+  find_tag_value("BinaryState", body)
+      .map(|value| value.trim().eq_ignore_ascii_case("error"))
+      .unwrap_or(false)
+}
+
+/// Try connecting to every port at once. Returns the first port to accept a
+/// connection within the timeout.
+fn sweep_ports(ip_address: IpAddr, candidates: Vec<u16>, timeout: Duration)
+    -> Option<u16> {
+  let timeout = match timeout.to_std() {
+    Err(_) => { return None; },
+    Ok(timeout) => { timeout },
+  };
+
+  let (sender, receiver) = channel();
+  let count = candidates.len();
+
+  for port in candidates {
+    let sender = sender.clone();
+    threads::spawn("wemo-port-probe", move || {
+      let socket = SocketAddr::new(ip_address, port);
+      let connected = TcpStream::connect_timeout(&socket, timeout).is_ok();
+      let _r = sender.send(if connected { Some(port) } else { None });
+    });
+  }
+
+  let deadline = Instant::now() + timeout;
+  for _ in 0..count {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    match receiver.recv_timeout(remaining) {
+      Err(_) => { return None; },
+      Ok(Some(port)) => { return Some(port); },
+      Ok(None) => {}, // Keep waiting for the others.
+    }
+  }
+
+  None
+}
+
 impl Display for Switch {
   fn fmt(&self, f : &mut Formatter) -> Result<(), Error> {
     write!(f, "Switch<{}>", self.name())
   }
 }
 
+/// What a device is compared and hashed by.
+#[derive(Debug, Eq, Hash, PartialEq)]
+enum Identity {
+  SerialNumber(SerialNumber),
+  IpAddress(IpAddr),
+  Unknown,
+}
+
+impl Switch {
+  /// The serial number, going by the UDN if it isn't known, or else the IP
+  /// address.
+  fn identity(&self) -> Identity {
+    let serial_number = self.serial_number.clone()
+        .or_else(|| {
+          self.get_udn().and_then(|udn| serial_from_udn(&udn))
+        });
+
+    match (serial_number, self.get_ip_address()) {
+      (Some(serial_number), _) => Identity::SerialNumber(serial_number),
+      (None, Some(ip_address)) => Identity::IpAddress(ip_address),
+      (None, None) => Identity::Unknown,
+    }
+  }
+}
+
+/// Switches are the same device if they have the same serial number, or
+/// UDN. Without either, they're compared by IP address, which changes if a
+/// dynamic-IP device is relocated. Switches with neither are only equal to
+/// themselves and their clones.
+///
+/// Hashing goes by the same identity, so a switch's hash changes when its
+/// UDN is fetched, or, without one, when it's relocated. Don't hash a switch,
+/// eg. as a map key or in a set, until its serial number is known: set
+/// `serial_number`, or call `fetch_udn` first. Switches from a search or a
+/// `Registry` already have one.
+impl PartialEq for Switch {
+  fn eq(&self, other: &Switch) -> bool {
+    match (self.identity(), other.identity()) {
+      (Identity::Unknown, _) | (_, Identity::Unknown) => {
+        Arc::ptr_eq(&self.shared, &other.shared)
+      },
+      (identity, other_identity) => identity == other_identity,
+    }
+  }
+}
+
+impl Eq for Switch {}
+
+/// See `PartialEq`: only hash switches whose serial number is known.
+impl Hash for Switch {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.identity().hash(state);
+  }
+}
+
+/// The serial number at the end of a WeMo UDN, eg. `221517K0101769` in
+/// `uuid:Socket-1_0-221517K0101769`.
+fn serial_from_udn(udn: &str) -> Option<SerialNumber> {
+  udn.splitn(3, '-')
+      .nth(2)
+      .filter(|serial_number| !serial_number.is_empty())
+      .map(|serial_number| serial_number.to_string())
+}
+
 #[cfg(test)]
 mod tests {
+  use config::CircuitBreakerPolicy;
+  use std::io::{Read, Write};
   use std::net::IpAddr;
+  use std::net::{TcpListener, UdpSocket};
   use std::str::FromStr;
-  use std::sync::RwLock;
+  use std::thread;
+  use std::time::UNIX_EPOCH;
   use super::*;
+  use testing::{FakeDevice, timeout, unique_serial_number};
+
+  fn ip(ip_address: &str) -> IpAddr {
+    IpAddr::from_str(ip_address).unwrap()
+  }
+
+  #[test]
+  fn test_clones_share_location() {
+    let switch = Switch::from_dynamic_ip_and_port(ip("1.1.1.1"), 49153);
+    let handle = switch.clone();
 
-  fn ip(ip_address: &str) -> IpAddr {
-    IpAddr::from_str(ip_address).unwrap()
+    switch.restore_location(ip("2.2.2.2"), 49154);
+    assert_eq!(Some(ip("2.2.2.2")), handle.get_ip_address());
+    assert_eq!(Some(49154), handle.get_port());
+  }
+
+  #[test]
+  fn test_identity() {
+    use std::collections::HashSet;
+
+    let mut found = Switch::from_dynamic_ip_and_port(ip("1.1.1.1"), 49153);
+    found.serial_number = Some("221517K0101769".to_string());
+    let by_udn = Switch::from_udn("uuid:Socket-1_0-221517K0101769");
+    let by_ip = Switch::from_static_ip(ip("1.1.1.1"));
+
+    assert!(found == by_udn);
+    assert!(found != by_ip);
+    assert!(by_ip == Switch::from_dynamic_ip(ip("1.1.1.1")));
+
+    let unknown = Switch::from_udn("");
+    assert!(unknown == unknown.clone());
+    assert!(unknown != Switch::from_udn(""));
+
+    let mut devices = HashSet::new();
+    assert!(devices.insert(found));
+    assert!(!devices.insert(by_udn));
+    assert!(devices.insert(by_ip));
+    assert_eq!(2, devices.len());
   }
 
   #[test]
@@ -583,35 +2029,24 @@ mod tests {
 
   #[test]
   fn test_get_ip_address_with_dynamic_ip() {
-    let switch = Switch {
-      device_identifier: DeviceIdentifier::Unimplemented, // no static IP
-      dynamic_ip_address: RwLock::new(Some(ip("1.1.1.1"))),
-      port: RwLock::new(None),
-      serial_number: None,
-    };
+    let switch = Switch::from_parts(
+        DeviceIdentifier::Unimplemented, // no static IP
+        Some(ip("1.1.1.1")), None, None);
 
     assert_eq!(Some(ip("1.1.1.1")), switch.get_ip_address());
 
     // If it were to have a static and dynamic IP (not allowed), the static IP
     // is the one that is returned.
-    let switch = Switch {
-      device_identifier: DeviceIdentifier::StaticIp(ip("2.2.2.2")),
-      dynamic_ip_address: RwLock::new(Some(ip("3.3.3.3"))),
-      port: RwLock::new(None),
-      serial_number: None,
-    };
+    let switch = Switch::from_parts(DeviceIdentifier::StaticIp(ip("2.2.2.2")),
+        Some(ip("3.3.3.3")), None, None);
 
     assert_eq!(Some(ip("2.2.2.2")), switch.get_ip_address());
   }
 
   #[test]
   fn test_get_ip_address_with_no_ip() {
-    let switch = Switch {
-      device_identifier: DeviceIdentifier::Unimplemented,
-      dynamic_ip_address: RwLock::new(None),
-      port: RwLock::new(None),
-      serial_number: None,
-    };
+    let switch = Switch::from_parts(DeviceIdentifier::Unimplemented,
+        None, None, None);
 
     assert_eq!(None, switch.get_ip_address());
   }
@@ -643,12 +2078,8 @@ mod tests {
 
   #[test]
   fn test_update_location_with_dynamic_ip() {
-    let switch = Switch {
-      device_identifier: DeviceIdentifier::Unimplemented,
-      dynamic_ip_address: RwLock::new(None),
-      port: RwLock::new(None),
-      serial_number: None,
-    };
+    let switch = Switch::from_parts(DeviceIdentifier::Unimplemented,
+        None, None, None);
 
     let found = Switch::from_static_ip_and_port(ip("2.2.2.2"), 2222);
 
@@ -659,6 +2090,525 @@ mod tests {
     assert_eq!(Some(ip("2.2.2.2")), switch.get_ip_address());
   }
 
+  #[test]
+  fn test_with_header() {
+    let switch = Switch::from_static_ip(ip("1.1.1.1"))
+        .with_header("Connection", "close")
+        .unwrap();
+
+    assert_eq!(vec![("Connection".to_string(), "close".to_string())],
+        switch.headers);
+
+    match switch.with_header("X-Trace", "1\r\nConnection: keep-alive") {
+      Err(WemoError::InvalidArgument { .. }) => {},
+      _ => panic!("expected the header to be refused"),
+    }
+  }
+
+  #[test]
+  fn test_batch_uses_one_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+      // Only a single connection is accepted.
+      let mut stream = listener.accept().unwrap().0;
+      let bodies = ["<BinaryState>0</BinaryState>",
+          "<BinaryState>1</BinaryState>"];
+
+      for body in bodies.iter() {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&request).contains("</s:Envelope>") {
+          let amount = stream.read(&mut buf).unwrap();
+          request.extend_from_slice(&buf[..amount]);
+        }
+
+        let response = format!("HTTP/1.1 200 OK\r\n\
+            CONTENT-LENGTH: {}\r\n\
+            \r\n\
+            {}", body.len(), body);
+        stream.write_all(response.as_bytes()).unwrap();
+      }
+    });
+
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), port);
+    let results = switch.batch(Duration::seconds(5), |b| {
+      b.get_state();
+      b.set_state(On);
+    }).unwrap();
+
+    assert_eq!(2, results.len());
+    assert_eq!(Off, *results[0].as_ref().unwrap());
+    assert_eq!(On, *results[1].as_ref().unwrap());
+  }
+
+  /// Read a SOAP request up to the end of its envelope.
+  fn read_request(stream: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !String::from_utf8_lossy(&request).contains("</s:Envelope>") {
+      match stream.read(&mut buf) {
+        Ok(0) | Err(_) => break,
+        Ok(amount) => request.extend_from_slice(&buf[..amount]),
+      }
+    }
+    String::from_utf8_lossy(&request).into_owned()
+  }
+
+  #[test]
+  fn test_closed_without_response() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // Read each request, then close without answering.
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        read_request(&mut stream);
+      }
+    });
+
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), port);
+    assert!(switch.turn_on(Duration::seconds(2)).is_err());
+    assert_eq!(None, switch.last_known_state());
+  }
+
+  #[test]
+  fn test_batch_refused_set_state() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // Refuses every change, and reads back as off.
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        thread::spawn(move || loop {
+          let request = read_request(&mut stream);
+          let body = if request.contains("#SetBinaryState") {
+            "<BinaryState>Error</BinaryState>"
+          } else if request.contains("#GetBinaryState") {
+            "<BinaryState>0</BinaryState>"
+          } else {
+            break;
+          };
+          let response = format!("HTTP/1.1 200 OK\r\n\
+              CONTENT-LENGTH: {}\r\n\
+              \r\n\
+              {}", body.len(), body);
+          stream.write_all(response.as_bytes()).unwrap();
+        });
+      }
+    });
+
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), port);
+    let results = switch.batch(Duration::seconds(5), |b| {
+      b.set_state(On);
+      b.set_state(Off);
+    }).unwrap();
+
+    match results[0] {
+      Err(WemoError::WemoError) => {},
+      ref other => panic!("expected a refusal, got {:?}", other),
+    }
+    assert_eq!(Off, *results[1].as_ref().unwrap());
+  }
+
+  #[test]
+  fn test_get_state_cancellable() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // Accept the connection, but never respond.
+    thread::spawn(move || {
+      let _stream = listener.accept().unwrap().0;
+      thread::sleep(::std::time::Duration::from_secs(10));
+    });
+
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+
+    thread::spawn(move || {
+      thread::sleep(::std::time::Duration::from_millis(50));
+      cancel.cancel();
+    });
+
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), port);
+    let start = PreciseTime::now();
+    let result = switch.get_state_cancellable(Duration::seconds(5), &token);
+
+    match result {
+      Err(WemoError::Cancelled) => {},
+      _ => panic!("Expected cancellation"),
+    }
+    assert!(start.to(PreciseTime::now()) < Duration::seconds(1));
+  }
+
+  #[test]
+  fn test_until_with_past_deadline() {
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), 1);
+
+    match switch.turn_on_until(Instant::now()) {
+      Err(WemoError::TimeoutError { .. }) => {},
+      _ => panic!("Expected timeout"),
+    }
+
+    // The time spent counts from when the deadline was set.
+    let deadline = Deadline::after(Duration::milliseconds(20));
+    thread::sleep(StdDuration::from_millis(30));
+    match switch.toggle_until(deadline) {
+      Err(WemoError::TimeoutError { stage, elapsed, .. }) => {
+        assert_eq!(TimeoutStage::Request, stage);
+        assert!(elapsed >= Duration::milliseconds(30));
+      },
+      other => panic!("Expected timeout, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_default_port_from_config() {
+    let mut config = WemoConfig::default();
+    config.default_ports = vec![1234];
+
+    let switch = Switch::from_static_ip(ip("127.0.0.1")).with_config(config);
+    assert_eq!(1234, switch.get_ports().preferred());
+    assert_eq!(None, switch.get_port());
+  }
+
+  #[test]
+  fn test_attach_body() {
+    let switch = Switch::from_static_ip(ip("127.0.0.1"));
+    let error = switch.attach_body(WemoError::ParsingError, "<html>");
+    assert!(error.response_body().is_none());
+
+    let mut config = WemoConfig::default();
+    config.error_body_limit = Some(2);
+    let switch = switch.with_config(config);
+
+    // The body is cut at a character boundary.
+    let error = switch.attach_body(WemoError::ParsingError, "<é>html");
+    assert_eq!(Some("<"), error.response_body());
+  }
+
+  #[test]
+  fn test_user_agent_from_config() {
+    let mut config = WemoConfig::default();
+    config.user_agent = Some("wemo-test".to_string());
+
+    let switch = Switch::from_static_ip(ip("127.0.0.1")).with_config(config);
+    let request = switch.get_state_request();
+
+    assert_eq!(vec![("User-Agent".to_string(), "wemo-test".to_string())],
+        request.headers);
+  }
+
+  #[test]
+  fn test_circuit_breaker_opens() {
+    // Nothing listens on this port once the listener is dropped.
+    let port = TcpListener::bind("127.0.0.1:0").unwrap()
+        .local_addr().unwrap().port();
+
+    let mut config = WemoConfig::default();
+    config.circuit_breaker = Some(CircuitBreakerPolicy {
+      failure_threshold: 2,
+      cool_down: Duration::seconds(60),
+    });
+
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), port)
+        .with_config(config);
+    let timeout = Duration::milliseconds(200);
+
+    assert!(switch.get_state(timeout).is_err());
+    assert_eq!(CircuitState::Closed, switch.circuit_state());
+    assert!(switch.get_state(timeout).is_err());
+    assert_eq!(CircuitState::Open, switch.circuit_state());
+
+    match switch.get_state_with_retry(timeout) {
+      Err(WemoError::CircuitOpen) => {},
+      other => panic!("Expected an open circuit, got {:?}", other),
+    }
+
+    let results = switch.batch(timeout, |b| { b.get_state(); }).unwrap();
+    match results[0] {
+      Err(WemoError::CircuitOpen) => {},
+      ref other => panic!("Expected an open circuit, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_circuit_breaker_cancelled_probe() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap()
+        .local_addr().unwrap().port();
+
+    let config = WemoConfig {
+      circuit_breaker: Some(CircuitBreakerPolicy {
+        failure_threshold: 1,
+        cool_down: Duration::milliseconds(20),
+      }),
+      ..WemoConfig::default()
+    };
+
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), port)
+        .with_config(config);
+    let timeout = Duration::milliseconds(200);
+
+    assert!(switch.get_state(timeout).is_err());
+    assert_eq!(CircuitState::Open, switch.circuit_state());
+    thread::sleep(::std::time::Duration::from_millis(30));
+
+    let cancellation = CancellationToken::new();
+    cancellation.cancel();
+    assert!(switch.turn_on_cancellable(timeout, &cancellation).is_err());
+
+    // The next request probes rather than finding the circuit stuck.
+    if let Err(WemoError::CircuitOpen) = switch.get_state(timeout) {
+      panic!("The probe was never released");
+    }
+    assert_eq!(CircuitState::Open, switch.circuit_state());
+  }
+
+  #[cfg(feature = "discovery")]
+  #[test]
+  fn test_negative_cache() {
+    // Nothing answers searches sent here.
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let mut config = WemoConfig::default();
+    config.ssdp_address = silent.local_addr().unwrap();
+
+    let switch = Switch::from_udn("uuid:Socket-1_0-MISSING")
+        .with_config(config.clone());
+
+    assert!(!switch.is_known_offline());
+    assert!(switch.relocate(Duration::milliseconds(50)).is_none());
+    assert!(switch.is_known_offline());
+
+    let start = PreciseTime::now();
+    assert!(switch.relocate(Duration::seconds(5)).is_none());
+    assert!(start.to(PreciseTime::now()) < Duration::seconds(1));
+
+    switch.clear_negative_cache();
+    assert!(!switch.is_known_offline());
+
+    config.retry_policy.negative_cache_ttl = Duration::zero();
+    let switch = Switch::from_udn("uuid:Socket-1_0-MISSING")
+        .with_config(config);
+    assert!(switch.relocate(Duration::milliseconds(50)).is_none());
+    assert!(!switch.is_known_offline());
+  }
+
+  #[test]
+  fn test_probe_ports() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let mut config = WemoConfig::default();
+    config.default_ports = vec![1, port];
+
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), 1)
+        .with_config(config);
+
+    assert_eq!(Some(port), switch.probe_ports(Duration::seconds(1)));
+    assert_eq!(Some(port), switch.get_port());
+  }
+
+  #[test]
+  fn test_probe() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let mut config = WemoConfig::default();
+    config.default_ports = vec![1, 2, 3, port];
+
+    let switch = Switch::from_dynamic_ip_and_port(ip("127.0.0.1"), 1)
+        .with_config(config);
+
+    let start = PreciseTime::now();
+    assert_eq!(Some(port), switch.probe(Duration::seconds(1)));
+    assert!(start.to(PreciseTime::now()) < Duration::milliseconds(100));
+    assert_eq!(Some(port), switch.get_port());
+    assert!(switch.is_reachable());
+
+    drop(listener);
+    assert_eq!(None, switch.probe(Duration::milliseconds(200)));
+    assert!(!switch.is_reachable());
+  }
+
+  #[test]
+  fn test_race_relocation() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // Nothing answers searches sent here, so only the probe can win.
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let mut config = WemoConfig::default();
+    config.default_ports = vec![1, port];
+    config.ssdp_address = silent.local_addr().unwrap();
+
+    let mut switch = Switch::from_dynamic_ip_and_port(ip("127.0.0.1"), 1)
+        .with_config(config);
+    switch.serial_number = Some("MISSING".to_string());
+
+    let start = PreciseTime::now();
+    assert!(switch.race_relocation(Duration::seconds(3)));
+    assert!(start.to(PreciseTime::now()) < Duration::seconds(1));
+    assert_eq!(Some(port), switch.get_port());
+    assert!(!switch.is_known_offline());
+  }
+
+  // With a search, it's the search that takes the time.
+  #[cfg(not(feature = "discovery"))]
+  #[test]
+  fn test_race_relocation_without_address() {
+    let switch = Switch::from_udn("uuid:Socket-1_0-221517K0101769");
+
+    let start = PreciseTime::now();
+    assert!(!switch.race_relocation(Duration::seconds(3)));
+    assert!(start.to(PreciseTime::now()) < Duration::seconds(1));
+  }
+
+  #[test]
+  fn test_preconnect_keep_alive() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+      // The port probe.
+      let _probe = listener.accept().unwrap();
+
+      // Both requests must arrive over the preconnected connection.
+      let mut stream = listener.accept().unwrap().0;
+      for _ in 0..2 {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&request).contains("</s:Envelope>") {
+          let amount = stream.read(&mut buf).unwrap();
+          request.extend_from_slice(&buf[..amount]);
+        }
+
+        let body = "<BinaryState>1</BinaryState>";
+        let response = format!("HTTP/1.1 200 OK\r\n\
+            CONTENT-LENGTH: {}\r\n\
+            \r\n\
+            {}", body.len(), body);
+        stream.write_all(response.as_bytes()).unwrap();
+      }
+    });
+
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), port);
+
+    assert_eq!(port, switch.preconnect(Duration::seconds(1), true).unwrap());
+    assert!(switch.is_preconnected());
+    assert_eq!(On, switch.get_state(Duration::seconds(1)).unwrap());
+    assert_eq!(On, switch.get_state(Duration::seconds(1)).unwrap());
+    assert!(switch.is_preconnected());
+  }
+
+  #[test]
+  fn test_preconnected_connection_closed() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+      // The port probe.
+      let _probe = listener.accept().unwrap();
+
+      // The device closes its side of the idle, preconnected connection.
+      let mut stale = listener.accept().unwrap().0;
+      stale.shutdown(::std::net::Shutdown::Write).unwrap();
+      read_request(&mut stale);
+
+      let mut stream = listener.accept().unwrap().0;
+      read_request(&mut stream);
+      let body = "<BinaryState>1</BinaryState>";
+      let response = format!("HTTP/1.1 200 OK\r\n\
+          CONTENT-LENGTH: {}\r\n\
+          \r\n\
+          {}", body.len(), body);
+      stream.write_all(response.as_bytes()).unwrap();
+    });
+
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), port);
+    switch.preconnect(Duration::seconds(1), false).unwrap();
+    assert_eq!(On, switch.get_state(Duration::seconds(2)).unwrap());
+    assert!(!switch.is_preconnected());
+  }
+
+  #[test]
+  fn test_ping() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), port);
+    assert!(switch.ping(Duration::seconds(1)));
+    assert!(switch.is_reachable());
+
+    drop(listener);
+    assert!(!switch.ping(Duration::seconds(1)));
+    assert!(!switch.is_reachable());
+  }
+
+  #[test]
+  fn test_fetch_udn() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+      let mut stream = listener.accept().unwrap().0;
+      let mut buf = [0; 1024];
+      let _r = stream.read(&mut buf).unwrap();
+      stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n\
+          <root><device><UDN>uuid:Socket-1_0-221517K0101769</UDN></device>\
+          </root>").unwrap();
+    });
+
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), port);
+
+    assert_eq!(None, switch.get_udn());
+    assert_eq!("uuid:Socket-1_0-221517K0101769",
+        switch.fetch_udn(Duration::seconds(1)).unwrap());
+  }
+
+  #[test]
+  fn test_state_report_cache() {
+    let switch = Switch::from_static_ip(ip("127.0.0.1"));
+    assert_eq!(None, switch.cached_state_report());
+
+    let pushed = switch.record_push(WemoState::On);
+    assert_eq!(StateSource::Push, pushed.source);
+
+    let cached = switch.cached_state_report().unwrap();
+    assert_eq!(WemoState::On, cached.state);
+    assert_eq!(StateSource::Cache, cached.source);
+    assert_eq!(pushed.fetched_at, cached.fetched_at);
+  }
+
+  #[test]
+  fn test_history() {
+    let switch = Switch::from_static_ip(ip("127.0.0.1"));
+    switch.record_push(WemoState::On);
+    assert!(switch.history(UNIX_EPOCH).is_empty());
+
+    switch.keep_history(10);
+    switch.record_push(WemoState::Off);
+    switch.record_push(WemoState::Off);
+    switch.record_push(WemoState::On);
+
+    let states = switch.history(UNIX_EPOCH).into_iter()
+        .map(|report| report.state)
+        .collect::<Vec<_>>();
+    assert_eq!(vec![WemoState::Off, WemoState::On], states);
+  }
+
+  #[test]
+  fn test_from_udn() {
+    let switch = Switch::from_udn("uuid:Socket-1_0-221517K0101769");
+    assert_eq!(Some("uuid:Socket-1_0-221517K0101769".to_string()),
+        switch.get_udn());
+    assert_eq!(None, switch.get_ip_address());
+  }
+
   #[test]
   fn test_name_with_ip_and_port() {
     let switch = Switch::from_static_ip_and_port(ip("1.2.3.4"), 1234);
@@ -673,12 +2623,418 @@ mod tests {
 
   #[test]
   fn test_name_without_ip() {
-    let switch = Switch {
-      device_identifier: DeviceIdentifier::Unimplemented,
-      dynamic_ip_address: RwLock::new(None),
-      port: RwLock::new(None),
-      serial_number: None,
-    };
+    let switch = Switch::from_parts(DeviceIdentifier::Unimplemented,
+        None, None, None);
     assert_eq!("UNKNOWN".to_string(), switch.name());
   }
+
+  #[test]
+  fn test_get_and_set_state() {
+    let device = FakeDevice::start_unique().unwrap();
+    let switch = device.switch();
+
+    assert_eq!(WemoState::Off, switch.get_state(timeout()).unwrap());
+    assert_eq!(WemoState::On, switch.turn_on(timeout()).unwrap());
+    assert_eq!(WemoState::On, device.state());
+    assert_eq!(WemoState::On, switch.get_state(timeout()).unwrap());
+    assert_eq!(3, device.request_count());
+  }
+
+  #[test]
+  fn test_strict_parsing_accepts_device() {
+    use config::ParsingMode;
+
+    let device = FakeDevice::start_unique().unwrap();
+    let mut config = device.config();
+    config.parsing_mode = ParsingMode::Strict;
+    let switch = device.switch().with_config(config);
+
+    assert_eq!(WemoState::On, switch.turn_on(timeout()).unwrap());
+    assert_eq!(WemoState::On, switch.get_state(timeout()).unwrap());
+    let results = switch.batch(timeout(), |b| { b.get_state(); }).unwrap();
+    assert_eq!(WemoState::On, *results[0].as_ref().unwrap());
+  }
+
+  #[test]
+  fn test_batch_strict_parsing() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // Answers with a bare state rather than a SOAP envelope.
+    thread::spawn(move || {
+      let mut stream = listener.accept().unwrap().0;
+      read_request(&mut stream);
+      let body = "<BinaryState>0</BinaryState>";
+      let response = format!("HTTP/1.1 200 OK\r\n\
+          CONTENT-LENGTH: {}\r\n\
+          \r\n\
+          {}", body.len(), body);
+      stream.write_all(response.as_bytes()).unwrap();
+    });
+
+    let config = WemoConfig {
+      parsing_mode: ParsingMode::Strict,
+      ..WemoConfig::default()
+    };
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), port)
+        .with_config(config);
+    let results = switch.batch(Duration::seconds(5), |b| { b.get_state(); })
+        .unwrap();
+    assert!(results[0].is_err());
+  }
+
+  #[test]
+  fn test_list_services() {
+    let device = FakeDevice::start_unique().unwrap();
+    let services = device.switch().list_services(timeout()).unwrap();
+    assert_eq!(1, services.len());
+    assert_eq!("urn:Belkin:service:basicevent:1", services[0].service_type);
+    assert_eq!("/upnp/control/basicevent1", services[0].control_url);
+  }
+
+  #[test]
+  fn test_describe_services() {
+    let device = FakeDevice::start_unique().unwrap();
+    let descriptions = device.switch().describe_services(timeout()).unwrap();
+    assert_eq!(1, descriptions.len());
+
+    let action = descriptions[0].action("SetBinaryState").unwrap();
+    assert_eq!(1, action.inputs().len());
+    assert_eq!(Some("Boolean"), action.arguments[0].data_type.as_deref());
+    assert_eq!(1, descriptions[0].action("GetBinaryState").unwrap()
+        .outputs().len());
+    assert!(descriptions[0].action("ReSetup").is_none());
+  }
+
+  #[test]
+  fn test_retry_after_dropped_request() {
+    let device = FakeDevice::start_unique().unwrap();
+    let switch = device.switch();
+    device.set_state(WemoState::On);
+    device.drop_next_requests(1);
+
+    assert_eq!(WemoState::On, switch.get_state_with_retry(timeout()).unwrap());
+    assert_eq!(2, device.request_count());
+  }
+
+  #[test]
+  fn test_retry_stays_within_timeout() {
+    let device = FakeDevice::start_unique().unwrap();
+    let switch = device.switch();
+    device.drop_next_requests(2);
+
+    // Shorter than the usual first attempt alone, so there's no retry.
+    match switch.get_state_with_retry(Duration::milliseconds(150)) {
+      Err(WemoError::TimeoutError { .. }) => {},
+      other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(1, device.request_count());
+  }
+
+  #[test]
+  fn test_first_attempt_timeout() {
+    let device = FakeDevice::start_unique().unwrap();
+    let mut config = device.config();
+    config.retry_policy.first_attempt_timeout = Duration::milliseconds(50);
+    let switch = device.switch().with_config(config);
+
+    device.set_state(WemoState::On);
+    device.drop_next_requests(1);
+
+    // Only enough time for a retry after a short first attempt.
+    let timeout = Duration::milliseconds(250);
+    assert_eq!(WemoState::On, switch.get_state_with_retry(timeout).unwrap());
+    assert_eq!(2, device.request_count());
+
+    // The usual first attempt takes all of it.
+    device.drop_next_requests(1);
+    match device.switch().get_state_with_retry(timeout) {
+      Err(WemoError::TimeoutError { .. }) => {},
+      other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(3, device.request_count());
+  }
+
+  #[test]
+  fn test_toggle_from_pushed_state() {
+    let device = FakeDevice::start_unique().unwrap();
+    let switch = device.switch();
+    switch.set_push_fed(true);
+    switch.record_push(WemoState::Off);
+
+    // The device's event confirms the change.
+    let pusher = switch.clone();
+    let handle = thread::spawn(move || {
+      thread::sleep(StdDuration::from_millis(50));
+      pusher.record_push(WemoState::On);
+    });
+    assert_eq!(WemoState::On, switch.toggle(timeout()).unwrap());
+    handle.join().unwrap();
+    assert_eq!(WemoState::On, device.state());
+    assert_eq!(1, device.request_count());
+
+    // Without an event, the new state is read back.
+    assert_eq!(WemoState::Off, switch.toggle(timeout()).unwrap());
+    assert_eq!(WemoState::Off, device.state());
+    assert_eq!(3, device.request_count());
+
+    // Turned on without a push: the refused change shows it was stale.
+    device.set_state(WemoState::On);
+    assert_eq!(WemoState::Off, switch.toggle(timeout()).unwrap());
+    assert_eq!(WemoState::Off, device.state());
+    assert_eq!(6, device.request_count());
+  }
+
+  #[test]
+  fn test_toggle_reports_read_error() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap()
+        .local_addr().unwrap().port();
+
+    let config = WemoConfig {
+      circuit_breaker: Some(CircuitBreakerPolicy {
+        failure_threshold: 1,
+        cool_down: Duration::seconds(60),
+      }),
+      ..WemoConfig::default()
+    };
+
+    let switch = Switch::from_static_ip_and_port(ip("127.0.0.1"), port)
+        .with_config(config);
+    let timeout = Duration::milliseconds(200);
+    assert!(switch.get_state(timeout).is_err());
+
+    // The reason the state couldn't be read comes through as is.
+    match switch.toggle(timeout) {
+      Err(WemoError::CircuitOpen) => {},
+      other => panic!("unexpected {:?}", other),
+    }
+    match switch.toggle_with_retry(timeout) {
+      Err(WemoError::CircuitOpen) => {},
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_wait_for_state() {
+    let device = Arc::new(FakeDevice::start_unique().unwrap());
+    let switch = device.switch();
+
+    let presser = device.clone();
+    let handle = thread::spawn(move || {
+      thread::sleep(StdDuration::from_millis(300));
+      presser.set_state(WemoState::OnWithoutLoad);
+    });
+
+    assert_eq!(WemoState::OnWithoutLoad,
+        switch.wait_for_state(WemoState::On, timeout()).unwrap());
+    handle.join().unwrap();
+
+    match switch.wait_for_state(WemoState::Off,
+                                Duration::milliseconds(300)) {
+      Err(WemoError::TimeoutError { stage, elapsed, retry_after }) => {
+        assert_eq!(TimeoutStage::Wait, stage);
+        assert!(elapsed >= Duration::milliseconds(300));
+        assert_eq!(None, retry_after);
+      },
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+
+  // Finding the new port needs a search.
+  #[cfg(feature = "discovery")]
+  #[test]
+  fn test_wait_until_online() {
+    let device = FakeDevice::start_unique().unwrap();
+    let switch = device.switch();
+    device.set_state(WemoState::On);
+
+    // Still booting, then up on another port.
+    device.drop_next_requests(1);
+    let port = device.move_to_new_port().unwrap();
+
+    assert_eq!(WemoState::On, switch.wait_until_online(timeout()).unwrap());
+    assert_eq!(Some(port), switch.get_port());
+
+    device.drop_next_requests(100);
+    match switch.wait_until_online(Duration::milliseconds(500)) {
+      Err(WemoError::TimeoutError { .. }) => {},
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_verify_identity() {
+    let device = FakeDevice::start_unique().unwrap();
+    let mut config = device.config();
+    config.verify_identity = true;
+    let switch = device.switch().with_config(config.clone());
+
+    assert_eq!(WemoState::On, switch.turn_on(timeout()).unwrap());
+    assert_eq!(WemoState::Off, switch.turn_off(timeout()).unwrap());
+
+    // Another device answering at the address.
+    let expected = unique_serial_number();
+    let mut other = device.switch().with_config(config);
+    other.serial_number = Some(expected.clone());
+    match other.turn_on(timeout()) {
+      Err(WemoError::WrongDevice { expected: wrong, found }) => {
+        assert_eq!(expected, wrong);
+        assert_eq!(Some(device.serial_number().to_string()), found);
+      },
+      other => panic!("unexpected {:?}", other),
+    }
+
+    // Only the two changes reached the device.
+    assert_eq!(WemoState::Off, device.state());
+    assert_eq!(2, device.request_count());
+  }
+
+  #[test]
+  fn test_verify_identity_after_takeover() {
+    let device = FakeDevice::start_unique().unwrap();
+    let mut config = device.config();
+    config.verify_identity = true;
+    let switch = device.switch().with_config(config);
+    assert_eq!(WemoState::On, switch.turn_on(timeout()).unwrap());
+
+    // A failed request forgets the device was identified...
+    device.drop_next_requests(1);
+    assert!(switch.turn_off(timeout()).is_err());
+    assert!(switch.shared.identity_verified_at.read().unwrap().is_none());
+    assert_eq!(WemoState::Off, switch.turn_off(timeout()).unwrap());
+
+    // ...as does finding another device where this one was, even at the
+    // same IP address.
+    let other = FakeDevice::start_unique().unwrap();
+    let address = other.http_address();
+    switch.restore_location(address.ip(), address.port());
+    match switch.turn_on(timeout()) {
+      Err(WemoError::WrongDevice { found, .. }) => {
+        assert_eq!(Some(other.serial_number().to_string()), found);
+      },
+      other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(0, other.request_count());
+  }
+
+  #[test]
+  fn test_paranoid_mode() {
+    let device = FakeDevice::start_unique().unwrap();
+    let address = device.http_address();
+    let static_switch = || {
+      let mut switch = Switch::from_static_ip_and_port(address.ip(),
+          address.port()).with_config(device.config());
+      switch.serial_number = Some(device.serial_number().to_string());
+      switch
+    };
+
+    // A check within the TTL is trusted.
+    let other = unique_serial_number();
+    let ttl = Duration::hours(1);
+    let mut switch = static_switch().with_paranoid_mode(ttl);
+    assert_eq!(WemoState::On, switch.turn_on(timeout()).unwrap());
+    switch.serial_number = Some(other.clone());
+    assert_eq!(WemoState::Off, switch.turn_off(timeout()).unwrap());
+
+    // Every change is checked once it has expired.
+    let ttl = Duration::zero();
+    let mut switch = static_switch().with_paranoid_mode(ttl);
+    assert_eq!(WemoState::On, switch.turn_on(timeout()).unwrap());
+    switch.serial_number = Some(other.clone());
+    match switch.turn_off(timeout()) {
+      Err(WemoError::WrongDevice { expected, .. }) => {
+        assert_eq!(other, expected);
+      },
+      other => panic!("unexpected {:?}", other),
+    }
+
+    assert_eq!(WemoState::On, device.state());
+    assert_eq!(3, device.request_count());
+  }
+
+  #[test]
+  fn test_paranoid_mode_without_identity() {
+    let device = FakeDevice::start_unique().unwrap();
+    let address = device.http_address();
+    let switch = Switch::from_static_ip_and_port(address.ip(), address.port())
+        .with_config(device.config())
+        .with_paranoid_mode(Duration::hours(1));
+
+    match switch.turn_on(timeout()) {
+      Err(WemoError::InvalidArgument { .. }) => {},
+      other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(0, device.request_count());
+  }
+
+  #[test]
+  fn test_retry_after_lost_set_response() {
+    let device = FakeDevice::start_unique().unwrap();
+    let switch = device.switch();
+    device.lose_next_responses(1);
+
+    assert_eq!(WemoState::On,
+        switch.set_state_with_retry(WemoState::On, timeout()).unwrap());
+    assert_eq!(WemoState::On, device.state());
+    // The set, the refused retry, and the read confirming it.
+    assert_eq!(3, device.request_count());
+  }
+
+  // Finding the new port needs a search.
+  #[cfg(feature = "discovery")]
+  #[test]
+  fn test_retry_relocates_after_port_change() {
+    let device = FakeDevice::start_unique().unwrap();
+    let switch = device.switch();
+    let port = device.move_to_new_port().unwrap();
+
+    assert_eq!(WemoState::On,
+        switch.set_state_with_retry(WemoState::On, timeout()).unwrap());
+    assert_eq!(WemoState::On, device.state());
+    assert_eq!(Some(port), switch.get_port());
+  }
+
+  #[cfg(feature = "subscriptions")]
+  #[test]
+  fn test_attach_subscriptions() {
+    let device = FakeDevice::start_unique().unwrap();
+    let callback_port = TcpListener::bind("127.0.0.1:0").unwrap()
+        .local_addr().unwrap().port();
+
+    let mut subscriptions = Subscriptions::new(callback_port, 600);
+    subscriptions.start_server().unwrap();
+
+    let switch = device.switch();
+    switch.attach_subscriptions(&subscriptions).unwrap();
+    assert!(switch.is_push_fed());
+    assert_eq!(None, switch.last_known_state());
+
+    device.set_state(WemoState::On);
+    let start = Instant::now();
+    while switch.last_known_state() != Some(WemoState::On) {
+      assert!(start.elapsed() < StdDuration::from_secs(3));
+      thread::sleep(StdDuration::from_millis(10));
+    }
+
+    switch.detach_subscriptions(&subscriptions).unwrap();
+    assert!(!switch.is_push_fed());
+    assert_eq!(0, device.subscriber_count());
+  }
+
+  #[cfg(feature = "subscriptions")]
+  #[test]
+  fn test_attach_subscriptions_until_dropped() {
+    let device = FakeDevice::start_unique().unwrap();
+    let callback_port = TcpListener::bind("127.0.0.1:0").unwrap()
+        .local_addr().unwrap().port();
+
+    let subscriptions = Subscriptions::new(callback_port, 600);
+    let switch = device.switch();
+    switch.attach_subscriptions(&subscriptions).unwrap();
+    assert!(switch.is_push_fed());
+
+    drop(subscriptions);
+    assert!(!switch.is_push_fed());
+    assert_eq!(0, device.subscriber_count());
+  }
 }
@@ -0,0 +1,42 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Waiting for a device's state in async applications.
+
+use device::background::Background;
+use device::state::WemoState;
+use device::switch::{Switch, WemoResult};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use time::Duration;
+
+/// A `Future` that resolves once the device reaches a state, like
+/// `Switch::wait_for_state`. The wait runs on a background thread, so this
+/// works with any executor; dropping the future cancels it.
+pub struct WaitForState {
+  wait: Background<WemoResult>,
+}
+
+impl WaitForState {
+  pub fn new(switch: Arc<Switch>, state: WemoState, timeout: Duration)
+      -> WaitForState {
+    WaitForState {
+      wait: Background::spawn_cancellable(move |cancellation| {
+        switch.wait_for_state_cancellable(state, timeout, cancellation)
+      }),
+    }
+  }
+}
+
+impl Future for WaitForState {
+  type Output = WemoResult;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<WemoResult> {
+    match Pin::new(&mut self.wait).poll(cx) {
+      Poll::Ready(Ok(result)) => Poll::Ready(result),
+      Poll::Ready(Err(error)) => Poll::Ready(Err(error)),
+      Poll::Pending => Poll::Pending,
+    }
+  }
+}
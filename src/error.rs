@@ -5,6 +5,7 @@ use std::fmt::Display;
 use std::fmt::Formatter;
 use std::fmt::Result;
 use std::io::Error as IoError;
+use time::Duration;
 
 // TODO: Work in progress unifying errors.
 // TODO: Alphabetize
@@ -21,8 +22,31 @@ pub enum WemoError {
   /// Couldn't parse the XML received from Wemo.
   ParsingError,
 
-  /// Indicates that a communication timeout elapsed.
-  TimeoutError,
+  /// The SOAP envelope of a response didn't have the expected structure.
+  /// Only reported in `ParsingMode::Strict`.
+  InvalidEnvelope { reason: String },
+
+  /// A device response couldn't be understood, with the start of its raw body
+  /// attached for bug reports. Only reported when
+  /// `WemoConfig::error_body_limit` is set; otherwise `cause` is returned on
+  /// its own.
+  UnexpectedResponse { cause: Box<WemoError>, body: String },
+
+  /// Indicates that a communication timeout elapsed, after `elapsed` in the
+  /// `stage` that ran out of time. `retry_after` suggests how long to wait
+  /// before trying again, if at all. See `TimeoutStage::retry_after`.
+  TimeoutError {
+    stage: TimeoutStage,
+    elapsed: Duration,
+    retry_after: Option<Duration>,
+  },
+
+  /// The device's circuit breaker is open after repeated failures, so the
+  /// request wasn't sent.
+  CircuitOpen,
+
+  /// The operation was cancelled via a `CancellationToken`.
+  Cancelled,
 
   /// Indicates that the WeMo reported a problem during the request.
   WemoError,
@@ -37,6 +61,94 @@ pub enum WemoError {
 
   /// Could not determine local IP address.
   NoLocalIp,
+
+  /// The device has no serial number to identify it by.
+  MissingSerialNumber,
+
+  /// The device didn't report Insight power usage, eg. it isn't an Insight.
+  MissingInsightParams,
+
+  /// A URL couldn't be parsed, eg. a device's `setup.xml` location.
+  InvalidUrl { reason: String },
+
+  /// A device file couldn't be understood.
+  ConfigError { reason: String },
+
+  /// An action's arguments didn't match the service's description, eg. a
+  /// missing or misspelled argument, or a header couldn't be sent as given.
+  /// The request wasn't sent.
+  InvalidArgument { reason: String },
+
+  /// The device at the last known address isn't the one expected, eg. after
+  /// a DHCP reshuffle gave the address to another device. Only reported with
+  /// `WemoConfig::verify_identity` or `Switch::with_paranoid_mode`. The
+  /// request wasn't sent.
+  WrongDevice { expected: String, found: Option<String> },
+
+  /// A call run on a background thread, eg. by `Background`, panicked with
+  /// `message`.
+  Panicked { message: String },
+}
+
+/// The step of an operation that ran out of time.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimeoutStage {
+  /// Connecting to the device.
+  Connect,
+  /// Waiting for the device to answer a request.
+  Request,
+  /// Searching for the device via SSDP, eg. to relocate it.
+  Search,
+  /// Waiting for the device to reach a state.
+  Wait,
+}
+
+impl TimeoutStage {
+  /// A suggested pause before retrying. A device that can't be connected to
+  /// or found is often rebooting or rejoining WiFi, which takes a while; one
+  /// that's slow to answer usually recovers quickly. Waits aren't retried.
+  pub fn retry_after(&self) -> Option<Duration> {
+    match *self {
+      TimeoutStage::Connect => Some(Duration::seconds(5)),
+      TimeoutStage::Request => Some(Duration::seconds(1)),
+      TimeoutStage::Search => Some(Duration::seconds(30)),
+      TimeoutStage::Wait => None,
+    }
+  }
+}
+
+impl WemoError {
+  /// A `TimeoutError` with the stage's suggested `retry_after`.
+  pub fn timeout(stage: TimeoutStage, elapsed: Duration) -> WemoError {
+    WemoError::TimeoutError {
+      stage: stage,
+      elapsed: elapsed,
+      retry_after: stage.retry_after(),
+    }
+  }
+
+  pub fn is_timeout(&self) -> bool {
+    match *self {
+      WemoError::TimeoutError { .. } => true,
+      _ => false,
+    }
+  }
+
+  /// How long to wait before retrying, if the error suggests it.
+  pub fn retry_after(&self) -> Option<Duration> {
+    match *self {
+      WemoError::TimeoutError { retry_after, .. } => retry_after,
+      _ => None,
+    }
+  }
+
+  /// The raw response body attached to the error, if any.
+  pub fn response_body(&self) -> Option<&str> {
+    match *self {
+      WemoError::UnexpectedResponse { ref body, .. } => Some(body),
+      _ => None,
+    }
+  }
 }
 
 impl From<IoError> for WemoError {
@@ -0,0 +1,212 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! JSON encodings of notifications, discovery events, and devices, enabled
+//! with the `json` feature. The webhook, WebSocket, and daemon features all
+//! emit these, so consumers of any of them can share one parser.
+//!
+//! Every object has a `version`, currently `SCHEMA_VERSION`, and a `type`.
+//! Within a version, fields are only ever added; renaming or removing one,
+//! or changing its meaning, bumps the version.
+//!
+//! * Notifications have `subscription_key`, `sequence`, `received_at` in
+//!   milliseconds since the Unix epoch, and `reconciled` when set. By `type`:
+//!   * `state`: `state`, one of `off`, `on`, `on_without_load`, or `unknown`.
+//!   * `load_removed` and `load_restored`: nothing more.
+//!   * `brightness`: `level`.
+//!   * `bulb`: `device_id`, and either `on`, `level`, or `capability` and
+//!     `value`.
+//!   * `sensor`: `triggered`.
+//!   * `relay`: `on`.
+//! * `discovered`: `serial_number`, `udn`, `model` or null, `ip_address`,
+//!   `port`, and `setup_url`.
+//! * `departed`: `serial_number`.
+//! * `device`: `serial_number` and `ip_address` or null, `name`, `port` or
+//!   null, `reachable`, and `state` as above or null when unknown.
+
+use device::state::WemoState;
+use device::switch::Switch;
+#[cfg(feature = "discovery")] use net::notify::SsdpNotification;
+#[cfg(feature = "discovery")] use net::ssdp::SsdpResponse;
+use serde_json::Value;
+use serde_json::builder::ObjectBuilder;
+#[cfg(feature = "subscriptions")]
+use std::time::UNIX_EPOCH;
+#[cfg(feature = "subscriptions")]
+use subscriptions::{BulbChange, Notification, NotificationType};
+
+/// The version of the encodings in this module.
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// eg. `{"received_at":1478113200000,"sequence":1,"state":"on",
+/// "subscription_key":"192.168.1.4:49153","type":"state","version":1}`.
+#[cfg(feature = "subscriptions")]
+pub fn notification(notification: &Notification) -> Value {
+  match notification.notification_type {
+    NotificationType::State { ref state } => {
+      fields(notification, "state")
+          .insert("state", state_name(state))
+          .build()
+    },
+    NotificationType::LoadRemoved => event(notification, "load_removed"),
+    NotificationType::LoadRestored => event(notification, "load_restored"),
+    NotificationType::Brightness { level } => {
+      fields(notification, "brightness")
+          .insert("level", level)
+          .build()
+    },
+    NotificationType::Bulb { ref device_id, ref change } => {
+      let builder = fields(notification, "bulb")
+          .insert("device_id", device_id);
+      match *change {
+        BulbChange::OnOff { on } => builder.insert("on", on),
+        BulbChange::Brightness { level } => builder.insert("level", level),
+        BulbChange::Other { ref capability, ref value } => {
+          builder.insert("capability", capability).insert("value", value)
+        },
+      }.build()
+    },
+    NotificationType::Sensor { triggered } => {
+      fields(notification, "sensor")
+          .insert("triggered", triggered)
+          .build()
+    },
+    NotificationType::Relay { on } => {
+      fields(notification, "relay")
+          .insert("on", on)
+          .build()
+    },
+  }
+}
+
+/// A notification without fields of its own, eg. `load_removed`.
+#[cfg(feature = "subscriptions")]
+fn event(notification: &Notification, event_type: &str) -> Value {
+  fields(notification, event_type).build()
+}
+
+/// The fields every notification has. `reconciled` is only included when
+/// set.
+#[cfg(feature = "subscriptions")]
+fn fields(notification: &Notification, event_type: &str) -> ObjectBuilder {
+  let received_at = notification.received_at.duration_since(UNIX_EPOCH)
+      .map(|since| since.as_secs() * 1_000 + since.subsec_millis() as u64)
+      .unwrap_or(0);
+
+  let builder = object(event_type)
+      .insert("subscription_key", &notification.subscription_key)
+      .insert("sequence", notification.sequence)
+      .insert("received_at", received_at);
+
+  if notification.reconciled {
+    builder.insert("reconciled", true)
+  } else {
+    builder
+  }
+}
+
+/// A device found by a search or announced with `ssdp:alive`.
+#[cfg(feature = "discovery")]
+pub fn search_result(response: &SsdpResponse) -> Value {
+  object("discovered")
+      .insert("serial_number", &response.serial_number)
+      .insert("udn", &response.udn)
+      .insert("model", response.model())
+      .insert("ip_address", response.ip_address.to_string())
+      .insert("port", response.port)
+      .insert("setup_url", response.setup_url.as_str())
+      .build()
+}
+
+#[cfg(feature = "discovery")]
+pub fn ssdp_notification(notification: &SsdpNotification) -> Value {
+  match *notification {
+    SsdpNotification::Alive(ref response) => search_result(response),
+    SsdpNotification::ByeBye { ref serial_number } => {
+      object("departed")
+          .insert("serial_number", serial_number)
+          .build()
+    },
+  }
+}
+
+/// A device and its state, if known, eg. as the daemon lists them.
+pub fn device(switch: &Switch, state: Option<&WemoState>) -> Value {
+  object("device")
+      .insert("serial_number", switch.serial_number.as_ref())
+      .insert("name", switch.name())
+      .insert("ip_address", switch.get_ip_address().map(|ip| ip.to_string()))
+      .insert("port", switch.get_port())
+      .insert("reachable", switch.is_reachable())
+      .insert("state", state.map(state_name))
+      .build()
+}
+
+/// How states are named in `state` fields. Unlike `WemoState::description`,
+/// these never change within a schema version.
+pub fn state_name(state: &WemoState) -> &'static str {
+  match *state {
+    WemoState::Off => "off",
+    WemoState::On => "on",
+    WemoState::OnWithoutLoad => "on_without_load",
+    WemoState::Unknown(_) => "unknown",
+  }
+}
+
+/// An object with the fields every encoding starts with.
+fn object(object_type: &str) -> ObjectBuilder {
+  ObjectBuilder::new()
+      .insert("version", SCHEMA_VERSION)
+      .insert("type", object_type)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_device() {
+    let mut switch = Switch::from_static_ip_and_port(
+        "192.168.1.20".parse().unwrap(), 49153);
+    switch.serial_number = Some("221517K0101769".to_string());
+
+    let encoded = device(&switch, Some(&WemoState::OnWithoutLoad));
+    let object = encoded.as_object().unwrap();
+    assert_eq!(Some(&Value::U64(SCHEMA_VERSION)), object.get("version"));
+    assert_eq!(Some("device"), object["type"].as_str());
+    assert_eq!(Some("221517K0101769"), object["serial_number"].as_str());
+    assert_eq!(Some("192.168.1.20"), object["ip_address"].as_str());
+    assert_eq!(Some(49153), object["port"].as_u64());
+    assert_eq!(Some("on_without_load"), object["state"].as_str());
+
+    let encoded = device(&switch, None);
+    assert_eq!(Some(&Value::Null), encoded.as_object().unwrap().get("state"));
+  }
+
+  #[cfg(feature = "subscriptions")]
+  #[test]
+  fn test_notification() {
+    use std::time::Duration;
+
+    let encoded = notification(&Notification {
+      notification_type: NotificationType::State { state: WemoState::On },
+      subscription_key: "192.168.1.4:49153".to_string(),
+      received_at: UNIX_EPOCH + Duration::from_millis(1478113200000),
+      sequence: 1,
+      reconciled: false,
+    });
+
+    assert_eq!("{\"received_at\":1478113200000,\"sequence\":1,\
+        \"state\":\"on\",\"subscription_key\":\"192.168.1.4:49153\",\
+        \"type\":\"state\",\"version\":1}", encoded.to_string());
+  }
+
+  #[cfg(feature = "discovery")]
+  #[test]
+  fn test_ssdp_notification() {
+    let departed = ssdp_notification(&SsdpNotification::ByeBye {
+      serial_number: "221517K0101769".to_string(),
+    });
+    assert_eq!("{\"serial_number\":\"221517K0101769\",\"type\":\"departed\",\
+        \"version\":1}", departed.to_string());
+  }
+}
@@ -3,42 +3,102 @@
 #![doc(html_logo_url = "http://i.imgur.com/bkgoCdy.png", 
        html_favicon_url = "http://i.imgur.com/bkgoCdy.png")]
 
+#[cfg(feature = "async")] extern crate futures_core;
 #[cfg(feature = "subscriptions")] extern crate get_if_addrs;
 #[cfg(feature = "subscriptions")] extern crate iron;
 #[cfg(feature = "subscriptions")] extern crate persistent;
+#[cfg(feature = "serialize")] extern crate serde;
+#[cfg(feature = "serde_json")] extern crate serde_json;
+#[cfg(feature = "time01")] extern crate time as time01;
 #[cfg(feature = "subscriptions")] extern crate urlencoded;
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate log;
-extern crate mio;
+#[cfg(feature = "discovery")] extern crate mio;
 extern crate regex;
+extern crate url as url_crate;
 
-// Re-export from the time crate.
-pub mod time {
-  extern crate time;
-  pub use self::time::{Duration, PreciseTime};
-}
-
-// Re-export from the url crate.
+/// Re-exports from the url crate, which wemo no longer exposes: device
+/// addresses are `DeviceLocation` and `SetupUrl`. See `UPGRADING.md`.
+// NB: Deprecating the module alone doesn't warn on paths through it, and
+// deprecating a `pub use` has no effect, hence the aliases.
+#[deprecated(since = "0.0.13",
+    note = "use `DeviceLocation` and `SetupUrl`; removed in the next release")]
 pub mod url {
-  extern crate url;
-  pub use self::url::{
-    Host,
-    ParseError,
-    Url,
-  };
+  #[deprecated(since = "0.0.13", note = "use `wemo::SetupUrl`")]
+  pub type Url = ::url_crate::Url;
+
+  #[deprecated(since = "0.0.13", note = "use `SetupUrl::ip_address`")]
+  pub type Host<S = String> = ::url_crate::Host<S>;
+
+  #[deprecated(since = "0.0.13", note = "`SetupUrl::parse` fails with \
+      `WemoError::InvalidUrl`")]
+  pub type ParseError = ::url_crate::ParseError;
 }
 
 #[cfg(feature = "subscriptions")] pub mod subscriptions;
+pub mod bulk;
+pub mod capture;
+pub mod config;
+#[cfg(all(unix, feature = "dbus"))] pub mod dbus;
 pub mod error;
+#[cfg(feature = "json")] pub mod json;
+pub mod locations;
+pub mod metrics;
+#[cfg(feature = "onboarding")] pub mod onboarding;
+pub mod prelude;
+pub mod registry;
+#[cfg(feature = "remoteaccess")] pub mod remoteaccess;
+#[cfg(any(test, feature = "testing"))] pub mod testing;
+pub mod time;
+#[cfg(feature = "webhooks")] pub mod webhook;
+#[cfg(feature = "websocket")] pub mod websocket;
 
+#[cfg(any(feature = "onboarding", feature = "websocket"))] mod base64;
+mod cancel;
+#[cfg(feature = "onboarding")] mod crypto;
+mod deadline;
 mod device;
 mod net;
 mod parsing;
+#[cfg(feature = "subscriptions")] mod queue;
+mod threads;
+mod toml;
 mod xml;
 
 // Friendly top-level exports.
 // FIXME: Not a good idea to alias stuff; shorter package names are better.
-pub use device::state::WemoState;
-pub use device::switch::{Switch, WemoResult};
-pub use net::ssdp::DeviceSearch;
-pub use net::ssdp::SsdpResponse;
+#[cfg(feature = "discovery")] pub use bulk::snapshot;
+pub use cancel::CancellationToken;
+pub use config::{CircuitBreakerPolicy, DeviceDefinition, DeviceKind};
+pub use config::{ParsingMode, RetryPolicy, WemoConfig};
+pub use deadline::Deadline;
+pub use device::alert::{PowerAlert, PowerLevel};
+pub use device::appliance::{ApplianceEvent, ApplianceMonitor, ApplianceState};
+#[cfg(feature = "async")] pub use device::background::Background;
+pub use device::breaker::CircuitState;
+pub use device::client::{ArgumentValue, Arguments, ServiceClient};
+pub use device::clock::{ClockAlert, ClockDrift, DeviceTime};
+pub use device::commands::CommandQueue;
+pub use device::insight::InsightParams;
+pub use device::liveness::LivenessMonitor;
+#[cfg(feature = "discovery")]
+pub use device::relocation::RelocationWorker;
+pub use device::sampler::{InsightSample, InsightSampler};
+pub use device::service::{Action, Argument, ArgumentDirection, Service};
+pub use device::service::ServiceDescription;
+pub use device::state::{BinaryState, StateReport, StateSource, WemoState};
+pub use device::switch::{Batch, Switch, WemoResult};
+#[cfg(feature = "async")] pub use device::wait::WaitForState;
+pub use metrics::WemoMetrics;
+#[cfg(all(feature = "async", feature = "discovery"))]
+pub use net::discovery::DiscoveryStream;
+pub use net::location::{DeviceLocation, SetupUrl};
+#[cfg(feature = "discovery")]
+pub use net::notify::{NotifyListener, SsdpNotification};
+pub use net::ports::DevicePorts;
+pub use net::range::Ipv4Range;
+#[cfg(feature = "discovery")]
+pub use net::search::{DeviceModel, DeviceSearch, SearchDiff};
+pub use net::ssdp::{ServerInfo, SsdpResponse};
+#[cfg(feature = "discovery")] pub use net::warm::WarmSearch;
+pub use net::warm::{load_search_results, save_search_results};
@@ -0,0 +1,237 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! A small on-disk cache of where each device was last found, so a freshly
+//! started process targets the right port immediately instead of relearning
+//! WeMo port drift every run.
+//!
+//! The cache is a TOML file with one table per device:
+//!
+//! ```toml
+//! [[locations]]
+//! serial_number = "221517K0101769"
+//! ip_address = "192.168.1.20"
+//! port = 49154
+//! ```
+
+use device::SerialNumber;
+use device::switch::Switch;
+use error::WemoError;
+use net::location::DeviceLocation;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use toml::{self, Value};
+
+/// The last known location of each device, by serial number, backed by a
+/// file. Changes are kept in memory until `save`.
+pub struct LocationStore {
+  path: PathBuf,
+  locations: RwLock<HashMap<SerialNumber, DeviceLocation>>,
+}
+
+impl LocationStore {
+  /// Read the cache at `path`. A missing file is an empty cache, created by
+  /// the first `save`.
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<LocationStore, WemoError> {
+    let path = path.as_ref().to_path_buf();
+    let mut contents = String::new();
+
+    match File::open(&path) {
+      Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+      Err(e) => { return Err(e.into()); },
+      Ok(mut file) => { file.read_to_string(&mut contents)?; },
+    }
+
+    Ok(LocationStore {
+      path: path,
+      locations: RwLock::new(parse_toml(&contents)?),
+    })
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// The device's last known location, if any.
+  pub fn get(&self, serial_number: &str) -> Option<DeviceLocation> {
+    self.locations.read()
+        .ok()
+        .and_then(|locations| locations.get(serial_number).cloned())
+  }
+
+  /// Remember the device's location. Returns whether it changed.
+  pub fn record(&self, serial_number: &str, location: DeviceLocation)
+      -> bool {
+    match self.locations.write() {
+      Err(_) => false,
+      Ok(mut locations) => {
+        locations.insert(serial_number.to_string(), location)
+            != Some(location)
+      },
+    }
+  }
+
+  /// Remember where the switch currently is, if its serial number, IP
+  /// address, and port are known. Returns whether that changed.
+  pub fn remember(&self, switch: &Switch) -> bool {
+    match (switch.serial_number.as_ref(), switch.location()) {
+      (Some(serial_number), Some(location)) => {
+        self.record(serial_number, location)
+      },
+      _ => false,
+    }
+  }
+
+  /// Point the switch at its last known location. Returns whether one was
+  /// known. Static IP addresses aren't changed, but their ports are.
+  pub fn restore(&self, switch: &Switch) -> bool {
+    let location = switch.serial_number.as_ref()
+        .and_then(|serial_number| self.get(serial_number));
+
+    match location {
+      None => false,
+      Some(location) => {
+        switch.restore_location(location.ip_address, location.port);
+        true
+      },
+    }
+  }
+
+  /// Write the cache to its file. The file is replaced whole, so a crash
+  /// while saving leaves the previous cache.
+  pub fn save(&self) -> Result<(), WemoError> {
+    let contents = {
+      let locations = self.locations.read().map_err(|_| WemoError::LockError)?;
+      to_toml(&locations)
+    };
+
+    let mut temporary = self.path.clone().into_os_string();
+    temporary.push(".tmp");
+
+    File::create(&temporary)?.write_all(contents.as_bytes())?;
+    fs::rename(&temporary, &self.path)?;
+    Ok(())
+  }
+}
+
+fn parse_toml(input: &str)
+    -> Result<HashMap<SerialNumber, DeviceLocation>, WemoError> {
+  let tables = toml::parse(input).map_err(cache_error)?;
+
+  let mut locations = HashMap::new();
+  for (i, &(ref name, ref table)) in tables.iter().enumerate() {
+    let invalid = |reason: &str| {
+      cache_error(format!("location {}: {}", i + 1, reason))
+    };
+
+    if name != "locations" {
+      return Err(cache_error(format!("unknown table '{}'", name)));
+    }
+
+    let serial_number = match table.get("serial_number") {
+      Some(&Value::String(ref serial_number)) => serial_number.clone(),
+      _ => { return Err(invalid("missing serial_number")); },
+    };
+
+    let ip_address = match table.get("ip_address") {
+      Some(&Value::String(ref ip_address)) => {
+        ip_address.parse().map_err(|_| invalid("invalid ip_address"))?
+      },
+      _ => { return Err(invalid("missing ip_address")); },
+    };
+
+    let port = match table.get("port") {
+      Some(&Value::Integer(port)) if port > 0 && port <= 65535 => port as u16,
+      _ => { return Err(invalid("port must be a number from 1 to 65535")); },
+    };
+
+    locations.insert(serial_number, DeviceLocation {
+      ip_address: ip_address,
+      port: port,
+    });
+  }
+
+  Ok(locations)
+}
+
+/// Encode the locations as TOML, sorted by serial number.
+fn to_toml(locations: &HashMap<SerialNumber, DeviceLocation>) -> String {
+  let mut serial_numbers = locations.keys().collect::<Vec<_>>();
+  serial_numbers.sort();
+
+  let mut toml = String::new();
+  for serial_number in serial_numbers {
+    let location = &locations[serial_number];
+    if !toml.is_empty() {
+      toml.push('\n');
+    }
+    toml.push_str("[[locations]]\n");
+    toml.push_str(&format!("serial_number = \"{}\"\n",
+        serial_number.replace('\\', "\\\\").replace('"', "\\\"")));
+    toml.push_str(&format!("ip_address = \"{}\"\n", location.ip_address));
+    toml.push_str(&format!("port = {}\n", location.port));
+  }
+  toml
+}
+
+fn cache_error<S: Into<String>>(reason: S) -> WemoError {
+  WemoError::ConfigError {
+    reason: format!("location cache: {}", reason.into()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::net::IpAddr;
+  use std::process;
+  use super::*;
+
+  fn ip(ip_address: &str) -> IpAddr {
+    ip_address.parse().unwrap()
+  }
+
+  #[test]
+  fn test_parse() {
+    let locations = parse_toml(r#"
+      [[locations]]
+      serial_number = "ABC"
+      ip_address = "1.1.1.1"
+      port = 49154
+    "#).unwrap();
+
+    assert_eq!(Some(&DeviceLocation::new(ip("1.1.1.1"), 49154)),
+        locations.get("ABC"));
+    assert_eq!(locations, parse_toml(&to_toml(&locations)).unwrap());
+
+    assert!(parse_toml("[[locations]]\nserial_number = \"ABC\"").is_err());
+    assert!(parse_toml("[[devices]]\n").is_err());
+  }
+
+  #[test]
+  fn test_save_and_restore() {
+    let path = env::temp_dir()
+        .join(format!("wemo-locations-{}.toml", process::id()));
+    let _r = fs::remove_file(&path);
+
+    let store = LocationStore::open(&path).unwrap();
+    let mut switch = Switch::from_dynamic_ip_and_port(ip("1.1.1.1"), 49154);
+    switch.serial_number = Some("ABC".to_string());
+
+    assert!(store.remember(&switch));
+    assert!(!store.remember(&switch));
+    store.save().unwrap();
+
+    let store = LocationStore::open(&path).unwrap();
+    let mut switch = Switch::from_udn("uuid:Socket-1_0-ABC");
+    switch.serial_number = Some("ABC".to_string());
+
+    assert!(store.restore(&switch));
+    assert_eq!(Some(ip("1.1.1.1")), switch.get_ip_address());
+    assert_eq!(Some(49154), switch.get_port());
+
+    fs::remove_file(&path).unwrap();
+  }
+}
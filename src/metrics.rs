@@ -0,0 +1,96 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Hooks for piping request, retry, timeout, and event counts into your own
+//! telemetry. Install an implementation once at startup with `set_metrics`.
+
+use std::sync::{Arc, RwLock};
+use time::Duration;
+
+/// Called as the library talks to devices. Every method does nothing by
+/// default, so implement only the ones you need. Calls happen on whichever
+/// thread made the request, so keep them cheap.
+pub trait WemoMetrics: Send + Sync {
+  /// A SOAP request finished. The action is eg.
+  /// `urn:Belkin:service:basicevent:1#GetBinaryState`.
+  fn on_request(&self, _soap_action: &str, _latency: Duration,
+                _success: bool) {}
+
+  /// A request failed and is being retried after relocating the device.
+  fn on_retry(&self, _soap_action: &str) {}
+
+  /// A SOAP request got no response before its timeout.
+  fn on_timeout(&self, _soap_action: &str) {}
+
+  /// A subscription event arrived from the device subscribed to as
+  /// `subscription_key`.
+  fn on_event(&self, _subscription_key: &str) {}
+
+  /// One of the library's background threads, eg. `wemo-resubscribe`,
+  /// panicked and is gone.
+  fn on_thread_panic(&self, _thread: &str, _message: &str) {}
+}
+
+lazy_static! {
+  static ref GLOBAL_METRICS: RwLock<Option<Arc<WemoMetrics>>> =
+      RwLock::new(None);
+}
+
+/// Report to `metrics` from now on, replacing any previous hooks.
+pub fn set_metrics(metrics: Arc<WemoMetrics>) {
+  match GLOBAL_METRICS.write() {
+    Err(_) => {}, // Ignore. Shouldn't occur.
+    Ok(mut global) => { *global = Some(metrics); },
+  }
+}
+
+/// Stop reporting metrics.
+pub fn clear_metrics() {
+  match GLOBAL_METRICS.write() {
+    Err(_) => {}, // Ignore. Shouldn't occur.
+    Ok(mut global) => { *global = None; },
+  }
+}
+
+/// Call `report` with the installed hooks, if any.
+pub fn report<F>(report: F) where F: FnOnce(&WemoMetrics) {
+  let metrics = GLOBAL_METRICS.read()
+      .ok()
+      .and_then(|global| global.clone());
+
+  if let Some(metrics) = metrics {
+    report(&*metrics);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use super::*;
+
+  struct CountingMetrics {
+    events: AtomicUsize,
+  }
+
+  impl WemoMetrics for CountingMetrics {
+    fn on_event(&self, subscription_key: &str) {
+      // Other tests may report events concurrently.
+      if subscription_key == "metrics-test" {
+        self.events.fetch_add(1, Ordering::SeqCst);
+      }
+    }
+  }
+
+  #[test]
+  fn test_report() {
+    let metrics = Arc::new(CountingMetrics { events: AtomicUsize::new(0) });
+    set_metrics(metrics.clone());
+
+    report(|m| m.on_event("metrics-test"));
+    report(|m| m.on_retry("ignored"));
+    clear_metrics();
+    report(|m| m.on_event("metrics-test"));
+
+    assert_eq!(1, metrics.events.load(Ordering::SeqCst));
+  }
+}
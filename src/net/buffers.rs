@@ -0,0 +1,130 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Read buffers shared by the SOAP client and the SSDP and NOTIFY listeners.
+//! A daemon opens connections and searches constantly, especially when
+//! devices are flapping, so buffers are returned to a pool when dropped
+//! rather than freed and allocated again.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// How many idle buffers the pool keeps.
+const MAX_POOLED: usize = 16;
+
+/// Buffers that grew larger than this are freed rather than kept.
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+lazy_static! {
+  static ref POOL: BufferPool = BufferPool::new(MAX_POOLED);
+}
+
+/// Take a buffer of `length` bytes from the shared pool. Its contents are
+/// left over from its last use, so only read what's been written to it.
+pub fn take(length: usize) -> PooledBuffer {
+  POOL.take(length)
+}
+
+/// A pool of byte buffers.
+pub struct BufferPool {
+  idle: Mutex<Vec<Vec<u8>>>,
+  max_pooled: usize,
+}
+
+impl BufferPool {
+  pub fn new(max_pooled: usize) -> BufferPool {
+    BufferPool {
+      idle: Mutex::new(Vec::new()),
+      max_pooled: max_pooled,
+    }
+  }
+
+  pub fn take(&'static self, length: usize) -> PooledBuffer {
+    let mut buffer = match self.idle.lock() {
+      Err(_) => None, // Ignore. Allocate a new one.
+      Ok(mut idle) => idle.pop(),
+    }.unwrap_or_default();
+
+    buffer.resize(length, 0);
+
+    PooledBuffer {
+      buffer: buffer,
+      pool: self,
+    }
+  }
+
+  fn give_back(&self, buffer: Vec<u8>) {
+    if buffer.capacity() == 0 || buffer.capacity() > MAX_POOLED_CAPACITY {
+      return;
+    }
+    if let Ok(mut idle) = self.idle.lock() {
+      if idle.len() < self.max_pooled {
+        idle.push(buffer);
+      }
+    }
+  }
+}
+
+/// A buffer that goes back to its pool when dropped. It derefs to a `Vec`,
+/// so it can be read into as a slice or grown.
+pub struct PooledBuffer {
+  buffer: Vec<u8>,
+  pool: &'static BufferPool,
+}
+
+impl Deref for PooledBuffer {
+  type Target = Vec<u8>;
+
+  fn deref(&self) -> &Vec<u8> {
+    &self.buffer
+  }
+}
+
+impl DerefMut for PooledBuffer {
+  fn deref_mut(&mut self) -> &mut Vec<u8> {
+    &mut self.buffer
+  }
+}
+
+impl Drop for PooledBuffer {
+  fn drop(&mut self) {
+    let buffer = ::std::mem::take(&mut self.buffer);
+    self.pool.give_back(buffer);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  lazy_static! {
+    static ref TEST_POOL: BufferPool = BufferPool::new(2);
+  }
+
+  fn idle() -> usize {
+    TEST_POOL.idle.lock().unwrap().len()
+  }
+
+  #[test]
+  fn test_buffer_pool() {
+    let mut buffer = TEST_POOL.take(1024);
+    assert_eq!(1024, buffer.len());
+    buffer[0] = 7;
+    let address = buffer.as_ptr();
+    drop(buffer);
+    assert_eq!(1, idle());
+
+    // The same allocation is handed out again, resized.
+    let buffer = TEST_POOL.take(512);
+    assert_eq!(512, buffer.len());
+    assert_eq!(address, buffer.as_ptr());
+    assert_eq!(0, idle());
+
+    // Only `max_pooled` are kept, and oversized ones are freed.
+    let buffers = (0..3).map(|_| TEST_POOL.take(16)).collect::<Vec<_>>();
+    drop(buffers);
+    assert_eq!(2, idle());
+    drop(TEST_POOL.take(MAX_POOLED_CAPACITY + 1));
+    assert_eq!(1, idle());
+    drop(buffer);
+  }
+}
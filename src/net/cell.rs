@@ -0,0 +1,177 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Lock-free cells for a device's cached location. Every request reads them,
+//! so many threads controlling one device shouldn't contend on a lock, and a
+//! thread panicking mid-update can't poison them.
+
+use std::hint;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, AtomicUsize};
+use std::sync::atomic::Ordering::SeqCst;
+
+/// Tags for what an `AtomicIpAddr` holds.
+const EMPTY: u8 = 0;
+const V4: u8 = 4;
+const V6: u8 = 6;
+
+/// An `Option<u16>` port. Zero is never a device's port, so it stands for
+/// `None`.
+#[derive(Debug, Default)]
+pub struct AtomicPort {
+  port: AtomicU32,
+}
+
+impl AtomicPort {
+  pub fn new(port: Option<u16>) -> AtomicPort {
+    AtomicPort { port: AtomicU32::new(encode_port(port)) }
+  }
+
+  pub fn load(&self) -> Option<u16> {
+    decode_port(self.port.load(SeqCst))
+  }
+
+  /// Store a port, returning the one it replaced.
+  pub fn swap(&self, port: Option<u16>) -> Option<u16> {
+    decode_port(self.port.swap(encode_port(port), SeqCst))
+  }
+}
+
+fn encode_port(port: Option<u16>) -> u32 {
+  port.map(|port| port as u32).unwrap_or(0)
+}
+
+fn decode_port(port: u32) -> Option<u16> {
+  match port {
+    0 => None,
+    port => Some(port as u16),
+  }
+}
+
+/// An `Option<IpAddr>`. An IPv6 address doesn't fit in one atomic word, so
+/// this is a sequence lock: writers bump the sequence to odd while they
+/// update the words, and readers retry if it changed underneath them.
+/// Readers never block writers, and writes are rare.
+#[derive(Debug, Default)]
+pub struct AtomicIpAddr {
+  sequence: AtomicUsize,
+  tag: AtomicU8,
+  high: AtomicU64,
+  low: AtomicU64,
+}
+
+impl AtomicIpAddr {
+  pub fn new(ip_address: Option<IpAddr>) -> AtomicIpAddr {
+    let cell = AtomicIpAddr::default();
+    cell.swap(ip_address);
+    cell
+  }
+
+  pub fn load(&self) -> Option<IpAddr> {
+    loop {
+      let before = self.sequence.load(SeqCst);
+      if before & 1 == 0 {
+        let ip_address = self.read();
+        if self.sequence.load(SeqCst) == before {
+          return ip_address;
+        }
+      }
+      hint::spin_loop();
+    }
+  }
+
+  /// Store an address, returning the one it replaced.
+  pub fn swap(&self, ip_address: Option<IpAddr>) -> Option<IpAddr> {
+    let sequence = self.lock();
+    let previous = self.read();
+
+    let (tag, high, low) = match ip_address {
+      None => (EMPTY, 0, 0),
+      Some(IpAddr::V4(ip)) => (V4, 0, u32::from(ip) as u64),
+      Some(IpAddr::V6(ip)) => {
+        let bits = u128::from(ip);
+        (V6, (bits >> 64) as u64, bits as u64)
+      },
+    };
+    self.tag.store(tag, SeqCst);
+    self.high.store(high, SeqCst);
+    self.low.store(low, SeqCst);
+
+    self.sequence.store(sequence.wrapping_add(2), SeqCst);
+    previous
+  }
+
+  /// Take the write side by making the sequence odd. Returns the even
+  /// sequence it started from.
+  fn lock(&self) -> usize {
+    loop {
+      let sequence = self.sequence.load(SeqCst);
+      if sequence & 1 == 0 && self.sequence.compare_exchange_weak(sequence,
+          sequence + 1, SeqCst, SeqCst).is_ok() {
+        return sequence;
+      }
+      hint::spin_loop();
+    }
+  }
+
+  /// May be torn unless the sequence is checked around it.
+  fn read(&self) -> Option<IpAddr> {
+    let high = self.high.load(SeqCst);
+    let low = self.low.load(SeqCst);
+    match self.tag.load(SeqCst) {
+      V4 => Some(IpAddr::V4(Ipv4Addr::from(low as u32))),
+      V6 => {
+        let bits = ((high as u128) << 64) | low as u128;
+        Some(IpAddr::V6(Ipv6Addr::from(bits)))
+      },
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use std::thread;
+  use super::*;
+
+  #[test]
+  fn test_atomic_port() {
+    let port = AtomicPort::new(None);
+    assert_eq!(None, port.load());
+    assert_eq!(None, port.swap(Some(49153)));
+    assert_eq!(Some(49153), port.load());
+    port.swap(Some(u16::MAX));
+    assert_eq!(Some(u16::MAX), port.load());
+    assert_eq!(Some(u16::MAX), port.swap(None));
+    assert_eq!(None, port.load());
+  }
+
+  #[test]
+  fn test_atomic_ip_addr() {
+    let v4: IpAddr = "192.168.1.20".parse().unwrap();
+    let v6: IpAddr = "fe80::1:2:3:4".parse().unwrap();
+
+    let cell = AtomicIpAddr::new(Some(v4));
+    assert_eq!(Some(v4), cell.load());
+    assert_eq!(Some(v4), cell.swap(Some(v6)));
+    assert_eq!(Some(v6), cell.load());
+    assert_eq!(Some(v6), cell.swap(None));
+    assert_eq!(None, cell.load());
+
+    // Readers only ever see whole addresses.
+    let cell = Arc::new(AtomicIpAddr::new(Some(v4)));
+    let writer = {
+      let cell = cell.clone();
+      thread::spawn(move || {
+        for i in 0..10_000 {
+          cell.swap(Some(if i % 2 == 0 { v6 } else { v4 }));
+        }
+      })
+    };
+    for _ in 0..10_000 {
+      let ip = cell.load();
+      assert!(ip == Some(v4) || ip == Some(v6));
+    }
+    writer.join().unwrap();
+  }
+}
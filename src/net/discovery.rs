@@ -0,0 +1,106 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Device discovery for async applications.
+
+use cancel::CancellationToken;
+use futures_core::Stream;
+use net::search::DeviceSearch;
+use net::ssdp::SsdpResponse;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use threads;
+
+/// A `Stream` of devices as they are discovered. The search runs on a
+/// background thread, so this works with any executor. The stream completes
+/// when the search times out or is cancelled; dropping it cancels the search.
+pub struct DiscoveryStream {
+  shared: Arc<Mutex<Shared>>,
+  cancellation: CancellationToken,
+}
+
+struct Shared {
+  found: VecDeque<SsdpResponse>,
+  finished: bool,
+  waker: Option<Waker>,
+}
+
+impl DiscoveryStream {
+  /// Begin searching for devices for up to `timeout_ms`.
+  pub fn new(timeout_ms: u64) -> DiscoveryStream {
+    DiscoveryStream::from_search(DeviceSearch::new(), timeout_ms)
+  }
+
+  /// Begin searching with a configured `DeviceSearch`. Cancelling the
+  /// search's `CancellationToken` completes the stream.
+  pub fn from_search(mut search: DeviceSearch, timeout_ms: u64)
+      -> DiscoveryStream {
+    let shared = Arc::new(Mutex::new(Shared {
+      found: VecDeque::new(),
+      finished: false,
+      waker: None,
+    }));
+
+    let cancellation = search.cancellation().clone();
+    let on_found_shared = shared.clone();
+    let finished_shared = shared.clone();
+
+    threads::spawn("wemo-discovery", move || {
+      search.search_with(timeout_ms, move |device| {
+        if let Ok(mut shared) = on_found_shared.lock() {
+          shared.found.push_back(device.clone());
+          if let Some(waker) = shared.waker.take() {
+            waker.wake();
+          }
+        }
+      });
+
+      if let Ok(mut shared) = finished_shared.lock() {
+        shared.finished = true;
+        if let Some(waker) = shared.waker.take() {
+          waker.wake();
+        }
+      }
+    });
+
+    DiscoveryStream {
+      shared: shared,
+      cancellation: cancellation,
+    }
+  }
+
+  /// End the search early. Devices already found are still yielded.
+  pub fn cancel(&self) {
+    self.cancellation.cancel();
+  }
+}
+
+impl Stream for DiscoveryStream {
+  type Item = SsdpResponse;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context)
+      -> Poll<Option<SsdpResponse>> {
+    let mut shared = match self.shared.lock() {
+      Err(_) => { return Poll::Ready(None); },
+      Ok(shared) => { shared },
+    };
+
+    if let Some(device) = shared.found.pop_front() {
+      return Poll::Ready(Some(device));
+    }
+
+    if shared.finished {
+      return Poll::Ready(None);
+    }
+
+    shared.waker = Some(cx.waker().clone());
+    Poll::Pending
+  }
+}
+
+impl Drop for DiscoveryStream {
+  fn drop(&mut self) {
+    self.cancel();
+  }
+}
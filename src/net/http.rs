@@ -0,0 +1,224 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Minimal blocking HTTP GET for the device description documents, eg.
+//! `setup.xml`, and POST for forwarding events. SOAP requests go through
+//! `SoapClient` instead.
+
+use error::{TimeoutStage, WemoError};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Instant;
+use time::Duration;
+
+/// Check that an extra header can be written as given: its name must be an
+/// HTTP token, and its value can't hold a line break, which would start
+/// another header.
+pub fn check_header(name: &str, value: &str) -> Result<(), WemoError> {
+  let is_token = !name.is_empty() && name.bytes().all(|byte| {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+  });
+  if !is_token {
+    return Err(WemoError::InvalidArgument {
+      reason: format!("Invalid header name {:?}", name),
+    });
+  }
+
+  if value.contains(&['\r', '\n', '\0'][..]) {
+    return Err(WemoError::InvalidArgument {
+      reason: format!("Invalid value for header {}", name),
+    });
+  }
+  Ok(())
+}
+
+/// Check every header with `check_header`.
+pub fn check_headers(headers: &[(String, String)]) -> Result<(), WemoError> {
+  for (name, value) in headers {
+    check_header(name, value)?;
+  }
+  Ok(())
+}
+
+/// Fetch `path` from the device and return the response body. Only a
+/// `200 OK` response is considered successful.
+pub fn get(ip_address: IpAddr,
+           port: u16,
+           path: &str,
+           headers: &[(String, String)],
+           timeout: Duration) -> Result<String, WemoError> {
+  check_headers(headers)?;
+  let start = Instant::now();
+  let timeout = match timeout.to_std() {
+    Err(_) => {
+      return Err(WemoError::timeout(TimeoutStage::Connect, Duration::zero()));
+    },
+    Ok(timeout) => { timeout },
+  };
+
+  let socket = SocketAddr::new(ip_address, port);
+  let mut stream = TcpStream::connect_timeout(&socket, timeout)
+      .map_err(|error| timed_out(error, TimeoutStage::Connect, start))?;
+  stream.set_read_timeout(Some(timeout))?;
+  stream.set_write_timeout(Some(timeout))?;
+
+  let mut request = format!("\
+      GET {} HTTP/1.1\r\n\
+      Host: {}\r\n\
+      Connection: close\r\n",
+      path, socket);
+
+  for &(ref name, ref value) in headers {
+    request.push_str(&format!("{}: {}\r\n", name, value));
+  }
+
+  request.push_str("\r\n");
+
+  let mut response = String::new();
+  stream.write_all(request.as_bytes())
+      .and_then(|_| stream.read_to_string(&mut response))
+      .map_err(|error| timed_out(error, TimeoutStage::Request, start))?;
+
+  parse_response(&response)
+      .map(|body| body.to_string())
+      .ok_or(WemoError::BadResponseError)
+}
+
+/// Send `body` to `path` and return the response status code. `host` is sent
+/// as the `Host` header.
+#[cfg(feature = "webhooks")]
+pub fn post(socket: SocketAddr,
+            host: &str,
+            path: &str,
+            headers: &[(String, String)],
+            body: &str,
+            timeout: Duration) -> Result<u16, WemoError> {
+  check_headers(headers)?;
+  let start = Instant::now();
+  let timeout = match timeout.to_std() {
+    Err(_) => {
+      return Err(WemoError::timeout(TimeoutStage::Connect, Duration::zero()));
+    },
+    Ok(timeout) => { timeout },
+  };
+
+  let mut stream = TcpStream::connect_timeout(&socket, timeout)
+      .map_err(|error| timed_out(error, TimeoutStage::Connect, start))?;
+  stream.set_read_timeout(Some(timeout))?;
+  stream.set_write_timeout(Some(timeout))?;
+
+  let mut request = format!("\
+      POST {} HTTP/1.1\r\n\
+      Host: {}\r\n\
+      Connection: close\r\n\
+      Content-Length: {}\r\n",
+      path, host, body.len());
+
+  for &(ref name, ref value) in headers {
+    request.push_str(&format!("{}: {}\r\n", name, value));
+  }
+
+  request.push_str("\r\n");
+  request.push_str(body);
+
+  let mut response = String::new();
+  stream.write_all(request.as_bytes())
+      .and_then(|_| stream.read_to_string(&mut response))
+      .map_err(|error| timed_out(error, TimeoutStage::Request, start))?;
+
+  parse_status(&response).ok_or(WemoError::BadResponseError)
+}
+
+/// The status code of a response.
+#[cfg(feature = "subscriptions")]
+pub fn parse_status(response: &str) -> Option<u16> {
+  response.lines()
+      .next()
+      .and_then(|status| status.split_whitespace().nth(1))
+      .and_then(|code| code.parse::<u16>().ok())
+}
+
+/// A `WemoError::TimeoutError` in `stage` if the socket timed out.
+fn timed_out(error: io::Error, stage: TimeoutStage, start: Instant)
+    -> WemoError {
+  match error.kind() {
+    ErrorKind::TimedOut | ErrorKind::WouldBlock => {
+      WemoError::timeout(stage, Duration::from(start.elapsed()))
+    },
+    _ => error.into(),
+  }
+}
+
+/// Return the body of a successful response.
+fn parse_response(response: &str) -> Option<&str> {
+  let status_ok = response.lines()
+      .next()
+      .map(|status| status.split_whitespace().nth(1) == Some("200"))
+      .unwrap_or(false);
+
+  if !status_ok {
+    return None;
+  }
+
+  response.find("\r\n\r\n").map(|index| &response[index + 4 ..])
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Read;
+  use std::io::Write;
+  use std::net::TcpListener;
+  use std::thread;
+  use super::*;
+
+  #[test]
+  fn test_parse_response() {
+    assert_eq!(Some("<root/>"),
+        parse_response("HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\n<root/>"));
+    assert_eq!(None, parse_response("HTTP/1.1 404 Not Found\r\n\r\n"));
+    assert_eq!(None, parse_response(""));
+  }
+
+  #[test]
+  fn test_get() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+      let mut stream = listener.accept().unwrap().0;
+      let mut buf = [0; 1024];
+      let amount = stream.read(&mut buf).unwrap();
+      let request = String::from_utf8_lossy(&buf[..amount]).to_string();
+      assert!(request.starts_with("GET /setup.xml HTTP/1.1\r\n"));
+
+      stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n<root/>").unwrap();
+    });
+
+    let body = get(address.ip(), address.port(), "/setup.xml", &[],
+        Duration::seconds(1)).unwrap();
+
+    assert_eq!("<root/>", body);
+  }
+
+  #[cfg(feature = "webhooks")]
+  #[test]
+  fn test_post() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+      let mut stream = listener.accept().unwrap().0;
+      let mut buf = [0; 1024];
+      let amount = stream.read(&mut buf).unwrap();
+      let request = String::from_utf8_lossy(&buf[..amount]).to_string();
+      assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+      assert!(request.ends_with("\r\n\r\n{}"));
+
+      stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+    });
+
+    let status = post(address, "example.com", "/hook", &[], "{}",
+        Duration::seconds(1)).unwrap();
+
+    assert_eq!(204, status);
+  }
+}
@@ -0,0 +1,134 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Where devices are on the network. These keep the `url` crate out of the
+//! public API, so depending on wemo doesn't pin a version of it.
+
+use error::WemoError;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use url_crate::{Host, Url};
+
+/// The IP address and port a device answers HTTP requests on.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct DeviceLocation {
+  pub ip_address: IpAddr,
+  pub port: u16,
+}
+
+impl DeviceLocation {
+  pub fn new(ip_address: IpAddr, port: u16) -> DeviceLocation {
+    DeviceLocation {
+      ip_address: ip_address,
+      port: port,
+    }
+  }
+
+  pub fn socket_addr(&self) -> SocketAddr {
+    SocketAddr::new(self.ip_address, self.port)
+  }
+
+  /// The usual description URL at this location, eg.
+  /// `http://192.168.1.20:49153/setup.xml`.
+  pub fn setup_url(&self) -> SetupUrl {
+    SetupUrl {
+      url: Url::parse(&format!("http://{}/setup.xml", self.socket_addr()))
+          .expect("an IP address and port form a valid URL"),
+    }
+  }
+}
+
+impl fmt::Display for DeviceLocation {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.socket_addr())
+  }
+}
+
+/// The URL of a device's `setup.xml` description, from the `LOCATION` header
+/// of its SSDP responses.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SetupUrl {
+  url: Url,
+}
+
+impl SetupUrl {
+  /// Fails with `WemoError::InvalidUrl` if `input` isn't an absolute URL.
+  pub fn parse(input: &str) -> Result<SetupUrl, WemoError> {
+    Url::parse(input)
+        .map(|url| SetupUrl { url: url })
+        .map_err(|error| WemoError::InvalidUrl {
+          reason: format!("{}: {}", error, input),
+        })
+  }
+
+  pub fn as_str(&self) -> &str {
+    self.url.as_str()
+  }
+
+  /// The IP address, if the URL has one rather than a domain name.
+  pub fn ip_address(&self) -> Option<IpAddr> {
+    match self.url.host() {
+      Some(Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+      Some(Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+      Some(Host::Domain(_)) | None => None,
+    }
+  }
+
+  /// The port, or the scheme's default if none is given, eg. 80 for `http`.
+  pub fn port(&self) -> Option<u16> {
+    self.url.port_or_known_default()
+  }
+
+  pub fn path(&self) -> &str {
+    self.url.path()
+  }
+
+  /// The device's address, if the URL has an IP address and port.
+  pub fn location(&self) -> Option<DeviceLocation> {
+    match (self.ip_address(), self.port()) {
+      (Some(ip_address), Some(port)) => {
+        Some(DeviceLocation::new(ip_address, port))
+      },
+      _ => None,
+    }
+  }
+}
+
+impl FromStr for SetupUrl {
+  type Err = WemoError;
+
+  fn from_str(input: &str) -> Result<SetupUrl, WemoError> {
+    SetupUrl::parse(input)
+  }
+}
+
+impl fmt::Display for SetupUrl {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.url)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_setup_url() {
+    let url = SetupUrl::parse("http://192.168.1.20:49153/setup.xml").unwrap();
+    let location = DeviceLocation::new("192.168.1.20".parse().unwrap(), 49153);
+
+    assert_eq!(Some(location), url.location());
+    assert_eq!("/setup.xml", url.path());
+    assert_eq!(url, location.setup_url());
+
+    let url = SetupUrl::parse("http://[fe80::1]/setup.xml").unwrap();
+    assert_eq!(Some("fe80::1".parse().unwrap()), url.ip_address());
+    assert_eq!(Some(80), url.port());
+    assert_eq!("http://[fe80::1]/setup.xml",
+        url.location().unwrap().setup_url().as_str());
+
+    assert_eq!(None, SetupUrl::parse("http://wemo.local/").unwrap()
+        .location());
+    assert!(SetupUrl::parse("setup.xml").is_err());
+  }
+}
@@ -1,4 +1,14 @@
 // Copyright (c) 2015 Brandon Thomas <bt@brand.io>
 
+#[cfg(all(feature = "async", feature = "discovery"))] pub mod discovery;
+#[cfg(feature = "discovery")] pub mod notify;
+#[cfg(feature = "discovery")] pub mod search;
+pub mod buffers;
+pub mod cell;
+pub mod http;
+pub mod location;
+pub mod ports;
+pub mod range;
 pub mod soap;
 pub mod ssdp;
+pub mod warm;
@@ -0,0 +1,175 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use device::SerialNumber;
+use error::WemoError;
+use net::buffers;
+use net::ssdp::{SsdpResponse, UPNP_PORT, parse_search_result};
+use regex::Regex;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use threads;
+
+/// How often the listener thread wakes to check whether it was stopped.
+const STOP_CHECK_MS: u64 = 200;
+
+/// Unsolicited SSDP announcements that devices multicast when they join or
+/// leave the network.
+#[derive(Clone, Debug)]
+pub enum SsdpNotification {
+  /// `ssdp:alive`: the device is on the network at this location. Sent at
+  /// startup, periodically, and after the device's IP address changes.
+  Alive(SsdpResponse),
+
+  /// `ssdp:byebye`: the device is leaving the network.
+  ByeBye { serial_number: SerialNumber },
+}
+
+/// Passively listens for SSDP `NOTIFY` announcements from WeMo devices on a
+/// background thread. Stops listening when dropped.
+pub struct NotifyListener {
+  stopped: Arc<AtomicBool>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl NotifyListener {
+  /// Join the SSDP multicast group and invoke the callback with each WeMo
+  /// announcement. Fails if the SSDP port (1900) is held exclusively by
+  /// another process.
+  pub fn start<F>(callback: F) -> Result<NotifyListener, WemoError>
+      where F: Fn(SsdpNotification) + Send + 'static {
+    let bind_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+        UPNP_PORT);
+
+    let socket = UdpSocket::bind(bind_address)?;
+    socket.join_multicast_v4(&Ipv4Addr::new(239, 255, 255, 250),
+        &Ipv4Addr::new(0, 0, 0, 0))?;
+    socket.set_read_timeout(Some(Duration::from_millis(STOP_CHECK_MS)))?;
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stop = stopped.clone();
+
+    let handle = threads::spawn("wemo-notify", move || {
+      let mut buf = buffers::take(8 * 1024);
+
+      while !stop.load(Ordering::SeqCst) {
+        let amount = match socket.recv_from(&mut buf) {
+          Err(_) => { continue; }, // Read timeout.
+          Ok((amount, _)) => { amount },
+        };
+
+        let message = String::from_utf8_lossy(&buf[..amount]);
+        if let Some(notification) = parse_notification(&message) {
+          callback(notification);
+        }
+      }
+    });
+
+    Ok(NotifyListener {
+      stopped: stopped,
+      handle: Some(handle),
+    })
+  }
+
+  /// Stop listening.
+  pub fn stop(&mut self) {
+    self.stopped.store(true, Ordering::SeqCst);
+    if let Some(handle) = self.handle.take() {
+      let _r = handle.join();
+    }
+  }
+}
+
+impl Drop for NotifyListener {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
+
+/// Parse a WeMo SSDP `NOTIFY` message. Other messages, including search
+/// requests and announcements from non-WeMo devices, are ignored.
+fn parse_notification(message: &str) -> Option<SsdpNotification> {
+  lazy_static! {
+    static ref NTS_REGEX: Regex =
+        Regex::new(r"(?im:^NTS:\s*(\S*)\s*$)").unwrap();
+    static ref SERIAL_REGEX: Regex = Regex::new(
+        r"(?im:^USN:\s*uuid:(Lightswitch|Insight|Socket)-\d_\d-([^:\s]*))")
+            .unwrap();
+  }
+
+  if !message.starts_with("NOTIFY") {
+    return None;
+  }
+
+  let nts = NTS_REGEX.captures(message).and_then(|cap| cap.at(1))?;
+
+  match nts {
+    "ssdp:alive" => parse_search_result(message).map(SsdpNotification::Alive),
+    "ssdp:byebye" => {
+      SERIAL_REGEX.captures(message)
+          .and_then(|cap| cap.at(2))
+          .map(|serial| {
+            SsdpNotification::ByeBye { serial_number: serial.to_string() }
+          })
+    },
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_alive() {
+    let message = "NOTIFY * HTTP/1.1\r\n\
+        HOST: 239.255.255.250:1900\r\n\
+        CACHE-CONTROL: max-age=86400\r\n\
+        LOCATION: http://192.168.1.20:49154/setup.xml\r\n\
+        NT: upnp:rootdevice\r\n\
+        NTS: ssdp:alive\r\n\
+        USN: uuid:Socket-1_0-221517K0101769::upnp:rootdevice\r\n\
+        \r\n";
+
+    match parse_notification(message) {
+      Some(SsdpNotification::Alive(response)) => {
+        assert_eq!("221517K0101769", response.serial_number);
+        assert_eq!("uuid:Socket-1_0-221517K0101769", response.udn);
+        assert_eq!("192.168.1.20".parse::<IpAddr>().unwrap(),
+            response.ip_address);
+        assert_eq!(49154, response.port);
+      },
+      _ => panic!("Expected ssdp:alive"),
+    }
+  }
+
+  #[test]
+  fn test_parse_byebye() {
+    let message = "NOTIFY * HTTP/1.1\r\n\
+        HOST: 239.255.255.250:1900\r\n\
+        NT: upnp:rootdevice\r\n\
+        NTS: ssdp:byebye\r\n\
+        USN: uuid:Insight-1_0-12345ABCDE::upnp:rootdevice\r\n\
+        \r\n";
+
+    match parse_notification(message) {
+      Some(SsdpNotification::ByeBye { serial_number }) => {
+        assert_eq!("12345ABCDE", serial_number);
+      },
+      _ => panic!("Expected ssdp:byebye"),
+    }
+  }
+
+  #[test]
+  fn test_parse_ignores_searches() {
+    let message = "M-SEARCH * HTTP/1.1\r\n\
+        HOST: 239.255.255.250:1900\r\n\
+        ST:urn:Belkin:device:*\r\n\
+        MAN:\"ssdp:discover\"\r\n\
+        \r\n";
+
+    assert!(parse_notification(message).is_none());
+  }
+}
@@ -0,0 +1,117 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use config::DEFAULT_PORTS;
+
+/// Knowledge of which ports a WeMo device might be listening on. Devices
+/// change ports occasionally, usually by incrementing the port number, so we
+/// track the last port that worked along with a list of candidates to fall
+/// back on.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DevicePorts {
+  last_known: Option<u16>,
+  candidates: Vec<u16>,
+}
+
+impl DevicePorts {
+  /// CTOR. The candidates are tried in order when the port isn't known.
+  pub fn new(last_known: Option<u16>, candidates: Vec<u16>) -> DevicePorts {
+    DevicePorts {
+      last_known: last_known,
+      candidates: candidates,
+    }
+  }
+
+  /// The last port the device was known to be using, if any.
+  pub fn last_known(&self) -> Option<u16> {
+    self.last_known
+  }
+
+  /// Record the port the device was found on.
+  pub fn set_last_known(&mut self, port: Option<u16>) {
+    self.last_known = port;
+  }
+
+  /// The ports tried when the port isn't known.
+  pub fn candidates(&self) -> &[u16] {
+    &self.candidates
+  }
+
+  /// Replace the ports tried when the port isn't known.
+  pub fn set_candidates(&mut self, candidates: Vec<u16>) {
+    self.candidates = candidates;
+  }
+
+  /// The port to use for the next request: the last known port, otherwise
+  /// the first candidate.
+  pub fn preferred(&self) -> u16 {
+    self.last_known
+        .or_else(|| self.candidates.get(0).cloned())
+        .unwrap_or(DEFAULT_PORTS[0])
+  }
+
+  /// The order to probe ports in when looking for the device: the last known
+  /// port, then the candidates above it (since devices tend to increment
+  /// their port), then the remaining candidates. There are no duplicates.
+  pub fn probe_order(&self) -> Vec<u16> {
+    let mut order = Vec::with_capacity(self.candidates.len() + 1);
+
+    if let Some(last_known) = self.last_known {
+      order.push(last_known);
+
+      let mut above = self.candidates.iter()
+          .cloned()
+          .filter(|port| *port > last_known)
+          .collect::<Vec<_>>();
+
+      above.sort();
+      order.extend(above);
+    }
+
+    for port in self.candidates.iter() {
+      if !order.contains(port) {
+        order.push(*port);
+      }
+    }
+
+    if order.is_empty() {
+      order.push(DEFAULT_PORTS[0]);
+    }
+
+    order
+  }
+}
+
+impl Default for DevicePorts {
+  fn default() -> DevicePorts {
+    DevicePorts::new(None, DEFAULT_PORTS.to_vec())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_preferred() {
+    let ports = DevicePorts::new(None, vec![49153, 49152]);
+    assert_eq!(49153, ports.preferred());
+
+    let ports = DevicePorts::new(Some(49155), vec![49153, 49152]);
+    assert_eq!(49155, ports.preferred());
+
+    let ports = DevicePorts::new(None, Vec::new());
+    assert_eq!(DEFAULT_PORTS[0], ports.preferred());
+  }
+
+  #[test]
+  fn test_probe_order() {
+    let ports = DevicePorts::new(None, vec![49153, 49152, 49154, 49155]);
+    assert_eq!(vec![49153, 49152, 49154, 49155], ports.probe_order());
+
+    let ports = DevicePorts::new(Some(49153), vec![49155, 49152, 49154, 49153]);
+    assert_eq!(vec![49153, 49154, 49155, 49152], ports.probe_order());
+
+    let ports = DevicePorts::new(Some(1234), vec![49153]);
+    assert_eq!(vec![1234, 49153], ports.probe_order());
+  }
+}
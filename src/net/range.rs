@@ -0,0 +1,173 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+use error::WemoError;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+/// A range of IPv4 addresses to search for devices, eg. an IoT VLAN that
+/// multicast doesn't reach. Parses from a single address (`192.168.20.7`), a
+/// subnet (`192.168.20.0/24`), or an inclusive span
+/// (`192.168.20.10-192.168.20.50`). A subnet's network and broadcast
+/// addresses aren't included. A range holds at most `MAX_ADDRESSES`
+/// addresses, eg. a /20, since each search sends a datagram to every one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ipv4Range {
+  first: Ipv4Addr,
+  last: Ipv4Addr,
+}
+
+impl Ipv4Range {
+  /// The most addresses a range holds.
+  pub const MAX_ADDRESSES: u32 = 4096;
+
+  /// The addresses from `first` through `last`, inclusive, but no more than
+  /// `MAX_ADDRESSES` of them from the lower one.
+  pub fn new(first: Ipv4Addr, last: Ipv4Addr) -> Ipv4Range {
+    let (first, last) = if u32::from(first) <= u32::from(last) {
+      (u32::from(first), u32::from(last))
+    } else {
+      (u32::from(last), u32::from(first))
+    };
+
+    let last = last.min(first.saturating_add(Ipv4Range::MAX_ADDRESSES - 1));
+    Ipv4Range { first: Ipv4Addr::from(first), last: Ipv4Addr::from(last) }
+  }
+
+  /// The usable host addresses of the subnet, eg. `192.168.20.1` through
+  /// `192.168.20.254` for `192.168.20.0/24`. Fails for subnets with more
+  /// than `MAX_ADDRESSES` of them.
+  pub fn subnet(network: Ipv4Addr, prefix_len: u8)
+      -> Result<Ipv4Range, WemoError> {
+    if prefix_len > 32 {
+      return Err(range_error(&format!("{}/{}", network, prefix_len)));
+    }
+
+    let mask = if prefix_len == 0 { 0 } else { !0u32 << (32 - prefix_len) };
+    let network = u32::from(network) & mask;
+    let broadcast = network | !mask;
+
+    // Point-to-point subnets have no network or broadcast address.
+    let (first, last) = if prefix_len >= 31 {
+      (network, broadcast)
+    } else {
+      (network + 1, broadcast - 1)
+    };
+
+    checked(Ipv4Addr::from(first), Ipv4Addr::from(last),
+        &format!("{}/{}", Ipv4Addr::from(network), prefix_len))
+  }
+
+  pub fn first(&self) -> Ipv4Addr {
+    self.first
+  }
+
+  pub fn last(&self) -> Ipv4Addr {
+    self.last
+  }
+
+  pub fn contains(&self, ip: Ipv4Addr) -> bool {
+    let ip = u32::from(ip);
+    ip >= u32::from(self.first) && ip <= u32::from(self.last)
+  }
+
+  /// Every address in the range, in order.
+  pub fn addresses(&self) -> Vec<Ipv4Addr> {
+    (u32::from(self.first)..=u32::from(self.last))
+        .map(Ipv4Addr::from)
+        .collect()
+  }
+}
+
+impl FromStr for Ipv4Range {
+  type Err = WemoError;
+
+  fn from_str(range: &str) -> Result<Ipv4Range, WemoError> {
+    let range = range.trim();
+    let address = |ip: &str| {
+      Ipv4Addr::from_str(ip.trim()).map_err(|_| range_error(range))
+    };
+
+    if let Some(slash) = range.find('/') {
+      let prefix_len = range[slash + 1..].trim().parse::<u8>()
+          .map_err(|_| range_error(range))?;
+      return Ipv4Range::subnet(address(&range[..slash])?, prefix_len);
+    }
+
+    if let Some(dash) = range.find('-') {
+      return checked(address(&range[..dash])?, address(&range[dash + 1..])?,
+          range);
+    }
+
+    let ip = address(range)?;
+    Ok(Ipv4Range::new(ip, ip))
+  }
+}
+
+impl fmt::Display for Ipv4Range {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.first == self.last {
+      write!(f, "{}", self.first)
+    } else {
+      write!(f, "{}-{}", self.first, self.last)
+    }
+  }
+}
+
+/// The range from `first` through `last`, unless it's too big to search.
+fn checked(first: Ipv4Addr, last: Ipv4Addr, range: &str)
+    -> Result<Ipv4Range, WemoError> {
+  let (low, high) = (u32::from(first).min(u32::from(last)),
+      u32::from(first).max(u32::from(last)));
+  if high - low >= Ipv4Range::MAX_ADDRESSES {
+    return Err(WemoError::ConfigError {
+      reason: format!("IP range '{}' has more than {} addresses", range,
+          Ipv4Range::MAX_ADDRESSES),
+    });
+  }
+  Ok(Ipv4Range::new(first, last))
+}
+
+fn range_error(range: &str) -> WemoError {
+  WemoError::ConfigError {
+    reason: format!("invalid IP range '{}'", range),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let range = Ipv4Range::from_str("192.168.20.0/24").unwrap();
+    assert_eq!(Ipv4Addr::new(192, 168, 20, 1), range.first());
+    assert_eq!(Ipv4Addr::new(192, 168, 20, 254), range.last());
+    assert_eq!(254, range.addresses().len());
+    assert!(range.contains(Ipv4Addr::new(192, 168, 20, 7)));
+    assert!(!range.contains(Ipv4Addr::new(192, 168, 21, 7)));
+
+    let range = Ipv4Range::from_str("192.168.20.10 - 192.168.20.12").unwrap();
+    assert_eq!(vec![Ipv4Addr::new(192, 168, 20, 10),
+        Ipv4Addr::new(192, 168, 20, 11), Ipv4Addr::new(192, 168, 20, 12)],
+        range.addresses());
+    assert_eq!("192.168.20.10-192.168.20.12", range.to_string());
+
+    let range = Ipv4Range::from_str("10.0.0.7").unwrap();
+    assert_eq!(vec![Ipv4Addr::new(10, 0, 0, 7)], range.addresses());
+    assert_eq!("10.0.0.7",
+        Ipv4Range::from_str("10.0.0.7/32").unwrap().to_string());
+
+    assert_eq!(4094, Ipv4Range::from_str("10.0.0.0/20").unwrap()
+        .addresses().len());
+    assert!(Ipv4Range::from_str("10.0.0.0/16").is_err());
+    assert!(Ipv4Range::from_str("10.0.0.0-10.0.255.255").is_err());
+    assert_eq!(Ipv4Addr::new(10, 0, 15, 255),
+        Ipv4Range::new(Ipv4Addr::new(10, 0, 0, 0),
+            Ipv4Addr::new(10, 0, 255, 255)).last());
+
+    assert!(Ipv4Range::from_str("10.0.0.0/33").is_err());
+    assert!(Ipv4Range::from_str("10.0.0").is_err());
+    assert!(Ipv4Range::from_str("10.0.0.1-x").is_err());
+  }
+}
@@ -0,0 +1,767 @@
+// Copyright (c) 2015-2016 Brandon Thomas <bt@brand.io>
+
+//! Searching for devices with SSDP. Needs the `discovery` feature; without
+//! it, switches are only reachable at statically configured addresses.
+
+use mio::{EventLoop, Handler, EventSet, PollOpt, Timeout, Token};
+use mio::udp::UdpSocket;
+
+use time::{Duration, PreciseTime};
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str;
+
+use bulk::MAX_CONCURRENT_REQUESTS;
+use cancel::CancellationToken;
+use config::{ParsingMode, WemoConfig, global_config};
+use device::{SerialNumber, Udn};
+use error::WemoError;
+#[cfg(feature = "async")] use net::discovery::DiscoveryStream;
+use net::buffers::{self, PooledBuffer};
+use net::http;
+use net::ssdp::{SsdpResponse, parse_search_result};
+use net::warm::WarmSearch;
+use threads;
+use xml::find_tag_value;
+
+/// Within a given search request, resend SSDP search requests
+/// every n millisec (until search request timeout).
+const RESEND_SSDP_MS: u64 = 300;
+
+/// Default maximum size of an SSDP response datagram. WeMo responses are well
+/// under a kilobyte; anything longer than this is truncated.
+const DEFAULT_MAX_DATAGRAM_SIZE: usize = 8 * 1024;
+
+const LISTENER: Token = Token(0);
+const SENDER: Token = Token(1);
+const TIMER_RESEND_SSDP: Token = Token(3);
+const TIMER_TIMEOUT: Token = Token(4);
+
+/// Uses UPNP SSDP to discover WeMo devices on the local network.
+pub struct DeviceSearch {
+  /// All of the found devices. Persisted between search requests.
+  found_devices: HashMap<SerialNumber, SsdpResponse>,
+
+  /// If present, search will end as soon as the device is found.
+  target_serial: Option<SerialNumber>,
+
+  /// If present, search will end as soon as the device is found.
+  target_ip_address: Option<IpAddr>,
+
+  /// If present, search will end as soon as the device is found.
+  target_udn: Option<Udn>,
+
+  /// If set, search will end as soon as any device is found.
+  target_any: bool,
+
+  /// Socket for SSDP search. Reused, along with its bound port, by every
+  /// search.
+  socket: UdpSocket,
+
+  /// NB: mio ties a socket to the first event loop it is registered with, so
+  /// the loop is kept for as long as the socket.
+  event_loop: Option<EventLoop<DeviceSearch>>,
+  registered: bool,
+
+  /// The pending resend, cleared when a search ends so it can't fire during
+  /// the next one.
+  resend_timer: Option<Timeout>,
+
+  /// When the search driven by `poll_results` began and how long it lasts.
+  /// Only set between `start` and `finish`.
+  poll_search: Option<(PreciseTime, Duration)>,
+
+  /// When the search request was last sent by `poll_results`.
+  last_sent: Option<PreciseTime>,
+
+  /// Whether the current search's target device has been found.
+  target_found: bool,
+
+  /// Whether the current search has been sent to `search_ranges`. Unlike
+  /// the multicast search, that isn't resent.
+  swept_ranges: bool,
+
+  /// Reused for every datagram received. Its length is the maximum datagram
+  /// size.
+  recv_buffer: PooledBuffer,
+
+  /// If present, invoked with each device the first time it is found.
+  on_found: Option<Box<FnMut(&SsdpResponse) + Send>>,
+
+  /// When cancelled, the search in progress ends at the next resend.
+  cancellation: CancellationToken,
+
+  /// Defaults, eg. the search timeout.
+  config: WemoConfig,
+}
+
+/// How the devices found changed between two searches. Each list is ordered
+/// by serial number.
+#[derive(Clone, Debug, Default)]
+pub struct SearchDiff {
+  /// Devices only found by the current search.
+  pub added: Vec<SsdpResponse>,
+  /// Devices only found by the previous search.
+  pub removed: Vec<SsdpResponse>,
+  /// Devices found at a new IP or port, as `(previous, current)`.
+  pub relocated: Vec<(SsdpResponse, SsdpResponse)>,
+}
+
+impl SearchDiff {
+  /// Whether nothing changed.
+  pub fn is_empty(&self) -> bool {
+    self.added.is_empty() && self.removed.is_empty()
+        && self.relocated.is_empty()
+  }
+}
+
+/// What a device's `setup.xml` says it is.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeviceModel {
+  /// eg. `Socket`, `Insight`, or `Lightswitch`.
+  pub name: String,
+  /// The model number, as reported. Most firmware reports a revision, eg.
+  /// `1.0`, and some the part number, eg. `F7C027`.
+  pub number: Option<String>,
+}
+
+impl DeviceModel {
+  pub fn parse(xml: &str) -> Result<DeviceModel, WemoError> {
+    let field = |name: &str| {
+      find_tag_value(name, xml)
+          .map(|value| value.trim().to_string())
+          .filter(|value| !value.is_empty())
+    };
+
+    Ok(DeviceModel {
+      name: field("modelName").ok_or(WemoError::ParsingError)?,
+      number: field("modelNumber"),
+    })
+  }
+
+  /// Whether `model` is the model's name or number, ignoring case.
+  pub fn matches(&self, model: &str) -> bool {
+    self.name.eq_ignore_ascii_case(model)
+        || self.number.iter().any(|number| number.eq_ignore_ascii_case(model))
+  }
+}
+
+impl DeviceSearch {
+
+  /// DeviceSearch CTOR.
+  pub fn new() -> DeviceSearch {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+    let udp_socket = UdpSocket::v4().unwrap();
+
+    udp_socket.bind(&socket).unwrap();
+
+    DeviceSearch {
+      found_devices: HashMap::new(),
+      target_serial: None,
+      target_ip_address: None,
+      target_udn: None,
+      target_any: false,
+      socket: udp_socket,
+      event_loop: None,
+      registered: false,
+      resend_timer: None,
+      poll_search: None,
+      last_sent: None,
+      target_found: false,
+      swept_ranges: false,
+      recv_buffer: buffers::take(DEFAULT_MAX_DATAGRAM_SIZE),
+      on_found: None,
+      cancellation: CancellationToken::new(),
+      config: global_config(),
+    }
+  }
+
+  /// Use these settings instead of the global `WemoConfig`.
+  pub fn with_config(mut self, config: WemoConfig) -> DeviceSearch {
+    self.config = config;
+    self
+  }
+
+  /// Set the maximum size of SSDP response datagrams. Longer responses are
+  /// truncated.
+  pub fn with_max_datagram_size(mut self, max_datagram_size: usize)
+      -> DeviceSearch {
+    self.recv_buffer.resize(max_datagram_size, 0);
+    self
+  }
+
+  /// Search for all devices on the network. A `DeviceSearch` can be reused
+  /// for any number of searches, which saves setting up a new socket each
+  /// time.
+  pub fn search(&mut self, timeout_ms: u64)
+      -> &HashMap<SerialNumber, SsdpResponse> {
+    //println!("search");
+    self.target_found = false;
+    self.swept_ranges = false;
+
+    let mut event_loop = match self.event_loop.take() {
+      Some(event_loop) => { event_loop },
+      None => { EventLoop::new().unwrap() },
+    };
+
+    if self.registered {
+      event_loop.reregister(&self.socket, SENDER, EventSet::writable(),
+                            PollOpt::edge()).unwrap();
+    } else {
+      event_loop.register(&self.socket, SENDER, EventSet::writable(),
+                          PollOpt::edge()).unwrap();
+      self.registered = true;
+    }
+
+    self.resend_timer =
+        event_loop.timeout_ms(TIMER_RESEND_SSDP, RESEND_SSDP_MS).ok();
+    let timeout = event_loop.timeout_ms(TIMER_TIMEOUT, timeout_ms).unwrap();
+
+    event_loop.run(self).unwrap();
+
+    // Don't let this search's timers fire during the next one.
+    event_loop.clear_timeout(timeout);
+    if let Some(resend_timer) = self.resend_timer.take() {
+      event_loop.clear_timeout(resend_timer);
+    }
+    self.event_loop = Some(event_loop);
+
+    &self.found_devices
+  }
+
+  /// Search for all devices on the network for the configured default
+  /// timeout.
+  pub fn search_default(&mut self) -> &HashMap<SerialNumber, SsdpResponse> {
+    let timeout_ms = self.config.default_timeout.num_milliseconds() as u64;
+    self.search(timeout_ms)
+  }
+
+  /// Search for all devices on the network, invoking the callback as each
+  /// new device is found rather than waiting for the search to finish.
+  pub fn search_with<F>(&mut self, timeout_ms: u64, on_found: F)
+      -> &HashMap<SerialNumber, SsdpResponse>
+      where F: FnMut(&SsdpResponse) + Send + 'static {
+    self.on_found = Some(Box::new(on_found));
+    self.search(timeout_ms);
+    self.on_found = None;
+    &self.found_devices
+  }
+
+  /// Search for devices in the background, yielding them as a `Stream` as
+  /// they are found.
+  #[cfg(feature = "async")]
+  pub fn into_stream(self, timeout_ms: u64) -> DiscoveryStream {
+    DiscoveryStream::from_search(self, timeout_ms)
+  }
+
+  /// Search in the background, returning `cached` results, eg. from
+  /// `load_search_results`, until fresh ones arrive.
+  pub fn warm_start(self, cached: HashMap<SerialNumber, SsdpResponse>,
+                    timeout_ms: u64) -> WarmSearch {
+    WarmSearch::start(self, cached, timeout_ms)
+  }
+
+  /// End searches early, returning whatever was found so far, when the token
+  /// is cancelled.
+  pub fn with_cancellation(mut self, cancellation: CancellationToken)
+      -> DeviceSearch {
+    self.cancellation = cancellation;
+    self
+  }
+
+  /// The token that ends searches early when cancelled.
+  pub fn cancellation(&self) -> &CancellationToken {
+    &self.cancellation
+  }
+
+  /// Search for a particular device by serial number.
+  /// Exits early when the target device is found.
+  pub fn search_for_serial(&mut self, target: &SerialNumber, timeout_ms: u64)
+      -> Option<&SsdpResponse> {
+    self.target_serial = Some(target.to_string());
+    self.search(timeout_ms);
+    self.found_devices.get(target)
+  }
+
+  /// Search for a particular device by IP address.
+  /// Exits early when the target device is found.
+  pub fn search_for_ip(&mut self, target: &IpAddr, timeout_ms: u64)
+      -> Option<&SsdpResponse> {
+    self.target_ip_address = Some(target.clone());
+    self.search(timeout_ms);
+
+    for result in self.found_devices.values() {
+      if &result.ip_address == target {
+        return Some(result);
+      }
+    }
+    None
+  }
+
+  /// Search for a particular device by UDN.
+  /// Exits early when the target device is found.
+  pub fn search_for_udn(&mut self, target: &Udn, timeout_ms: u64)
+      -> Option<&SsdpResponse> {
+    self.target_udn = Some(target.to_string());
+    self.search(timeout_ms);
+
+    for result in self.found_devices.values() {
+      if &result.udn == target {
+        return Some(result);
+      }
+    }
+    None
+  }
+
+  /// Search for whichever device answers first, eg. the only one on a
+  /// device's own access point. Exits early when one is found.
+  pub fn search_for_any(&mut self, timeout_ms: u64)
+      -> Option<&SsdpResponse> {
+    self.target_any = true;
+    self.search(timeout_ms);
+    self.found_devices.values().next()
+  }
+
+  /// Begin a search without blocking, for callers driving discovery from
+  /// their own event loop. Call `poll_results` periodically until
+  /// `is_finished`, then `finish`.
+  pub fn start(&mut self, timeout_ms: u64) {
+    let now = PreciseTime::now();
+    self.target_found = false;
+    self.swept_ranges = false;
+    self.poll_search = Some((now, Duration::milliseconds(timeout_ms as i64)));
+    self.last_sent = Some(now);
+    self.send_request();
+  }
+
+  /// Read whatever responses have arrived since the last poll without
+  /// blocking, resending the search request as needed. Returns the devices
+  /// found for the first time.
+  pub fn poll_results(&mut self) -> Vec<SsdpResponse> {
+    let mut new_devices = Vec::new();
+
+    if self.poll_search.is_none() {
+      return new_devices;
+    }
+
+    self.receive_responses(&mut new_devices);
+
+    let resend_due = self.last_sent
+        .map(|sent| sent.to(PreciseTime::now())
+            >= Duration::milliseconds(RESEND_SSDP_MS as i64))
+        .unwrap_or(true);
+
+    if resend_due && !self.is_finished() {
+      self.last_sent = Some(PreciseTime::now());
+      self.send_request();
+    }
+
+    new_devices
+  }
+
+  /// Whether the search begun by `start` has timed out, been cancelled, or
+  /// found its target.
+  pub fn is_finished(&self) -> bool {
+    let timed_out = match self.poll_search {
+      None => true,
+      Some((started, timeout)) => started.to(PreciseTime::now()) >= timeout,
+    };
+
+    timed_out || self.target_found || self.cancellation.is_cancelled()
+  }
+
+  /// End the search begun by `start`, collecting any last responses.
+  pub fn finish(&mut self) -> &HashMap<SerialNumber, SsdpResponse> {
+    if self.poll_search.is_some() {
+      self.receive_responses(&mut Vec::new());
+    }
+
+    self.poll_search = None;
+    self.last_sent = None;
+    &self.found_devices
+  }
+
+  /// Whether search results were found.
+  pub fn has_results(&self) -> bool {
+    self.found_devices.len() != 0
+  }
+
+  /// Get the results.
+  pub fn get_results(&self) -> &HashMap<SerialNumber, SsdpResponse> {
+    &self.found_devices
+  }
+
+  /// Reset the search results and search target, if set.
+  pub fn reset(&mut self) {
+    self.found_devices = HashMap::new();
+    self.target_serial = None;
+    self.target_ip_address = None;
+    self.target_udn = None;
+    self.target_any = false;
+    self.target_found = false;
+  }
+
+  /// Compare the results of two searches, eg. a `get_results` kept from the
+  /// last scan and the one just finished.
+  pub fn diff(previous: &HashMap<SerialNumber, SsdpResponse>,
+              current: &HashMap<SerialNumber, SsdpResponse>) -> SearchDiff {
+    let mut diff = SearchDiff::default();
+
+    for (serial_number, found) in current.iter() {
+      match previous.get(serial_number) {
+        None => diff.added.push(found.clone()),
+        Some(before) if before.location() != found.location() => {
+          diff.relocated.push((before.clone(), found.clone()));
+        },
+        Some(_) => {}, // Unchanged.
+      }
+    }
+
+    diff.removed = previous.iter()
+        .filter(|&(serial_number, _)| !current.contains_key(serial_number))
+        .map(|(_, before)| before.clone())
+        .collect();
+
+    diff.added.sort_by(|a, b| a.serial_number.cmp(&b.serial_number));
+    diff.removed.sort_by(|a, b| a.serial_number.cmp(&b.serial_number));
+    diff.relocated.sort_by(|a, b| a.0.serial_number.cmp(&b.0.serial_number));
+    diff
+  }
+
+  /// The devices found so far whose model name or number is `model`, eg.
+  /// `Insight` or `F7C027`. The devices' `setup.xml` are fetched
+  /// concurrently, like `bulk::get_states`; devices that don't answer within
+  /// `timeout` are left out.
+  pub fn filter_by_model(&self, model: &str, timeout: Duration)
+      -> HashMap<SerialNumber, SsdpResponse> {
+    let headers = self.config.request_headers();
+    let headers = &headers;
+
+    let found = self.found_devices.iter().collect::<Vec<_>>();
+    let models = threads::map_bounded(&found, MAX_CONCURRENT_REQUESTS,
+        |&(_, response)| {
+          http::get(response.ip_address, response.port,
+              response.setup_url.path(), headers, timeout)
+              .and_then(|xml| DeviceModel::parse(&xml))
+        });
+
+    found.into_iter()
+        .zip(models)
+        .filter_map(|((serial_number, response), model_result)| {
+          match model_result {
+            Some(Ok(ref found)) if found.matches(model) => {
+              Some((serial_number.clone(), response.clone()))
+            },
+            Some(Ok(_)) => None,
+            Some(Err(error)) => {
+              debug!(target: "wemo", serial = serial_number.as_str();
+                  "Couldn't read model of {}: {:?}", serial_number, error);
+              None
+            },
+            None => None, // Panicked.
+          }
+        })
+        .collect()
+  }
+
+  /// Send SSDP search command.
+  fn write_request(&mut self, event_loop: &mut EventLoop<DeviceSearch>) {
+    self.send_request();
+
+    event_loop.reregister(&self.socket, LISTENER, EventSet::readable(),
+                          PollOpt::edge()).unwrap();
+  }
+
+  /// Send the M-SEARCH datagram, multicast and, once per search, to each
+  /// address in the configured search ranges.
+  fn send_request(&mut self) {
+    let ssdp_address = self.config.ssdp_address;
+
+    // "ST:upnp:rootdevice\r\n" // All SSDP/UPNP hardware.
+    // "ST:urn:Belkin:device:lightswitch:1\r\n" // Lightswitch.
+
+    let header = format!("\
+        M-SEARCH * HTTP/1.1\r\n\
+        HOST: {}:{}\r\n\
+        ST:urn:Belkin:device:*\r\n\
+        MAN:\"ssdp:discover\"\r\n\
+        MX:5\r\n\
+        \r\n",
+        &ssdp_address.ip(),
+        &ssdp_address.port());
+
+
+    if self.config.multicast_search {
+      self.socket.send_to(&mut header.as_bytes(), &ssdp_address)
+          .unwrap();
+    }
+
+    if self.swept_ranges {
+      return;
+    }
+    self.swept_ranges = true;
+
+    for range in self.config.search_ranges.iter() {
+      for ip in range.addresses() {
+        let target = SocketAddr::new(IpAddr::V4(ip), ssdp_address.port());
+        let _r = self.socket.send_to(header.as_bytes(), &target);
+      }
+    }
+  }
+
+  /// Read SSDP responses and add WeMo devices to the map.
+  fn read_response(&mut self, event_loop: &mut EventLoop<DeviceSearch>) {
+    self.receive_responses(&mut Vec::new());
+
+    if self.target_found {
+      event_loop.shutdown();
+    }
+  }
+
+  /// Read every datagram waiting on the socket, adding WeMo devices to the
+  /// map and the ones found for the first time to `new_devices`. Stops early
+  /// if the target device is found.
+  fn receive_responses(&mut self, new_devices: &mut Vec<SsdpResponse>) {
+    loop {
+      let amount = match self.socket.recv_from(&mut self.recv_buffer) {
+        Ok(Some((amount, _))) => { amount },
+        Ok(None) | Err(_) => { return; }, // Nothing more to read.
+      };
+
+      let datagram = &self.recv_buffer[..amount];
+      // Only lenient parsing of invalid UTF-8 copies the datagram.
+      let response_headers = match self.config.parsing_mode {
+        ParsingMode::Lenient => Some(String::from_utf8_lossy(datagram)),
+        _ => str::from_utf8(datagram).ok().map(Cow::Borrowed),
+      };
+      let parsed_response = response_headers
+          .and_then(|response_headers| parse_search_result(&response_headers));
+
+      let device = match parsed_response {
+        None => { continue; },
+        Some(device) => { device },
+      };
+
+      let serial_number = device.serial_number.clone();
+      let ip_address: IpAddr = device.ip_address.clone();
+      let found_udn = self.target_udn.as_ref() == Some(&device.udn);
+
+      if !self.found_devices.contains_key(&serial_number) {
+        if let Some(ref mut on_found) = self.on_found {
+          on_found(&device);
+        }
+        new_devices.push(device.clone());
+      }
+
+      self.found_devices.insert(serial_number.clone(), device);
+
+      if self.target_serial.is_some() {
+        let cmp: &str = serial_number.as_ref();
+
+        if self.target_serial.as_ref().unwrap() == cmp {
+          self.target_found = true;
+          return;
+        }
+      } else if self.target_ip_address.is_some() {
+        if self.target_ip_address.as_ref().unwrap() == &ip_address {
+          self.target_found = true;
+          return;
+        }
+      } else if found_udn || self.target_any {
+        self.target_found = true;
+        return;
+      }
+    }
+  }
+}
+
+impl Handler for DeviceSearch {
+  type Timeout = Token;
+  type Message = u32;
+
+  /// Handle events on the socket.
+  fn ready(&mut self, event_loop: &mut EventLoop<DeviceSearch>, _token: Token,
+           events: EventSet) {
+    if events.is_readable() {
+      self.read_response(event_loop);
+    }
+
+    if events.is_writable() {
+      self.write_request(event_loop);
+    }
+  }
+
+  /// Manages timeouts: reenqueuing search and overall search timeout.
+  fn timeout(&mut self, event_loop: &mut EventLoop<DeviceSearch>,
+             token: Token) {
+    match token {
+      TIMER_TIMEOUT => { event_loop.shutdown(); },
+      TIMER_RESEND_SSDP => {
+        if self.cancellation.is_cancelled() {
+          event_loop.shutdown();
+          return;
+        }
+
+        // Resend the SSDP search request every `RESEND_SSDP_MS` as long
+        // as we're still searching (eg. TIMER_TIMEOUT not called).
+        event_loop.reregister(&self.socket, SENDER, EventSet::writable(),
+                          PollOpt::edge()).unwrap();
+        self.resend_timer =
+            event_loop.timeout_ms(TIMER_RESEND_SSDP, RESEND_SSDP_MS).ok();
+      },
+      _ => {},
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use testing::{FakeDevice, timeout};
+
+  #[test]
+  fn test_search_reuses_socket() {
+    let mut search = DeviceSearch::new();
+    let port = search.socket.local_addr().unwrap().port();
+
+    search.search(50);
+    search.search(50);
+
+    assert_eq!(port, search.socket.local_addr().unwrap().port());
+  }
+
+  #[test]
+  fn test_poll_search() {
+    let mut search = DeviceSearch::new();
+    assert!(search.is_finished());
+
+    search.start(50);
+    assert!(!search.is_finished());
+
+    while !search.is_finished() {
+      let _new_devices = search.poll_results();
+    }
+
+    let _results = search.finish();
+    assert!(search.poll_results().is_empty());
+  }
+
+  #[test]
+  fn test_diff() {
+    let response = |serial_number: &str, port: u16| {
+      parse_search_result(&format!("HTTP/1.1 200 OK\r\n\
+          LOCATION: http://192.168.1.20:{}/setup.xml\r\n\
+          USN: uuid:Socket-1_0-{}::upnp:rootdevice\r\n\
+          \r\n", port, serial_number)).unwrap()
+    };
+    let results = |responses: Vec<SsdpResponse>| {
+      responses.into_iter()
+          .map(|response| (response.serial_number.clone(), response))
+          .collect::<HashMap<_, _>>()
+    };
+
+    let previous = results(vec![response("A", 49153), response("B", 49153),
+        response("C", 49153)]);
+    let current = results(vec![response("A", 49153), response("B", 49154),
+        response("D", 49153)]);
+
+    let diff = DeviceSearch::diff(&previous, &current);
+    assert_eq!(vec![response("D", 49153)], diff.added);
+    assert_eq!(vec![response("C", 49153)], diff.removed);
+    assert_eq!(1, diff.relocated.len());
+    assert_eq!(49153, diff.relocated[0].0.port);
+    assert_eq!(49154, diff.relocated[0].1.port);
+
+    assert!(DeviceSearch::diff(&current, &current).is_empty());
+  }
+
+  #[test]
+  fn test_device_model() {
+    let model = DeviceModel::parse("<device>\
+        <modelName>Insight</modelName>\
+        <modelNumber>F7C029</modelNumber>\
+        </device>").unwrap();
+    assert_eq!("Insight", model.name);
+    assert!(model.matches("insight"));
+    assert!(model.matches("F7C029"));
+    assert!(!model.matches("Socket"));
+
+    assert!(DeviceModel::parse("<device></device>").is_err());
+  }
+
+  #[test]
+  fn test_search_finds_device() {
+    let device = FakeDevice::start_unique().unwrap();
+    let mut search = DeviceSearch::new().with_config(device.config());
+
+    let serial_number = device.serial_number().to_string();
+    let result = search.search_for_serial(&serial_number, 2000)
+        .cloned()
+        .unwrap();
+
+    assert_eq!(device.udn(), result.udn);
+    assert_eq!(device.http_address().port(), result.port);
+    assert_eq!(Some("1.0".to_string()),
+        result.server.and_then(|server| server.upnp_version));
+  }
+
+  #[test]
+  fn test_filter_by_model() {
+    let device = FakeDevice::start_unique().unwrap();
+    let mut search = DeviceSearch::new().with_config(device.config());
+    let serial_number = device.serial_number().to_string();
+    search.search_for_serial(&serial_number, 2000).unwrap();
+
+    let sockets = search.filter_by_model("Socket", timeout());
+    assert!(sockets.contains_key(device.serial_number()));
+    assert!(search.filter_by_model("Insight", timeout()).is_empty());
+  }
+
+  #[test]
+  fn test_unicast_search() {
+    let device = FakeDevice::start_unique().unwrap();
+    let mut config = device.config();
+    config.multicast_search = false;
+    config.search_ranges = vec!["127.0.0.1".parse().unwrap()];
+    // The search address is only used for its port.
+    config.ssdp_address = SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)),
+        device.ssdp_address().port());
+
+    let mut search = DeviceSearch::new().with_config(config);
+    let serial_number = device.serial_number().to_string();
+    let result = search.search_for_serial(&serial_number, 2000)
+        .cloned()
+        .unwrap();
+    assert_eq!(device.http_address().port(), result.port);
+  }
+
+  #[test]
+  fn test_unicast_search_sent_once() {
+    let listener = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    listener.set_read_timeout(Some(::std::time::Duration::from_millis(50)))
+        .unwrap();
+
+    let config = WemoConfig {
+      multicast_search: false,
+      search_ranges: vec!["127.0.0.1".parse().unwrap()],
+      ssdp_address: SocketAddr::new(
+          IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)),
+          listener.local_addr().unwrap().port()),
+      ..WemoConfig::default()
+    };
+
+    // Long enough for several resends.
+    let mut search = DeviceSearch::new().with_config(config);
+    search.search(1000);
+
+    let mut buf = [0; 1024];
+    let mut received = 0;
+    while listener.recv_from(&mut buf).is_ok() {
+      received += 1;
+    }
+    assert_eq!(1, received);
+  }
+}
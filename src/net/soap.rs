@@ -1,12 +1,36 @@
 // Copyright (c) 2015-2016 Brandon Thomas <bt@brand.io>
 
-use mio::tcp::{Shutdown, TcpStream};
-use mio::{EventLoop, Handler, EventSet, PollOpt, Token};
-use std::io::{Read, Write};
-use std::net::{IpAddr, SocketAddr};
+use cancel::CancellationToken;
+use capture::{self, CaptureKind};
+use error::WemoError;
+use metrics;
+use net::buffers::{self, PooledBuffer};
+use net::http::{check_header, check_headers};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::str;
+use std::time::{Duration as StdDuration, Instant};
+use time::PreciseTime;
+use xml;
 
-const CLIENT: Token = Token(0);
-const TIMEOUT: Token = Token(1);
+/// How often to check for cancellation while a request is in flight.
+const CANCEL_CHECK_MS: u64 = 20;
+
+/// The standard SOAP encoding, which WeMo services expect by default.
+pub const SOAP_ENCODING: &'static str =
+    "http://schemas.xmlsoap.org/soap/encoding/";
+
+/// The fixed parts of an envelope. `SoapEnvelope::to_xml` fills in the
+/// encoding style, action, service, and arguments between them.
+const ENVELOPE_START: &'static str = "\
+    <?xml version=\"1.0\" encoding=\"utf-8\"?>\
+    <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"";
+const BODY_START: &'static str = "><s:Body><u:";
+const BODY_END: &'static str = "></s:Body></s:Envelope>";
+
+/// Write buffers that grew past this for an unusually large request aren't
+/// kept for the next one.
+const MAX_KEPT_BUFFER: usize = 16 * 1024;
 
 /// Represents a SOAP request to a WeMo device.
 #[derive(Clone)]
@@ -14,123 +38,526 @@ pub struct SoapRequest {
   pub request_path: String,
   pub soap_action: String,
   pub http_post_payload: String,
+  /// Extra HTTP headers to send, in order, after the standard ones. Ones
+  /// that can't be sent as given fail the request.
+  pub headers: Vec<(String, String)>,
+}
+
+impl SoapRequest {
+  /// SoapRequest CTOR.
+  pub fn new(request_path: &str, soap_action: &str, http_post_payload: String)
+      -> SoapRequest {
+    SoapRequest {
+      request_path: request_path.to_string(),
+      soap_action: soap_action.to_string(),
+      http_post_payload: http_post_payload,
+      headers: Vec::new(),
+    }
+  }
+
+  /// Add an extra HTTP header to the request, unless it can't be sent as
+  /// given, eg. its value holds a line break.
+  pub fn header(mut self, name: &str, value: &str)
+      -> Result<SoapRequest, WemoError> {
+    check_header(name, value)?;
+    self.headers.push((name.to_string(), value.to_string()));
+    Ok(self)
+  }
+}
+
+/// Builds the SOAP envelope for calling an action on a service, eg.
+/// `SetBinaryState` on `urn:Belkin:service:basicevent:1`.
+#[derive(Clone, Debug)]
+pub struct SoapEnvelope {
+  pub service_urn: String,
+  pub action: String,
+  /// The envelope's `encodingStyle`, if it has one.
+  pub encoding_style: Option<String>,
+  /// The action's arguments, in order, as unescaped text.
+  pub arguments: Vec<(String, String)>,
+}
+
+impl SoapEnvelope {
+  /// An envelope with no arguments in the standard SOAP encoding.
+  pub fn new(service_urn: &str, action: &str) -> SoapEnvelope {
+    SoapEnvelope {
+      service_urn: service_urn.to_string(),
+      action: action.to_string(),
+      encoding_style: Some(SOAP_ENCODING.to_string()),
+      arguments: Vec::new(),
+    }
+  }
+
+  /// Use another `encodingStyle`, or leave it out with `None`.
+  pub fn encoding_style(mut self, encoding_style: Option<&str>)
+      -> SoapEnvelope {
+    self.encoding_style = encoding_style.map(|style| style.to_string());
+    self
+  }
+
+  /// Add an argument to the action.
+  pub fn argument(mut self, name: &str, value: &str) -> SoapEnvelope {
+    self.arguments.push((name.to_string(), value.to_string()));
+    self
+  }
+
+  /// The `SOAPACTION` header, eg.
+  /// `urn:Belkin:service:basicevent:1#SetBinaryState`.
+  pub fn soap_action(&self) -> String {
+    format!("{}#{}", self.service_urn, self.action)
+  }
+
+  /// The envelope's XML.
+  pub fn to_xml(&self) -> String {
+    let arguments_length = self.arguments.iter()
+        .map(|&(ref name, ref value)| 2 * name.len() + value.len() + 5)
+        .sum::<usize>();
+    let mut xml = String::with_capacity(ENVELOPE_START.len()
+        + BODY_START.len() + BODY_END.len() + 2 * self.action.len()
+        + self.service_urn.len() + arguments_length + 64);
+
+    xml.push_str(ENVELOPE_START);
+    if let Some(ref style) = self.encoding_style {
+      xml.push_str(" s:encodingStyle=\"");
+      xml::escape_into(style, &mut xml);
+      xml.push('"');
+    }
+
+    xml.push_str(BODY_START);
+    xml.push_str(&self.action);
+    xml.push_str(" xmlns:u=\"");
+    xml::escape_into(&self.service_urn, &mut xml);
+    xml.push_str("\">");
+
+    for &(ref name, ref value) in self.arguments.iter() {
+      xml.push('<');
+      xml.push_str(name);
+      xml.push('>');
+      xml::escape_into(value, &mut xml);
+      xml.push_str("</");
+      xml.push_str(name);
+      xml.push('>');
+    }
+
+    xml.push_str("</u:");
+    xml.push_str(&self.action);
+    xml.push_str(BODY_END);
+    xml
+  }
+
+  /// A request posting the envelope to the service's control URL.
+  pub fn into_request(self, request_path: &str) -> SoapRequest {
+    SoapRequest::new(request_path, &self.soap_action(), self.to_xml())
+  }
 }
 
-/// An HTTP client for making SOAP requests.
+/// An HTTP client for making SOAP requests. Responses are delimited by their
+/// `Content-Length`, so several requests can be made over one connection if
+/// the device keeps it alive. The connection is opened by the first request,
+/// within its timeout.
 pub struct SoapClient {
-  stream_socket: TcpStream,
-  soap_request: Option<SoapRequest>,
-  soap_response: Option<String>,
+  address: SocketAddr,
+  stream: Option<TcpStream>,
+  /// The encoded request, reused between requests on the connection.
+  write_buffer: Vec<u8>,
+  response_buffer: PooledBuffer,
 }
 
 impl SoapClient {
-  pub fn connect(remote_ip_addr: IpAddr, port: u16) -> Option<SoapClient> {
-    let socket = SocketAddr::new(remote_ip_addr, port);
-
-    match TcpStream::connect(&socket) {
-      Err(_) => { None },
-      Ok(stream_socket) => {
-        stream_socket.set_keepalive(None).unwrap();
-
-        Some(SoapClient {
-          stream_socket: stream_socket,
-          soap_request: None,
-          soap_response: None,
-        })
-      }
+  pub fn new(remote_ip_addr: IpAddr, port: u16) -> SoapClient {
+    SoapClient {
+      address: SocketAddr::new(remote_ip_addr, port),
+      stream: None,
+      write_buffer: Vec::new(),
+      response_buffer: buffers::take(0),
     }
   }
 
+  /// Open the connection now rather than on the first request. Returns
+  /// whether it's open.
+  pub fn open(&mut self, timeout_ms: u64) -> bool {
+    let deadline = Instant::now() + StdDuration::from_millis(timeout_ms);
+    self.open_by(deadline).is_ok()
+  }
+
   /// Make a synchronous SOAP HTTP request and return the raw response.
   pub fn post(&mut self, soap_request: SoapRequest, timeout_ms: u64)
       -> Option<String> {
-    self.soap_request = Some(soap_request);
+    self.post_cancellable(soap_request, timeout_ms, None)
+  }
+
+  /// Make a synchronous SOAP HTTP request that ends early, returning nothing,
+  /// if the token is cancelled.
+  pub fn post_cancellable(&mut self, soap_request: SoapRequest,
+                          timeout_ms: u64,
+                          cancellation: Option<&CancellationToken>)
+      -> Option<String> {
+    let start = PreciseTime::now();
+    let deadline = Instant::now() + StdDuration::from_millis(timeout_ms);
+
+    let result = self.exchange(&soap_request, deadline, cancellation);
+    let timed_out = result.as_ref().err() == Some(&Failure::TimedOut);
+
+    // The connection can't be trusted after a failed request.
+    let response = result.ok();
+    if response.is_none() {
+      if let Some(stream) = self.stream.take() {
+        let _r = stream.shutdown(Shutdown::Both);
+      }
+    }
+
+    if self.write_buffer.capacity() > MAX_KEPT_BUFFER {
+      self.write_buffer = Vec::new();
+    }
 
-    let mut event_loop = EventLoop::new().unwrap();
+    let latency = start.to(PreciseTime::now());
+    let success = response.is_some();
+    let soap_action = &soap_request.soap_action;
 
-    event_loop.timeout_ms(TIMEOUT, timeout_ms).unwrap();
+    metrics::report(|metrics| {
+      if timed_out {
+        metrics.on_timeout(soap_action);
+      }
+      metrics.on_request(soap_action, latency, success);
+    });
 
-    event_loop.register(&self.stream_socket, CLIENT, EventSet::writable(),
-                        PollOpt::edge()).unwrap();
+    response
+  }
 
-    event_loop.run(self).unwrap();
+  fn exchange(&mut self, request: &SoapRequest, deadline: Instant,
+              cancellation: Option<&CancellationToken>)
+      -> Result<String, Failure> {
+    // The headers are public, so they may not have been checked when added.
+    if let Err(e) = check_headers(&request.headers) {
+      warn!(target: "wemo", "Not sending request: {:?}", e);
+      return Err(Failure::Error);
+    }
 
-    self.soap_response.take()
+    self.open_by(deadline)?;
+    self.write_buffer.clear();
+    encode_request(request, &mut self.write_buffer);
+    self.write_request(deadline)?;
+    self.read_response(deadline, cancellation)
+  }
+
+  fn open_by(&mut self, deadline: Instant) -> Result<(), Failure> {
+    if self.stream.is_none() {
+      let stream = TcpStream::connect_timeout(&self.address,
+          remaining(deadline)?).map_err(failure)?;
+      self.stream = Some(stream);
+    }
+    Ok(())
   }
 
   /// Perform the SOAP HTTP request.
-  fn write_request(&mut self, event_loop: &mut EventLoop<SoapClient>) {
-    let header = {
-      let request = match self.soap_request.as_ref() {
-        Some(req) => { req },
-        None => { return; },
+  fn write_request(&mut self, deadline: Instant) -> Result<(), Failure> {
+    let stream = self.stream.as_mut().ok_or(Failure::Error)?;
+    stream.set_write_timeout(Some(remaining(deadline)?)).map_err(failure)?;
+
+    stream.write_all(&self.write_buffer).map_err(|e| {
+      debug!(target: "wemo", peer:? = self.address;
+          "error writing socket: {:?}", e);
+      failure(e)
+    })?;
+
+    capture::record(CaptureKind::SoapRequest, Some(self.address),
+        &self.write_buffer);
+    Ok(())
+  }
+
+  /// Read the HTTP response until it has been fully received. While the
+  /// request can be cancelled, reads wake up periodically to check.
+  fn read_response(&mut self, deadline: Instant,
+                   cancellation: Option<&CancellationToken>)
+      -> Result<String, Failure> {
+    let stream = self.stream.as_mut().ok_or(Failure::Error)?;
+    let mut buf = [0; 4096];
+    let mut closed = false;
+
+    self.response_buffer.clear();
+
+    while !is_complete_response(&self.response_buffer) {
+      if cancellation.map(|c| c.is_cancelled()).unwrap_or(false) {
+        debug!(target: "wemo", peer:? = self.address;
+            "SoapClient request cancelled");
+        return Err(Failure::Error);
+      }
+
+      let wait = match cancellation {
+        None => remaining(deadline)?,
+        Some(_) => remaining(deadline)?
+            .min(StdDuration::from_millis(CANCEL_CHECK_MS)),
       };
+      stream.set_read_timeout(Some(wait)).map_err(failure)?;
 
-      format!("\
-          POST {} HTTP/1.1\r\n\
-          Content-Type: text/xml; charset=\"utf-8\"\r\n\
-          Accept:\r\n\
-          SOAPACTION: \"{}\"\r\n\
-          Content-Length: {}\r\n\
-          \r\n\
-          {}",
-          &request.request_path,
-          &request.soap_action,
-          &request.http_post_payload.len(),
-          &request.http_post_payload)
-    };
-
-    match self.stream_socket.write_all(&mut header.as_bytes()) {
-      Err(_) => {
-        debug!(target: "wemo", "error writing socket");
-      },
-      Ok(_) => {
-        event_loop.deregister(&self.stream_socket).unwrap();
-        event_loop.register(&self.stream_socket, CLIENT, EventSet::readable(),
-                                PollOpt::edge()).unwrap();
-
-        self.soap_request = None;
-      },
+      match stream.read(&mut buf) {
+        Ok(0) => {
+          closed = true;
+          break;
+        },
+        Ok(amount) => {
+          self.response_buffer.extend_from_slice(&buf[..amount]);
+        },
+        Err(ref e) if is_timeout(e) || e.kind() == ErrorKind::Interrupted => {
+          continue;
+        },
+        Err(e) => {
+          debug!(target: "wemo", peer:? = self.address;
+              "error reading socket: {:?}", e);
+          return Err(failure(e));
+        },
+      }
     }
-  }
 
-  /// Read and save the HTTP response.
-  fn read_response(&mut self, event_loop: &mut EventLoop<SoapClient>) {
-    let mut buf = String::new();
-    let result = self.stream_socket.read_to_string(&mut buf);
+    capture::record(CaptureKind::SoapResponse, Some(self.address),
+        &self.response_buffer);
 
-    match result {
-      Err(e) => {
-        debug!(target: "wemo", "error reading socket: {:?}", e);
-      },
-      Ok(_) => {
-        self.soap_response = Some(buf.clone());
-        event_loop.shutdown();
-      },
+    if closed {
+      self.stream = None;
+      // Devices close idle connections; closing before answering in full
+      // means the request may never have been handled. Responses without a
+      // `Content-Length`, from older firmware, only end at the close.
+      if !is_complete_at_close(&self.response_buffer) {
+        debug!(target: "wemo", peer:? = self.address;
+            "Connection closed before a complete response");
+        return Err(Failure::Error);
+      }
     }
+
+    Ok(String::from_utf8_lossy(&self.response_buffer).into_owned())
+  }
+}
+
+/// Why a request got no response.
+#[derive(PartialEq)]
+enum Failure {
+  TimedOut,
+  Error,
+}
+
+/// The time left until `deadline`, failing once it has passed.
+fn remaining(deadline: Instant) -> Result<StdDuration, Failure> {
+  let now = Instant::now();
+  if now >= deadline {
+    debug!(target: "wemo", "SoapClient received timeout");
+    return Err(Failure::TimedOut);
+  }
+  Ok(deadline - now)
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+  error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut
+}
+
+fn failure(error: io::Error) -> Failure {
+  if is_timeout(&error) { Failure::TimedOut } else { Failure::Error }
+}
+
+/// Write the HTTP request for `request` to `out`.
+fn encode_request(request: &SoapRequest, out: &mut Vec<u8>) {
+  // Writing to a `Vec` can't fail.
+  let _r = write!(out, "\
+      POST {} HTTP/1.1\r\n\
+      Content-Type: text/xml; charset=\"utf-8\"\r\n\
+      Accept:\r\n\
+      SOAPACTION: \"{}\"\r\n",
+      request.request_path, request.soap_action);
+
+  for &(ref name, ref value) in request.headers.iter() {
+    let _r = write!(out, "{}: {}\r\n", name, value);
+  }
+
+  let _r = write!(out, "Content-Length: {}\r\n\r\n",
+      request.http_post_payload.len());
+  out.extend_from_slice(request.http_post_payload.as_bytes());
+}
+
+/// Whether the buffer holds a complete HTTP response: all of the headers and
+/// `Content-Length` bytes of body. Without a `Content-Length` we have to wait
+/// for the device to close the connection.
+fn is_complete_response(buf: &[u8]) -> bool {
+  match framing(buf) {
+    Some((header_end, Some(length))) => buf.len() >= header_end + length,
+    _ => false,
   }
 }
 
-impl Handler for SoapClient {
-  type Timeout = Token;
-  type Message = ();
-
-  /// Handle events on the socket.
-  fn ready(&mut self, event_loop: &mut EventLoop<SoapClient>, _token: Token,
-           events: EventSet) {
-    if events.is_readable() {
-      self.read_response(event_loop);
-    } else if events.is_writable() {
-      self.write_request(event_loop);
+/// Whether the buffer holds a complete HTTP response once the device has
+/// closed the connection, when a body without a `Content-Length` is done.
+fn is_complete_at_close(buf: &[u8]) -> bool {
+  match framing(buf) {
+    Some((header_end, Some(length))) => buf.len() >= header_end + length,
+    Some((_, None)) => true,
+    None => false,
+  }
+}
+
+/// Where the headers end and the `Content-Length`, if any, once all of the
+/// headers have been received.
+fn framing(buf: &[u8]) -> Option<(usize, Option<usize>)> {
+  let header_end = buf.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+  let headers = str::from_utf8(&buf[..header_end]).ok()?;
+
+  let content_length = headers.lines()
+      .filter_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+          (Some(name), Some(value)) => {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+              value.trim().parse::<usize>().ok()
+            } else {
+              None
+            }
+          },
+          _ => None,
+        }
+      })
+      .next();
+
+  Some((header_end, content_length))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_envelope() {
+    let request = SoapEnvelope::new("urn:Belkin:service:basicevent:1",
+        "SetBinaryState")
+        .argument("BinaryState", "1")
+        .into_request("/upnp/control/basicevent1");
+
+    assert_eq!("urn:Belkin:service:basicevent:1#SetBinaryState",
+        request.soap_action);
+    assert_eq!("\
+        <?xml version=\"1.0\" encoding=\"utf-8\"?>\
+        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+            s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+          <s:Body>\
+            <u:SetBinaryState xmlns:u=\"urn:Belkin:service:basicevent:1\">\
+              <BinaryState>1</BinaryState>\
+            </u:SetBinaryState>\
+          </s:Body>\
+        </s:Envelope>", request.http_post_payload);
+
+    let envelope = SoapEnvelope::new("urn:Belkin:service:rules:1", "GetRules")
+        .encoding_style(None)
+        .argument("Name", "Lamp & fan");
+    assert!(envelope.to_xml().contains("<s:Envelope xmlns:s=\"\
+        http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>"));
+    assert!(envelope.to_xml().contains("<Name>Lamp &amp; fan</Name>"));
+  }
+
+  #[test]
+  fn test_encode_request() {
+    let request = SoapRequest::new("/upnp/control/basicevent1",
+        "urn:Belkin:service:basicevent:1#GetBinaryState", "<xml/>".to_string())
+        .header("X-Trace", "1").unwrap();
+
+    let mut buffer = b"previous request".to_vec();
+    buffer.clear();
+    encode_request(&request, &mut buffer);
+
+    assert_eq!("\
+        POST /upnp/control/basicevent1 HTTP/1.1\r\n\
+        Content-Type: text/xml; charset=\"utf-8\"\r\n\
+        Accept:\r\n\
+        SOAPACTION: \"urn:Belkin:service:basicevent:1#GetBinaryState\"\r\n\
+        X-Trace: 1\r\n\
+        Content-Length: 6\r\n\
+        \r\n\
+        <xml/>", str::from_utf8(&buffer).unwrap());
+  }
+
+  #[test]
+  fn test_is_complete_response() {
+    let response = b"HTTP/1.1 200 OK\r\n\
+        CONTENT-LENGTH: 5\r\n\
+        CONTENT-TYPE: text/xml\r\n\
+        \r\n\
+        12345";
+
+    assert!(is_complete_response(response));
+    assert!(!is_complete_response(&response[..response.len() - 1]));
+    assert!(!is_complete_response(b"HTTP/1.1 200 OK\r\nCONTENT-LENGTH: 5"));
+  }
+
+  #[test]
+  fn test_invalid_header() {
+    let request = || SoapRequest::new("/upnp/control/basicevent1",
+        "urn:Belkin:service:basicevent:1#GetBinaryState", "<xml/>".to_string());
+
+    for &(name, value) in [("X-Trace", "1\r\nSOAPACTION: \"other\""),
+                           ("X-Trace\r\nSOAPACTION", "1"),
+                           ("X Trace", "1"),
+                           ("", "1")].iter() {
+      match request().header(name, value) {
+        Err(WemoError::InvalidArgument { .. }) => {},
+        _ => panic!("expected {:?}: {:?} to be refused", name, value),
+      }
     }
+
+    // Headers set directly are checked before sending.
+    let mut request = request();
+    request.headers.push(("X-Trace".to_string(), "1\n".to_string()));
+    let mut client = SoapClient::new("127.0.0.1".parse().unwrap(), 1);
+    assert!(client.post(request, 100).is_none());
   }
 
-  /// Timeout the SOAP HTTP request.
-  fn timeout(&mut self, event_loop: &mut EventLoop<SoapClient>,
-             _token: Token) {
-    debug!(target: "wemo", "SoapClient received timeout");
-    // NB: Shutdown seems to error if the wrong port was connected to.
-    let _r = self.stream_socket.shutdown(Shutdown::Both);
-    event_loop.shutdown();
+  #[test]
+  fn test_post_closed_without_response() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    // Read the request, then close without answering.
+    thread::spawn(move || {
+      let mut stream = listener.accept().unwrap().0;
+      let mut buf = [0; 1024];
+      let _r = stream.read(&mut buf);
+    });
+
+    let request = SoapRequest::new("/upnp/control/basicevent1",
+        "urn:Belkin:service:basicevent:1#GetBinaryState",
+        "<xml/>".to_string());
+    let mut client = SoapClient::new(address.ip(), address.port());
+    assert_eq!(None, client.post(request, 2000));
+  }
+
+  #[test]
+  fn test_is_complete_response_without_content_length() {
+    let response = b"HTTP/1.1 200 OK\r\n\r\n12345";
+    assert!(!is_complete_response(response));
+    assert!(is_complete_at_close(response));
+    assert!(!is_complete_at_close(b"HTTP/1.1 200 OK\r\n"));
+    assert!(!is_complete_at_close(b"HTTP/1.1 200 OK\r\n\
+        CONTENT-LENGTH: 5\r\n\r\n1234"));
+  }
+
+  #[test]
+  fn test_post_closed_without_content_length() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    // Older firmware answers without a Content-Length and closes.
+    thread::spawn(move || {
+      let mut stream = listener.accept().unwrap().0;
+      let mut buf = [0; 1024];
+      let _r = stream.read(&mut buf);
+      let _r = stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n<xml/>");
+    });
+
+    let request = SoapRequest::new("/upnp/control/basicevent1",
+        "urn:Belkin:service:basicevent:1#GetBinaryState",
+        "<xml/>".to_string());
+    let mut client = SoapClient::new(address.ip(), address.port());
+    let response = client.post(request, 2000).unwrap();
+    assert!(response.ends_with("<xml/>"));
   }
 }
@@ -1,289 +1,306 @@
 // Copyright (c) 2015-2016 Brandon Thomas <bt@brand.io>
 
-extern crate mio;
+#[cfg(feature = "serialize")] use serde::{Serialize, Serializer};
 
-use mio::{EventLoop, Handler, EventSet, PollOpt, Token};
-use mio::udp::UdpSocket;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
 
-use regex::Regex;
-use url::Url;
-
-use std::collections::HashMap;
-use std::net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::str::FromStr;
-
-use device::SerialNumber;
-
-/// Within a given search request, resend SSDP search requests
-/// every n millisec (until search request timeout).
-const RESEND_SSDP_MS: u64 = 300;
+use device::{SerialNumber, Udn};
+use net::location::{DeviceLocation, SetupUrl};
 
 pub const UPNP_PORT: u16 = 1900;
-const LISTENER: Token = Token(0);
-const SENDER: Token = Token(1);
-const TIMER_RESEND_SSDP: Token = Token(3);
-const TIMER_TIMEOUT: Token = Token(4);
 
 /// WeMo Device SSDP Responses.
 #[derive(Clone,Debug)]
 pub struct SsdpResponse {
   pub serial_number: SerialNumber,
+  /// The full UDN from the USN header, eg. `uuid:Socket-1_0-221517K0101769`.
+  pub udn: Udn,
   pub ip_address: IpAddr,
   pub port: u16,
-  pub setup_url: Url,
+  pub setup_url: SetupUrl,
+  /// The SERVER header, if the device sent one.
+  pub server: Option<ServerInfo>,
 }
 
-/// Uses UPNP SSDP to discover WeMo devices on the local network.
-pub struct DeviceSearch {
-  /// All of the found devices. Persisted between search requests.
-  found_devices: HashMap<SerialNumber, SsdpResponse>,
-
-  /// If present, search will end as soon as the device is found.
-  target_serial: Option<SerialNumber>,
-
-  /// If present, search will end as soon as the device is found.
-  target_ip_address: Option<IpAddr>,
+impl SsdpResponse {
+  /// Where the device answered from.
+  pub fn location(&self) -> DeviceLocation {
+    DeviceLocation::new(self.ip_address, self.port)
+  }
 
-  /// Socket for SSDP search.
-  socket: UdpSocket,
+  /// The kind of device named in its UDN, eg. `Socket`, `Insight`, or
+  /// `Lightswitch`.
+  pub fn model(&self) -> Option<&str> {
+    let udn = self.udn.trim_start_matches("uuid:");
+    udn.find('-')
+        .map(|end| &udn[..end])
+        .filter(|model| !model.is_empty())
+  }
 }
 
-impl DeviceSearch {
-
-  /// DeviceSearch CTOR.
-  pub fn new() -> DeviceSearch {
-    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
-    let udp_socket = UdpSocket::v4().unwrap();
-
-    udp_socket.bind(&socket).unwrap();
-
-    DeviceSearch {
-      found_devices: HashMap::new(),
-      target_serial: None,
-      target_ip_address: None,
-      socket: udp_socket,
-    }
+/// Responses are from the same device if they have the same serial number,
+/// even if it has since moved, so `HashSet`s of responses from several
+/// searches hold each device once.
+impl PartialEq for SsdpResponse {
+  fn eq(&self, other: &SsdpResponse) -> bool {
+    self.serial_number == other.serial_number
   }
+}
 
-  /// Search for all devices on the network.
-  pub fn search(&mut self, timeout_ms: u64)
-      -> &HashMap<SerialNumber, SsdpResponse> {
-    //println!("search");
-    let mut event_loop = EventLoop::new().unwrap();
-    event_loop.register(&self.socket, SENDER, EventSet::writable(),
-                            PollOpt::edge()).unwrap();
-
-    event_loop.timeout_ms(TIMER_RESEND_SSDP, RESEND_SSDP_MS).unwrap();
-    event_loop.timeout_ms(TIMER_TIMEOUT, timeout_ms).unwrap();
+impl Eq for SsdpResponse {}
 
-    event_loop.run(self).unwrap();
+impl Hash for SsdpResponse {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.serial_number.hash(state);
+  }
+}
 
-    &self.found_devices
+/// eg. `Insight 221517K0101769 at 192.168.1.20:49153`.
+impl fmt::Display for SsdpResponse {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} {} at {}", self.model().unwrap_or("WeMo"),
+        self.serial_number, self.location())
   }
+}
 
-  /// Search for a particular device by serial number.
-  /// Exits early when the target device is found.
-  pub fn search_for_serial(&mut self, target: &SerialNumber, timeout_ms: u64)
-      -> Option<&SsdpResponse> {
-    self.target_serial = Some(target.to_string());
-    self.search(timeout_ms);
-    self.found_devices.get(target)
+/// With the `serialize` feature. Addresses and URLs are strings, and
+/// `server` is the raw SERVER header or null.
+#[cfg(feature = "serialize")]
+impl Serialize for SsdpResponse {
+  fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+      where S: Serializer {
+    let mut state = serializer.serialize_struct("SsdpResponse", 7)?;
+    serializer.serialize_struct_elt(&mut state, "serial_number",
+        &self.serial_number)?;
+    serializer.serialize_struct_elt(&mut state, "udn", &self.udn)?;
+    serializer.serialize_struct_elt(&mut state, "model", self.model())?;
+    serializer.serialize_struct_elt(&mut state, "ip_address",
+        self.ip_address.to_string())?;
+    serializer.serialize_struct_elt(&mut state, "port", self.port)?;
+    serializer.serialize_struct_elt(&mut state, "setup_url",
+        self.setup_url.as_str())?;
+    serializer.serialize_struct_elt(&mut state, "server",
+        self.server.as_ref().map(|server| server.raw.as_str()))?;
+    serializer.serialize_struct_end(state)
   }
+}
 
-  /// Search for a particular device by IP address.
-  /// Exits early when the target device is found.
-  pub fn search_for_ip(&mut self, target: &IpAddr, timeout_ms: u64)
-      -> Option<&SsdpResponse> {
-    self.target_ip_address = Some(target.clone());
-    self.search(timeout_ms);
+/// The SERVER header, `OS/version, UPnP/version, product/version`, eg.
+/// `Linux/2.6.21, UPnP/1.0, Portable SDK for UPnP devices/1.6.18`. Parts the
+/// device reports as `Unspecified` are `None`.
+#[derive(Clone,Debug,PartialEq)]
+pub struct ServerInfo {
+  /// The header as sent.
+  pub raw: String,
+  pub os: Option<String>,
+  pub upnp_version: Option<String>,
+  /// The product and firmware build, when reported.
+  pub product: Option<String>,
+}
 
-    for result in self.found_devices.values() {
-      if &result.ip_address == target {
-        return Some(result);
-      }
+impl ServerInfo {
+  /// Parse the value of a SERVER header.
+  pub fn parse(raw: &str) -> ServerInfo {
+    let raw = raw.trim();
+    let mut parts = raw.splitn(3, ',')
+        .map(|part| part.trim())
+        .map(|part| match part {
+          "" | "Unspecified" => None,
+          part => Some(part.to_string()),
+        });
+
+    let os = parts.next().and_then(|part| part);
+    let upnp_version = parts.next()
+        .and_then(|part| part)
+        .map(|part| part.trim_start_matches("UPnP/").to_string());
+    let product = parts.next().and_then(|part| part);
+
+    ServerInfo {
+      raw: raw.to_string(),
+      os: os,
+      upnp_version: upnp_version,
+      product: product,
     }
-    None
-  }
-
-  /// Whether search results were found.
-  pub fn has_results(&self) -> bool {
-    self.found_devices.len() != 0
   }
+}
 
-  /// Get the results.
-  pub fn get_results(&self) -> &HashMap<SerialNumber, SsdpResponse> {
-    &self.found_devices
-  }
 
-  /// Reset the search results and search target, if set.
-  pub fn reset(&mut self) {
-    self.found_devices = HashMap::new();
-    self.target_serial = None;
-    self.target_ip_address = None;
+/// Parse the WeMo SSDP Response Headers.
+/// The location header, `LOCATION: http://192.168.1.4:49153/setup.xml`,
+/// becomes `http://192.168.1.4:49153/setup.xml`.
+/// The USN header, `USN: uuid:Insight-1_0-12345ABCDE::upnp:rootdevice`,
+/// contains the serial number `12345ABCDE` and the UDN
+/// `uuid:Insight-1_0-12345ABCDE`.
+/// The SERVER header, if present, is parsed into `ServerInfo`.
+/// Headers are read in one pass as slices of `response_headers`; only the
+/// fields kept in the `SsdpResponse` are copied.
+#[cfg(any(test, feature = "discovery"))]
+pub fn parse_search_result(response_headers: &str) -> Option<SsdpResponse> {
+  let mut location = None;
+  let mut usn = None;
+  let mut server = None;
+
+  for (name, value) in headers(response_headers) {
+    if name.eq_ignore_ascii_case("LOCATION") {
+      location = Some(value);
+    } else if name.eq_ignore_ascii_case("USN") {
+      // Only WeMo USNs count, here and in `parse_usn`.
+      if let Some(parsed) = parse_usn(value) {
+        usn = Some(parsed);
+      }
+    } else if name.eq_ignore_ascii_case("SERVER") && server.is_none() {
+      server = Some(value);
+    }
   }
 
-  /// Send SSDP search command.
-  fn write_request(&mut self, event_loop: &mut EventLoop<DeviceSearch>) {
-    let multicast_ip = Ipv4Addr::new(239, 255, 255, 250);
-    let multicast_socket = SocketAddr::V4(SocketAddrV4::new(multicast_ip, UPNP_PORT));
-
-    // "ST:upnp:rootdevice\r\n" // All SSDP/UPNP hardware.
-    // "ST:urn:Belkin:device:lightswitch:1\r\n" // Lightswitch.
-
-    let header = format!("\
-        M-SEARCH * HTTP/1.1\r\n\
-        HOST: {}:{}\r\n\
-        ST:urn:Belkin:device:*\r\n\
-        MAN:\"ssdp:discover\"\r\n\
-        MX:5\r\n\
-        \r\n",
-        &multicast_ip,
-        &UPNP_PORT);
+  let url = SetupUrl::parse(location?).ok()?;
+  let location = url.location()?;
+  let (udn, serial_number) = usn?;
 
+  Some(SsdpResponse {
+    serial_number: serial_number.to_string(),
+    udn: udn.to_string(),
+    ip_address: location.ip_address,
+    port: location.port,
+    setup_url: url,
+    server: server.map(ServerInfo::parse),
+  })
+}
 
-    self.socket.send_to(&mut header.as_bytes(), &multicast_socket)
-        .unwrap();
+/// The `NAME: value` lines of a message, with the values trimmed.
+#[cfg(any(test, feature = "discovery"))]
+fn headers(message: &str) -> impl Iterator<Item = (&str, &str)> {
+  message.lines().filter_map(|line| {
+    let colon = line.find(':')?;
+    Some((&line[..colon], line[colon + 1..].trim()))
+  })
+}
 
-    event_loop.reregister(&self.socket, LISTENER, EventSet::readable(),
-                          PollOpt::edge()).unwrap();
+/// Split a WeMo USN, eg. `uuid:Insight-1_0-12345ABCDE::upnp:rootdevice`,
+/// into its UDN, `uuid:Insight-1_0-12345ABCDE`, and serial number,
+/// `12345ABCDE`. Other devices' USNs give `None`.
+#[cfg(any(test, feature = "discovery"))]
+fn parse_usn(usn: &str) -> Option<(&str, &str)> {
+  const PREFIX: &'static str = "uuid:";
+
+  // NB: Any host can send these, so slice only on checked boundaries.
+  match usn.get(..PREFIX.len()) {
+    Some(prefix) if prefix.eq_ignore_ascii_case(PREFIX) => {},
+    _ => return None,
   }
 
-  /// Read SSDP responses and add WeMo devices to the map.
-  fn read_response(&mut self, event_loop: &mut EventLoop<DeviceSearch>) {
-    // FIXME: Cleanup this awful garbage code.
-    let mut buf = [0; 1024 * 1024];
-
-    let parsed_response = {
-      let result = self.socket.recv_from(&mut buf);
-      match result {
-        Err(_) => { None },
-        Ok(response) => {
-          match response {
-            None => { None },
-            Some((amt, _)) => {
-              let mut vec: Vec<u8> = Vec::with_capacity(amt);
-              for i in 0 .. amt {
-                vec.push(buf[i]);
-              }
-
-              let response_headers = String::from_utf8(vec).unwrap();
-              parse_search_result(response_headers.as_ref())
-            },
-          }
-        },
-      }
-    };
-
-    if parsed_response.is_some() {
-      let device = parsed_response.unwrap();
-      let serial_number = device.serial_number.clone();
-      let ip_address: IpAddr = device.ip_address.clone();
-
-      self.found_devices.insert(serial_number.clone(), device);
-
-      if self.target_serial.is_some() {
-        let cmp: &str = serial_number.as_ref();
-
-        if self.target_serial.as_ref().unwrap() == cmp {
-          event_loop.shutdown();
-          return;
-        }
-      } else if self.target_ip_address.is_some() {
-        if self.target_ip_address.as_ref().unwrap() == &ip_address {
-          event_loop.shutdown();
-          return;
-        }
-      }
-    }
+  let rest = &usn[PREFIX.len()..];
+  let device = &rest[..rest.rfind("::")?];
+  let mut parts = device.splitn(3, '-');
+  let model = parts.next()?;
+  let version = parts.next()?.as_bytes();
+  let serial_number = parts.next()?;
+
+  let is_wemo = ["Lightswitch", "Insight", "Socket"].iter()
+      .any(|wemo| model.eq_ignore_ascii_case(wemo));
+  let is_version = version.len() == 3
+      && version[0].is_ascii_digit()
+      && version[1] == b'_'
+      && version[2].is_ascii_digit();
+
+  if !is_wemo || !is_version {
+    return None;
   }
-}
-
-impl Handler for DeviceSearch {
-  type Timeout = Token;
-  type Message = u32;
 
-  /// Handle events on the socket.
-  fn ready(&mut self, event_loop: &mut EventLoop<DeviceSearch>, _token: Token,
-           events: EventSet) {
-    if events.is_readable() {
-      self.read_response(event_loop);
-    }
+  let udn_length = device.find(|c: char| c == ':' || c.is_whitespace())
+      .unwrap_or(device.len());
+  Some((&usn[..PREFIX.len() + udn_length], serial_number))
+}
 
-    if events.is_writable() {
-      self.write_request(event_loop);
-    }
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_server_header() {
+    let response = "HTTP/1.1 200 OK\r\n\
+        LOCATION: http://192.168.1.20:49153/setup.xml\r\n\
+        SERVER: Linux/2.6.21, UPnP/1.0, Portable SDK for UPnP \
+        devices/1.6.18\r\n\
+        USN: uuid:Socket-1_0-221517K0101769::upnp:rootdevice\r\n\
+        \r\n";
+
+    let server = parse_search_result(response).unwrap().server.unwrap();
+    assert_eq!(Some("Linux/2.6.21".to_string()), server.os);
+    assert_eq!(Some("1.0".to_string()), server.upnp_version);
+    assert_eq!(Some("Portable SDK for UPnP devices/1.6.18".to_string()),
+        server.product);
+
+    let server = ServerInfo::parse("Unspecified, UPnP/1.0, Unspecified");
+    assert_eq!(None, server.os);
+    assert_eq!(Some("1.0".to_string()), server.upnp_version);
+    assert_eq!(None, server.product);
   }
 
-  /// Manages timeouts: reenqueuing search and overall search timeout.
-  fn timeout(&mut self, event_loop: &mut EventLoop<DeviceSearch>,
-             token: Token) {
-    match token {
-      TIMER_TIMEOUT => { event_loop.shutdown(); },
-      TIMER_RESEND_SSDP => {
-        // Resend the SSDP search request every `RESEND_SSDP_MS` as long
-        // as we're still searching (eg. TIMER_TIMEOUT not called).
-        event_loop.reregister(&self.socket, SENDER, EventSet::writable(),
-                          PollOpt::edge()).unwrap();
-        event_loop.timeout_ms(TIMER_RESEND_SSDP, RESEND_SSDP_MS).unwrap();
-      },
-      _ => {},
-    }
+  #[test]
+  fn test_parse_search_result() {
+    let response = parse_search_result("HTTP/1.1 200 OK\r\n\
+        location:http://192.168.1.20:49153/setup.xml \r\n\
+        USN: uuid:Socket-1_0-221517K0101769::urn:Belkin:device:controllee:1\r\n\
+        \r\n").unwrap();
+
+    assert_eq!("221517K0101769", response.serial_number);
+    assert_eq!("uuid:Socket-1_0-221517K0101769", response.udn);
+    assert_eq!(49153, response.port);
+    assert!(response.server.is_none());
+
+    // Other UPnP devices answer searches too.
+    assert!(parse_search_result("HTTP/1.1 200 OK\r\n\
+        LOCATION: http://192.168.1.30:1400/xml/device_description.xml\r\n\
+        USN: uuid:RINCON_000E58-1_0-1400::upnp:rootdevice\r\n\
+        \r\n").is_none());
+    assert!(parse_search_result("HTTP/1.1 200 OK\r\n\
+        USN: uuid:Socket-1_0-221517K0101769::upnp:rootdevice\r\n\
+        \r\n").is_none());
   }
-}
 
-/// Parse the WeMo SSDP Response Headers.
-/// The location header, `LOCATION: http://192.168.1.4:49153/setup.xml`,
-/// becomes `http://192.168.1.4:49153/setup.xml`.
-/// The USN header, `USN: uuid:Insight-1_0-12345ABCDE::upnp:rootdevice`,
-/// contains the serial number `12345ABCDE`.
-fn parse_search_result(response_headers: &str) -> Option<SsdpResponse> {
-  // FIXME: Cleanup parsing code.
-  let location_regex = Regex::new(r"(?im:^LOCATION:\s*(.*)$)").unwrap();
-  let serial_regex = Regex::new(
-      r"(?im:^USN:\s*uuid:(Lightswitch|Insight|Socket)-\d_\d-(.*)::)")
-          .unwrap();
-
-  let url_result : Option<Url> = {
-    let mut result : Option<Url> = None;
-    for cap in location_regex.captures_iter(response_headers) {
-      let matched_url = cap.at(1).unwrap_or("");
-      result = match Url::parse(matched_url) {
-        Ok(u) => { Some(u) },
-        Err(_) => { None },
-      }
-    }
-    result
-  };
-
-  if url_result.is_none() { return None; }
-
-  let url = url_result.unwrap();
-
-  if url.host().is_none() { return None; }
+  #[test]
+  fn test_parse_usn() {
+    assert_eq!(Some(("UUID:Insight-1_0-12345ABCDE", "12345ABCDE")),
+        parse_usn("UUID:Insight-1_0-12345ABCDE::upnp:rootdevice"));
 
-  let host = url.host_str().unwrap(); // FIXME
-  let port = url.port().unwrap_or(80);
-
-  let ip_address : Result<IpAddr, AddrParseError>
-      = IpAddr::from_str(host);
+    // Malformed USNs from other hosts mustn't panic.
+    assert_eq!(None, parse_usn("uuid::upnp:rootdevice"));
+    assert_eq!(None, parse_usn("uuuu\u{20ac}x::y"));
+    assert_eq!(None, parse_usn("uuid:"));
+    assert_eq!(None, parse_usn(""));
+  }
 
-  if ip_address.is_err() { return None; }
+  #[test]
+  fn test_display() {
+    let response = parse_search_result("HTTP/1.1 200 OK\r\n\
+        LOCATION: http://192.168.1.20:49153/setup.xml\r\n\
+        USN: uuid:Insight-1_0-221517K0101769::upnp:rootdevice\r\n\
+        \r\n").unwrap();
 
-  let serial_number : Option<SerialNumber> = {
-    let mut result : Option<SerialNumber> = None;
-    for cap in serial_regex.captures_iter(response_headers) {
-      let parsed = cap.at(2).unwrap_or("");
-      result = Some(parsed.to_string());
-    }
-    result
-  };
+    assert_eq!("Insight 221517K0101769 at 192.168.1.20:49153",
+        response.to_string());
 
-  if serial_number.is_none() { return None; }
+    let mut moved = response.clone();
+    moved.port = 49154;
+    assert_eq!(response, moved);
+  }
 
-  Some(SsdpResponse {
-    serial_number: serial_number.unwrap(),
-    ip_address: ip_address.unwrap(),
-    port: port,
-    setup_url: url.clone(),
-  })
+  #[cfg(all(feature = "serialize", feature = "serde_json"))]
+  #[test]
+  fn test_serialize() {
+    let response = parse_search_result("HTTP/1.1 200 OK\r\n\
+        LOCATION: http://192.168.1.20:49153/setup.xml\r\n\
+        USN: uuid:Socket-1_0-221517K0101769::upnp:rootdevice\r\n\
+        \r\n").unwrap();
+
+    assert_eq!("{\"serial_number\":\"221517K0101769\",\
+        \"udn\":\"uuid:Socket-1_0-221517K0101769\",\"model\":\"Socket\",\
+        \"ip_address\":\"192.168.1.20\",\"port\":49153,\
+        \"setup_url\":\"http://192.168.1.20:49153/setup.xml\",\
+        \"server\":null}",
+        ::serde_json::to_string(&response).unwrap());
+  }
 }
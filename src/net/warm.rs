@@ -0,0 +1,273 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Warm-starting discovery from the results of an earlier search, eg. so a
+//! GUI can show devices at startup instead of waiting for SSDP. Saved
+//! results can be loaded without the `discovery` feature, eg. as the static
+//! device list of a control-only build.
+
+#[cfg(feature = "discovery")] use cancel::CancellationToken;
+use device::SerialNumber;
+use error::WemoError;
+use net::location::SetupUrl;
+#[cfg(feature = "discovery")] use net::search::DeviceSearch;
+use net::ssdp::{ServerInfo, SsdpResponse};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+#[cfg(feature = "discovery")] use std::sync::{Arc, Mutex};
+#[cfg(feature = "discovery")] use std::thread::JoinHandle;
+#[cfg(feature = "discovery")] use threads;
+use toml::{self, Table, Value};
+
+/// A search running in the background that starts out with cached results.
+/// `results` returns the cached devices immediately, updated with fresh
+/// responses as they arrive. Once the search finishes, only the devices it
+/// found are returned. Dropping it cancels the search.
+#[cfg(feature = "discovery")]
+pub struct WarmSearch {
+  shared: Arc<Mutex<Shared>>,
+  cancellation: CancellationToken,
+  handle: Option<JoinHandle<()>>,
+}
+
+#[cfg(feature = "discovery")]
+struct Shared {
+  cached: HashMap<SerialNumber, SsdpResponse>,
+  fresh: HashMap<SerialNumber, SsdpResponse>,
+  refreshed: bool,
+}
+
+#[cfg(feature = "discovery")]
+impl WarmSearch {
+  /// Search for up to `timeout_ms` in the background, starting from
+  /// `cached`, eg. from `load_search_results`.
+  pub fn start(mut search: DeviceSearch,
+               cached: HashMap<SerialNumber, SsdpResponse>,
+               timeout_ms: u64) -> WarmSearch {
+    let shared = Arc::new(Mutex::new(Shared {
+      cached: cached,
+      fresh: HashMap::new(),
+      refreshed: false,
+    }));
+
+    let cancellation = search.cancellation().clone();
+    let found_shared = shared.clone();
+    let finished_shared = shared.clone();
+
+    let handle = threads::spawn("wemo-warm-search", move || {
+      search.reset();
+      search.search_with(timeout_ms, move |device| {
+        if let Ok(mut shared) = found_shared.lock() {
+          shared.fresh.insert(device.serial_number.clone(), device.clone());
+        }
+      });
+
+      if let Ok(mut shared) = finished_shared.lock() {
+        shared.refreshed = true;
+      }
+    });
+
+    WarmSearch {
+      shared: shared,
+      cancellation: cancellation,
+      handle: Some(handle),
+    }
+  }
+
+  /// The devices known so far: fresh responses, plus any cached devices not
+  /// seen again yet while the search is still running.
+  pub fn results(&self) -> HashMap<SerialNumber, SsdpResponse> {
+    match self.shared.lock() {
+      Err(_) => HashMap::new(),
+      Ok(shared) => {
+        let mut results = if shared.refreshed {
+          HashMap::new()
+        } else {
+          shared.cached.clone()
+        };
+        results.extend(shared.fresh.iter()
+            .map(|(serial, device)| (serial.clone(), device.clone())));
+        results
+      },
+    }
+  }
+
+  /// Whether the background search has finished.
+  pub fn is_refreshed(&self) -> bool {
+    self.shared.lock()
+        .map(|shared| shared.refreshed)
+        .unwrap_or(true)
+  }
+
+  /// Wait for the background search to finish, returning what it found.
+  pub fn wait(mut self) -> HashMap<SerialNumber, SsdpResponse> {
+    if let Some(handle) = self.handle.take() {
+      let _r = handle.join();
+    }
+    self.results()
+  }
+
+  /// End the search early. Devices already found are kept.
+  pub fn cancel(&self) {
+    self.cancellation.cancel();
+  }
+}
+
+#[cfg(feature = "discovery")]
+impl Drop for WarmSearch {
+  fn drop(&mut self) {
+    self.cancel();
+  }
+}
+
+/// Read search results saved by `save_search_results`.
+pub fn load_search_results<P: AsRef<Path>>(path: P)
+    -> Result<HashMap<SerialNumber, SsdpResponse>, WemoError> {
+  let mut contents = String::new();
+  File::open(path)?.read_to_string(&mut contents)?;
+
+  let tables = toml::parse(&contents).map_err(snapshot_error)?;
+
+  let mut results = HashMap::new();
+  for (i, &(ref name, ref table)) in tables.iter().enumerate() {
+    if name != "devices" {
+      return Err(snapshot_error(format!("unknown table '{}'", name)));
+    }
+    let response = from_table(table)
+        .map_err(|reason| snapshot_error(format!("device {}: {}", i + 1,
+            reason)))?;
+    results.insert(response.serial_number.clone(), response);
+  }
+  Ok(results)
+}
+
+/// Write search results, eg. `DeviceSearch::get_results`, for warm-starting
+/// a later `WarmSearch`. The file is TOML with one `[[devices]]` table per
+/// device.
+pub fn save_search_results<P: AsRef<Path>>(path: P,
+    results: &HashMap<SerialNumber, SsdpResponse>) -> Result<(), WemoError> {
+  let mut serial_numbers = results.keys().collect::<Vec<_>>();
+  serial_numbers.sort();
+
+  let mut contents = String::new();
+  for serial_number in serial_numbers {
+    let response = &results[serial_number];
+    if !contents.is_empty() {
+      contents.push('\n');
+    }
+    contents.push_str("[[devices]]\n");
+    contents.push_str(&format!("serial_number = {}\n",
+        toml_string(&response.serial_number)));
+    contents.push_str(&format!("udn = {}\n", toml_string(&response.udn)));
+    contents.push_str(&format!("ip_address = \"{}\"\n", response.ip_address));
+    contents.push_str(&format!("port = {}\n", response.port));
+    contents.push_str(&format!("setup_url = {}\n",
+        toml_string(response.setup_url.as_str())));
+    if let Some(ref server) = response.server {
+      contents.push_str(&format!("server = {}\n", toml_string(&server.raw)));
+    }
+  }
+
+  File::create(path)?.write_all(contents.as_bytes())?;
+  Ok(())
+}
+
+fn from_table(table: &Table) -> Result<SsdpResponse, String> {
+  let string = |key: &str| match table.get(key) {
+    Some(&Value::String(ref value)) => Ok(value.clone()),
+    _ => Err(format!("missing {}", key)),
+  };
+
+  let port = match table.get("port") {
+    Some(&Value::Integer(port)) if port > 0 && port <= 65535 => port as u16,
+    _ => { return Err("port must be a number from 1 to 65535".to_string()); },
+  };
+
+  let server = match table.get("server") {
+    None => None,
+    Some(_) => Some(ServerInfo::parse(&string("server")?)),
+  };
+
+  Ok(SsdpResponse {
+    serial_number: string("serial_number")?,
+    udn: string("udn")?,
+    ip_address: string("ip_address")?.parse()
+        .map_err(|_| "invalid ip_address".to_string())?,
+    port: port,
+    setup_url: SetupUrl::parse(&string("setup_url")?)
+        .map_err(|_| "invalid setup_url".to_string())?,
+    server: server,
+  })
+}
+
+fn toml_string(value: &str) -> String {
+  format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn snapshot_error<S: Into<String>>(reason: S) -> WemoError {
+  WemoError::ConfigError {
+    reason: format!("search results: {}", reason.into()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use std::process;
+  use super::*;
+  #[cfg(feature = "discovery")] use testing::FakeDevice;
+
+  #[test]
+  fn test_save_and_load() {
+    let path = env::temp_dir()
+        .join(format!("wemo-search-results-{}.toml", process::id()));
+
+    let mut results = HashMap::new();
+    results.insert("ABC".to_string(), SsdpResponse {
+      serial_number: "ABC".to_string(),
+      udn: "uuid:Socket-1_0-ABC".to_string(),
+      ip_address: "1.1.1.1".parse().unwrap(),
+      port: 49153,
+      setup_url: SetupUrl::parse("http://1.1.1.1:49153/setup.xml").unwrap(),
+      server: Some(ServerInfo::parse("Unspecified, UPnP/1.0, Unspecified")),
+    });
+
+    save_search_results(&path, &results).unwrap();
+    let loaded = load_search_results(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let response = loaded.get("ABC").unwrap();
+    assert_eq!(1, loaded.len());
+    assert_eq!("uuid:Socket-1_0-ABC", response.udn);
+    assert_eq!(49153, response.port);
+    assert_eq!(results["ABC"].setup_url, response.setup_url);
+    assert_eq!(results["ABC"].server, response.server);
+  }
+
+  #[cfg(feature = "discovery")]
+  #[test]
+  fn test_warm_start() {
+    let device = FakeDevice::start_unique().unwrap();
+    let found = DeviceSearch::new().with_config(device.config())
+        .search_for_serial(&device.serial_number().to_string(), 2000)
+        .cloned()
+        .unwrap();
+
+    // Cached from an earlier run, along with a device that's since gone.
+    let mut gone = found.clone();
+    gone.serial_number = "GONE".to_string();
+    let mut cached = HashMap::new();
+    cached.insert(found.serial_number.clone(), found);
+    cached.insert(gone.serial_number.clone(), gone);
+
+    let warm = DeviceSearch::new().with_config(device.config())
+        .warm_start(cached, 500);
+    assert_eq!(2, warm.results().len());
+
+    let refreshed = warm.wait();
+    assert_eq!(1, refreshed.len());
+    assert!(refreshed.contains_key(device.serial_number()));
+  }
+}
@@ -0,0 +1,424 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! First-time setup of a device fresh out of the box or after a factory
+//! reset, enabled with the `onboarding` feature. An unconfigured device runs
+//! its own access point, eg. `WeMo.Switch.1A2`. With this computer joined to
+//! it, `onboard` finds the device, reads the MAC address and serial number
+//! its WiFi password encryption is keyed on, sends the home network's
+//! settings with `ConnectHomeNetwork`, and once the device has joined, waits
+//! for it to appear on the home network. The device's access point goes away
+//! when setup closes, so the computer has to rejoin the home network, eg. on
+//! its own, within the timeout.
+
+use base64;
+use config::{WemoConfig, global_config};
+use crypto;
+use deadline::Deadline;
+use device::SerialNumber;
+use device::service::Service;
+use device::switch::Switch;
+use error::{TimeoutStage, WemoError};
+use net::search::DeviceSearch;
+use std::thread;
+use time::Duration;
+use xml::{self, find_tag_value};
+
+/// The services setup goes through. Unconfigured devices offer them
+/// alongside `basicevent1`.
+const META_INFO_SERVICE: &'static str = "urn:Belkin:service:metainfo:1";
+const WIFI_SETUP_SERVICE: &'static str = "urn:Belkin:service:WiFiSetup:1";
+
+/// How often to ask whether the device has joined the home network.
+const NETWORK_STATUS_POLL_MS: i64 = 1000;
+
+/// How often to search for the device on the home network.
+const SEARCH_ATTEMPT_MS: i64 = 3000;
+
+/// What an unconfigured device reports about itself in `GetMetaInfo`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeviceInfo {
+  /// eg. `EC1A59F0A1B2`.
+  pub mac_address: String,
+  pub serial_number: SerialNumber,
+  /// The whole `|` separated `MetaInfo`, which also carries the firmware
+  /// version and product name.
+  pub raw: String,
+}
+
+impl DeviceInfo {
+  /// Parse a `MetaInfo` value, eg. `EC1A59F0A1B2|221517K0101769|...`. The
+  /// MAC address must be 12 hex digits, and the serial number 4 to 32
+  /// letters and digits.
+  pub fn parse(meta_info: &str) -> Result<DeviceInfo, WemoError> {
+    let mut fields = meta_info.trim().split('|');
+
+    match (fields.next(), fields.next()) {
+      (Some(mac), Some(serial)) if is_valid_info(mac, serial) => {
+        Ok(DeviceInfo {
+          mac_address: mac.to_string(),
+          serial_number: serial.to_string(),
+          raw: meta_info.trim().to_string(),
+        })
+      },
+      _ => Err(WemoError::ParsingError),
+    }
+  }
+}
+
+fn is_valid_info(mac_address: &str, serial_number: &str) -> bool {
+  mac_address.len() == 12
+      && mac_address.bytes().all(|byte| byte.is_ascii_hexdigit())
+      && serial_number.len() >= 4
+      && serial_number.len() <= 32
+      && serial_number.bytes().all(|byte| byte.is_ascii_alphanumeric())
+}
+
+/// A network the device can see, from `GetApList`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccessPoint {
+  pub ssid: String,
+  pub channel: String,
+  /// Signal strength as a percentage.
+  pub strength: Option<u8>,
+  /// eg. `WPA2PSK`, or `OPEN` for networks without a password.
+  pub auth: String,
+  /// eg. `AES`, or `NONE`.
+  pub encryption: String,
+}
+
+impl AccessPoint {
+  pub fn is_open(&self) -> bool {
+    self.auth.eq_ignore_ascii_case("OPEN")
+  }
+}
+
+/// An unconfigured device, reached over its own access point.
+pub struct SetupDevice {
+  switch: Switch,
+  services: Vec<Service>,
+  info: DeviceInfo,
+}
+
+impl SetupDevice {
+  /// Search the device's access point for it. Only the device is on that
+  /// network, so the search ends with the first one found.
+  pub fn find(config: WemoConfig, timeout: Duration)
+      -> Result<SetupDevice, WemoError> {
+    let deadline = Deadline::after(timeout);
+    let mut search = DeviceSearch::new().with_config(config.clone());
+
+    let found = search.search_for_any(timeout.num_milliseconds().max(0) as u64)
+        .cloned()
+        .ok_or_else(|| deadline.timed_out(TimeoutStage::Search))?;
+
+    let switch = Switch::from_dynamic_ip_and_port(found.ip_address,
+        found.port).with_config(config);
+    SetupDevice::connect(switch, deadline.check()?)
+  }
+
+  /// Read the services and device info of the device behind `switch`, eg.
+  /// one at its usual setup address, `10.22.22.1:49152`.
+  pub fn connect(switch: Switch, timeout: Duration)
+      -> Result<SetupDevice, WemoError> {
+    let deadline = Deadline::after(timeout);
+    let services = switch.list_services(timeout)?;
+
+    let meta_info = find_service(&services, META_INFO_SERVICE)?;
+    let body = switch.send_action(meta_info, "GetMetaInfo", &[],
+        deadline.check()?)?;
+    let info = find_tag_value("MetaInfo", &body)
+        .ok_or(WemoError::ParsingError)
+        .and_then(DeviceInfo::parse)?;
+
+    Ok(SetupDevice {
+      switch: switch,
+      services: services,
+      info: info,
+    })
+  }
+
+  pub fn info(&self) -> &DeviceInfo {
+    &self.info
+  }
+
+  /// The networks the device can see.
+  pub fn access_points(&self, timeout: Duration)
+      -> Result<Vec<AccessPoint>, WemoError> {
+    let body = self.wifi_setup("GetApList", &[], timeout)?;
+
+    // Unlike most values, the list spans lines.
+    let ap_list = xml::with_regex(r"(?is:<ApList>(.*?)</ApList>)", |re| {
+      re.captures(&body).and_then(|capture| capture.at(1))
+          .map(xml::unescape)
+    });
+
+    ap_list.ok()
+        .and_then(|ap_list| ap_list)
+        .map(|ap_list| parse_access_points(&ap_list))
+        .ok_or(WemoError::ParsingError)
+  }
+
+  /// Send the home network's settings, then wait for the device to join it
+  /// and close setup. Fails with `WemoError::WemoError` if the device
+  /// couldn't join, eg. with the wrong password.
+  pub fn connect_home_network(&self, network: &AccessPoint, password: &str,
+                              timeout: Duration) -> Result<(), WemoError> {
+    let deadline = Deadline::after(timeout);
+    let password = if network.is_open() {
+      String::new()
+    } else {
+      encrypt_password(password, &self.info)?
+    };
+
+    let arguments = [
+      ("ssid".to_string(), network.ssid.clone()),
+      ("auth".to_string(), network.auth.clone()),
+      ("password".to_string(), password),
+      ("encrypt".to_string(), network.encryption.clone()),
+      ("channel".to_string(), network.channel.clone()),
+    ];
+    self.wifi_setup("ConnectHomeNetwork", &arguments, deadline.check()?)?;
+
+    loop {
+      let timeout = deadline.check_for(TimeoutStage::Wait)?;
+      let body = self.wifi_setup("GetNetworkStatus", &[], timeout)?;
+
+      match find_tag_value("NetworkStatus", &body).map(|s| s.trim()) {
+        // 3 is joined, but without reaching Belkin's cloud.
+        Some("1") | Some("3") => { break; },
+        Some("2") => {
+          warn!(target: "wemo", ssid = network.ssid.as_str(),
+              action = "onboard"; "Device couldn't join {}", network.ssid);
+          return Err(WemoError::WemoError);
+        },
+        _ => {}, // Still joining.
+      }
+
+      let pause = deadline.capped(
+          Duration::milliseconds(NETWORK_STATUS_POLL_MS));
+      if let Ok(pause) = pause.to_std() {
+        thread::sleep(pause);
+      }
+    }
+
+    self.wifi_setup("CloseSetup", &[], deadline.check()?)?;
+    Ok(())
+  }
+
+  /// Search the home network for the device once it has left setup. The
+  /// search is repeated, since this computer may still be rejoining the
+  /// home network.
+  pub fn wait_for_device(&self, timeout: Duration)
+      -> Result<Switch, WemoError> {
+    let deadline = Deadline::after(timeout);
+    let config = self.switch.config().clone();
+    let mut search = DeviceSearch::new().with_config(config.clone());
+
+    loop {
+      let attempt = deadline.check_for(TimeoutStage::Search)?
+          .min(Duration::milliseconds(SEARCH_ATTEMPT_MS));
+
+      let found = search.search_for_serial(&self.info.serial_number,
+          attempt.num_milliseconds() as u64).cloned();
+
+      if let Some(found) = found {
+        let mut switch = Switch::from_dynamic_ip_and_port(found.ip_address,
+            found.port).with_config(config);
+        switch.serial_number = Some(found.serial_number);
+        return Ok(switch);
+      }
+      search.reset();
+    }
+  }
+
+  fn wifi_setup(&self, action: &str, arguments: &[(String, String)],
+                timeout: Duration) -> Result<String, WemoError> {
+    let service = find_service(&self.services, WIFI_SETUP_SERVICE)?;
+    self.switch.send_action(service, action, arguments, timeout)
+  }
+}
+
+/// Run the whole out-of-box setup: find the device on its access point,
+/// connect it to the home network `ssid`, and return it once it's found
+/// there. Uses the global config.
+pub fn onboard(ssid: &str, password: &str, timeout: Duration)
+    -> Result<Switch, WemoError> {
+  let deadline = Deadline::after(timeout);
+  let device = SetupDevice::find(global_config(), timeout)?;
+
+  info!(target: "wemo", serial = device.info().serial_number.as_str(),
+      action = "onboard"; "Connecting {} to {}", device.info().serial_number,
+      ssid);
+
+  let network = device.access_points(deadline.check()?)?
+      .into_iter()
+      .find(|network| network.ssid == ssid)
+      .ok_or_else(|| WemoError::InvalidArgument {
+        reason: format!("the device can't see a network named {}", ssid),
+      })?;
+
+  device.connect_home_network(&network, password, deadline.check()?)?;
+  device.wait_for_device(deadline.check()?)
+}
+
+/// Encrypt a WiFi password for `ConnectHomeNetwork`. The key data is the
+/// first half of the MAC address, the serial number, and the second half of
+/// the MAC address; its first 8 bytes are the salt and first 16 the IV, as
+/// for `openssl enc -aes-128-cbc -md md5`. The base64 ciphertext is followed
+/// by its length and the password's length, each as two hex digits. Fails
+/// with `WemoError::InvalidArgument` if the info wouldn't pass
+/// `DeviceInfo::parse`.
+pub fn encrypt_password(password: &str, info: &DeviceInfo)
+    -> Result<String, WemoError> {
+  if !is_valid_info(&info.mac_address, &info.serial_number) {
+    return Err(WemoError::InvalidArgument {
+      reason: format!("can't encrypt with MAC address {:?} and serial \
+          number {:?}", info.mac_address, info.serial_number),
+    });
+  }
+
+  let (mac_start, mac_end) = info.mac_address.split_at(6);
+  let key_data = format!("{}{}{}", mac_start, info.serial_number, mac_end);
+  let key_data = key_data.as_bytes();
+
+  let mut iv = [0; 16];
+  iv.copy_from_slice(&key_data[..16]);
+
+  let encrypted = base64::encode(&crypto::openssl_aes128_cbc(
+      password.as_bytes(), key_data, &key_data[..8], &iv));
+
+  Ok(format!("{}{:02x}{:02x}", encrypted, encrypted.len(), password.len()))
+}
+
+fn find_service<'a>(services: &'a [Service], service_type: &str)
+    -> Result<&'a Service, WemoError> {
+  services.iter()
+      .find(|service| service.service_type == service_type)
+      .ok_or_else(|| WemoError::InvalidArgument {
+        reason: format!("no service {}; is the device in setup mode?",
+            service_type),
+      })
+}
+
+/// Parse an `ApList`, eg. `Page:1/1/2$\nHome|6|100|WPA2PSK/AES,\n...`. Each
+/// network is `SSID|channel|strength|auth/encryption`.
+fn parse_access_points(ap_list: &str) -> Vec<AccessPoint> {
+  let networks = match ap_list.find('$') {
+    None => ap_list,
+    Some(index) => &ap_list[index + 1..],
+  };
+
+  networks.split([',', '\n'])
+      .filter_map(|entry| {
+        // SSIDs may contain `|`, so split from the end.
+        let mut fields = entry.trim().rsplitn(4, '|');
+        let security = fields.next()?;
+        let strength = fields.next()?;
+        let channel = fields.next()?;
+        let ssid = fields.next()?;
+
+        let mut security = security.splitn(2, '/');
+        Some(AccessPoint {
+          ssid: ssid.to_string(),
+          channel: channel.trim().to_string(),
+          strength: strength.trim().parse().ok(),
+          auth: security.next().unwrap_or("").trim().to_string(),
+          encryption: security.next().unwrap_or("NONE").trim().to_string(),
+        })
+      })
+      .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use testing::{FakeDevice, unique_serial_number};
+
+  fn info() -> DeviceInfo {
+    DeviceInfo::parse("EC1A59F0A1B2|221517K0101769|Plugin Device|\
+        WeMo_WW_2.00.11057.PVT-OWRT-SNS|WeMo_Smart_Socket|Socket").unwrap()
+  }
+
+  #[test]
+  fn test_encrypt_password() {
+    assert_eq!("uXg0Ks1a5Vvhe+4Gv2X5gw==1808",
+        encrypt_password("hunter22", &info()).unwrap());
+    assert!(DeviceInfo::parse("221517K0101769").is_err());
+  }
+
+  #[test]
+  fn test_invalid_device_info() {
+    for &meta_info in ["EC1A59F0A1B2|K01", // Serial number too short.
+                       "EC1A59F0A1B2|221517K0101769\u{e9}",
+                       "EC1A59F0A1BZ|221517K0101769", // Not hex.
+                       "EC1A59F0\u{e9}\u{e9}|221517K0101769"].iter() {
+      match DeviceInfo::parse(meta_info) {
+        Err(WemoError::ParsingError) => {},
+        other => panic!("expected {:?} to be refused, got {:?}", meta_info,
+            other),
+      }
+    }
+
+    let mut info = info();
+    info.serial_number = "K01".to_string();
+    assert!(encrypt_password("hunter22", &info).is_err());
+    info.serial_number = "221517K0101769".to_string();
+    info.mac_address = "EC1A5\u{e9}9F0A1".to_string();
+    assert!(encrypt_password("hunter22", &info).is_err());
+  }
+
+  #[test]
+  fn test_parse_access_points() {
+    let networks = parse_access_points("Page:1/1/3$\n\
+        Home|6|100|WPA2PSK/AES,\n\
+        Cafe | Guest|11|42|OPEN/NONE,\n\
+        Old|1|7|WEP,\n");
+
+    assert_eq!(3, networks.len());
+    assert_eq!("Home", networks[0].ssid);
+    assert_eq!("6", networks[0].channel);
+    assert_eq!(Some(100), networks[0].strength);
+    assert_eq!("WPA2PSK", networks[0].auth);
+    assert_eq!("AES", networks[0].encryption);
+    assert_eq!("Cafe | Guest", networks[1].ssid);
+    assert!(networks[1].is_open());
+    assert_eq!("NONE", networks[2].encryption);
+  }
+
+  #[test]
+  fn test_onboard_fake_device() {
+    let device = FakeDevice::start_unconfigured(&unique_serial_number())
+        .unwrap();
+    let timeout = Duration::seconds(5);
+
+    let setup = SetupDevice::connect(device.switch(), timeout).unwrap();
+    assert_eq!(device.serial_number(), setup.info().serial_number);
+
+    let home = setup.access_points(timeout).unwrap()
+        .into_iter()
+        .find(|network| network.ssid == "Home")
+        .unwrap();
+    setup.connect_home_network(&home, "hunter22", timeout).unwrap();
+
+    let (ssid, password) = device.home_network().unwrap();
+    assert_eq!("Home", ssid);
+    assert_eq!(encrypt_password("hunter22", setup.info()).unwrap(),
+        password);
+    assert!(!device.is_unconfigured());
+
+    let switch = setup.wait_for_device(timeout).unwrap();
+    assert_eq!(Some(device.http_address().port()), switch.get_port());
+  }
+
+  #[test]
+  fn test_find_setup_device() {
+    let device = FakeDevice::start_unconfigured(&unique_serial_number())
+        .unwrap();
+
+    // The search ends as soon as the device answers, leaving time to read
+    // its info.
+    let setup = SetupDevice::find(device.config(), Duration::seconds(5))
+        .unwrap();
+    assert_eq!(device.serial_number(), setup.info().serial_number);
+  }
+}
@@ -5,26 +5,341 @@
 //! am committing one of the gravest of sins in order to parse results from
 //! responses: using regular expressions. Please don't hate me.
 
-use device::state::WemoState;
+use config::ParsingMode;
+use device::Udn;
+use device::insight::InsightParams;
+use device::service::{Action, Argument, ArgumentDirection, Service};
+use std::collections::HashMap;
+use device::state::{BinaryState, WemoState};
 use error::WemoError;
 use regex::Regex;
+use xml::{find_tag_value, with_regex};
+
+const SOAP_NAMESPACE: &'static str = "http://schemas.xmlsoap.org/soap/envelope/";
+
+/// The state reported for values that lenient parsing can't read.
+pub const UNREADABLE_STATE: WemoState = WemoState::Unknown(u16::MAX);
 
 /// Parse the device state from XML returned via subscription events.
 pub fn parse_state(xml: &str) -> Result<WemoState, WemoError> {
+  parse_binary_state(xml).map(|binary_state| binary_state.state)
+}
+
+/// Parse the device state from XML returned via subscription events.
+pub fn parse_state_with(xml: &str, mode: ParsingMode)
+    -> Result<WemoState, WemoError> {
+  match mode {
+    ParsingMode::Standard | ParsingMode::Strict => parse_state(xml),
+    ParsingMode::Lenient => {
+      parse_binary_state_leniently(xml).map(|binary_state| binary_state.state)
+    },
+  }
+}
+
+/// Parse the `BinaryState` tag in the given mode.
+pub fn parse_binary_state_with(xml: &str, mode: ParsingMode)
+    -> Result<BinaryState, WemoError> {
+  match mode {
+    ParsingMode::Standard | ParsingMode::Strict => parse_binary_state(xml),
+    ParsingMode::Lenient => parse_binary_state_leniently(xml),
+  }
+}
+
+/// Check that a SOAP response has an `Envelope` in the SOAP namespace, a
+/// `Body`, no `Fault`, and an `action_response` element, eg.
+/// `GetBinaryStateResponse`, in the `service` namespace, eg.
+/// `urn:Belkin:service:basicevent:1`.
+pub fn validate_envelope(xml: &str, action_response: &str, service: &str)
+    -> Result<(), WemoError> {
+  lazy_static! {
+    static ref ENVELOPE: Regex =
+        Regex::new(r"<([A-Za-z_][\w.-]*):Envelope\b([^>]*)>").unwrap();
+  }
+
+  let invalid = |reason: String| WemoError::InvalidEnvelope { reason: reason };
+
+  let envelope = ENVELOPE.captures(xml)
+      .ok_or_else(|| invalid("missing Envelope".to_string()))?;
+  let prefix = envelope.at(1).unwrap_or("");
+  let attributes = envelope.at(2).unwrap_or("");
+
+  let soap_namespace = format!("xmlns:{}=\"{}\"", prefix, SOAP_NAMESPACE);
+  if !attributes.contains(&soap_namespace) {
+    return Err(invalid("Envelope is not in the SOAP namespace".to_string()));
+  }
+
+  if !xml.contains(&format!("<{}:Body>", prefix)) {
+    return Err(invalid("missing Body".to_string()));
+  }
+
+  if xml.contains(&format!("<{}:Fault>", prefix)) {
+    return Err(invalid("response is a SOAP Fault".to_string()));
+  }
+
+  let response = format!(r"<([A-Za-z_][\w.-]*):{}\b([^>]*)>",
+      action_response);
+  let captures = with_regex(&response, |re| {
+    re.captures(xml).map(|captures| {
+      (captures.at(1).unwrap_or(""), captures.at(2).unwrap_or(""))
+    })
+  }).map_err(|_| WemoError::ParsingError)?;
+
+  let (response_prefix, attributes) = captures
+      .ok_or_else(|| invalid(format!("missing {}", action_response)))?;
+
+  let service_namespace = format!("xmlns:{}=\"{}\"",
+      response_prefix, service);
+
+  if !attributes.contains(&service_namespace) {
+    return Err(invalid(format!("{} is not in the {} namespace",
+        action_response, service)));
+  }
+
+  Ok(())
+}
+
+/// Like `parse_binary_state`, but any text inside the tag is accepted. A state
+/// that isn't a number becomes `UNREADABLE_STATE`, and unreadable Insight
+/// fields are dropped. Only a missing tag is an error.
+fn parse_binary_state_leniently(xml: &str) -> Result<BinaryState, WemoError> {
+  lazy_static! {
+    static ref RE: Regex =
+        Regex::new(r"(?s)<BinaryState>(.*?)</BinaryState>").unwrap();
+  }
+
+  let value = RE.captures(xml)
+      .and_then(|matches| matches.at(1))
+      .ok_or(WemoError::ParsingError)?;
+
+  let fields = value.split('|').map(|field| field.trim()).collect::<Vec<_>>();
+
+  let state = fields[0].parse::<u64>()
+      .ok()
+      .and_then(WemoState::from_u64)
+      .unwrap_or(UNREADABLE_STATE);
+
+  let extended = fields[1..].iter().map(|field| field.to_string()).collect();
+  Ok(BinaryState::new(state, InsightParams::from_fields(&fields[1..]))
+      .with_extended_fields(extended))
+}
+
+/// Parse the `BinaryState` tag from either a `GetBinaryState` SOAP response or
+/// a subscription event. Insight devices append pipe-delimited power fields
+/// to the state, eg. `8|1479872570|0|0|432|1234|56|0|0|-123`, which are
+/// returned when present.
+pub fn parse_binary_state(xml: &str) -> Result<BinaryState, WemoError> {
   lazy_static! {
     static ref RE: Regex =
-        Regex::new(r"<BinaryState>(\d)(\|-?\d+)*</BinaryState>").unwrap();
+        Regex::new(r"<BinaryState>(\d+)((?:\|-?\d+)*)</BinaryState>").unwrap();
   }
 
   let matches = RE.captures(xml).ok_or(WemoError::ParsingError)?;
-  let state = matches.at(1).ok_or(WemoError::ParsingError)?;
+  let state = matches.at(1)
+      .and_then(|state| state.parse::<u64>().ok())
+      .and_then(WemoState::from_u64)
+      .ok_or(WemoError::ParsingError)?;
+
+  let fields = match matches.at(2) {
+    None | Some("") => Vec::new(),
+    Some(extended) => extended[1..].split('|').collect::<Vec<_>>(),
+  };
+
+  let extended = fields.iter().map(|field| field.to_string()).collect();
+  Ok(BinaryState::new(state, InsightParams::from_fields(&fields))
+      .with_extended_fields(extended))
+}
+
+/// Parse the `Brightness` tag a Dimmer sends in subscription events, eg.
+/// `<Brightness>50</Brightness>`, as a percentage.
+pub fn parse_brightness(xml: &str) -> Result<u8, WemoError> {
+  find_tag_value("Brightness", xml)
+      .and_then(|level| level.trim().parse::<u8>().ok())
+      .and_then(|level| if level <= 100 { Some(level) } else { None })
+      .ok_or(WemoError::ParsingError)
+}
+
+/// One bulb's state change on a WeMo Link bridge.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BulbEvent {
+  /// The bulb's end-device ID, eg. `94103EA2B27803ED`.
+  pub device_id: String,
+  /// Which capability changed, eg. `10006` for on/off.
+  pub capability: String,
+  pub value: String,
+}
+
+/// Parse the escaped `StatusChange` document sent in bridge subscription
+/// events, eg. `<StateEvent><DeviceID available="YES">94103EA2B27803ED
+/// </DeviceID><CapabilityId>10006</CapabilityId><Value>1</Value>
+/// </StateEvent>`.
+pub fn parse_bulb_event(xml: &str) -> Result<BulbEvent, WemoError> {
+  lazy_static! {
+    static ref RE: Regex = Regex::new(concat!(
+        r"<DeviceID[^>]*>\s*([^<]*?)\s*</DeviceID>",
+        r"\s*<CapabilityId>\s*([^<]*?)\s*</CapabilityId>",
+        r"\s*<Value>\s*([^<]*?)\s*</Value>")).unwrap();
+  }
+
+  let xml = unescape(xml);
+  let matches = RE.captures(&xml).ok_or(WemoError::ParsingError)?;
+
+  let field = |i| matches.at(i).map(|value| value.to_string());
+  match (field(1), field(2), field(3)) {
+    (Some(device_id), Some(capability), Some(value)) => {
+      if device_id.is_empty() || capability.is_empty() {
+        return Err(WemoError::ParsingError);
+      }
+      Ok(BulbEvent {
+        device_id: device_id,
+        capability: capability,
+        value: value,
+      })
+    },
+    _ => Err(WemoError::ParsingError),
+  }
+}
+
+/// Parse the name and value of each attribute in the escaped `attributeList`
+/// sent in Maker subscription events, eg. `<attribute><name>Sensor</name>
+/// <value>1</value></attribute>`.
+pub fn parse_attribute_list(xml: &str)
+    -> Result<Vec<(String, String)>, WemoError> {
+  lazy_static! {
+    static ref RE: Regex = Regex::new(concat!(
+        r"<attribute>\s*<name>\s*([^<]*?)\s*</name>",
+        r"\s*<value>\s*([^<]*?)\s*</value>")).unwrap();
+  }
+
+  let xml = unescape(xml);
+  let attributes = RE.captures_iter(&xml)
+      .filter_map(|matches| match (matches.at(1), matches.at(2)) {
+        (Some(name), Some(value)) => {
+          Some((name.to_string(), value.to_string()))
+        },
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+
+  if attributes.is_empty() {
+    Err(WemoError::ParsingError)
+  } else {
+    Ok(attributes)
+  }
+}
+
+/// Undo XML escaping of the predefined entities.
+fn unescape(xml: &str) -> String {
+  xml.replace("&lt;", "<")
+      .replace("&gt;", ">")
+      .replace("&quot;", "\"")
+      .replace("&apos;", "'")
+      .replace("&amp;", "&")
+}
+
+/// Parse the `UDN` tag from a device's `setup.xml`, eg.
+/// `<UDN>uuid:Socket-1_0-221517K0101769</UDN>`.
+pub fn parse_udn(xml: &str) -> Result<Udn, WemoError> {
+  find_tag_value("UDN", xml)
+      .map(|udn| udn.trim())
+      .and_then(|udn| if udn.starts_with("uuid:") { Some(udn) } else { None })
+      .map(|udn| udn.to_string())
+      .ok_or(WemoError::ParsingError)
+}
+
+/// Parse the `serviceList` from a device's `setup.xml`. Every service must
+/// have each of its fields.
+pub fn parse_services(xml: &str) -> Result<Vec<Service>, WemoError> {
+  lazy_static! {
+    static ref SERVICE: Regex =
+        Regex::new(r"(?s)<service>(.*?)</service>").unwrap();
+  }
+
+  let field = |name: &str, service: &str| {
+    find_tag_value(name, service)
+        .map(|value| value.trim().to_string())
+        .ok_or(WemoError::ParsingError)
+  };
+
+  SERVICE.captures_iter(xml)
+      .filter_map(|capture| capture.at(1))
+      .map(|service| {
+        Ok(Service {
+          service_type: field("serviceType", service)?,
+          service_id: field("serviceId", service)?,
+          control_url: field("controlURL", service)?,
+          event_url: field("eventSubURL", service)?,
+          scpd_url: field("SCPDURL", service)?,
+        })
+      })
+      .collect()
+}
+
+/// Parse the actions from a service's SCPD, along with the data type of each
+/// argument's related state variable. Arguments without a direction are
+/// taken as inputs.
+pub fn parse_scpd(xml: &str) -> Result<Vec<Action>, WemoError> {
+  lazy_static! {
+    static ref ACTION: Regex =
+        Regex::new(r"(?s)<action>(.*?)</action>").unwrap();
+    static ref ARGUMENT_LIST: Regex =
+        Regex::new(r"(?s)<argumentList>.*?</argumentList>").unwrap();
+    static ref ARGUMENT: Regex =
+        Regex::new(r"(?s)<argument>(.*?)</argument>").unwrap();
+  }
 
-  match state {
-    "0" => Ok(WemoState::Off),
-    "1" => Ok(WemoState::On),
-    "8" => Ok(WemoState::OnWithoutLoad),
-    _ => Err(WemoError::ParsingError), // TODO: Drop "unknown" WemoState.
+  if !xml.contains("<scpd") {
+    return Err(WemoError::ParsingError);
   }
+
+  let value = |name: &str, xml: &str| {
+    find_tag_value(name, xml).map(|value| value.trim().to_string())
+  };
+
+  // State variables carry attributes, eg. `sendEvents="yes"`.
+  let data_types = xml.split("<stateVariable").skip(1)
+      .filter_map(|variable| variable.split("</stateVariable>").next())
+      .filter_map(|variable| {
+        match (value("name", variable), value("dataType", variable)) {
+          (Some(name), Some(data_type)) => Some((name, data_type)),
+          _ => None,
+        }
+      })
+      .collect::<HashMap<_, _>>();
+
+  let mut actions = Vec::new();
+
+  for action in ACTION.captures_iter(xml).filter_map(|capture| capture.at(1)) {
+    // The action's own name is the one outside its argument list.
+    let name = value("name", &ARGUMENT_LIST.replace_all(action, ""))
+        .ok_or(WemoError::ParsingError)?;
+
+    let mut arguments = Vec::new();
+    for argument in ARGUMENT.captures_iter(action)
+        .filter_map(|capture| capture.at(1)) {
+      let related_state_variable = value("relatedStateVariable", argument);
+      let direction = match value("direction", argument) {
+        Some(ref direction) if direction.eq_ignore_ascii_case("out") => {
+          ArgumentDirection::Out
+        },
+        _ => ArgumentDirection::In,
+      };
+
+      arguments.push(Argument {
+        name: value("name", argument).ok_or(WemoError::ParsingError)?,
+        direction: direction,
+        data_type: related_state_variable.as_ref()
+            .and_then(|variable| data_types.get(variable).cloned()),
+        related_state_variable: related_state_variable,
+      });
+    }
+
+    actions.push(Action {
+      name: name,
+      arguments: arguments,
+    });
+  }
+
+  Ok(actions)
 }
 
 #[cfg(test)]
@@ -32,6 +347,59 @@ mod tests {
   use device::state::WemoState;
   use super::*;
 
+  #[test]
+  fn bridge_notifications() {
+    let xml = concat!(
+        r#"<e:propertyset xmlns:e="urn:schemas-upnp-org:event-1-0">"#,
+        r#"<e:property><StatusChange>&lt;?xml version=&quot;1.0&quot; "#,
+        r#"encoding=&quot;utf-8&quot;?&gt;&lt;StateEvent&gt;&lt;DeviceID "#,
+        r#"available=&quot;YES&quot;&gt;94103EA2B27803ED&lt;/DeviceID&gt;"#,
+        r#"&lt;CapabilityId&gt;10006&lt;/CapabilityId&gt;&lt;Value&gt;1"#,
+        r#"&lt;/Value&gt;&lt;/StateEvent&gt;</StatusChange></e:property>"#,
+        r#"</e:propertyset>"#);
+
+    assert_eq!(BulbEvent {
+      device_id: "94103EA2B27803ED".to_string(),
+      capability: "10006".to_string(),
+      value: "1".to_string(),
+    }, parse_bulb_event(xml).unwrap());
+
+    assert!(parse_bulb_event("<StatusChange></StatusChange>").is_err());
+  }
+
+  #[test]
+  fn maker_notifications() {
+    let xml = concat!(
+        r#"<e:propertyset xmlns:e="urn:schemas-upnp-org:event-1-0">"#,
+        r#"<e:property><attributeList>&lt;attribute&gt;&lt;name&gt;Switch"#,
+        r#"&lt;/name&gt;&lt;value&gt;1&lt;/value&gt;&lt;/attribute&gt;"#,
+        r#"&lt;attribute&gt;&lt;name&gt;Sensor&lt;/name&gt;&lt;value&gt;0"#,
+        r#"&lt;/value&gt;&lt;/attribute&gt;</attributeList></e:property>"#,
+        r#"</e:propertyset>"#);
+
+    assert_eq!(vec![("Switch".to_string(), "1".to_string()),
+        ("Sensor".to_string(), "0".to_string())],
+        parse_attribute_list(xml).unwrap());
+
+    assert!(parse_attribute_list("<attributeList></attributeList>").is_err());
+  }
+
+  #[test]
+  fn dimmer_notifications() {
+    let xml = r#"
+      <e:propertyset xmlns:e="urn:schemas-upnp-org:event-1-0">
+        <e:property>
+          <Brightness>42</Brightness>
+        </e:property>
+      </e:propertyset>
+    "#;
+
+    assert_eq!(42, parse_brightness(xml).unwrap());
+    assert!(parse_brightness("<Brightness>101</Brightness>").is_err());
+    assert!(parse_brightness("<Brightness>dim</Brightness>").is_err());
+    assert!(parse_brightness("<BinaryState>1</BinaryState>").is_err());
+  }
+
   #[test]
   fn switch_notifications() {
     let xml = r#"
@@ -91,4 +459,229 @@ mod tests {
 
     assert_eq!(WemoState::OnWithoutLoad, parse_state(xml).unwrap());
   }
+
+  #[test]
+  fn setup_services() {
+    let xml = r#"
+      <root xmlns="urn:Belkin:device-1-0">
+        <device>
+          <serviceList>
+            <service>
+              <serviceType>urn:Belkin:service:basicevent:1</serviceType>
+              <serviceId>urn:Belkin:serviceId:basicevent1</serviceId>
+              <controlURL>/upnp/control/basicevent1</controlURL>
+              <eventSubURL>/upnp/event/basicevent1</eventSubURL>
+              <SCPDURL>/eventservice.xml</SCPDURL>
+            </service>
+            <service>
+              <serviceType>urn:Belkin:service:metainfo:1</serviceType>
+              <serviceId>urn:Belkin:serviceId:metainfo1</serviceId>
+              <controlURL>/upnp/control/metainfo1</controlURL>
+              <eventSubURL>/upnp/event/metainfo1</eventSubURL>
+              <SCPDURL>/metainfoservice.xml</SCPDURL>
+            </service>
+          </serviceList>
+        </device>
+      </root>"#;
+
+    let services = parse_services(xml).unwrap();
+    assert_eq!(2, services.len());
+    assert_eq!(Service {
+      service_type: "urn:Belkin:service:basicevent:1".to_string(),
+      service_id: "urn:Belkin:serviceId:basicevent1".to_string(),
+      control_url: "/upnp/control/basicevent1".to_string(),
+      event_url: "/upnp/event/basicevent1".to_string(),
+      scpd_url: "/eventservice.xml".to_string(),
+    }, services[0]);
+    assert_eq!("/metainfoservice.xml", services[1].scpd_url);
+
+    assert!(parse_services("<root></root>").unwrap().is_empty());
+    assert!(parse_services(
+        "<service><serviceType>x</serviceType></service>").is_err());
+  }
+
+  #[test]
+  fn scpd_actions() {
+    let xml = r#"<?xml version="1.0"?>
+      <scpd xmlns="urn:Belkin:service-1-0">
+        <specVersion><major>1</major><minor>0</minor></specVersion>
+        <actionList>
+          <action>
+            <name>SetBinaryState</name>
+            <argumentList>
+              <argument>
+                <retval />
+                <name>BinaryState</name>
+                <relatedStateVariable>BinaryState</relatedStateVariable>
+                <direction>in</direction>
+              </argument>
+            </argumentList>
+          </action>
+          <action>
+            <name>GetFriendlyName</name>
+            <argumentList>
+              <argument>
+                <name>FriendlyName</name>
+                <relatedStateVariable>FriendlyName</relatedStateVariable>
+                <direction>out</direction>
+              </argument>
+            </argumentList>
+          </action>
+          <action>
+            <name>ReSetup</name>
+          </action>
+        </actionList>
+        <serviceStateTable>
+          <stateVariable sendEvents="yes">
+            <name>BinaryState</name>
+            <dataType>Boolean</dataType>
+            <defaultValue>0</defaultValue>
+          </stateVariable>
+          <stateVariable sendEvents="yes">
+            <name>FriendlyName</name>
+            <dataType>string</dataType>
+          </stateVariable>
+        </serviceStateTable>
+      </scpd>"#;
+
+    let actions = parse_scpd(xml).unwrap();
+    assert_eq!(vec!["SetBinaryState", "GetFriendlyName", "ReSetup"],
+        actions.iter().map(|action| action.name.as_str()).collect::<Vec<_>>());
+
+    assert_eq!(vec![Argument {
+      name: "BinaryState".to_string(),
+      direction: ArgumentDirection::In,
+      related_state_variable: Some("BinaryState".to_string()),
+      data_type: Some("Boolean".to_string()),
+    }], actions[0].arguments);
+
+    assert_eq!(ArgumentDirection::Out, actions[1].arguments[0].direction);
+    assert_eq!(Some("string".to_string()), actions[1].arguments[0].data_type);
+    assert!(actions[2].arguments.is_empty());
+
+    assert!(parse_scpd("<root></root>").is_err());
+    assert!(parse_scpd("<scpd><action><argumentList/></action></scpd>")
+        .is_err());
+  }
+
+  #[test]
+  fn soap_responses() {
+    let xml = r#"
+      <s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"
+          s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+        <s:Body>
+          <u:GetBinaryStateResponse xmlns:u="urn:Belkin:service:basicevent:1">
+            <BinaryState>1</BinaryState>
+          </u:GetBinaryStateResponse>
+        </s:Body>
+      </s:Envelope>"#;
+
+    let binary_state = parse_binary_state(xml).unwrap();
+    assert_eq!(WemoState::On, binary_state.state);
+    assert_eq!(None, binary_state.insight);
+
+    let xml = r#"
+      <s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"
+          s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+        <s:Body>
+          <u:GetBinaryStateResponse xmlns:u="urn:Belkin:service:basicevent:1">
+            <BinaryState>Error</BinaryState>
+          </u:GetBinaryStateResponse>
+        </s:Body>
+      </s:Envelope>"#;
+
+    assert!(parse_binary_state(xml).is_err());
+  }
+
+  #[test]
+  fn insight_extended_fields() {
+    let xml = "<BinaryState>8|1479872570|0|0|432|1234|56|0|0|-123</BinaryState>";
+    let insight = parse_binary_state(xml).unwrap().insight.unwrap();
+
+    assert_eq!(1479872570, insight.last_change);
+    assert_eq!(432, insight.on_total);
+    assert_eq!(1234, insight.time_period);
+    assert_eq!(56, insight.average_power);
+    assert_eq!(-123, insight.total_energy);
+    assert_eq!(None, insight.power_threshold);
+
+    let binary_state = parse_binary_state(xml).unwrap();
+    assert_eq!(9, binary_state.extended_fields().len());
+    assert_eq!("1479872570", binary_state.extended_fields()[0]);
+    assert_eq!("-123", binary_state.extended_fields()[8]);
+
+    // Too few fields to be Insight parameters.
+    let xml = "<BinaryState>1|1479872570|0</BinaryState>";
+    let binary_state = parse_binary_state(xml).unwrap();
+    assert_eq!(WemoState::On, binary_state.state);
+    assert_eq!(None, binary_state.insight);
+    assert_eq!(&["1479872570".to_string(), "0".to_string()],
+        binary_state.extended_fields());
+  }
+
+  #[test]
+  fn lenient_binary_state() {
+    let lenient = ParsingMode::Lenient;
+
+    assert_eq!(WemoState::On,
+        parse_state_with("<BinaryState> 1 </BinaryState>", lenient).unwrap());
+    assert_eq!(UNREADABLE_STATE,
+        parse_state_with("<BinaryState>Error</BinaryState>", lenient).unwrap());
+    assert_eq!(UNREADABLE_STATE,
+        parse_state_with("<BinaryState></BinaryState>", lenient).unwrap());
+    assert!(parse_state_with("<TernaryState>1</TernaryState>", lenient)
+        .is_err());
+
+    // Standard parsing rejects what lenient parsing degrades.
+    assert!(parse_state_with("<BinaryState>Error</BinaryState>",
+        ParsingMode::Standard).is_err());
+
+    let xml = "<BinaryState>8|1479872570|x|0|432|1234|56|0|0|-123</BinaryState>";
+    let binary_state = parse_binary_state_with(xml, lenient).unwrap();
+    assert_eq!(WemoState::OnWithoutLoad, binary_state.state);
+    assert_eq!(None, binary_state.insight);
+  }
+
+  #[test]
+  fn strict_envelopes() {
+    let service = "urn:Belkin:service:basicevent:1";
+    let xml = r#"
+      <s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"
+          s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+        <s:Body>
+          <u:GetBinaryStateResponse xmlns:u="urn:Belkin:service:basicevent:1">
+            <BinaryState>1</BinaryState>
+          </u:GetBinaryStateResponse>
+        </s:Body>
+      </s:Envelope>"#;
+
+    assert!(validate_envelope(xml, "GetBinaryStateResponse", service).is_ok());
+    assert!(validate_envelope(xml, "SetBinaryStateResponse", service).is_err());
+    assert!(validate_envelope(xml, "GetBinaryStateResponse",
+        "urn:Belkin:service:insight:1").is_err());
+
+    let wrong_namespace = xml.replace("schemas.xmlsoap.org", "example.com");
+    assert!(validate_envelope(&wrong_namespace, "GetBinaryStateResponse",
+        service).is_err());
+
+    assert!(validate_envelope("<BinaryState>1</BinaryState>",
+        "GetBinaryStateResponse", service).is_err());
+  }
+
+  #[test]
+  fn setup_udn() {
+    let xml = r#"
+      <root xmlns="urn:Belkin:device-1-0">
+        <device>
+          <deviceType>urn:Belkin:device:controllee:1</deviceType>
+          <friendlyName>Living Room</friendlyName>
+          <UDN>uuid:Socket-1_0-221517K0101769</UDN>
+          <serialNumber>221517K0101769</serialNumber>
+        </device>
+      </root>"#;
+
+    assert_eq!("uuid:Socket-1_0-221517K0101769", parse_udn(xml).unwrap());
+    assert!(parse_udn("<UDN>Socket-1_0-221517K0101769</UDN>").is_err());
+    assert!(parse_udn("<root></root>").is_err());
+  }
 }
@@ -0,0 +1,27 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! The types most programs need, in one import:
+//!
+//! ```no_run
+//! use wemo::prelude::*;
+//!
+//! # #[cfg(feature = "discovery")] fn main() {
+//! let mut search = DeviceSearch::new();
+//! for (_, device) in search.search(5_000) {
+//!   let switch = Switch::from_dynamic_ip_and_port(device.ip_address,
+//!       device.port);
+//!   let _r = switch.turn_on(Duration::seconds(5));
+//! }
+//! # }
+//! # #[cfg(not(feature = "discovery"))] fn main() {}
+//! ```
+
+pub use config::WemoConfig;
+pub use device::state::WemoState;
+pub use device::switch::{Switch, WemoResult};
+pub use error::{TimeoutStage, WemoError};
+#[cfg(feature = "discovery")] pub use net::search::DeviceSearch;
+pub use net::ssdp::SsdpResponse;
+#[cfg(feature = "subscriptions")]
+pub use subscriptions::{Notification, NotificationType, Subscriptions};
+pub use time::Duration;
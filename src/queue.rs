@@ -0,0 +1,167 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! A bounded queue for handing notifications to a callback thread.
+
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+/// What to do with a notification that arrives while the queue is full.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverflowPolicy {
+  /// Drop the oldest queued notification to make room.
+  DropOldest,
+  /// Drop the notification that just arrived.
+  DropNewest,
+  /// Wait for room, holding up the device's request until there is.
+  Block,
+}
+
+pub struct BoundedQueue<T> {
+  capacity: usize,
+  policy: OverflowPolicy,
+  state: Mutex<QueueState<T>>,
+  changed: Condvar,
+}
+
+struct QueueState<T> {
+  items: VecDeque<T>,
+  closed: bool,
+}
+
+impl<T> BoundedQueue<T> {
+  /// Holds at least one item.
+  pub fn new(capacity: usize, policy: OverflowPolicy) -> BoundedQueue<T> {
+    BoundedQueue {
+      capacity: capacity.max(1),
+      policy: policy,
+      state: Mutex::new(QueueState {
+        items: VecDeque::with_capacity(capacity.max(1)),
+        closed: false,
+      }),
+      changed: Condvar::new(),
+    }
+  }
+
+  /// Add the item, or apply the overflow policy if the queue is full.
+  /// Returns the item that was dropped, if any. Items pushed after the queue
+  /// is closed are dropped.
+  pub fn push(&self, item: T) -> Option<T> {
+    let mut state = match self.state.lock() {
+      Err(_) => { return Some(item); },
+      Ok(state) => state,
+    };
+
+    loop {
+      if state.closed {
+        return Some(item);
+      }
+
+      if state.items.len() < self.capacity {
+        state.items.push_back(item);
+        self.changed.notify_all();
+        return None;
+      }
+
+      match self.policy {
+        OverflowPolicy::DropOldest => {
+          let oldest = state.items.pop_front();
+          state.items.push_back(item);
+          self.changed.notify_all();
+          return oldest;
+        },
+        OverflowPolicy::DropNewest => { return Some(item); },
+        OverflowPolicy::Block => {
+          state = match self.changed.wait(state) {
+            Err(_) => { return Some(item); },
+            Ok(state) => state,
+          };
+        },
+      }
+    }
+  }
+
+  /// Wait for the next item. Returns `None` once the queue is closed and
+  /// empty.
+  pub fn pop(&self) -> Option<T> {
+    let mut state = match self.state.lock() {
+      Err(_) => { return None; },
+      Ok(state) => state,
+    };
+
+    loop {
+      if let Some(item) = state.items.pop_front() {
+        self.changed.notify_all();
+        return Some(item);
+      }
+
+      if state.closed {
+        return None;
+      }
+
+      state = match self.changed.wait(state) {
+        Err(_) => { return None; },
+        Ok(state) => state,
+      };
+    }
+  }
+
+  /// Stop accepting items. Those already queued can still be popped.
+  pub fn close(&self) {
+    match self.state.lock() {
+      Err(_) => {}, // Ignore.
+      Ok(mut state) => {
+        state.closed = true;
+        self.changed.notify_all();
+      },
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.state.lock().map(|state| state.items.len()).unwrap_or(0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use std::thread;
+  use std::time::Duration;
+  use super::*;
+
+  #[test]
+  fn test_drop_policies() {
+    let queue = BoundedQueue::new(2, OverflowPolicy::DropOldest);
+    assert_eq!(None, queue.push(1));
+    assert_eq!(None, queue.push(2));
+    assert_eq!(Some(1), queue.push(3));
+    assert_eq!(2, queue.len());
+    assert_eq!(Some(2), queue.pop());
+
+    let queue = BoundedQueue::new(2, OverflowPolicy::DropNewest);
+    assert_eq!(None, queue.push(1));
+    assert_eq!(None, queue.push(2));
+    assert_eq!(Some(3), queue.push(3));
+    assert_eq!(Some(1), queue.pop());
+
+    queue.close();
+    assert_eq!(Some(4), queue.push(4));
+    assert_eq!(Some(2), queue.pop());
+    assert_eq!(None, queue.pop());
+  }
+
+  #[test]
+  fn test_block() {
+    let queue = Arc::new(BoundedQueue::new(1, OverflowPolicy::Block));
+    assert_eq!(None, queue.push(1));
+
+    let pusher = queue.clone();
+    let handle = thread::spawn(move || pusher.push(2));
+
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(1, queue.len());
+    assert_eq!(Some(1), queue.pop());
+
+    assert_eq!(None, handle.join().unwrap());
+    assert_eq!(Some(2), queue.pop());
+  }
+}
@@ -0,0 +1,742 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! A shared collection of known devices, keyed by serial number.
+
+use bulk;
+use config::{self, DeviceDefinition, DeviceKind};
+use device::SerialNumber;
+use device::liveness::LivenessMonitor;
+use device::state::{StateReport, WemoState};
+use device::switch::{Switch, WemoResult};
+use error::WemoError;
+use locations::LocationStore;
+#[cfg(feature = "discovery")]
+use net::notify::{NotifyListener, SsdpNotification};
+use net::ssdp::SsdpResponse;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use time::Duration;
+
+/// Called with a device that came online or went offline.
+type PresenceCallback = Arc<Fn(&Arc<Switch>) + Sync + Send>;
+
+/// Tracks known devices by serial number and keeps their cached locations up
+/// to date, eg. from SSDP announcements.
+pub struct Registry {
+  devices: RwLock<HashMap<SerialNumber, Arc<Switch>>>,
+  metadata: RwLock<HashMap<SerialNumber, DeviceMetadata>>,
+  history_capacity: Option<usize>,
+  locations: Option<LocationStore>,
+  /// Devices last seen going offline. Devices are assumed online until then.
+  offline: RwLock<HashSet<SerialNumber>>,
+  online_callbacks: RwLock<Vec<PresenceCallback>>,
+  offline_callbacks: RwLock<Vec<PresenceCallback>>,
+}
+
+/// What the user has said about a device, as opposed to what the device
+/// reports about itself. Kept with the device definitions by `save`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceMetadata {
+  /// The user's name for the device. Unlike the WeMo friendly name, the
+  /// Belkin app can't overwrite it.
+  pub name: Option<String>,
+  pub kind: DeviceKind,
+  /// Other names the device can be looked up by with `by_alias`. Each alias
+  /// belongs to at most one device.
+  pub aliases: Vec<String>,
+  /// Rooms or other groups the device belongs to, for controlling them
+  /// together, eg. with `turn_off_zone`.
+  pub zones: Vec<String>,
+}
+
+impl Registry {
+  /// CTOR.
+  pub fn new() -> Registry {
+    Registry {
+      devices: RwLock::new(HashMap::new()),
+      metadata: RwLock::new(HashMap::new()),
+      history_capacity: None,
+      locations: None,
+      offline: RwLock::new(HashSet::new()),
+      online_callbacks: RwLock::new(Vec::new()),
+      offline_callbacks: RwLock::new(Vec::new()),
+    }
+  }
+
+  /// Keep the last `capacity` state transitions of each device added from
+  /// now on, for `history`.
+  pub fn with_history(mut self, capacity: usize) -> Registry {
+    self.history_capacity = Some(capacity);
+    self
+  }
+
+  /// Point devices added from now on at their locations in `locations`, and
+  /// remember the locations found by searches and announcements there. See
+  /// `save_locations`.
+  pub fn with_locations(mut self, locations: LocationStore) -> Registry {
+    self.locations = Some(locations);
+    self
+  }
+
+  /// Add a device. Devices are identified by serial number, so it must have
+  /// one. Replaces any device with the same serial number.
+  pub fn insert(&self, switch: Arc<Switch>) -> Result<(), WemoError> {
+    let serial_number = switch.serial_number.clone()
+        .ok_or(WemoError::MissingSerialNumber)?;
+
+    if let Some(capacity) = self.history_capacity {
+      switch.keep_history(capacity);
+    }
+    if let Some(ref locations) = self.locations {
+      locations.restore(&switch);
+    }
+
+    self.devices.write().map_err(|_| WemoError::LockError)?
+        .insert(serial_number, switch);
+    Ok(())
+  }
+
+  /// Add devices found by a `DeviceSearch`. Devices that are already known
+  /// have their locations updated instead.
+  pub fn insert_search_results(&self,
+      results: &HashMap<SerialNumber, SsdpResponse>) -> Result<(), WemoError> {
+    let mut devices = self.devices.write().map_err(|_| WemoError::LockError)?;
+
+    for (serial_number, response) in results.iter() {
+      if let Some(switch) = devices.get(serial_number) {
+        switch.update_from_ssdp(response);
+        continue;
+      }
+
+      let mut switch = Switch::from_dynamic_ip_and_port(response.ip_address,
+          response.port);
+      switch.serial_number = Some(serial_number.clone());
+      if let Some(capacity) = self.history_capacity {
+        switch.keep_history(capacity);
+      }
+      devices.insert(serial_number.clone(), Arc::new(switch));
+    }
+
+    if let Some(ref locations) = self.locations {
+      let mut changed = false;
+      for serial_number in results.keys() {
+        if let Some(switch) = devices.get(serial_number) {
+          changed |= locations.remember(switch);
+        }
+      }
+      if changed {
+        if let Err(e) = locations.save() {
+          warn!(target: "wemo", action = "save_locations";
+              "Couldn't save device locations: {:?}", e);
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Remember where every device currently is and write the location cache,
+  /// eg. periodically or at shutdown, to keep locations learned by
+  /// relocation. Does nothing without `with_locations`.
+  pub fn save_locations(&self) -> Result<(), WemoError> {
+    match self.locations {
+      None => Ok(()),
+      Some(ref locations) => {
+        for switch in self.devices().iter() {
+          locations.remember(switch);
+        }
+        locations.save()
+      },
+    }
+  }
+
+  /// Add devices declared in a device file, eg. from `config::load`, along
+  /// with their metadata. Replaces any devices with the same serial numbers.
+  pub fn insert_definitions(&self, definitions: &[DeviceDefinition])
+      -> Result<(), WemoError> {
+    for definition in definitions.iter() {
+      self.insert(Arc::new(definition.to_switch()))?;
+      self.set_metadata(&definition.serial_number, DeviceMetadata {
+        name: definition.name.clone(),
+        kind: definition.kind,
+        aliases: Vec::new(),
+        zones: definition.zones.clone(),
+      })?;
+      for alias in definition.aliases.iter() {
+        self.set_alias(&definition.serial_number, alias)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Read a device file with `config::load` and add its devices.
+  pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<(), WemoError> {
+    self.insert_definitions(&config::load(path)?)
+  }
+
+  /// Write the known devices and their metadata to a device file, eg. after
+  /// changing aliases. Devices without a known address are left out.
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), WemoError> {
+    config::save(path, &self.definitions())
+  }
+
+  /// The known devices and their metadata as device definitions, sorted by
+  /// serial number. Devices without a known address are left out.
+  pub fn definitions(&self) -> Vec<DeviceDefinition> {
+    let mut definitions = self.devices().iter()
+        .filter_map(|switch| {
+          let serial_number = switch.serial_number.clone()?;
+          let metadata = self.metadata(&serial_number).unwrap_or_default();
+          Some(DeviceDefinition {
+            name: metadata.name,
+            ip_address: switch.get_ip_address()?,
+            port: switch.get_port(),
+            kind: metadata.kind,
+            aliases: metadata.aliases,
+            zones: metadata.zones,
+            serial_number: serial_number,
+          })
+        })
+        .collect::<Vec<_>>();
+
+    definitions.sort_by(|a, b| a.serial_number.cmp(&b.serial_number));
+    definitions
+  }
+
+  /// A device's metadata, if any has been set.
+  pub fn metadata(&self, serial_number: &str) -> Option<DeviceMetadata> {
+    self.metadata.read()
+        .ok()
+        .and_then(|metadata| metadata.get(serial_number).cloned())
+  }
+
+  /// Replace a device's metadata. Its aliases are taken from any other device
+  /// that has them.
+  pub fn set_metadata(&self, serial_number: &str, metadata: DeviceMetadata)
+      -> Result<(), WemoError> {
+    let mut all = self.metadata.write().map_err(|_| WemoError::LockError)?;
+
+    for other in all.values_mut() {
+      other.aliases.retain(|alias| !metadata.aliases.contains(alias));
+    }
+    all.insert(serial_number.to_string(), metadata);
+    Ok(())
+  }
+
+  /// Let the device be looked up by `alias`, taking the alias from any other
+  /// device that has it.
+  pub fn set_alias(&self, serial_number: &str, alias: &str)
+      -> Result<(), WemoError> {
+    let mut all = self.metadata.write().map_err(|_| WemoError::LockError)?;
+
+    for other in all.values_mut() {
+      other.aliases.retain(|existing| existing != alias);
+    }
+    all.entry(serial_number.to_string())
+        .or_insert_with(DeviceMetadata::default)
+        .aliases
+        .push(alias.to_string());
+    Ok(())
+  }
+
+  /// Stop using `alias`. Returns whether any device had it.
+  pub fn remove_alias(&self, alias: &str) -> bool {
+    match self.metadata.write() {
+      Err(_) => false,
+      Ok(mut all) => {
+        let mut removed = false;
+        for metadata in all.values_mut() {
+          let before = metadata.aliases.len();
+          metadata.aliases.retain(|existing| existing != alias);
+          removed |= metadata.aliases.len() != before;
+        }
+        removed
+      },
+    }
+  }
+
+  /// Add the device to a zone.
+  pub fn add_to_zone(&self, serial_number: &str, zone: &str)
+      -> Result<(), WemoError> {
+    let mut all = self.metadata.write().map_err(|_| WemoError::LockError)?;
+
+    let zones = &mut all.entry(serial_number.to_string())
+        .or_insert_with(DeviceMetadata::default)
+        .zones;
+    if !zones.iter().any(|existing| existing == zone) {
+      zones.push(zone.to_string());
+    }
+    Ok(())
+  }
+
+  /// Take the device out of a zone. Returns whether it was in it.
+  pub fn remove_from_zone(&self, serial_number: &str, zone: &str) -> bool {
+    match self.metadata.write() {
+      Err(_) => false,
+      Ok(mut all) => {
+        match all.get_mut(serial_number) {
+          None => false,
+          Some(metadata) => {
+            let before = metadata.zones.len();
+            metadata.zones.retain(|existing| existing != zone);
+            metadata.zones.len() != before
+          },
+        }
+      },
+    }
+  }
+
+  /// Every zone with a device in it, sorted.
+  pub fn zones(&self) -> Vec<String> {
+    let mut zones = self.metadata.read()
+        .map(|all| {
+          all.values()
+              .flat_map(|metadata| metadata.zones.iter().cloned())
+              .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    zones.sort();
+    zones.dedup();
+    zones
+  }
+
+  /// The known devices in a zone, sorted by serial number.
+  pub fn zone(&self, zone: &str) -> Vec<Arc<Switch>> {
+    let mut serial_numbers = self.metadata.read()
+        .map(|all| {
+          all.iter()
+              .filter(|&(_, metadata)| metadata.zones.iter()
+                  .any(|existing| existing == zone))
+              .map(|(serial_number, _)| serial_number.clone())
+              .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    serial_numbers.sort();
+    serial_numbers.iter()
+        .filter_map(|serial_number| self.get(serial_number))
+        .collect()
+  }
+
+  /// Get the state of every device in a zone concurrently. See
+  /// `bulk::get_states`.
+  pub fn get_zone_states(&self, zone: &str, timeout: Duration)
+      -> HashMap<SerialNumber, WemoResult> {
+    bulk::get_states(&self.zone(zone), timeout)
+  }
+
+  /// Set the state of every device in a zone concurrently, using each
+  /// device's default timeout and retry policy. Results are keyed by serial
+  /// number.
+  pub fn set_zone_state(&self, zone: &str, state: WemoState)
+      -> HashMap<SerialNumber, WemoResult> {
+    bulk::set_states_default(&self.zone(zone), state)
+  }
+
+  /// Turn on every device in a zone. See `set_zone_state`.
+  pub fn turn_on_zone(&self, zone: &str) -> HashMap<SerialNumber, WemoResult> {
+    self.set_zone_state(zone, WemoState::On)
+  }
+
+  /// Turn off every device in a zone. See `set_zone_state`.
+  pub fn turn_off_zone(&self, zone: &str)
+      -> HashMap<SerialNumber, WemoResult> {
+    self.set_zone_state(zone, WemoState::Off)
+  }
+
+  /// Look up a device by one of its aliases.
+  pub fn by_alias(&self, alias: &str) -> Option<Arc<Switch>> {
+    let serial_number = self.metadata.read()
+        .ok()
+        .and_then(|all| {
+          all.iter()
+              .find(|&(_, metadata)| metadata.aliases.iter()
+                  .any(|existing| existing == alias))
+              .map(|(serial_number, _)| serial_number.clone())
+        });
+
+    serial_number.and_then(|serial_number| self.get(&serial_number))
+  }
+
+  /// Look up a device by serial number.
+  pub fn get(&self, serial_number: &str) -> Option<Arc<Switch>> {
+    self.devices.read()
+        .ok()
+        .and_then(|devices| devices.get(serial_number).cloned())
+  }
+
+  /// A device's state transitions at or after `since`, oldest first. Only
+  /// kept when the registry was created `with_history`.
+  pub fn history(&self, serial_number: &str, since: SystemTime)
+      -> Vec<StateReport> {
+    self.get(serial_number)
+        .map(|switch| switch.history(since))
+        .unwrap_or_default()
+  }
+
+  /// Remove a device, and its metadata, by serial number.
+  pub fn remove(&self, serial_number: &str) -> Option<Arc<Switch>> {
+    if let Ok(mut metadata) = self.metadata.write() {
+      metadata.remove(serial_number);
+    }
+    if let Ok(mut offline) = self.offline.write() {
+      offline.remove(serial_number);
+    }
+    self.devices.write()
+        .ok()
+        .and_then(|mut devices| devices.remove(serial_number))
+  }
+
+  /// All known devices.
+  pub fn devices(&self) -> Vec<Arc<Switch>> {
+    self.devices.read()
+        .map(|devices| devices.values().cloned().collect())
+        .unwrap_or_default()
+  }
+
+  /// Call `callback` whenever a device comes back online after going
+  /// offline. See `report_presence`.
+  pub fn on_online<F>(&self, callback: F)
+                      where F: Fn(&Arc<Switch>) + Sync + Send + 'static {
+    if let Ok(mut callbacks) = self.online_callbacks.write() {
+      callbacks.push(Arc::new(callback));
+    }
+  }
+
+  /// Call `callback` whenever a device goes offline, eg. to alert when a
+  /// plug falls off the network. See `report_presence`.
+  pub fn on_offline<F>(&self, callback: F)
+                       where F: Fn(&Arc<Switch>) + Sync + Send + 'static {
+    if let Ok(mut callbacks) = self.offline_callbacks.write() {
+      callbacks.push(Arc::new(callback));
+    }
+  }
+
+  /// Whether the device hasn't been reported offline since it was last
+  /// reported online. Unknown devices aren't online.
+  pub fn is_online(&self, serial_number: &str) -> bool {
+    self.get(serial_number).is_some() && self.offline.read()
+        .map(|offline| !offline.contains(serial_number))
+        .unwrap_or(true)
+  }
+
+  /// Record whether a device is online, calling the `on_online` or
+  /// `on_offline` callbacks if that changed. This is fed by `monitor`,
+  /// `handle_notification`, and `handle_renewal`, but may also be called
+  /// with the results of your own health checks. Unknown devices are
+  /// ignored.
+  pub fn report_presence(&self, serial_number: &str, online: bool) {
+    let switch = match self.get(serial_number) {
+      None => { return; },
+      Some(switch) => { switch },
+    };
+
+    let changed = match self.offline.write() {
+      Err(_) => false,
+      Ok(mut offline) => {
+        if online {
+          offline.remove(serial_number)
+        } else {
+          offline.insert(serial_number.to_string())
+        }
+      },
+    };
+
+    if !changed {
+      return;
+    }
+
+    info!(target: "wemo", serial = serial_number, online = online,
+        action = "presence"; "Device {} went {}", serial_number,
+        if online { "online" } else { "offline" });
+
+    let callbacks = if online {
+      &self.online_callbacks
+    } else {
+      &self.offline_callbacks
+    };
+
+    // Cloned so callbacks can use the registry.
+    let callbacks = callbacks.read()
+        .map(|callbacks| callbacks.clone())
+        .unwrap_or_default();
+
+    for callback in callbacks.iter() {
+      callback(&switch);
+    }
+  }
+
+  /// Ping the known devices every `interval` with a `LivenessMonitor`,
+  /// reporting their presence. Devices added later aren't pinged until the
+  /// monitor is restarted.
+  pub fn monitor(registry: Arc<Registry>, interval: Duration,
+                 timeout: Duration) -> LivenessMonitor {
+    let devices = registry.devices();
+    LivenessMonitor::start_with(devices, interval, timeout,
+        move |switch, reachable| {
+          if let Some(ref serial_number) = switch.serial_number {
+            registry.report_presence(serial_number, reachable);
+          }
+        })
+  }
+
+  /// Apply the outcome of renewing a subscription, eg. from
+  /// `Subscriptions::with_renewal_callback`. A failed renewal reports the
+  /// device at the subscription's IP address offline, and a successful one
+  /// online.
+  pub fn handle_renewal(&self, subscription_key: &str, renewed: bool) {
+    let ip_address = match SocketAddr::from_str(subscription_key) {
+      Err(_) => { return; },
+      Ok(socket) => { socket.ip() },
+    };
+
+    let serial_numbers = self.devices().iter()
+        .filter(|switch| switch.get_ip_address() == Some(ip_address))
+        .filter_map(|switch| switch.serial_number.clone())
+        .collect::<Vec<_>>();
+
+    for serial_number in serial_numbers.iter() {
+      self.report_presence(serial_number, renewed);
+    }
+  }
+
+  /// Apply an SSDP announcement. An `ssdp:alive` from a known device updates
+  /// its cached location, so the first request after a DHCP renewal doesn't
+  /// fail, and reports it online. An `ssdp:byebye` reports it offline.
+  /// Unknown devices are ignored.
+  #[cfg(feature = "discovery")]
+  pub fn handle_notification(&self, notification: &SsdpNotification) {
+    match *notification {
+      SsdpNotification::Alive(ref response) => {
+        if let Some(switch) = self.get(&response.serial_number) {
+          if switch.get_ip_address() != Some(response.ip_address)
+              || switch.get_port() != Some(response.port) {
+            info!(target: "wemo", serial = response.serial_number.as_str(),
+                ip:% = response.ip_address, port = response.port,
+                action = "relocate"; "Device {} moved to {}:{}",
+                response.serial_number, response.ip_address, response.port);
+          }
+          switch.update_from_ssdp(response);
+          self.report_presence(&response.serial_number, true);
+
+          if let Some(ref locations) = self.locations {
+            if locations.remember(&switch) {
+              if let Err(e) = locations.save() {
+                warn!(target: "wemo", action = "save_locations";
+                    "Couldn't save device locations: {:?}", e);
+              }
+            }
+          }
+        }
+      },
+      SsdpNotification::ByeBye { ref serial_number } => {
+        self.report_presence(serial_number, false);
+      },
+    }
+  }
+
+  /// Listen for SSDP announcements in the background and apply them to the
+  /// registry until the returned listener is dropped.
+  #[cfg(feature = "discovery")]
+  pub fn listen(registry: Arc<Registry>) -> Result<NotifyListener, WemoError> {
+    NotifyListener::start(move |notification| {
+      registry.handle_notification(&notification);
+    })
+  }
+}
+
+impl Default for Registry {
+  fn default() -> Registry {
+    Registry::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  #[cfg(feature = "discovery")] use net::notify::SsdpNotification;
+  #[cfg(feature = "discovery")] use net::ssdp::SsdpResponse;
+  use std::net::IpAddr;
+  use std::str::FromStr;
+  use std::sync::Arc;
+  use super::*;
+  #[cfg(feature = "discovery")] use net::location::SetupUrl;
+
+  fn ip(ip_address: &str) -> IpAddr {
+    IpAddr::from_str(ip_address).unwrap()
+  }
+
+  #[cfg(feature = "discovery")]
+  fn response(serial: &str, ip_address: &str, port: u16) -> SsdpResponse {
+    SsdpResponse {
+      serial_number: serial.to_string(),
+      udn: format!("uuid:Socket-1_0-{}", serial),
+      ip_address: ip(ip_address),
+      port: port,
+      setup_url: SetupUrl::parse(&format!("http://{}:{}/setup.xml", ip_address,
+          port)).unwrap(),
+      server: None,
+    }
+  }
+
+  #[test]
+  fn test_insert_requires_serial() {
+    let registry = Registry::new();
+    let switch = Switch::from_dynamic_ip(ip("1.1.1.1"));
+    assert!(registry.insert(Arc::new(switch)).is_err());
+  }
+
+  #[cfg(feature = "discovery")]
+  #[test]
+  fn test_alive_updates_location() {
+    let registry = Registry::new();
+    let mut switch = Switch::from_dynamic_ip_and_port(ip("1.1.1.1"), 49153);
+    switch.serial_number = Some("ABC".to_string());
+    registry.insert(Arc::new(switch)).unwrap();
+
+    let alive = SsdpNotification::Alive(response("ABC", "2.2.2.2", 49154));
+    registry.handle_notification(&alive);
+
+    let switch = registry.get("ABC").unwrap();
+    assert_eq!(Some(ip("2.2.2.2")), switch.get_ip_address());
+    assert_eq!(Some(49154), switch.get_port());
+
+    // Unknown devices aren't added.
+    let alive = SsdpNotification::Alive(response("XYZ", "3.3.3.3", 49153));
+    registry.handle_notification(&alive);
+    assert!(registry.get("XYZ").is_none());
+  }
+
+  #[cfg(feature = "discovery")]
+  #[test]
+  fn test_presence() {
+    let registry = Registry::new();
+    let mut switch = Switch::from_dynamic_ip_and_port(ip("1.1.1.1"), 49153);
+    switch.serial_number = Some("ABC".to_string());
+    registry.insert(Arc::new(switch)).unwrap();
+
+    let changes = Arc::new(RwLock::new(Vec::new()));
+    let online = changes.clone();
+    registry.on_online(move |_| online.write().unwrap().push(true));
+    let offline = changes.clone();
+    registry.on_offline(move |_| offline.write().unwrap().push(false));
+
+    let bye_bye = SsdpNotification::ByeBye {
+      serial_number: "ABC".to_string(),
+    };
+    registry.handle_notification(&bye_bye);
+    registry.handle_notification(&bye_bye);
+    assert!(!registry.is_online("ABC"));
+
+    let alive = SsdpNotification::Alive(response("ABC", "1.1.1.1", 49153));
+    registry.handle_notification(&alive);
+    assert!(registry.is_online("ABC"));
+
+    // The device may have moved to another port since subscribing.
+    registry.handle_renewal("1.1.1.1:49152", false);
+    registry.handle_renewal("2.2.2.2:49153", true);
+    registry.report_presence("XYZ", false);
+
+    assert_eq!(vec![false, true, false], *changes.read().unwrap());
+    assert!(!registry.is_online("XYZ"));
+  }
+
+  #[test]
+  fn test_aliases() {
+    let registry = Registry::new();
+    registry.insert_definitions(&config::parse_toml(r#"
+      [[devices]]
+      name = "Porch light"
+      serial_number = "ABC"
+      ip_address = "1.1.1.1"
+      aliases = ["porch", "front"]
+
+      [[devices]]
+      serial_number = "XYZ"
+      ip_address = "2.2.2.2"
+    "#).unwrap()).unwrap();
+
+    let by_alias = |alias| registry.by_alias(alias)
+        .and_then(|switch| switch.serial_number.clone());
+
+    assert_eq!(Some("ABC".to_string()), by_alias("porch"));
+    assert_eq!(None, by_alias("Porch light"));
+
+    // Aliases move between devices.
+    registry.set_alias("XYZ", "front").unwrap();
+    assert_eq!(Some("XYZ".to_string()), by_alias("front"));
+    assert_eq!(vec!["porch".to_string()],
+        registry.metadata("ABC").unwrap().aliases);
+
+    assert!(registry.remove_alias("porch"));
+    assert!(!registry.remove_alias("porch"));
+    assert_eq!(None, by_alias("porch"));
+
+    let definitions = registry.definitions();
+    assert_eq!(2, definitions.len());
+    assert_eq!(Some("Porch light".to_string()), definitions[0].name);
+    assert_eq!(vec!["front".to_string()], definitions[1].aliases);
+  }
+
+  #[test]
+  fn test_zones() {
+    let registry = Registry::new();
+    registry.insert_definitions(&config::parse_toml(r#"
+      [[devices]]
+      serial_number = "ABC"
+      ip_address = "127.0.0.1"
+      port = 1
+      zones = ["upstairs", "bedroom"]
+
+      [[devices]]
+      serial_number = "XYZ"
+      ip_address = "127.0.0.1"
+      port = 1
+    "#).unwrap()).unwrap();
+
+    registry.add_to_zone("XYZ", "upstairs").unwrap();
+    registry.add_to_zone("XYZ", "upstairs").unwrap();
+
+    assert_eq!(vec!["bedroom".to_string(), "upstairs".to_string()],
+        registry.zones());
+
+    let serial_numbers = |zone| registry.zone(zone).iter()
+        .filter_map(|switch| switch.serial_number.clone())
+        .collect::<Vec<_>>();
+
+    assert_eq!(vec!["ABC".to_string(), "XYZ".to_string()],
+        serial_numbers("upstairs"));
+
+    assert!(registry.remove_from_zone("ABC", "upstairs"));
+    assert!(!registry.remove_from_zone("ABC", "upstairs"));
+    assert_eq!(vec!["XYZ".to_string()], serial_numbers("upstairs"));
+    assert_eq!(vec!["bedroom".to_string()], registry.definitions()[0].zones);
+
+    // Nothing listens on port 1, so every request fails quickly.
+    let results = registry.get_zone_states("upstairs",
+        Duration::milliseconds(500));
+    assert_eq!(1, results.len());
+    assert!(results.get("XYZ").unwrap().is_err());
+    assert!(registry.get_zone_states("attic", Duration::zero()).is_empty());
+  }
+
+  #[test]
+  fn test_history() {
+    let registry = Registry::new().with_history(10);
+    let mut switch = Switch::from_dynamic_ip_and_port(ip("1.1.1.1"), 49153);
+    switch.serial_number = Some("ABC".to_string());
+    registry.insert(Arc::new(switch)).unwrap();
+
+    let since = SystemTime::now();
+    registry.get("ABC").unwrap().record_push(WemoState::On);
+
+    let history = registry.history("ABC", since);
+    assert_eq!(1, history.len());
+    assert_eq!(WemoState::On, history[0].state);
+    assert!(registry.history("XYZ", since).is_empty());
+  }
+}
@@ -0,0 +1,257 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Pairing devices with Belkin's cloud, enabled with the `remoteaccess`
+//! feature. The WeMo app pairs a device with its `RemoteAccess` action,
+//! after which the device keeps a connection to the cloud open so the app
+//! can control it from anywhere. `is_paired` and `audit` check that without
+//! changing anything, and `RemoteAccess::unpair` turns it off again, eg. for
+//! a fleet that should only be controlled locally.
+
+use bulk::MAX_CONCURRENT_REQUESTS;
+use deadline::Deadline;
+use device::SerialNumber;
+use device::service::Service;
+use device::switch::Switch;
+use error::WemoError;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use threads;
+use time::Duration;
+use xml::find_tag_value;
+
+pub const REMOTE_ACCESS_SERVICE: &'static str =
+    "urn:Belkin:service:remoteaccess:1";
+
+/// `dst` values for `RemoteAccess`.
+const PAIR: &'static str = "0";
+const UNPAIR: &'static str = "2";
+
+/// The phone or app that remote access is granted to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Requester {
+  /// Identifies the requester to the cloud, eg. the app's install ID.
+  pub device_id: String,
+  /// Shown in the WeMo app's list of paired devices.
+  pub device_name: String,
+}
+
+/// What the device and cloud agreed on when pairing.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Pairing {
+  pub home_id: String,
+  pub plugin_private_key: String,
+  pub smart_private_key: String,
+  pub smart_unique_id: String,
+  /// How many requesters the device is now paired with.
+  pub paired_count: Option<u32>,
+}
+
+impl Pairing {
+  /// Parse a `RemoteAccessResponse`. A failed pairing has a `statusCode` of
+  /// `F`, with the cloud's reason in `description`.
+  pub fn parse(body: &str) -> Result<Pairing, WemoError> {
+    let field = |name: &str| {
+      find_tag_value(name, body).map(|value| value.trim().to_string())
+    };
+
+    match field("statusCode").as_deref() {
+      Some("S") => {},
+      Some(_) => {
+        warn!(target: "wemo", action = "remote_access";
+            "Remote access refused: {}",
+            field("description").unwrap_or_default());
+        return Err(WemoError::WemoError);
+      },
+      None => { return Err(WemoError::ParsingError); },
+    }
+
+    Ok(Pairing {
+      home_id: field("homeId").unwrap_or_default(),
+      plugin_private_key: field("pluginprivateKey").unwrap_or_default(),
+      smart_private_key: field("smartprivateKey").unwrap_or_default(),
+      smart_unique_id: field("smartUniqueId").unwrap_or_default(),
+      paired_count: field("numSmartDev").and_then(|n| n.parse().ok()),
+    })
+  }
+}
+
+/// A device's `remoteaccess` service. Older firmware doesn't have one.
+pub struct RemoteAccess<'a> {
+  switch: &'a Switch,
+  service: Service,
+}
+
+impl<'a> RemoteAccess<'a> {
+  pub fn connect(switch: &'a Switch, timeout: Duration)
+      -> Result<RemoteAccess<'a>, WemoError> {
+    let service = find_service(switch, REMOTE_ACCESS_SERVICE, timeout)?;
+    Ok(RemoteAccess {
+      switch: switch,
+      service: service,
+    })
+  }
+
+  /// Pair the device with Belkin's cloud on behalf of `requester`.
+  pub fn pair(&self, requester: &Requester, timeout: Duration)
+      -> Result<Pairing, WemoError> {
+    let body = self.send(PAIR, requester, None, timeout)?;
+    Pairing::parse(&body)
+  }
+
+  /// Remove an earlier pairing, so the cloud can no longer reach the device
+  /// on the requester's behalf.
+  pub fn unpair(&self, requester: &Requester, pairing: &Pairing,
+                timeout: Duration) -> Result<(), WemoError> {
+    let body = self.send(UNPAIR, requester, Some(pairing), timeout)?;
+    Pairing::parse(&body).map(|_| ())
+  }
+
+  fn send(&self, dst: &str, requester: &Requester, pairing: Option<&Pairing>,
+          timeout: Duration) -> Result<String, WemoError> {
+    let arguments = remote_access_arguments(dst, requester, pairing);
+
+    info!(target: "wemo", serial:? = self.switch.serial_number,
+        action = "remote_access", dst = dst;
+        "Remote access for {} on {}", requester.device_name,
+        self.switch.name());
+
+    self.switch.send_action(&self.service, "RemoteAccess", &arguments,
+        timeout)
+  }
+}
+
+/// Whether the device belongs to a cloud home, ie. has been paired for
+/// remote access. Only reads the device's home ID.
+pub fn is_paired(switch: &Switch, timeout: Duration)
+    -> Result<bool, WemoError> {
+  let deadline = Deadline::after(timeout);
+  let service = find_service(switch,
+      &switch.config().basic_event_service, timeout)?;
+  let body = switch.send_action(&service, "GetHomeId", &[],
+      deadline.check()?)?;
+
+  let home_id = find_tag_value("HomeId", &body)
+      .ok_or(WemoError::ParsingError)?;
+  Ok(!home_id.trim().is_empty())
+}
+
+/// Check whether each device has been paired for remote access, concurrently
+/// like `bulk::get_states`. Results are keyed the same way.
+pub fn audit<S>(switches: &[S], timeout: Duration)
+    -> HashMap<SerialNumber, Result<bool, WemoError>>
+    where S: Borrow<Switch> + Sync {
+  let results = threads::map_bounded(switches, MAX_CONCURRENT_REQUESTS,
+      |switch| is_paired(switch.borrow(), timeout));
+
+  switches.iter()
+      .zip(results)
+      .filter_map(|(switch, result)| {
+        let switch = switch.borrow();
+        let key = switch.serial_number.clone()
+            .unwrap_or_else(|| switch.name());
+        result.map(|result| (key, result))
+      })
+      .collect()
+}
+
+fn find_service(switch: &Switch, service_type: &str, timeout: Duration)
+    -> Result<Service, WemoError> {
+  switch.list_services(timeout)?
+      .into_iter()
+      .find(|service| service.service_type == service_type)
+      .ok_or_else(|| WemoError::InvalidArgument {
+        reason: format!("{} has no service {}", switch.name(), service_type),
+      })
+}
+
+/// The arguments `RemoteAccess` takes. The keys are left empty when
+/// pairing, for the device and cloud to fill in.
+fn remote_access_arguments(dst: &str, requester: &Requester,
+                           pairing: Option<&Pairing>)
+    -> Vec<(String, String)> {
+  let field = |value: fn(&Pairing) -> &String| {
+    pairing.map(|pairing| value(pairing).clone()).unwrap_or_default()
+  };
+
+  vec![
+    ("DeviceId".to_string(), requester.device_id.clone()),
+    ("dst".to_string(), dst.to_string()),
+    ("HomeId".to_string(), field(|pairing| &pairing.home_id)),
+    ("DeviceName".to_string(), requester.device_name.clone()),
+    ("MacAddr".to_string(), String::new()),
+    ("pluginprivateKey".to_string(),
+        field(|pairing| &pairing.plugin_private_key)),
+    ("smartprivateKey".to_string(),
+        field(|pairing| &pairing.smart_private_key)),
+    ("smartUniqueId".to_string(), field(|pairing| &pairing.smart_unique_id)),
+    ("numSmartDev".to_string(), String::new()),
+  ]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const RESPONSE: &'static str = "\
+      <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">\
+        <s:Body>\
+          <u:RemoteAccessResponse \
+              xmlns:u=\"urn:Belkin:service:remoteaccess:1\">\
+            <homeId>1101801</homeId>\
+            <pluginprivateKey>aABbCc-1234</pluginprivateKey>\
+            <smartprivateKey>dDeEfF-5678</smartprivateKey>\
+            <resultCode>PLGN_200</resultCode>\
+            <description>Successful</description>\
+            <statusCode>S</statusCode>\
+            <smartUniqueId>android-1</smartUniqueId>\
+            <numSmartDev>1</numSmartDev>\
+          </u:RemoteAccessResponse>\
+        </s:Body>\
+      </s:Envelope>";
+
+  #[test]
+  fn test_parse_pairing() {
+    let pairing = Pairing::parse(RESPONSE).unwrap();
+    assert_eq!("1101801", pairing.home_id);
+    assert_eq!("aABbCc-1234", pairing.plugin_private_key);
+    assert_eq!("dDeEfF-5678", pairing.smart_private_key);
+    assert_eq!("android-1", pairing.smart_unique_id);
+    assert_eq!(Some(1), pairing.paired_count);
+
+    let refused = RESPONSE.replace("<statusCode>S", "<statusCode>F");
+    match Pairing::parse(&refused) {
+      Err(WemoError::WemoError) => {},
+      other => panic!("expected a refusal, got {:?}", other),
+    }
+    match Pairing::parse("<s:Envelope></s:Envelope>") {
+      Err(WemoError::ParsingError) => {},
+      other => panic!("expected a parsing error, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_remote_access_arguments() {
+    let requester = Requester {
+      device_id: "android-1".to_string(),
+      device_name: "Phone".to_string(),
+    };
+    let value = |arguments: &[(String, String)], name: &str| {
+      arguments.iter()
+          .find(|&&(ref key, _)| key == name)
+          .map(|&(_, ref value)| value.clone())
+          .unwrap()
+    };
+
+    let pair = remote_access_arguments(PAIR, &requester, None);
+    assert_eq!(9, pair.len());
+    assert_eq!("0", value(&pair, "dst"));
+    assert_eq!("Phone", value(&pair, "DeviceName"));
+    assert_eq!("", value(&pair, "HomeId"));
+
+    let pairing = Pairing::parse(RESPONSE).unwrap();
+    let unpair = remote_access_arguments(UNPAIR, &requester, Some(&pairing));
+    assert_eq!("2", value(&unpair, "dst"));
+    assert_eq!("1101801", value(&unpair, "HomeId"));
+    assert_eq!("aABbCc-1234", value(&unpair, "pluginprivateKey"));
+  }
+}
@@ -1,32 +1,71 @@
 // Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
 
+use capture::{self, CaptureKind};
+use config::{ParsingMode, global_config};
+use device::SerialNumber;
 use device::state::WemoState;
+use device::switch::Switch;
 use error::WemoError;
 use get_if_addrs::IfAddr;
 use get_if_addrs::get_if_addrs;
+use iron::Handler;
 use iron::Iron;
 use iron::IronError;
 use iron::IronResult;
 use iron::Listening;
-use iron::Plugin;
 use iron::Request;
 use iron::Response;
 use iron::status;
-use parsing::parse_state;
+use metrics;
+use net::http::{check_header, check_headers, parse_status};
+use net::ports::DevicePorts;
+use net::ssdp::{SsdpResponse, UPNP_PORT};
+use parsing::{parse_attribute_list, parse_brightness, parse_bulb_event};
+use parsing::parse_state_with;
+use queue::BoundedQueue;
 use std::boxed::Box;
 use std::collections::HashMap;
 use std::io::Read;
 use std::io::Write;
 use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
 use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::net::UdpSocket;
 use std::ops::Fn;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, Sender, channel};
 use std::thread::JoinHandle;
-use std::thread::Thread;
-use std::thread;
-use std::time::Duration;
-use urlencoded::UrlEncodedQuery;
+use std::time::{Duration, SystemTime};
+use threads;
+
+pub use queue::OverflowPolicy;
+
+/// Where devices publish switch events.
+const BASIC_EVENT_PATH: &'static str = "/upnp/event/basicevent1";
+
+/// Where WeMo Link bridges publish bulb events.
+const BRIDGE_EVENT_PATH: &'static str = "/upnp/event/bridge1";
+
+/// Where Makers publish sensor and relay events.
+const DEVICE_EVENT_PATH: &'static str = "/upnp/event/deviceevent1";
+
+/// How often subscriptions are renewed.
+const RENEW_SECS: u64 = 30;
+
+/// How often renewal is retried while every subscription is failing to renew,
+/// eg. while the network is down.
+const RECOVERY_SECS: u64 = 5;
+
+/// How long SUBSCRIBE and UNSUBSCRIBE requests wait to connect, send, and
+/// read the response.
+const GENA_TIMEOUT_SECS: u64 = 1;
 
 /// Individual subscription notifications.
 #[derive(Clone, Debug, PartialEq)]
@@ -37,31 +76,651 @@ pub struct Notification {
   /// Note that the port may have been changed by the Wemo device, and that the
   /// IP could differ if the router changed it.
   pub subscription_key: String,
+
+  /// When the event carrying the notification arrived, eg. for measuring
+  /// latency.
+  pub received_at: SystemTime,
+
+  /// Counts up from 1 with each notification from the subscription, for
+  /// ordering notifications and dropping duplicates.
+  pub sequence: u64,
+
+  /// Whether this came from polling the device after events may have been
+  /// missed, eg. a failed renewal or a gap in the device's `SEQ` numbers,
+  /// rather than from an event. Only `State` notifications are reconciled.
+  pub reconciled: bool,
 }
 
 /// Each type of supported notification.
 /// More may be added in the future.
 #[derive(Clone, Debug, PartialEq)]
 pub enum NotificationType {
-  State { state: WemoState }
+  State { state: WemoState },
+
+  /// The device went from `On` to `OnWithoutLoad`: it's still on, but the
+  /// appliance plugged into it stopped drawing power. Sent after the `State`
+  /// notification.
+  LoadRemoved,
+
+  /// The device went from `OnWithoutLoad` back to `On`. Sent after the
+  /// `State` notification.
+  LoadRestored,
+
+  /// A Dimmer's brightness changed, eg. at the wall switch. `level` is a
+  /// percentage.
+  Brightness { level: u8 },
+
+  /// A bulb on a WeMo Link bridge changed. Only sent for subscriptions made
+  /// with `Subscriptions::subscribe_bridge`.
+  Bulb { device_id: String, change: BulbChange },
+
+  /// A Maker's sensor input changed, eg. a doorbell or garage door contact.
+  /// Only sent for subscriptions made with `Subscriptions::subscribe_maker`.
+  Sensor { triggered: bool },
+
+  /// A Maker's relay switched. Only sent for subscriptions made with
+  /// `Subscriptions::subscribe_maker`.
+  Relay { on: bool },
+}
+
+/// What changed about a bulb on a WeMo Link bridge.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BulbChange {
+  OnOff { on: bool },
+  /// Brightness from 0 to 255.
+  Brightness { level: u8 },
+  /// A capability that isn't decoded, eg. color temperature, with its raw
+  /// value.
+  Other { capability: String, value: String },
+}
+
+impl BulbChange {
+  fn from_capability(capability: &str, value: &str) -> BulbChange {
+    // Brightness is "LEVEL" or "LEVEL:TRANSITION_TIME".
+    let first = value.split(':').next().unwrap_or("").trim();
+
+    let decoded = match capability {
+      "10006" => first.parse::<u8>().ok().map(|on| {
+        BulbChange::OnOff { on: on != 0 }
+      }),
+      "10008" => first.parse::<u8>().ok().map(|level| {
+        BulbChange::Brightness { level: level }
+      }),
+      _ => None,
+    };
+
+    decoded.unwrap_or_else(|| BulbChange::Other {
+      capability: capability.to_string(),
+      value: value.to_string(),
+    })
+  }
 }
 
 struct Subscription {
-  callback: Option<Box<Fn(Notification) + Sync + Send>>,
+  callback: Option<Arc<Fn(Notification) + Sync + Send>>,
+
+  /// Ports the device may have moved to since subscribing.
+  ports: Mutex<DevicePorts>,
+
+  /// The state reported by the previous event, for noticing transitions.
+  last_state: Mutex<Option<WemoState>>,
+
+  /// The UPnP event URL subscribed to.
+  event_path: &'static str,
+
+  /// The sequence number of the last notification.
+  sequence: AtomicU64,
+
+  /// The `SID` and `SEQ` of the previous event, for noticing missed ones.
+  event_seq: Mutex<Option<(Option<String>, u32)>>,
+
+  /// Set when events may have been missed, until the state is polled.
+  needs_reconcile: AtomicBool,
+
+  /// The `SID` the device gave the latest subscription, for renewing and
+  /// unsubscribing.
+  sid: Mutex<Option<String>>,
+
+  /// The host the device was asked to call back on, eg. the local IP.
+  callback_host: Mutex<String>,
+
+  /// Set while the subscription is live, for a switch trusting its pushes.
+  /// See `Switch::attach_subscriptions`.
+  push_fed: Mutex<Option<Arc<AtomicBool>>>,
+
+  counters: Arc<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+  /// Notifications dropped because the queue was full.
+  overflowed: AtomicU64,
+
+  /// Times the callback panicked.
+  panics: AtomicU64,
+}
+
+/// How a subscription is doing, eg. for health checks.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SubscriptionStatus {
+  /// The device subscribed to, in "IP:PORT" form.
+  pub subscription_key: String,
+
+  /// The UPnP event URL subscribed to.
+  pub event_path: String,
+
+  /// The port the device was last subscribed to on, if known.
+  pub port: Option<u16>,
+
+  /// The sequence number of the last notification.
+  pub sequence: u64,
+
+  /// Notifications dropped because the queue was full. Dropped notifications
+  /// leave gaps in the sequence numbers delivered.
+  pub overflowed: u64,
+
+  /// Times the callback panicked. Panics are caught and logged, and later
+  /// notifications are still delivered.
+  pub panics: u64,
+}
+
+/// A notification waiting in the queue for its callback.
+struct Delivery {
+  callback: Arc<Fn(Notification) + Sync + Send>,
+  notification: Notification,
+  counters: Arc<Counters>,
+}
+
+impl Delivery {
+  /// Invoke the callback, catching and logging any panic so it doesn't take
+  /// down the server.
+  fn invoke(self) {
+    let Delivery { callback, notification, counters } = self;
+    let subscription_key = notification.subscription_key.clone();
+    let sequence = notification.sequence;
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+      callback(notification)
+    }));
+
+    if let Err(cause) = result {
+      counters.panics.fetch_add(1, Ordering::SeqCst);
+      let message = threads::panic_message(&*cause);
+      error!(target: "wemo", subscription_key = subscription_key.as_str(),
+          sequence = sequence;
+          "Notification callback panicked: {}", message);
+    }
+  }
+}
+
+/// Invokes callbacks from a background thread, so slow callbacks don't hold
+/// up devices' requests. Stops once queued notifications are delivered when
+/// dropped.
+struct Dispatcher {
+  queue: Arc<BoundedQueue<Delivery>>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl Dispatcher {
+  fn start(capacity: usize, policy: OverflowPolicy) -> Dispatcher {
+    let queue = Arc::new(BoundedQueue::<Delivery>::new(capacity, policy));
+    let pending = queue.clone();
+
+    let handle = threads::spawn("wemo-dispatch", move || {
+      while let Some(delivery) = pending.pop() {
+        delivery.invoke();
+      }
+    });
+
+    Dispatcher {
+      queue: queue,
+      handle: Some(handle),
+    }
+  }
+
+  fn deliver(&self, delivery: Delivery) {
+    if let Some(dropped) = self.queue.push(delivery) {
+      dropped.counters.overflowed.fetch_add(1, Ordering::SeqCst);
+      warn!(target: "wemo",
+          subscription_key = dropped.notification.subscription_key.as_str(),
+          sequence = dropped.notification.sequence;
+          "Notification queue is full, dropped a notification");
+    }
+  }
 }
 
+impl Drop for Dispatcher {
+  fn drop(&mut self) {
+    self.queue.close();
+    if let Some(handle) = self.handle.take() {
+      let _r = handle.join();
+    }
+  }
+}
+
+/// Processes event notifications from subscribed devices, independent of any
+/// HTTP server. The server started by `Subscriptions::start_server` is just
+/// one way to feed it; get one with `Subscriptions::handler` to pass it
+/// requests received some other way.
+#[derive(Clone)]
+pub struct NotificationHandler {
+  subscriptions: Arc<RwLock<HashMap<String, Arc<Subscription>>>>,
+  parsing_mode: ParsingMode,
+  dispatcher: Option<Arc<Dispatcher>>,
+}
+
+impl NotificationHandler {
+  /// Handle one NOTIFY request, invoking the subscription's callback. `path`
+  /// is the request path and query the device called back on, eg.
+  /// `/?from=192.168.1.4:49153`, which identifies the subscription.
+  pub fn handle(&self, path: &str, headers: &[(String, String)], body: &str)
+                -> Result<(), WemoError> {
+    self.handle_from(None, path, headers, body)
+  }
+
+  /// Handle an HTTP request routed from your own server, returning the status
+  /// code to respond with. Devices send NOTIFY requests; anything else gets
+  /// 405 Method Not Allowed.
+  pub fn handle_request(&self, method: &str, path: &str,
+                        headers: &[(String, String)], body: &str) -> u16 {
+    if method != "NOTIFY" && method != "POST" {
+      return 405;
+    }
+
+    match self.handle(path, headers, body) {
+      Ok(_) => 200,
+      Err(_) => 500,
+    }
+  }
+
+  fn handle_from(&self, peer: Option<SocketAddr>, path: &str,
+                 headers: &[(String, String)], body: &str)
+                 -> Result<(), WemoError> {
+    let received_at = SystemTime::now();
+    capture::record(CaptureKind::GenaNotify, peer, body.as_bytes());
+
+    // Only property changes carry state.
+    let nts = headers.iter()
+        .find(|&&(ref name, _)| name.eq_ignore_ascii_case("NTS"))
+        .map(|&(_, ref value)| value.trim());
+
+    match nts {
+      Some(nts) if nts != "upnp:propchange" => { return Ok(()); },
+      _ => {},
+    }
+
+    // Device is contained in a query string variable, "from".
+    let host = subscription_key(path).ok_or(WemoError::SubscriptionError)?;
+
+    let state = if body.contains("BinaryState") {
+      Some(parse_state_with(body, self.parsing_mode)?)
+    } else {
+      None
+    };
+
+    let brightness = if body.contains("<Brightness>") {
+      match parse_brightness(body) {
+        Ok(level) => Some(level),
+        Err(_) if self.parsing_mode == ParsingMode::Lenient => None,
+        Err(e) => { return Err(e); },
+      }
+    } else {
+      None
+    };
+
+    let bulb = if body.contains("StatusChange") {
+      match parse_bulb_event(body) {
+        Ok(event) => Some(event),
+        Err(_) if self.parsing_mode == ParsingMode::Lenient => None,
+        Err(e) => { return Err(e); },
+      }
+    } else {
+      None
+    };
+
+    let attributes = if body.contains("attributeList") {
+      match parse_attribute_list(body) {
+        Ok(attributes) => attributes,
+        Err(_) if self.parsing_mode == ParsingMode::Lenient => Vec::new(),
+        Err(e) => { return Err(e); },
+      }
+    } else {
+      Vec::new()
+    };
+
+    let maker = attributes.iter()
+        .filter_map(|&(ref name, ref value)| {
+          let value = value.parse::<u8>().ok()?;
+          match name.as_str() {
+            // NB: The sensor reads 1 when idle and 0 when triggered.
+            "Sensor" => {
+              Some(NotificationType::Sensor { triggered: value == 0 })
+            },
+            "Switch" => Some(NotificationType::Relay { on: value != 0 }),
+            _ => None,
+          }
+        })
+        .collect::<Vec<_>>();
+
+    let header = |wanted: &str| headers.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+        .map(|(_, value)| value.trim());
+
+    let event_sid = header("SID");
+    let event_seq = header("SEQ").and_then(|value| value.parse::<u32>().ok());
+
+    // An event carrying the state is as good as polling for it.
+    if let Some(event_seq) = event_seq {
+      if self.missed_events(&host, event_sid, event_seq) && state.is_none() {
+        self.reconcile_in_background(&host);
+      }
+    }
+
+    if state.is_none() && brightness.is_none() && bulb.is_none()
+        && maker.is_empty() {
+      // TODO: Handle other types of state update.
+      return Ok(());
+    }
+
+    let subscriptions = self.subscriptions.read()
+        .map_err(|_| WemoError::SubscriptionError)?;
+
+    let subscription = subscriptions.get(&host)
+        .ok_or(WemoError::SubscriptionError)?;
+
+    metrics::report(|metrics| metrics.on_event(&host));
+
+    let mut notification_types = Vec::new();
+
+    if let Some(state) = state {
+      notification_types.extend(state_changes(subscription, state));
+    }
+
+    if let Some(level) = brightness {
+      notification_types.push(NotificationType::Brightness { level: level });
+    }
+
+    if let Some(event) = bulb {
+      notification_types.push(NotificationType::Bulb {
+        change: BulbChange::from_capability(&event.capability, &event.value),
+        device_id: event.device_id,
+      });
+    }
+
+    notification_types.extend(maker);
+
+    let deliveries = deliveries(subscription, &host, notification_types,
+        received_at, false);
+
+    // NB: Don't hold up other subscriptions while waiting on the queue.
+    drop(subscriptions);
+
+    self.dispatch(deliveries);
+    Ok(())
+  }
+
+  fn dispatch(&self, deliveries: Vec<Delivery>) {
+    for delivery in deliveries.into_iter() {
+      match self.dispatcher {
+        None => delivery.invoke(),
+        Some(ref dispatcher) => dispatcher.deliver(delivery),
+      }
+    }
+  }
+
+  /// Record the device's `SEQ` for an event, returning whether events were
+  /// missed since the previous one. Devices count from 0 for each `SID`,
+  /// wrapping around to 1, so events from a new subscription start afresh.
+  fn missed_events(&self, host: &str, event_sid: Option<&str>,
+      event_seq: u32) -> bool {
+    let subscriptions = match self.subscriptions.read() {
+      Err(_) => { return false; },
+      Ok(subscriptions) => subscriptions,
+    };
+
+    let subscription = match subscriptions.get(host) {
+      None => { return false; },
+      Some(subscription) => subscription,
+    };
+
+    let event_sid = event_sid.map(|sid| sid.to_string());
+    let previous = match subscription.event_seq.lock() {
+      Err(_) => { return false; },
+      Ok(mut last_seq) => last_seq.replace((event_sid.clone(), event_seq)),
+    };
+
+    let missed = match previous {
+      Some((ref previous_sid, previous))
+          if *previous_sid == event_sid && event_seq != 0 => {
+        let expected = if previous == u32::MAX { 1 } else { previous + 1 };
+        event_seq > expected
+      },
+      _ => false,
+    };
+
+    if missed {
+      warn!(target: "wemo", subscription_key = host,
+          event_seq = event_seq, previous:? = previous;
+          "Missed events from the device, polling its state");
+      subscription.needs_reconcile.store(true, Ordering::SeqCst);
+    }
+    missed
+  }
+
+  fn reconcile_in_background(&self, host: &str) {
+    let handler = self.clone();
+    let host = host.to_string();
+    threads::spawn("wemo-reconcile", move || {
+      let _r = handler.reconcile(&host);
+    });
+  }
+
+  /// Mark every subscription as possibly having missed events, eg. while the
+  /// server was down.
+  fn mark_all(&self) {
+    if let Ok(subscriptions) = self.subscriptions.read() {
+      for subscription in subscriptions.values() {
+        subscription.needs_reconcile.store(true, Ordering::SeqCst);
+      }
+    }
+  }
+
+  /// Reconcile each of `hosts` that may have missed events.
+  fn reconcile_marked(&self, hosts: &[String]) {
+    for host in hosts.iter() {
+      let marked = self.subscriptions.read()
+          .ok()
+          .and_then(|subscriptions| {
+            subscriptions.get(host)
+                .map(|s| s.needs_reconcile.load(Ordering::SeqCst))
+          })
+          .unwrap_or(false);
+
+      if marked {
+        let _r = self.reconcile(host);
+      }
+    }
+  }
+
+  /// Poll the state of the switch subscribed to at `host`, and deliver it as
+  /// a reconciled `State` notification so consumers catch up with any
+  /// events they missed.
+  fn reconcile(&self, host: &str) -> Result<(), WemoError> {
+    let location = {
+      let subscriptions = self.subscriptions.read()
+          .map_err(|_| WemoError::LockError)?;
+      let subscription = subscriptions.get(host)
+          .ok_or(WemoError::SubscriptionError)?;
+
+      // Only switches have a state to poll.
+      if subscription.event_path != BASIC_EVENT_PATH {
+        subscription.needs_reconcile.store(false, Ordering::SeqCst);
+        return Ok(());
+      }
+
+      let socket = SocketAddr::from_str(host)
+          .map_err(|_| WemoError::SubscriptionError)?;
+      let port = subscription.ports.lock()
+          .ok()
+          .and_then(|ports| ports.last_known())
+          .unwrap_or(socket.port());
+      SocketAddr::new(socket.ip(), port)
+    };
+
+    let switch = Switch::from_static_ip_and_port(location.ip(),
+        location.port());
+    let state = switch.get_state(switch.config().default_timeout)
+        .map_err(|error| {
+          debug!(target: "wemo", subscription_key = host;
+              "Couldn't poll the state to reconcile: {:?}", error);
+          error
+        })?;
+
+    let subscriptions = self.subscriptions.read()
+        .map_err(|_| WemoError::LockError)?;
+    let subscription = subscriptions.get(host)
+        .ok_or(WemoError::SubscriptionError)?;
+
+    subscription.needs_reconcile.store(false, Ordering::SeqCst);
+    info!(target: "wemo", subscription_key = host;
+        "Reconciled the state after missed events: {}", state);
+
+    let deliveries = deliveries(subscription, host,
+        state_changes(subscription, state), SystemTime::now(), true);
+    drop(subscriptions);
+
+    self.dispatch(deliveries);
+    Ok(())
+  }
+}
+
+/// The notifications for a new state reading: the state, then any change to
+/// whether the appliance is drawing power.
+fn state_changes(subscription: &Subscription, state: WemoState)
+                 -> Vec<NotificationType> {
+  let previous = subscription.last_state.lock()
+      .ok()
+      .and_then(|mut last_state| last_state.replace(state.clone()));
+
+  let load_change = match (previous, &state) {
+    (Some(WemoState::On), &WemoState::OnWithoutLoad) => {
+      Some(NotificationType::LoadRemoved)
+    },
+    (Some(WemoState::OnWithoutLoad), &WemoState::On) => {
+      Some(NotificationType::LoadRestored)
+    },
+    _ => None,
+  };
+
+  let mut notification_types = vec![NotificationType::State { state: state }];
+  notification_types.extend(load_change);
+  notification_types
+}
+
+/// Number the notifications for the subscription's callback, if it has one.
+fn deliveries(subscription: &Subscription, host: &str,
+              notification_types: Vec<NotificationType>,
+              received_at: SystemTime, reconciled: bool) -> Vec<Delivery> {
+  let callback = match subscription.callback {
+    None => { return Vec::new(); },
+    Some(ref callback) => callback.clone(),
+  };
+
+  notification_types.into_iter()
+      .map(|notification_type| Delivery {
+        callback: callback.clone(),
+        notification: Notification {
+          notification_type: notification_type,
+          subscription_key: host.to_string(),
+          received_at: received_at,
+          sequence: subscription.sequence.fetch_add(1, Ordering::SeqCst) + 1,
+          reconciled: reconciled,
+        },
+        counters: subscription.counters.clone(),
+      })
+      .collect()
+}
+
+impl Handler for NotificationHandler {
+  fn handle(&self, request: &mut Request) -> IronResult<Response> {
+    let mut body = String::new();
+    request.body.read_to_string(&mut body)
+        .map_err(|e| WemoError::IoError { cause: e })?;
+
+    let path = format!("/?{}", request.url.query().unwrap_or(""));
+    let headers = request.headers.iter()
+        .map(|header| (header.name().to_string(), header.value_string()))
+        .collect::<Vec<_>>();
+
+    self.handle_from(Some(request.remote_addr), &path, &headers, &body)?;
+
+    Ok(Response::with((status::Ok, "")))
+  }
+}
+
+/// The "from" query string variable of a callback path.
+fn subscription_key(path: &str) -> Option<String> {
+  let query = match path.find('?') {
+    None => { return None; },
+    Some(position) => { &path[position + 1..] },
+  };
+
+  query.split('&')
+      .filter_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+          (Some("from"), Some(value)) => percent_decode(value),
+          _ => None,
+        }
+      })
+      .next()
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+  let bytes = value.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+
+  while i < bytes.len() {
+    match bytes[i] {
+      b'%' => {
+        let hex = value.get(i + 1..i + 3)?;
+        decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        i += 3;
+      },
+      b'+' => { decoded.push(b' '); i += 1; },
+      byte => { decoded.push(byte); i += 1; },
+    }
+  }
+
+  String::from_utf8(decoded).ok()
+}
+
+/// Called with a subscription key and whether it renewed.
+type RenewalCallback = Arc<Fn(&str, bool) + Sync + Send>;
+
 /// Subscriptions objects manage Wemo device event notifications. You can
 /// register subscriptions against multiple devices; an Iron HTTP server will
 /// be started to receive callback notifications from the Wemo devices, and a
 /// background thread will handle subscription management. You should only
-/// ever need one of these objects.
+/// ever need one of these objects. When dropped, it stops resubscribing and
+/// unsubscribes from every device. The server stops handling notifications,
+/// but keeps its port until the process exits; see `stop_server`.
 pub struct Subscriptions {
   callback_port: u16,
+  callback_path: String,
+  advertised_host: Option<String>,
+  advertised_port: Option<u16>,
   subscription_ttl_sec: u16,
   server_handle: Option<Listening>,
-  polling_handle: Option<JoinHandle<Thread>>,
-  continue_polling: bool,
-  subscriptions: Arc<RwLock<HashMap<String, Subscription>>>,
+  polling_handle: Option<JoinHandle<()>>,
+  /// Dropped to stop the resubscribing thread.
+  stop_polling: Option<Sender<()>>,
+  subscriptions: Arc<RwLock<HashMap<String, Arc<Subscription>>>>,
+  headers: Vec<(String, String)>,
+  parsing_mode: ParsingMode,
+  dispatcher: Option<Arc<Dispatcher>>,
+  renewal_callback: Option<RenewalCallback>,
 }
 
 impl Subscriptions {
@@ -71,14 +730,114 @@ impl Subscriptions {
   pub fn new(callback_port: u16, subscription_ttl_sec: u16) -> Self {
     Subscriptions {
       callback_port: callback_port,
+      callback_path: "/".to_string(),
+      advertised_host: None,
+      advertised_port: None,
       subscription_ttl_sec: subscription_ttl_sec,
       server_handle: None,
       polling_handle: None,
-      continue_polling: false,
-      subscriptions: Arc::new(RwLock::new(HashMap::default()))
+      stop_polling: None,
+      subscriptions: Arc::new(RwLock::new(HashMap::default())),
+      headers: global_config().request_headers(),
+      parsing_mode: global_config().parsing_mode,
+      dispatcher: None,
+      renewal_callback: None,
+    }
+  }
+
+  /// Send an extra HTTP header with every SUBSCRIBE request. Fails if the
+  /// header can't be sent as given, eg. its value holds a line break.
+  pub fn with_header(mut self, name: &str, value: &str)
+      -> Result<Self, WemoError> {
+    check_header(name, value)?;
+    self.headers.push((name.to_string(), value.to_string()));
+    Ok(self)
+  }
+
+  /// Ask devices to call back on this path instead of `/`, eg. when
+  /// notifications are routed from your own server with `handler`.
+  pub fn with_callback_path(mut self, path: &str) -> Self {
+    self.callback_path = path.to_string();
+    self
+  }
+
+  /// Ask devices to call back on this host and port instead of the local IP
+  /// and callback port, eg. when the callback server is behind port
+  /// forwarding, in a container, or behind NAT. The server still listens on
+  /// the callback port.
+  pub fn with_advertised_address(mut self, host: &str, port: u16) -> Self {
+    self.advertised_host = Some(host.to_string());
+    self.advertised_port = Some(port);
+    self
+  }
+
+  /// Parse event notifications in this mode instead of the global
+  /// `WemoConfig`'s.
+  pub fn with_parsing_mode(mut self, parsing_mode: ParsingMode) -> Self {
+    self.parsing_mode = parsing_mode;
+    self
+  }
+
+  /// Invoke callbacks from a background thread, queueing up to `capacity`
+  /// notifications for them, instead of while handling the device's request.
+  /// When the queue is full, `policy` decides which notification is dropped,
+  /// if any. Dropped notifications are counted in `status`.
+  pub fn with_queue(mut self, capacity: usize, policy: OverflowPolicy)
+                    -> Self {
+    self.dispatcher = Some(Arc::new(Dispatcher::start(capacity, policy)));
+    self
+  }
+
+  /// Call `callback` with each subscription key and whether it renewed,
+  /// after every renewal, eg. for `Registry::handle_renewal`.
+  pub fn with_renewal_callback<F>(mut self, callback: F) -> Self
+                                  where F: Fn(&str, bool) + Sync + Send
+                                      + 'static {
+    self.renewal_callback = Some(Arc::new(callback));
+    self
+  }
+
+  /// A handler for this object's subscriptions, for feeding notifications
+  /// received by your own HTTP server.
+  pub fn handler(&self) -> NotificationHandler {
+    NotificationHandler {
+      subscriptions: self.subscriptions.clone(),
+      parsing_mode: self.parsing_mode,
+      dispatcher: self.dispatcher.clone(),
     }
   }
 
+  /// The status of each subscription, ordered by subscription key.
+  pub fn status(&self) -> Vec<SubscriptionStatus> {
+    let subscriptions = match self.subscriptions.read() {
+      Err(_) => { return Vec::new(); },
+      Ok(subscriptions) => subscriptions,
+    };
+
+    let mut statuses = subscriptions.iter()
+        .map(|(host, subscription)| SubscriptionStatus {
+          subscription_key: host.to_string(),
+          event_path: subscription.event_path.to_string(),
+          port: subscription.ports.lock()
+              .ok()
+              .and_then(|ports| ports.last_known()),
+          sequence: subscription.sequence.load(Ordering::SeqCst),
+          overflowed: subscription.counters.overflowed.load(Ordering::SeqCst),
+          panics: subscription.counters.panics.load(Ordering::SeqCst),
+        })
+        .collect::<Vec<_>>();
+
+    statuses.sort_by(|a, b| a.subscription_key.cmp(&b.subscription_key));
+    statuses
+  }
+
+  /// How many notifications are waiting in the queue for their callbacks.
+  pub fn queued(&self) -> usize {
+    self.dispatcher.as_ref()
+        .map(|dispatcher| dispatcher.queue.len())
+        .unwrap_or(0)
+  }
+
   /// Subscribe to push notifications from a Wemo device.
   /// The provided callback is invoked when notifications are received.
   /// This should be done after launching the server to avoid missing
@@ -86,75 +845,128 @@ impl Subscriptions {
   pub fn subscribe<F>(&self, host: &str, callback: F)
                       -> Result<(), WemoError>
                       where F: Fn(Notification) + Sync + Send + 'static {
-    let local_ip = get_local_ip()?;
+    self.subscribe_to(host, BASIC_EVENT_PATH, Arc::new(callback))
+  }
+
+  /// Subscribe to bulb events from a WeMo Link bridge, which are delivered as
+  /// `NotificationType::Bulb` notifications, keyed by end-device ID. This
+  /// keeps bulb states current without polling. Replaces any other
+  /// subscription to the same host.
+  pub fn subscribe_bridge<F>(&self, host: &str, callback: F)
+                             -> Result<(), WemoError>
+                             where F: Fn(Notification) + Sync + Send + 'static {
+    self.subscribe_to(host, BRIDGE_EVENT_PATH, Arc::new(callback))
+  }
+
+  /// Subscribe to sensor and relay events from a WeMo Maker, which are
+  /// delivered as `NotificationType::Sensor` and `NotificationType::Relay`
+  /// notifications. Replaces any other subscription to the same host.
+  pub fn subscribe_maker<F>(&self, host: &str, callback: F)
+                            -> Result<(), WemoError>
+                            where F: Fn(Notification) + Sync + Send + 'static {
+    self.subscribe_to(host, DEVICE_EVENT_PATH, Arc::new(callback))
+  }
 
-    send_subscribe(local_ip, host, self.subscription_ttl_sec,
-        self.callback_port)?;
+  /// Subscribe to every device in a search's results, eg. from
+  /// `DeviceSearch::search`, with one callback for them all. Notifications
+  /// are keyed by each device's `ip:port`. A device that can't be
+  /// subscribed to doesn't stop the others; each result is returned, keyed
+  /// by serial number.
+  pub fn subscribe_all<F>(&self, devices: &HashMap<SerialNumber, SsdpResponse>,
+                          callback: F)
+                          -> HashMap<SerialNumber, Result<(), WemoError>>
+                          where F: Fn(Notification) + Sync + Send + 'static {
+    let callback: Arc<Fn(Notification) + Sync + Send> = Arc::new(callback);
+
+    let results = devices.iter()
+        .map(|(serial_number, response)| {
+          let host = response.location().to_string();
+          let result = self.subscribe_to(&host, BASIC_EVENT_PATH,
+              callback.clone());
+
+          if let Err(ref error) = result {
+            warn!(target: "wemo", serial = serial_number.as_str(),
+                subscription_key = host.as_str();
+                "Couldn't subscribe to {}: {:?}", host, error);
+          }
+          (serial_number.clone(), result)
+        })
+        .collect::<HashMap<_, _>>();
+
+    info!(target: "wemo", devices = results.len();
+        "Subscribed to {} of {} devices",
+        results.values().filter(|result| result.is_ok()).count(),
+        results.len());
+    results
+  }
 
-    let subscription = Subscription { callback: Some(Box::new(callback)) };
+  fn subscribe_to(&self, host: &str, event_path: &'static str,
+                  callback: Arc<Fn(Notification) + Sync + Send>)
+                  -> Result<(), WemoError> {
+    let callback_host = match self.advertised_host {
+      Some(ref advertised_host) => advertised_host.clone(),
+      None => callback_ip(host)?.to_string(),
+    };
+    let mut ports = initial_ports(host);
+
+    let sid = subscribe_with_ports(&callback_host, host, event_path,
+        &mut ports, self.subscription_ttl_sec, self.advertised_port(),
+        &self.callback_path, &self.headers)?;
+
+    let subscription = Subscription {
+      callback: Some(callback),
+      ports: Mutex::new(ports),
+      last_state: Mutex::new(None),
+      event_path: event_path,
+      sequence: AtomicU64::new(0),
+      event_seq: Mutex::new(None),
+      needs_reconcile: AtomicBool::new(false),
+      sid: Mutex::new(sid),
+      callback_host: Mutex::new(callback_host),
+      push_fed: Mutex::new(None),
+      counters: Arc::new(Counters::default()),
+    };
 
     self.register_subscription(host, subscription)?;
     Ok(())
   }
 
-  /// Remove a subscription.
+  /// Remove a subscription, and cancel it on the device if it's reachable.
   pub fn unsubscribe(&self, host: &str) -> Result<(), WemoError> {
-    self.subscriptions.write().map_err(|_| WemoError::LockError)?
+    let removed = self.subscriptions.write()
+        .map_err(|_| WemoError::LockError)?
         .remove(host);
+
+    if let Some(subscription) = removed {
+      cancel(host, &subscription, &self.headers);
+    }
     Ok(())
   }
 
+  /// Remove every subscription, cancelling each on its device.
+  pub fn unsubscribe_all(&self) {
+    let removed = match self.subscriptions.write() {
+      Err(_) => { return; },
+      Ok(mut subscriptions) => subscriptions.drain().collect::<Vec<_>>(),
+    };
+
+    for (host, subscription) in removed {
+      cancel(&host, &subscription, &self.headers);
+    }
+  }
+
   /// Start the HTTP server so it can begin receiving push notifications. A
   /// background thread to resubscribe will also be launched. Calling this
-  /// function is nonblocking, but it returns a thread guard that will
-  /// automatically join with the parent once it is dropped.
+  /// function is nonblocking; the server and thread run until stopped or
+  /// until this object is dropped.
   pub fn start_server(&mut self) -> Result<(), WemoError> {
     if self.server_handle.is_some() {
       return Ok(());
     }
 
-    let subs = self.subscriptions.clone();
-
     // TODO: Request headers contain a re-subscribe UUID, which should be used
     // instead of subscribing again without a subscription ID.
-    let handler = move |request: &mut Request| -> IronResult<Response> {
-      let mut body = String::new();
-      request.body.read_to_string(&mut body)
-          .map_err(|e| WemoError::IoError { cause: e })?;
-
-      // Device is contained in a query string variable, "from".
-      let host = request.get_ref::<UrlEncodedQuery>()
-          .map_err(|_| WemoError::SubscriptionError)
-          .and_then(|hashmap| hashmap.get("from")
-              .and_then(|vec| vec.get(0))
-              .ok_or(WemoError::SubscriptionError))?;
-
-      if !body.contains("BinaryState") {
-        // TODO: Handle other types of state update.
-        return Ok(Response::with((status::Ok, "")));
-      }
-
-      let state = parse_state(&body)?;
-
-      let subscriptions = subs.read()
-          .map_err(|_| WemoError::SubscriptionError)?;
-
-      let subscription = subscriptions.get(host)
-          .ok_or(WemoError::SubscriptionError)?;
-
-      if subscription.callback.is_some() {
-        let callback = subscription.callback.as_ref().unwrap();
-        let notification = Notification {
-          notification_type: NotificationType::State {
-            state: state,
-          },
-          subscription_key: host.to_string(),
-        };
-        callback(notification);
-      }
-
-      Ok(Response::with((status::Ok, "")))
-    };
+    let handler = self.handler();
 
     let listen_address = format!("0.0.0.0:{}", self.callback_port);
 
@@ -163,14 +975,33 @@ impl Subscriptions {
 
     self.server_handle = Some(server);
 
+    // Events sent while the server was down were missed.
+    let hosts = self.hosts();
+    if !hosts.is_empty() {
+      let handler = self.handler();
+      handler.mark_all();
+      threads::spawn("wemo-reconcile",
+          move || handler.reconcile_marked(&hosts));
+    }
+
     self.start_polling();
 
     Ok(())
   }
 
+  /// Keep subscriptions renewed without starting the HTTP server, for when
+  /// notifications are received by your own server and passed to `handler`.
+  /// Set the callback port (and path) to where that server listens.
+  pub fn start_resubscribing(&mut self) {
+    self.start_polling();
+  }
+
   /// Stop the HTTP server from running. Also stops resubscription process.
-  /// Warning: This may not work the server from listening. See the following
-  /// issue on Iron/Hyper: https://github.com/hyperium/hyper/issues/338
+  /// Warning: Hyper can't stop listening, so the callback port stays bound
+  /// until the process exits, and `start_server` can't be called again with
+  /// the same port. Notifications that still arrive are ignored once their
+  /// subscriptions are removed. See the following issue on Iron/Hyper:
+  /// https://github.com/hyperium/hyper/issues/338
   pub fn stop_server(&mut self) -> Result<(), WemoError> {
     if self.server_handle.is_none() {
       return Ok(());
@@ -195,99 +1026,616 @@ impl Subscriptions {
     }
 
     let subscription_ttl_sec = self.subscription_ttl_sec;
-    let callback_port = self.callback_port;
+    let callback_port = self.advertised_port();
+    let callback_path = self.callback_path.clone();
+    let advertised_host = self.advertised_host.clone();
     let subscriptions = self.subscriptions.clone();
+    let headers = self.headers.clone();
+    let renewal_callback = self.renewal_callback.clone();
+    let handler = self.handler();
+    let (stop_polling, stopped) = channel::<()>();
+
+    let handle = threads::spawn("wemo-resubscribe", move || {
+      let mut last_ip = None;
+      let mut last_renewal = SystemTime::now();
+      let mut recovering = false;
 
-    let handle = thread::spawn(move || {
       loop {
-        //thread::sleep(Duration::from_secs(300)); // 60 * 5
-        thread::sleep(Duration::from_secs(30));
+        let interval = if recovering { RECOVERY_SECS } else { RENEW_SECS };
+        match stopped.recv_timeout(Duration::from_secs(interval)) {
+          Err(RecvTimeoutError::Timeout) => {},
+          _ => { break; }, // Stopped.
+        }
 
-        let subs = match subscriptions.read() {
-          Err(_) => continue, // TODO: LOG
-          Ok(subs) => subs,
-        };
+        // Sleeping much longer than asked means the host was suspended, and
+        // the devices have likely dropped our subscriptions.
+        let now = SystemTime::now();
+        let resumed = now.duration_since(last_renewal)
+            .map(|slept| slept > Duration::from_secs(interval * 3))
+            .unwrap_or(false);
+        last_renewal = now;
 
-        // TODO: A single failure can hold things up, causing missed events
-        // from temporarily dropped subscriptions. Also, I need to mitigate
-        // change of ports (and IP addresses).
         let local_ip = match get_local_ip() {
-          Err(_) => continue, // TODO: LOG
+          Err(_) => {
+            recovering = true;
+            continue;
+          },
           Ok(ip) => ip,
         };
 
-        for (host, _subscription) in subs.iter() {
-          let _r = send_subscribe(local_ip, host, subscription_ttl_sec,
-              callback_port);
+        if last_ip.is_some() && last_ip != Some(local_ip) {
+          info!(target: "wemo", local_ip:% = local_ip;
+              "Local IP changed, resubscribing with the new callback URL");
+        } else if resumed {
+          info!(target: "wemo", local_ip:% = local_ip;
+              "Resumed from sleep, resubscribing");
+          handler.mark_all();
+        }
+        last_ip = Some(local_ip);
+
+        // TODO: A single failure can hold things up, causing missed events
+        // from temporarily dropped subscriptions.
+        let outcomes = renew_each(&subscriptions, local_ip,
+            advertised_host.as_deref(),
+            subscription_ttl_sec, callback_port, &callback_path, &headers);
+
+        if let Some(ref callback) = renewal_callback {
+          for &(ref host, renewed) in outcomes.iter() {
+            callback(host, renewed);
+          }
+        }
+
+        // Catch up on devices that are reachable again.
+        let reachable = outcomes.iter()
+            .filter(|&&(_, renewed)| renewed)
+            .map(|&(ref host, _)| host.clone())
+            .collect::<Vec<_>>();
+        handler.reconcile_marked(&reachable);
+
+        let renewed = outcomes.iter().filter(|&&(_, renewed)| renewed).count();
+        let failed = outcomes.len() - renewed;
+
+        if renewed == 0 && failed > 0 {
+          if !recovering {
+            warn!(target: "wemo", failed = failed;
+                "Every subscription failed to renew, retrying until the \
+                network recovers");
+          }
+          recovering = true;
+        } else {
+          if recovering {
+            info!(target: "wemo", renewed = renewed;
+                "Subscriptions recovered");
+          }
+          recovering = false;
         }
       }
     });
 
-    self.continue_polling = true;
+    self.stop_polling = Some(stop_polling);
     self.polling_handle = Some(handle);
   }
 
-  // Consume handle. Not threadsafe.
+  // Wait for any renewal in progress. Not threadsafe.
   fn stop_polling(&mut self) {
-    self.polling_handle = None; // Drops handle.
-    self.continue_polling = false;
+    self.stop_polling = None; // Disconnects the thread.
+    if let Some(handle) = self.polling_handle.take() {
+      let _r = handle.join();
+    }
+  }
+
+  /// Renew every subscription now rather than waiting for the next renewal,
+  /// eg. when the host's network changed. A subscription whose callback URL
+  /// would change is replaced with a fresh one. Returns how many
+  /// subscriptions were renewed.
+  pub fn resubscribe_all(&self) -> Result<usize, WemoError> {
+    let local_ip = get_local_ip()?;
+    let outcomes = renew_each(&self.subscriptions, local_ip,
+        self.advertised_host.as_deref(),
+        self.subscription_ttl_sec, self.advertised_port(),
+        &self.callback_path, &self.headers);
+
+    if let Some(ref callback) = self.renewal_callback {
+      for &(ref host, renewed) in outcomes.iter() {
+        callback(host, renewed);
+      }
+    }
+
+    Ok(outcomes.iter().filter(|&&(_, renewed)| renewed).count())
+  }
+
+  /// The port devices are asked to call back on.
+  fn advertised_port(&self) -> u16 {
+    self.advertised_port.unwrap_or(self.callback_port)
+  }
+
+  fn hosts(&self) -> Vec<String> {
+    self.subscriptions.read()
+        .map(|subscriptions| subscriptions.keys().cloned().collect())
+        .unwrap_or_default()
+  }
+
+  /// Keep `push_fed` set while the subscription to `host` is live, clearing
+  /// it when a renewal fails or the subscription is cancelled or dropped.
+  pub(crate) fn track_push_fed(&self, host: &str, push_fed: Arc<AtomicBool>)
+                               -> Result<(), WemoError> {
+    let subscriptions = self.subscriptions.read()
+        .map_err(|_| WemoError::LockError)?;
+    let subscription = subscriptions.get(host)
+        .ok_or(WemoError::SubscriptionError)?;
+
+    push_fed.store(true, Ordering::SeqCst);
+    *subscription.push_fed.lock().map_err(|_| WemoError::LockError)? =
+        Some(push_fed);
+    Ok(())
   }
 
   fn register_subscription(&self, host: &str, subscription: Subscription)
                            -> Result<(), WemoError> {
     self.subscriptions.write().map_err(|_| WemoError::LockError)?
-        .insert(host.to_string(), subscription);
+        .insert(host.to_string(), Arc::new(subscription));
     Ok(())
   }
 }
 
+impl Drop for Subscriptions {
+  fn drop(&mut self) {
+    self.stop_polling();
+    self.unsubscribe_all();
+    let _r = self.stop_server();
+  }
+}
+
+// NB: Called from thread, can't reference 'self'.
+/// Renew each subscription, calling back on `advertised_host` if set, else
+/// the local IP on each device's subnet, or `local_ip`. Returns each
+/// subscription key with whether it renewed.
+fn renew_each(subscriptions: &RwLock<HashMap<String, Arc<Subscription>>>,
+              local_ip: IpAddr,
+              advertised_host: Option<&str>,
+              subscription_ttl_sec: u16,
+              callback_port: u16,
+              callback_path: &str,
+              headers: &[(String, String)]) -> Vec<(String, bool)> {
+  // NB: Renewing takes a round trip per device, so work from a snapshot
+  // rather than keep subscribing and unsubscribing waiting on the lock.
+  let subs = match subscriptions.read() {
+    Err(_) => { return Vec::new(); },
+    Ok(subs) => {
+      subs.iter()
+          .map(|(host, subscription)| (host.clone(), subscription.clone()))
+          .collect::<Vec<_>>()
+    },
+  };
+
+  let mut outcomes = Vec::with_capacity(subs.len());
+
+  for (host, subscription) in subs.iter() {
+    let mut ports = match subscription.ports.lock() {
+      Err(_) => continue, // TODO: LOG
+      Ok(ports) => ports,
+    };
+
+    // Prefer the interface on the device's subnet over the default.
+    let callback_host = match advertised_host {
+      Some(advertised_host) => advertised_host.to_string(),
+      None => {
+        SocketAddr::from_str(host).ok()
+            .and_then(|socket| get_local_ip_for(socket.ip()).ok())
+            .unwrap_or(local_ip)
+            .to_string()
+      },
+    };
+
+    let previous_sid = subscription.sid.lock().ok()
+        .and_then(|sid| sid.clone());
+    let same_callback = subscription.callback_host.lock()
+        .map(|previous| *previous == callback_host)
+        .unwrap_or(false);
+
+    // Renew the device's subscription rather than start another, which
+    // would leave the old one sending events until it lapsed. A renewal
+    // can't change the callback, though.
+    let renewable = previous_sid.as_ref().filter(|_| same_callback);
+    let renewed = renewable.and_then(|sid| {
+      let target = last_target(host, &ports);
+      match send_renewal(&target, subscription.event_path, sid,
+          subscription_ttl_sec, headers) {
+        Ok(sid) => Some(sid),
+        Err(error) => {
+          debug!(target: "wemo", subscription_key = host.as_str(),
+              error:? = error; "Couldn't renew, subscribing again");
+          None
+        },
+      }
+    });
+
+    let result = match renewed {
+      Some(sid) => Ok(Some(sid)),
+      None => {
+        let target = last_target(host, &ports);
+        let result = subscribe_with_ports(&callback_host, host,
+            subscription.event_path, &mut ports, subscription_ttl_sec,
+            callback_port, callback_path, headers);
+
+        // The device may still hold the old subscription, eg. if it only
+        // refused the renewal for moving port.
+        if let (Ok(Some(sid)), Some(previous)) = (&result, &previous_sid) {
+          if sid != previous {
+            let _r = send_unsubscribe(&target, subscription.event_path,
+                previous, headers);
+          }
+        }
+        result
+      },
+    };
+
+    if result.is_ok() {
+      if let Ok(mut previous) = subscription.callback_host.lock() {
+        *previous = callback_host.clone();
+      }
+    }
+
+    // NB: A new subscription starts with an event carrying the state.
+    set_push_fed(subscription, result.is_ok());
+
+    match result {
+      Ok(Some(ref sid)) => {
+        if let Ok(mut last_sid) = subscription.sid.lock() {
+          *last_sid = Some(sid.clone());
+        }
+      },
+      Ok(None) => {},
+      Err(_) => {
+        subscription.needs_reconcile.store(true, Ordering::SeqCst);
+      },
+    }
+    outcomes.push((host.to_string(), result.is_ok()));
+  }
+
+  outcomes
+}
+
+/// The local IP to give the device at `host` in its callback URL.
+fn callback_ip(host: &str) -> Result<IpAddr, WemoError> {
+  match SocketAddr::from_str(host) {
+    Ok(socket) => get_local_ip_for(socket.ip()),
+    Err(_) => get_local_ip(),
+  }
+}
+
+/// The ports to track for a subscription to "IP:PORT".
+fn initial_ports(host: &str) -> DevicePorts {
+  let port = SocketAddr::from_str(host).ok().map(|socket| socket.port());
+  DevicePorts::new(port, global_config().default_ports)
+}
+
+// NB: Called from thread, can't reference 'self'.
+/// Subscribe to the device, trying each of its candidate ports in turn if it
+/// moved. Hosts that aren't "IP:PORT" are only tried as given. Notifications
+/// are always keyed by the original host. Returns the device's `SID`, if it
+/// sent one.
+fn subscribe_with_ports(callback_host: &str,
+                        host: &str,
+                        event_path: &str,
+                        ports: &mut DevicePorts,
+                        subscription_ttl_sec: u16,
+                        callback_port: u16,
+                        callback_path: &str,
+                        headers: &[(String, String)])
+                        -> Result<Option<String>, WemoError> {
+  let ip_address = match SocketAddr::from_str(host) {
+    Err(_) => {
+      return send_subscribe(callback_host, host, event_path,
+          subscription_ttl_sec, callback_port, callback_path, headers);
+    },
+    Ok(socket) => socket.ip(),
+  };
+
+  let mut result = Err(WemoError::SubscriptionError);
+
+  for port in ports.probe_order() {
+    let target = SocketAddr::new(ip_address, port).to_string();
+    result = send_subscribe_to(callback_host, host, &target, event_path,
+        subscription_ttl_sec, callback_port, callback_path, headers);
+
+    if result.is_ok() {
+      ports.set_last_known(Some(port));
+      break;
+    }
+  }
+
+  result
+}
+
 // NB: Called from thread, can't reference 'self'.
-fn send_subscribe(local_ip: IpAddr,
+fn send_subscribe(callback_host: &str,
                   host: &str,
+                  event_path: &str,
                   subscription_ttl_sec: u16,
-                  callback_port: u16) -> Result<(), WemoError> {
-  let callback_url = format!("http://{}:{}/?from={}",
-    local_ip, callback_port, host);
+                  callback_port: u16,
+                  callback_path: &str,
+                  headers: &[(String, String)])
+                  -> Result<Option<String>, WemoError> {
+  send_subscribe_to(callback_host, host, host, event_path,
+      subscription_ttl_sec, callback_port, callback_path, headers)
+}
+
+/// Send the SUBSCRIBE request to `target`, asking for notifications keyed by
+/// `host` to be sent to `callback_host`, eg. the local IP. Returns the
+/// device's `SID`, if it sent one.
+fn send_subscribe_to(callback_host: &str,
+                     host: &str,
+                     target: &str,
+                     event_path: &str,
+                     subscription_ttl_sec: u16,
+                     callback_port: u16,
+                     callback_path: &str,
+                     headers: &[(String, String)])
+                     -> Result<Option<String>, WemoError> {
+  let callback_url = format!("http://{}:{}{}?from={}",
+    callback_host, callback_port, callback_path, host);
+
+  check_headers(headers)?;
+  let extra_headers = headers.iter()
+      .map(|&(ref name, ref value)| format!("{}: {}\r\n", name, value))
+      .collect::<String>();
 
   let header = format!("\
-      SUBSCRIBE /upnp/event/basicevent1 HTTP/1.1\r\n\
+      SUBSCRIBE {} HTTP/1.1\r\n\
       CALLBACK: <{}>\r\n\
       NT: upnp:event\r\n\
       TIMEOUT: Second-{}\r\n\
       Host: {}\r\n\
+      {}\
       \r\n",
+    event_path,
     callback_url,
     subscription_ttl_sec,
-    host);
+    target,
+    extra_headers);
+
+  let mut stream = connect(target)?;
+
+  stream.write_all(header.as_bytes())?;
+  capture::record(CaptureKind::GenaSubscribe, stream.peer_addr().ok(),
+      header.as_bytes());
+
+  // NB: A device that doesn't answer in time is still subscribed; it just
+  // can't be unsubscribed from later.
+  let mut response = [0; 1024];
+  let sid = match stream.read(&mut response) {
+    Err(_) => None, // Ignore.
+    Ok(length) => parse_sid(&String::from_utf8_lossy(&response[..length])),
+  };
+
+  Ok(sid)
+}
+
+/// Renew the subscription `sid` at `target` for another `subscription_ttl_sec`
+/// seconds, as UPnP specifies: with its `SID` and without a `CALLBACK`.
+/// Returns the `SID` the device answers with, the same one unless it says
+/// otherwise. Fails if the device no longer knows the subscription.
+fn send_renewal(target: &str,
+                event_path: &str,
+                sid: &str,
+                subscription_ttl_sec: u16,
+                headers: &[(String, String)]) -> Result<String, WemoError> {
+  check_headers(headers)?;
+  let extra_headers = headers.iter()
+      .map(|(name, value)| format!("{}: {}\r\n", name, value))
+      .collect::<String>();
+
+  let header = format!("\
+      SUBSCRIBE {} HTTP/1.1\r\n\
+      SID: {}\r\n\
+      TIMEOUT: Second-{}\r\n\
+      Host: {}\r\n\
+      {}\
+      \r\n",
+    event_path,
+    sid,
+    subscription_ttl_sec,
+    target,
+    extra_headers);
+
+  let mut stream = connect(target)?;
+
+  stream.write_all(header.as_bytes())?;
+  capture::record(CaptureKind::GenaSubscribe, stream.peer_addr().ok(),
+      header.as_bytes());
+
+  let mut response = [0; 1024];
+  let length = stream.read(&mut response)?;
+  let response = String::from_utf8_lossy(&response[..length]);
+
+  match parse_status(&response) {
+    Some(200) => Ok(parse_sid(&response).unwrap_or_else(|| sid.to_string())),
+    _ => Err(WemoError::SubscriptionError),
+  }
+}
+
+/// Where the device at `host` was last subscribed to, "IP:PORT" with the
+/// port it was last found on.
+fn last_target(host: &str, ports: &DevicePorts) -> String {
+  match SocketAddr::from_str(host) {
+    Err(_) => host.to_string(),
+    Ok(socket) => {
+      let port = ports.last_known().unwrap_or(socket.port());
+      SocketAddr::new(socket.ip(), port).to_string()
+    },
+  }
+}
+
+/// Cancel a removed subscription on its device, if it gave us an `SID`.
+/// Failures are only logged, since the subscription will lapse anyway.
+fn cancel(host: &str, subscription: &Subscription,
+          headers: &[(String, String)]) {
+  set_push_fed(subscription, false);
+
+  let sid = match subscription.sid.lock() {
+    Err(_) => None,
+    Ok(sid) => sid.clone(),
+  };
+  let sid = match sid {
+    None => { return; },
+    Some(sid) => sid,
+  };
+
+  // Unsubscribe from wherever the device was last subscribed to.
+  let target = match subscription.ports.lock() {
+    Err(_) => host.to_string(),
+    Ok(ports) => last_target(host, &ports),
+  };
+
+  if let Err(error) = send_unsubscribe(&target, subscription.event_path,
+      &sid, headers) {
+    debug!(target: "wemo", host = host, error:? = error;
+        "Couldn't unsubscribe from {}", host);
+  }
+}
 
-  let mut stream = TcpStream::connect(host)?;
+/// Tell the switch tracking `subscription`, if any, whether to trust its
+/// pushes.
+fn set_push_fed(subscription: &Subscription, push_fed: bool) {
+  if let Ok(tracked) = subscription.push_fed.lock() {
+    if let Some(ref tracked) = *tracked {
+      tracked.store(push_fed, Ordering::SeqCst);
+    }
+  }
+}
+
+fn send_unsubscribe(target: &str,
+                    event_path: &str,
+                    sid: &str,
+                    headers: &[(String, String)]) -> Result<(), WemoError> {
+  check_headers(headers)?;
+  let extra_headers = headers.iter()
+      .map(|&(ref name, ref value)| format!("{}: {}\r\n", name, value))
+      .collect::<String>();
 
-  stream.set_read_timeout(Some(Duration::from_secs(1)))?;
-  stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+  let header = format!("\
+      UNSUBSCRIBE {} HTTP/1.1\r\n\
+      SID: {}\r\n\
+      Host: {}\r\n\
+      {}\
+      \r\n",
+    event_path,
+    sid,
+    target,
+    extra_headers);
 
-  stream.write(header.as_bytes())?;
+  let mut stream = connect(target)?;
 
-  // TODO: Read response.
+  stream.write_all(header.as_bytes())?;
+  capture::record(CaptureKind::GenaUnsubscribe, stream.peer_addr().ok(),
+      header.as_bytes());
 
+  // Wait for the device to handle it.
+  let _r = stream.read(&mut [0; 1024]);
   Ok(())
 }
 
+/// Connect for a SUBSCRIBE or UNSUBSCRIBE request, without waiting out the
+/// OS's connect timeout on a device that's gone, eg. when unsubscribing from
+/// every device on drop.
+fn connect(target: &str) -> Result<TcpStream, WemoError> {
+  let timeout = Duration::from_secs(GENA_TIMEOUT_SECS);
+  let mut last_error = None;
+
+  for address in target.to_socket_addrs()? {
+    match TcpStream::connect_timeout(&address, timeout) {
+      Ok(stream) => {
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        return Ok(stream);
+      },
+      Err(error) => { last_error = Some(error); },
+    }
+  }
+
+  Err(last_error.map(WemoError::from).unwrap_or(WemoError::SubscriptionError))
+}
+
+/// The `SID` header of a SUBSCRIBE response, eg. `uuid:...`.
+fn parse_sid(response: &str) -> Option<String> {
+  response.lines()
+      .filter_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+          (Some(name), Some(value)) if name.trim()
+              .eq_ignore_ascii_case("SID") => Some(value.trim().to_string()),
+          _ => None,
+        }
+      })
+      .find(|sid| !sid.is_empty())
+}
+
 /// Attempt to get the local IP address on the network.
 /// Returns the first non-loopback, local Ipv4 network interface.
 pub fn get_local_ip() -> Result<IpAddr, WemoError> {
+  local_interfaces()?.first()
+      .ok_or(WemoError::NoLocalIp)
+      .map(|&(ip, _)| IpAddr::V4(ip))
+}
+
+/// Get the local IP address the device can reach, ie. the one on an
+/// interface whose subnet contains the device's IP, so that devices get a
+/// reachable callback URL on hosts with VPNs, VMs, or several networks.
+/// Devices on other subnets get the address the host routes to them from,
+/// eg. through a gateway. Falls back to `get_local_ip`.
+pub fn get_local_ip_for(device: IpAddr) -> Result<IpAddr, WemoError> {
+  select_local_ip(&local_interfaces()?, device, routed_local_ip(device))
+      .ok_or(WemoError::NoLocalIp)
+}
+
+/// The local address the host would send to the device from. Connecting a
+/// UDP socket only consults the routing table; nothing is sent.
+fn routed_local_ip(device: IpAddr) -> Option<IpAddr> {
+  let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+  socket.connect(SocketAddr::new(device, UPNP_PORT)).ok()?;
+  socket.local_addr().ok()
+      .map(|address| address.ip())
+      .filter(|ip| !ip.is_unspecified() && !ip.is_loopback())
+}
+
+/// The address and netmask of each non-loopback, local Ipv4 network
+/// interface.
+fn local_interfaces() -> Result<Vec<(Ipv4Addr, Ipv4Addr)>, WemoError> {
   // TODO: Get rid of this dependency. Didn't realize it was GPL.
   let ips = get_if_addrs()?;
 
   // Only non-loopback Ipv4 addresses that aren't docker interfaces.
-  let filtered = ips.iter()
-      .filter(|x| match x.addr { IfAddr::V4(..) => true, _ => false } )
+  let interfaces = ips.iter()
       .filter(|x| !x.addr.is_loopback())
       .filter(|x| !x.name.contains("docker"))
-      .collect::<Vec<_>>();
+      .filter_map(|x| match x.addr {
+        IfAddr::V4(ref addr) => Some((addr.ip, addr.netmask)),
+        _ => None,
+      })
+      .collect();
 
-  filtered.get(0)
-      .ok_or(WemoError::NoLocalIp)
-      .map(|x| x.addr.ip())
+  Ok(interfaces)
+}
+
+/// The interface on the device's subnet, else the routed address, else the
+/// first interface.
+fn select_local_ip(interfaces: &[(Ipv4Addr, Ipv4Addr)], device: IpAddr,
+                   routed: Option<IpAddr>) -> Option<IpAddr> {
+  let device = match device {
+    IpAddr::V4(ip) => Some(u32::from(ip)),
+    IpAddr::V6(_) => None,
+  };
+
+  interfaces.iter()
+      .find(|&&(ip, netmask)| {
+        let mask = u32::from(netmask);
+        device.map(|device| device & mask == u32::from(ip) & mask)
+            .unwrap_or(false)
+      })
+      .map(|&(ip, _)| IpAddr::V4(ip))
+      .or(routed)
+      .or(interfaces.first().map(|&(ip, _)| IpAddr::V4(ip)))
 }
 
 impl From<WemoError> for IronError {
@@ -314,9 +1662,12 @@ mod tests {
   use std::net::TcpStream;
   use std::sync::Arc;
   use std::sync::RwLock;
+  use std::sync::mpsc::channel;
   use std::thread;
   use std::time::Duration;
+  use net::ports::DevicePorts;
   use super::*;
+  use testing::{FakeDevice, unique_serial_number};
 
   fn next_test_port() -> u16 {
     // Taken from rust-utp, since `std::net::test` not available to import.
@@ -332,6 +1683,472 @@ mod tests {
     SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port))
   }
 
+  /// A subscription to `host` that hasn't had any events yet.
+  fn subscription<F>(host: &str, event_path: &'static str, callback: F)
+      -> Subscription where F: Fn(Notification) + Sync + Send + 'static {
+    Subscription {
+      callback: Some(Arc::new(callback)),
+      ports: Mutex::new(initial_ports(host)),
+      last_state: Mutex::new(None),
+      event_path: event_path,
+      sequence: AtomicU64::new(0),
+      event_seq: Mutex::new(None),
+      needs_reconcile: AtomicBool::new(false),
+      sid: Mutex::new(None),
+      callback_host: Mutex::new(String::new()),
+      push_fed: Mutex::new(None),
+      counters: Arc::new(Counters::default()),
+    }
+  }
+
+  /// Read a SUBSCRIBE request and answer it as a device would.
+  fn accept_subscribe(stream: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut byte = [0; 1];
+    while !request.ends_with(b"\r\n\r\n") {
+      match stream.read(&mut byte).unwrap() {
+        0 => { break; },
+        _ => request.push(byte[0]),
+      }
+    }
+    stream.write_all(b"HTTP/1.1 200 OK\r\n\
+        SID: uuid:test-subscription\r\n\
+        TIMEOUT: Second-600\r\n\
+        Content-Length: 0\r\n\r\n").unwrap();
+    String::from_utf8(request).unwrap()
+  }
+
+  #[test]
+  fn test_notification_handler() {
+    let subs = Subscriptions::new(next_test_port(), 1000);
+    let notification = Arc::new(RwLock::new(None));
+    let notify = notification.clone();
+
+    let subscription = subscription("192.168.1.4:49153", BASIC_EVENT_PATH,
+        move |n| {
+          *notify.write().unwrap() = Some(n);
+        });
+    subs.register_subscription("192.168.1.4:49153", subscription).unwrap();
+
+    let handler = subs.handler();
+    let headers = vec![("NTS".to_string(), "upnp:propchange".to_string())];
+    handler.handle("/?from=192.168.1.4%3A49153", &headers,
+        "<BinaryState>1</BinaryState>").unwrap();
+
+    let notice = notification.read().unwrap().clone().unwrap();
+    assert_eq!(NotificationType::State { state: WemoState::On },
+        notice.notification_type);
+    assert_eq!("192.168.1.4:49153", notice.subscription_key);
+    assert_eq!(1, notice.sequence);
+    assert!(notice.received_at <= SystemTime::now());
+
+    assert!(handler.handle("/", &[], "<BinaryState>1</BinaryState>")
+        .is_err());
+    assert_eq!(405, handler.handle_request("GET", "/wemo?from=x", &[], ""));
+    assert_eq!(200, handler.handle_request("NOTIFY",
+        "/wemo?from=192.168.1.4:49153", &[], "<BinaryState>0</BinaryState>"));
+
+    let notice = notification.read().unwrap().clone().unwrap();
+    assert_eq!(2, notice.sequence);
+  }
+
+  #[test]
+  fn test_reconcile_after_missed_events() {
+    let device = FakeDevice::start_unique().unwrap();
+    device.set_state(WemoState::On);
+    let host = device.http_address().to_string();
+
+    let subs = Subscriptions::new(next_test_port(), 1000);
+    let (sender, notifications) = channel();
+    let sender = Mutex::new(sender);
+
+    let subscription = subscription(&host, BASIC_EVENT_PATH,
+        move |n| {
+          let _r = sender.lock().unwrap().send(n);
+        });
+    subs.register_subscription(&host, subscription).unwrap();
+
+    let handler = subs.handler();
+    let path = format!("/?from={}", host);
+    let event = |seq: &str| vec![
+      ("NTS".to_string(), "upnp:propchange".to_string()),
+      ("SEQ".to_string(), seq.to_string()),
+    ];
+
+    handler.handle(&path, &event("0"), "<Brightness>40</Brightness>").unwrap();
+    handler.handle(&path, &event("1"), "<Brightness>50</Brightness>").unwrap();
+    // Events 2 and 3 were lost.
+    handler.handle(&path, &event("4"), "<Brightness>60</Brightness>").unwrap();
+
+    let received = (0..4)
+        .map(|_| notifications.recv_timeout(Duration::from_secs(3)).unwrap())
+        .collect::<Vec<_>>();
+    let reconciled = received.iter()
+        .filter(|n| n.reconciled)
+        .collect::<Vec<_>>();
+
+    assert_eq!(1, reconciled.len());
+    assert_eq!(NotificationType::State { state: WemoState::On },
+        reconciled[0].notification_type);
+    assert_eq!(1, device.request_count());
+    assert!(notifications.recv_timeout(Duration::from_millis(100)).is_err());
+  }
+
+  #[test]
+  fn test_missed_events_per_sid() {
+    let host = "192.168.1.20:49153";
+    let subs = Subscriptions::new(next_test_port(), 1000);
+    subs.register_subscription(host, subscription(host, BASIC_EVENT_PATH,
+        |_| {})).unwrap();
+
+    let handler = subs.handler();
+
+    // A renewal that fell back to a new subscription overlaps the old one.
+    assert!(!handler.missed_events(host, Some("uuid:old"), 0));
+    assert!(!handler.missed_events(host, Some("uuid:old"), 1));
+    assert!(!handler.missed_events(host, Some("uuid:new"), 0));
+    assert!(!handler.missed_events(host, Some("uuid:old"), 2));
+    assert!(!handler.missed_events(host, Some("uuid:new"), 1));
+    assert!(handler.missed_events(host, Some("uuid:new"), 3));
+  }
+
+  #[test]
+  fn test_queue_overflow() {
+    let subs = Subscriptions::new(next_test_port(), 1000)
+        .with_queue(1, OverflowPolicy::DropNewest);
+
+    let (started, wait_started) = channel();
+    let (release, wait_release) = channel::<()>();
+    let started = Mutex::new(started);
+    let wait_release = Mutex::new(wait_release);
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let notify = delivered.clone();
+
+    let subscription = subscription("192.168.1.4:49153", BASIC_EVENT_PATH,
+        move |n: Notification| {
+          let _r = started.lock().unwrap().send(());
+          let _r = wait_release.lock().unwrap().recv();
+          notify.lock().unwrap().push(n.sequence);
+        });
+    subs.register_subscription("192.168.1.4:49153", subscription).unwrap();
+
+    let handler = subs.handler();
+    let notify = |handler: &NotificationHandler| {
+      handler.handle("/?from=192.168.1.4:49153", &[],
+          "<BinaryState>1</BinaryState>").unwrap();
+    };
+
+    // The callback blocks on the first notification, the second fills the
+    // queue, and the rest are dropped rather than holding up the handler.
+    notify(&handler);
+    wait_started.recv().unwrap();
+    for _ in 0..3 {
+      notify(&handler);
+    }
+
+    let status = subs.status();
+    assert_eq!(1, status.len());
+    assert_eq!(4, status[0].sequence);
+    assert_eq!(2, status[0].overflowed);
+    assert_eq!(1, subs.queued());
+
+    drop(release);
+    drop(handler);
+    drop(subs);
+    assert_eq!(vec![1, 2], *delivered.lock().unwrap());
+  }
+
+  #[test]
+  fn test_callback_panics() {
+    let subs = Subscriptions::new(next_test_port(), 1000);
+    let delivered = Arc::new(Mutex::new(Vec::new()));
+    let notify = delivered.clone();
+
+    let subscription = subscription("192.168.1.4:49153", BASIC_EVENT_PATH,
+        move |n: Notification| {
+          if n.sequence == 1 {
+            panic!("callback failed");
+          }
+          notify.lock().unwrap().push(n.sequence);
+        });
+    subs.register_subscription("192.168.1.4:49153", subscription).unwrap();
+
+    let handler = subs.handler();
+    for _ in 0..2 {
+      assert!(handler.handle("/?from=192.168.1.4:49153", &[],
+          "<BinaryState>1</BinaryState>").is_ok());
+    }
+
+    assert_eq!(vec![2], *delivered.lock().unwrap());
+    assert_eq!(1, subs.status()[0].panics);
+  }
+
+  #[test]
+  fn test_load_notifications() {
+    let subs = Subscriptions::new(next_test_port(), 1000);
+    let notifications = Arc::new(Mutex::new(Vec::new()));
+    let notify = notifications.clone();
+
+    let subscription = subscription("192.168.1.4:49153", BASIC_EVENT_PATH,
+        move |n: Notification| {
+          notify.lock().unwrap().push(n.notification_type);
+        });
+    subs.register_subscription("192.168.1.4:49153", subscription).unwrap();
+
+    let handler = subs.handler();
+    for state in ["8", "1", "8", "0", "8"].iter() {
+      let body = format!("<BinaryState>{}</BinaryState>", state);
+      handler.handle("/?from=192.168.1.4:49153", &[], &body).unwrap();
+    }
+
+    let state = |state| NotificationType::State { state: state };
+    assert_eq!(vec![
+      state(WemoState::OnWithoutLoad),
+      state(WemoState::On),
+      NotificationType::LoadRestored,
+      state(WemoState::OnWithoutLoad),
+      NotificationType::LoadRemoved,
+      state(WemoState::Off),
+      state(WemoState::OnWithoutLoad),
+    ], *notifications.lock().unwrap());
+
+    handler.handle("/?from=192.168.1.4:49153", &[],
+        "<BinaryState>1</BinaryState>").unwrap();
+    assert_eq!(Some(&NotificationType::LoadRestored),
+        notifications.lock().unwrap().last());
+  }
+
+  #[test]
+  fn test_brightness_notifications() {
+    let subs = Subscriptions::new(next_test_port(), 1000);
+    let notifications = Arc::new(Mutex::new(Vec::new()));
+    let notify = notifications.clone();
+
+    let subscription = subscription("192.168.1.4:49153", BASIC_EVENT_PATH,
+        move |n: Notification| {
+          notify.lock().unwrap().push(n.notification_type);
+        });
+    subs.register_subscription("192.168.1.4:49153", subscription).unwrap();
+
+    let handler = subs.handler();
+    handler.handle("/?from=192.168.1.4:49153", &[],
+        "<BinaryState>1</BinaryState><Brightness>60</Brightness>").unwrap();
+    handler.handle("/?from=192.168.1.4:49153", &[],
+        "<Brightness>25</Brightness>").unwrap();
+    assert!(handler.handle("/?from=192.168.1.4:49153", &[],
+        "<Brightness>bright</Brightness>").is_err());
+
+    assert_eq!(vec![
+      NotificationType::State { state: WemoState::On },
+      NotificationType::Brightness { level: 60 },
+      NotificationType::Brightness { level: 25 },
+    ], *notifications.lock().unwrap());
+  }
+
+  #[test]
+  fn test_bulb_notifications() {
+    let subs = Subscriptions::new(next_test_port(), 1000);
+    let notifications = Arc::new(Mutex::new(Vec::new()));
+    let notify = notifications.clone();
+
+    let subscription = subscription("192.168.1.9:49153", BRIDGE_EVENT_PATH,
+        move |n: Notification| {
+          notify.lock().unwrap().push(n.notification_type);
+        });
+    subs.register_subscription("192.168.1.9:49153", subscription).unwrap();
+
+    let handler = subs.handler();
+    for &(capability, value) in [("10006", "0"), ("10008", "128:0"),
+        ("30301", "250:0")].iter() {
+      let body = format!(concat!("<StatusChange>&lt;StateEvent&gt;",
+          "&lt;DeviceID available=&quot;YES&quot;&gt;94103EA2B27803ED",
+          "&lt;/DeviceID&gt;&lt;CapabilityId&gt;{}&lt;/CapabilityId&gt;",
+          "&lt;Value&gt;{}&lt;/Value&gt;&lt;/StateEvent&gt;</StatusChange>"),
+          capability, value);
+      handler.handle("/?from=192.168.1.9:49153", &[], &body).unwrap();
+    }
+
+    let bulb = |change| NotificationType::Bulb {
+      device_id: "94103EA2B27803ED".to_string(),
+      change: change,
+    };
+    assert_eq!(vec![
+      bulb(BulbChange::OnOff { on: false }),
+      bulb(BulbChange::Brightness { level: 128 }),
+      bulb(BulbChange::Other {
+        capability: "30301".to_string(),
+        value: "250:0".to_string(),
+      }),
+    ], *notifications.lock().unwrap());
+  }
+
+  #[test]
+  fn test_maker_notifications() {
+    let subs = Subscriptions::new(next_test_port(), 1000);
+    let notifications = Arc::new(Mutex::new(Vec::new()));
+    let notify = notifications.clone();
+
+    let subscription = subscription("192.168.1.7:49153", DEVICE_EVENT_PATH,
+        move |n: Notification| {
+          notify.lock().unwrap().push(n.notification_type);
+        });
+    subs.register_subscription("192.168.1.7:49153", subscription).unwrap();
+
+    let handler = subs.handler();
+    let attribute = |name, value| format!(concat!("&lt;attribute&gt;",
+        "&lt;name&gt;{}&lt;/name&gt;&lt;value&gt;{}&lt;/value&gt;",
+        "&lt;/attribute&gt;"), name, value);
+
+    for &(name, value) in [("Sensor", "0"), ("Sensor", "1"),
+        ("Switch", "1")].iter() {
+      let body = format!("<attributeList>{}{}</attributeList>",
+          attribute(name, value), attribute("SensorPresent", "1"));
+      handler.handle("/?from=192.168.1.7:49153", &[], &body).unwrap();
+    }
+
+    assert_eq!(vec![
+      NotificationType::Sensor { triggered: true },
+      NotificationType::Sensor { triggered: false },
+      NotificationType::Relay { on: true },
+    ], *notifications.lock().unwrap());
+  }
+
+  #[test]
+  fn test_send_subscribe_to_bridge() {
+    let socket_addr = next_test_ip4();
+    let listener = TcpListener::bind(&socket_addr).unwrap();
+    let host = format!("localhost:{}", socket_addr.port());
+
+    thread::spawn(move || {
+      let local_ip = "127.0.0.1";
+      super::send_subscribe(local_ip, &host, BRIDGE_EVENT_PATH, 600, 8080,
+          "/", &[]).unwrap();
+    });
+
+    let mut stream = listener.accept().unwrap().0;
+    let buf = accept_subscribe(&mut stream);
+
+    assert!(buf.starts_with("SUBSCRIBE /upnp/event/bridge1 HTTP/1.1\r\n"));
+  }
+
+  #[test]
+  fn test_send_subscribe_with_callback_path() {
+    let socket_addr = next_test_ip4();
+    let listener = TcpListener::bind(&socket_addr).unwrap();
+    let host = format!("localhost:{}", socket_addr.port());
+
+    thread::spawn(move || {
+      let local_ip = "127.0.0.1";
+      super::send_subscribe(local_ip, &host, BASIC_EVENT_PATH, 600, 8080,
+          "/wemo/events", &[])
+          .unwrap();
+    });
+
+    let mut stream = listener.accept().unwrap().0;
+    let buf = accept_subscribe(&mut stream);
+
+    let expected_callback = format!(
+        "CALLBACK: <http://127.0.0.1:8080/wemo/events?from=localhost:{}>",
+        socket_addr.port());
+    assert!(buf.contains(&expected_callback));
+  }
+
+  #[test]
+  fn test_advertised_address() {
+    let socket_addr = next_test_ip4();
+    let listener = TcpListener::bind(&socket_addr).unwrap();
+    let host = format!("localhost:{}", socket_addr.port());
+
+    let device = thread::spawn(move || {
+      accept_subscribe(&mut listener.accept().unwrap().0)
+    });
+
+    let subs = Subscriptions::new(next_test_port(), 600)
+        .with_advertised_address("wemo.example.net", 8443);
+    subs.subscribe(&host, |_n: Notification| {}).unwrap();
+
+    let buf = device.join().unwrap();
+
+    let expected_callback = format!(
+        "CALLBACK: <http://wemo.example.net:8443/?from=localhost:{}>",
+        socket_addr.port());
+    assert!(buf.contains(&expected_callback));
+  }
+
+  #[test]
+  fn test_select_local_ip() {
+    let vpn = (Ipv4Addr::new(10, 8, 0, 6), Ipv4Addr::new(255, 255, 255, 0));
+    let lan = (Ipv4Addr::new(192, 168, 1, 20),
+        Ipv4Addr::new(255, 255, 255, 0));
+    let interfaces = [vpn, lan];
+
+    let routed = Some(IpAddr::V4(vpn.0));
+
+    let device = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 4));
+    assert_eq!(Some(IpAddr::V4(lan.0)),
+        select_local_ip(&interfaces, device, routed));
+
+    // Devices on no local subnet get the routed address, or else the first
+    // interface.
+    let device = IpAddr::V4(Ipv4Addr::new(172, 16, 0, 4));
+    let routed = Some(IpAddr::V4(lan.0));
+    assert_eq!(routed, select_local_ip(&interfaces, device, routed));
+    assert_eq!(Some(IpAddr::V4(vpn.0)),
+        select_local_ip(&interfaces, device, None));
+    assert_eq!(None, select_local_ip(&[], device, None));
+  }
+
+  #[test]
+  fn test_parse_sid() {
+    let response = "HTTP/1.1 200 OK\r\n\
+        sid: uuid:7206f5ac-1dd2-11b2-80f3-e76de858414e\r\n\
+        TIMEOUT: Second-600\r\n\r\n";
+    assert_eq!(Some("uuid:7206f5ac-1dd2-11b2-80f3-e76de858414e".to_string()),
+        parse_sid(response));
+    assert_eq!(None, parse_sid("HTTP/1.1 412 Precondition Failed\r\n\r\n"));
+  }
+
+  #[test]
+  fn test_renew_each() {
+    let socket_addr = next_test_ip4();
+    let listener = TcpListener::bind(&socket_addr).unwrap();
+    let reachable = format!("localhost:{}", socket_addr.port());
+
+    let subscriptions = RwLock::new(HashMap::new());
+    let mut push_fed = Vec::new();
+    for host in [reachable.as_str(), "localhost:1"].iter() {
+      let subscription = subscription(host, BASIC_EVENT_PATH, |_n| {});
+      let flag = Arc::new(AtomicBool::new(true));
+      *subscription.push_fed.lock().unwrap() = Some(flag.clone());
+      push_fed.push(flag);
+      subscriptions.write().unwrap().insert(host.to_string(),
+          Arc::new(subscription));
+    }
+
+    let device = thread::spawn(move || {
+      accept_subscribe(&mut listener.accept().unwrap().0)
+    });
+
+    let local_ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+    let mut outcomes = super::renew_each(&subscriptions, local_ip, None, 600,
+        8080, "/", &[]);
+    outcomes.sort();
+
+    // The device's new SID replaces the old one.
+    assert_eq!(Some("uuid:test-subscription".to_string()),
+        *subscriptions.read().unwrap()[&reachable].sid.lock().unwrap());
+    assert_eq!(vec![("localhost:1".to_string(), false), (reachable, true)],
+        outcomes);
+
+    // Pushes from the lapsed subscription can't be trusted.
+    assert!(push_fed[0].load(Ordering::SeqCst));
+    assert!(!push_fed[1].load(Ordering::SeqCst));
+
+    // The renewal calls back on the current local IP.
+    let buf = device.join().unwrap();
+    assert!(buf.contains("CALLBACK: <http://10.0.0.2:8080/?from=localhost:"));
+  }
+
   #[test]
   fn test_send_subscribe() {
     let socket_addr = next_test_ip4();
@@ -339,13 +2156,42 @@ mod tests {
     let host = format!("localhost:{}", socket_addr.port());
 
     thread::spawn(move || {
-      let local_ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-      super::send_subscribe(local_ip, &host, 600, 8080).unwrap();
+      let local_ip = "127.0.0.1";
+      super::send_subscribe(local_ip, &host, BASIC_EVENT_PATH, 600, 8080, "/",
+          &[]).unwrap();
+    });
+
+    let mut stream = listener.accept().unwrap().0;
+    let buf = accept_subscribe(&mut stream);
+
+    let expected = format!("\
+      SUBSCRIBE /upnp/event/basicevent1 HTTP/1.1\r\n\
+      CALLBACK: <http://127.0.0.1:8080/?from=localhost:{}>\r\n\
+      NT: upnp:event\r\n\
+      TIMEOUT: Second-600\r\n\
+      Host: localhost:{}\r\n\
+      \r\n",
+        socket_addr.port(),
+        socket_addr.port());
+
+    assert_eq!(expected, buf);
+  }
+
+  #[test]
+  fn test_send_subscribe_with_headers() {
+    let socket_addr = next_test_ip4();
+    let listener = TcpListener::bind(&socket_addr).unwrap();
+    let host = format!("localhost:{}", socket_addr.port());
+
+    thread::spawn(move || {
+      let local_ip = "127.0.0.1";
+      let headers = vec![("Connection".to_string(), "close".to_string())];
+      super::send_subscribe(local_ip, &host, BASIC_EVENT_PATH, 600, 8080, "/",
+          &headers).unwrap();
     });
 
     let mut stream = listener.accept().unwrap().0;
-    let mut buf = String::new();
-    stream.read_to_string(&mut buf).unwrap();
+    let buf = accept_subscribe(&mut stream);
 
     let expected = format!("\
       SUBSCRIBE /upnp/event/basicevent1 HTTP/1.1\r\n\
@@ -353,6 +2199,7 @@ mod tests {
       NT: upnp:event\r\n\
       TIMEOUT: Second-600\r\n\
       Host: localhost:{}\r\n\
+      Connection: close\r\n\
       \r\n",
         socket_addr.port(),
         socket_addr.port());
@@ -360,6 +2207,50 @@ mod tests {
     assert_eq!(expected, buf);
   }
 
+  #[test]
+  fn test_invalid_header() {
+    match Subscriptions::new(3000, 600).with_header("X-Trace", "1\r\n") {
+      Err(WemoError::InvalidArgument { .. }) => {},
+      _ => panic!("expected the header to be refused"),
+    }
+
+    let headers = vec![("NT: upnp:event\r\nX".to_string(), "1".to_string())];
+    match super::send_unsubscribe("127.0.0.1:1", BASIC_EVENT_PATH, "uuid:1",
+        &headers) {
+      Err(WemoError::InvalidArgument { .. }) => {},
+      other => panic!("expected the header to be refused, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_subscribe_follows_port_drift() {
+    let socket_addr = next_test_ip4();
+    let listener = TcpListener::bind(&socket_addr).unwrap();
+
+    // Subscribed while on another port, which is now closed.
+    let host = format!("127.0.0.1:{}", next_test_port());
+    let mut ports = DevicePorts::new(Some(1), vec![socket_addr.port()]);
+
+    let subscriber_host = host.clone();
+    thread::spawn(move || {
+      let local_ip = "127.0.0.1";
+      super::subscribe_with_ports(local_ip, &subscriber_host,
+          BASIC_EVENT_PATH, &mut ports, 600, 8080, "/", &[]).unwrap();
+      assert_eq!(Some(socket_addr.port()), ports.last_known());
+    });
+
+    let mut stream = listener.accept().unwrap().0;
+    let buf = accept_subscribe(&mut stream);
+
+    // Notifications are still keyed by the original host.
+    let expected_callback = format!(
+        "CALLBACK: <http://127.0.0.1:8080/?from={}>", host);
+    let expected_host = format!("Host: 127.0.0.1:{}", socket_addr.port());
+
+    assert!(buf.contains(&expected_callback));
+    assert!(buf.contains(&expected_host));
+  }
+
   // FIXME/NB: This test fails when not connected to a network. *facepalm*
   #[test]
   fn test_callback_invocation() {
@@ -404,4 +2295,90 @@ mod tests {
     assert_eq!(expected, notice.notification_type);
     assert_eq!(host, notice.subscription_key);
   }
+
+  #[test]
+  fn test_subscription_receives_events() {
+    let device = FakeDevice::start_unique().unwrap();
+    let mut subscriptions = Subscriptions::new(next_test_port(), 600);
+    subscriptions.start_server().unwrap();
+
+    let (sender, receiver) = channel();
+    let sender = Mutex::new(sender);
+    let host = device.http_address().to_string();
+
+    subscriptions.subscribe(&host, move |notification| {
+      let _r = sender.lock().unwrap().send(notification);
+    }).unwrap();
+
+    assert_eq!(1, device.subscriber_count());
+    device.set_state(WemoState::On);
+
+    let notification = receiver.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(NotificationType::State { state: WemoState::On },
+        notification.notification_type);
+    assert_eq!(host, notification.subscription_key);
+
+    subscriptions.stop_server().unwrap();
+  }
+
+  #[test]
+  fn test_subscribe_all() {
+    use net::location::SetupUrl;
+
+    let device = FakeDevice::start_unique().unwrap();
+    let closed = next_test_ip4();
+    let gone = unique_serial_number();
+
+    let response = |serial_number: &str, address: SocketAddr| SsdpResponse {
+      serial_number: serial_number.to_string(),
+      udn: format!("uuid:Socket-1_0-{}", serial_number),
+      ip_address: address.ip(),
+      port: address.port(),
+      setup_url: SetupUrl::parse(&format!("http://{}/setup.xml", address))
+          .unwrap(),
+      server: None,
+    };
+    let mut devices = HashMap::new();
+    devices.insert(device.serial_number().to_string(),
+        response(device.serial_number(), device.http_address()));
+    devices.insert(gone.clone(), response(&gone, closed));
+
+    let mut subscriptions = Subscriptions::new(next_test_port(), 600);
+    subscriptions.start_server().unwrap();
+
+    let results = subscriptions.subscribe_all(&devices, |_n| {});
+    assert_eq!(2, results.len());
+    assert!(results[device.serial_number()].is_ok());
+    assert!(results[&gone].is_err());
+    assert_eq!(1, device.subscriber_count());
+  }
+
+  #[test]
+  fn test_dropping_subscriptions_unsubscribes() {
+    let device = FakeDevice::start_unique().unwrap();
+    let mut subscriptions = Subscriptions::new(next_test_port(), 600);
+    subscriptions.start_server().unwrap();
+    subscriptions.subscribe(&device.http_address().to_string(), |_n| {})
+        .unwrap();
+    assert_eq!(1, device.subscriber_count());
+
+    drop(subscriptions);
+    assert_eq!(0, device.subscriber_count());
+  }
+
+  #[test]
+  fn test_renewal_keeps_one_subscription() {
+    let device = FakeDevice::start_unique().unwrap();
+    let mut subscriptions = Subscriptions::new(next_test_port(), 600);
+    subscriptions.start_server().unwrap();
+    let host = device.http_address().to_string();
+    subscriptions.subscribe(&host, |_n| {}).unwrap();
+
+    assert_eq!(1, subscriptions.resubscribe_all().unwrap());
+    assert_eq!(1, subscriptions.resubscribe_all().unwrap());
+    assert_eq!(1, device.subscriber_count());
+
+    drop(subscriptions);
+    assert_eq!(0, device.subscriber_count());
+  }
 }
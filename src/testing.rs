@@ -0,0 +1,736 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! A fake WeMo device for tests, enabled with the `testing` feature. It serves
+//! `setup.xml` and its service description, `GetBinaryState`,
+//! `SetBinaryState`, `SUBSCRIBE`, and `UNSUBSCRIBE` over HTTP on localhost,
+//! answers SSDP searches sent to its own UDP socket, and pushes events to
+//! subscribers when its state changes. Like a real device, it starts a new
+//! subscription, with its own `SID` and `SEQ`, for every `SUBSCRIBE` with a
+//! `CALLBACK`, and renews one named by `SID`. It can also drop requests or
+//! move to a new port to exercise retries and relocation. Started
+//! unconfigured, it also offers the `metainfo` and `WiFiSetup` services used
+//! by onboarding.
+
+use config::WemoConfig;
+use device::state::WemoState;
+use device::switch::Switch;
+use parsing::parse_state;
+use xml::find_tag_value;
+use std::io::{ErrorKind, Read, Write};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+#[cfg(test)] use time;
+use url_crate::Url;
+
+/// How often the server threads check whether they were stopped.
+const POLL_MS: u64 = 5;
+
+/// How long to wait on a client that stops sending.
+const CLIENT_TIMEOUT_MS: u64 = 5000;
+
+/// Numbers the serials handed out by `unique_serial_number`.
+static NEXT_SERIAL_NUMBER: AtomicUsize = AtomicUsize::new(1);
+
+/// A fake WeMo Socket listening on localhost. Stopped when dropped.
+pub struct FakeDevice {
+  shared: Arc<Shared>,
+  ssdp_address: SocketAddr,
+}
+
+/// State shared with the server threads.
+struct Shared {
+  serial_number: String,
+  state: Mutex<WemoState>,
+  listener: Mutex<TcpListener>,
+  http_address: Mutex<SocketAddr>,
+  subscribers: Mutex<Vec<Subscriber>>,
+  next_sid: AtomicUsize,
+  requests: AtomicUsize,
+  requests_to_drop: AtomicUsize,
+  responses_to_lose: AtomicUsize,
+  /// Whether it's waiting to be set up, and the network it was told to join.
+  unconfigured: AtomicBool,
+  home_network: Mutex<Option<(String, String)>>,
+  stopped: AtomicBool,
+}
+
+impl FakeDevice {
+  /// Start a device with the given serial number, initially off.
+  pub fn start(serial_number: &str) -> io::Result<FakeDevice> {
+    FakeDevice::start_with(serial_number, false)
+  }
+
+  /// Start a device with a serial number from `unique_serial_number`, so
+  /// tests running side by side don't find one another's devices.
+  pub fn start_unique() -> io::Result<FakeDevice> {
+    FakeDevice::start(&unique_serial_number())
+  }
+
+  /// Start a device that's fresh out of the box, waiting to be told which
+  /// network to join.
+  pub fn start_unconfigured(serial_number: &str) -> io::Result<FakeDevice> {
+    FakeDevice::start_with(serial_number, true)
+  }
+
+  fn start_with(serial_number: &str, unconfigured: bool)
+      -> io::Result<FakeDevice> {
+    let listener = bind_listener()?;
+    let http_address = listener.local_addr()?;
+
+    let ssdp_socket = UdpSocket::bind(localhost(0))?;
+    ssdp_socket.set_read_timeout(Some(Duration::from_millis(POLL_MS)))?;
+    let ssdp_address = ssdp_socket.local_addr()?;
+
+    let shared = Arc::new(Shared {
+      serial_number: serial_number.to_string(),
+      state: Mutex::new(WemoState::Off),
+      listener: Mutex::new(listener),
+      http_address: Mutex::new(http_address),
+      subscribers: Mutex::new(Vec::new()),
+      next_sid: AtomicUsize::new(1),
+      requests: AtomicUsize::new(0),
+      requests_to_drop: AtomicUsize::new(0),
+      responses_to_lose: AtomicUsize::new(0),
+      unconfigured: AtomicBool::new(unconfigured),
+      home_network: Mutex::new(None),
+      stopped: AtomicBool::new(false),
+    });
+
+    let http_shared = shared.clone();
+    thread::spawn(move || serve_http(http_shared));
+
+    let ssdp_shared = shared.clone();
+    thread::spawn(move || serve_ssdp(ssdp_shared, ssdp_socket));
+
+    Ok(FakeDevice {
+      shared: shared,
+      ssdp_address: ssdp_address,
+    })
+  }
+
+  pub fn serial_number(&self) -> &str {
+    &self.shared.serial_number
+  }
+
+  /// The UDN the device reports, eg. `uuid:Socket-1_0-221517K0101769`.
+  pub fn udn(&self) -> String {
+    udn(&self.shared.serial_number)
+  }
+
+  /// Where the device currently serves HTTP.
+  pub fn http_address(&self) -> SocketAddr {
+    self.shared.http_address()
+  }
+
+  /// Where the device answers SSDP searches.
+  pub fn ssdp_address(&self) -> SocketAddr {
+    self.ssdp_address
+  }
+
+  /// Settings that send SSDP searches to this device and try its current
+  /// port first.
+  pub fn config(&self) -> WemoConfig {
+    let mut config = WemoConfig::default();
+    config.ssdp_address = self.ssdp_address;
+    config.default_ports = vec![self.http_address().port()];
+    config
+  }
+
+  /// A `Switch` for this device, using `config`.
+  pub fn switch(&self) -> Switch {
+    let address = self.http_address();
+    let mut switch = Switch::from_dynamic_ip_and_port(address.ip(),
+        address.port()).with_config(self.config());
+    switch.serial_number = Some(self.shared.serial_number.clone());
+    switch
+  }
+
+  pub fn state(&self) -> WemoState {
+    self.shared.state()
+  }
+
+  /// Change the state as if the device's button were pressed, notifying
+  /// subscribers.
+  pub fn set_state(&self, state: WemoState) {
+    self.shared.set_state(state);
+  }
+
+  /// How many event subscriptions the device holds.
+  pub fn subscriber_count(&self) -> usize {
+    self.shared.subscribers.lock().unwrap().len()
+  }
+
+  /// How many SOAP requests the device has received, including dropped ones.
+  pub fn request_count(&self) -> usize {
+    self.shared.requests.load(Ordering::SeqCst)
+  }
+
+  /// Never respond to the next `count` SOAP requests, as if the device had
+  /// hung.
+  pub fn drop_next_requests(&self, count: usize) {
+    self.shared.requests_to_drop.store(count, Ordering::SeqCst);
+  }
+
+  /// Carry out the next `count` SOAP requests but never respond to them, as
+  /// if the responses were lost.
+  pub fn lose_next_responses(&self, count: usize) {
+    self.shared.responses_to_lose.store(count, Ordering::SeqCst);
+  }
+
+  /// Whether setup hasn't been closed yet.
+  pub fn is_unconfigured(&self) -> bool {
+    self.shared.unconfigured.load(Ordering::SeqCst)
+  }
+
+  /// The SSID and encrypted password sent with `ConnectHomeNetwork`, if any.
+  pub fn home_network(&self) -> Option<(String, String)> {
+    self.shared.home_network.lock().unwrap().clone()
+  }
+
+  /// Stop listening on the current port and listen on a new one, as WeMo
+  /// devices occasionally do. Returns the new port.
+  pub fn move_to_new_port(&self) -> io::Result<u16> {
+    let listener = bind_listener()?;
+    let address = listener.local_addr()?;
+
+    match self.shared.listener.lock() {
+      Err(_) => { return Err(io::Error::new(ErrorKind::Other, "poisoned")); },
+      Ok(mut current) => { *current = listener; },
+    }
+    match self.shared.http_address.lock() {
+      Err(_) => { return Err(io::Error::new(ErrorKind::Other, "poisoned")); },
+      Ok(mut current) => { *current = address; },
+    }
+
+    Ok(address.port())
+  }
+}
+
+impl Drop for FakeDevice {
+  fn drop(&mut self) {
+    self.shared.stopped.store(true, Ordering::SeqCst);
+  }
+}
+
+impl Shared {
+  fn http_address(&self) -> SocketAddr {
+    *self.http_address.lock().unwrap()
+  }
+
+  fn state(&self) -> WemoState {
+    self.state.lock().unwrap().clone()
+  }
+
+  fn set_state(&self, state: WemoState) {
+    *self.state.lock().unwrap() = state.clone();
+
+    let events = self.subscribers.lock().unwrap()
+        .iter_mut()
+        .map(|subscriber| {
+          let seq = subscriber.seq;
+          subscriber.seq += 1;
+          (subscriber.callback.clone(), subscriber.sid.clone(), seq)
+        })
+        .collect::<Vec<_>>();
+
+    for (callback, sid, seq) in events {
+      let _r = send_event(&callback, &sid, seq, &state);
+    }
+  }
+
+  fn is_stopped(&self) -> bool {
+    self.stopped.load(Ordering::SeqCst)
+  }
+}
+
+/// A serial number no other call in this process has returned, eg.
+/// `FAKE0000000001`.
+pub fn unique_serial_number() -> String {
+  format!("FAKE{:010}", NEXT_SERIAL_NUMBER.fetch_add(1, Ordering::SeqCst))
+}
+
+/// A generous timeout for the crate's own tests' requests to a fake device.
+#[cfg(test)]
+pub fn timeout() -> time::Duration {
+  time::Duration::seconds(3)
+}
+
+fn localhost(port: u16) -> SocketAddr {
+  SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port)
+}
+
+fn udn(serial_number: &str) -> String {
+  format!("uuid:Socket-1_0-{}", serial_number)
+}
+
+fn bind_listener() -> io::Result<TcpListener> {
+  let listener = TcpListener::bind(localhost(0))?;
+  listener.set_nonblocking(true)?;
+  Ok(listener)
+}
+
+fn serve_http(shared: Arc<Shared>) {
+  while !shared.is_stopped() {
+    let accepted = shared.listener.lock().unwrap().accept();
+
+    match accepted {
+      Ok((stream, _)) => {
+        let connection_shared = shared.clone();
+        thread::spawn(move || {
+          let _r = serve_connection(connection_shared, stream);
+        });
+      },
+      Err(_) => { thread::sleep(Duration::from_millis(POLL_MS)); },
+    }
+  }
+}
+
+fn serve_ssdp(shared: Arc<Shared>, socket: UdpSocket) {
+  let mut buf = [0; 2048];
+
+  while !shared.is_stopped() {
+    let (amount, sender) = match socket.recv_from(&mut buf) {
+      Err(_) => { continue; },
+      Ok(received) => { received },
+    };
+
+    if !String::from_utf8_lossy(&buf[..amount]).starts_with("M-SEARCH") {
+      continue;
+    }
+
+    let response = format!("\
+        HTTP/1.1 200 OK\r\n\
+        CACHE-CONTROL: max-age=86400\r\n\
+        LOCATION: http://{}/setup.xml\r\n\
+        SERVER: Unspecified, UPnP/1.0, Unspecified\r\n\
+        ST: urn:Belkin:device:controllee:1\r\n\
+        USN: {}::urn:Belkin:device:controllee:1\r\n\
+        \r\n",
+        shared.http_address(),
+        udn(&shared.serial_number));
+
+    let _r = socket.send_to(response.as_bytes(), sender);
+  }
+}
+
+/// A request read off the wire.
+struct HttpRequest {
+  method: String,
+  path: String,
+  headers: Vec<(String, String)>,
+  body: String,
+}
+
+impl HttpRequest {
+  fn header(&self, name: &str) -> Option<&str> {
+    self.headers.iter()
+        .find(|&&(ref key, _)| key.eq_ignore_ascii_case(name))
+        .map(|&(_, ref value)| value.as_str())
+  }
+}
+
+fn serve_connection(shared: Arc<Shared>, mut stream: TcpStream)
+    -> io::Result<()> {
+  stream.set_nonblocking(false)?;
+  stream.set_read_timeout(Some(Duration::from_millis(CLIENT_TIMEOUT_MS)))?;
+
+  loop {
+    let request = match read_request(&mut stream)? {
+      None => { return Ok(()); },
+      Some(request) => { request },
+    };
+
+    let keep_alive = request.header("Connection")
+        .map(|value| value.eq_ignore_ascii_case("keep-alive"))
+        .unwrap_or(false);
+
+    let response = match (request.method.as_str(), request.path.as_str()) {
+      ("GET", "/setup.xml") => {
+        ok(&setup_xml(&shared.serial_number,
+            shared.unconfigured.load(Ordering::SeqCst)))
+      },
+      ("GET", "/eventservice.xml") => ok(EVENT_SERVICE_XML),
+      ("POST", "/upnp/control/basicevent1") => {
+        shared.requests.fetch_add(1, Ordering::SeqCst);
+
+        let dropped = shared.requests_to_drop.load(Ordering::SeqCst);
+        if dropped > 0 {
+          shared.requests_to_drop.store(dropped - 1, Ordering::SeqCst);
+          // Hang until the client gives up.
+          let _r = stream.read_to_end(&mut Vec::new());
+          return Ok(());
+        }
+
+        let response = handle_soap(&shared, &request);
+
+        let lost = shared.responses_to_lose.load(Ordering::SeqCst);
+        if lost > 0 {
+          shared.responses_to_lose.store(lost - 1, Ordering::SeqCst);
+          let _r = stream.read_to_end(&mut Vec::new());
+          return Ok(());
+        }
+
+        response
+      },
+      ("POST", "/upnp/control/metainfo1")
+          | ("POST", "/upnp/control/WiFiSetup1")
+          if shared.unconfigured.load(Ordering::SeqCst) => {
+        handle_setup(&shared, &request)
+      },
+      ("SUBSCRIBE", "/upnp/event/basicevent1") => {
+        handle_subscribe(&shared, &request)
+      },
+      ("UNSUBSCRIBE", "/upnp/event/basicevent1") => {
+        handle_unsubscribe(&shared, &request)
+      },
+      _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+    };
+
+    stream.write_all(response.as_bytes())?;
+
+    if !keep_alive {
+      return Ok(());
+    }
+  }
+}
+
+/// Read one request, or `None` if the client closed the connection.
+fn read_request(stream: &mut TcpStream) -> io::Result<Option<HttpRequest>> {
+  let mut data = Vec::new();
+  let mut buf = [0; 1024];
+
+  let header_end = loop {
+    if let Some(index) = find(&data, b"\r\n\r\n") {
+      break index;
+    }
+
+    let amount = stream.read(&mut buf)?;
+    if amount == 0 {
+      return Ok(None);
+    }
+    data.extend_from_slice(&buf[..amount]);
+  };
+
+  let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+  let mut lines = head.split("\r\n");
+
+  let mut request_line = lines.next().unwrap_or("").split_whitespace();
+  let method = request_line.next().unwrap_or("").to_string();
+  let path = request_line.next().unwrap_or("").to_string();
+
+  let headers = lines
+      .filter_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+          (Some(name), Some(value)) => {
+            Some((name.trim().to_string(), value.trim().to_string()))
+          },
+          _ => None,
+        }
+      })
+      .collect::<Vec<_>>();
+
+  let content_length = headers.iter()
+      .find(|&&(ref name, _)| name.eq_ignore_ascii_case("Content-Length"))
+      .and_then(|&(_, ref value)| value.parse::<usize>().ok())
+      .unwrap_or(0);
+
+  let body_start = header_end + 4;
+  while data.len() < body_start + content_length {
+    let amount = stream.read(&mut buf)?;
+    if amount == 0 {
+      break;
+    }
+    data.extend_from_slice(&buf[..amount]);
+  }
+
+  let body_end = data.len().min(body_start + content_length);
+  let body = String::from_utf8_lossy(&data[body_start..body_end]).to_string();
+
+  Ok(Some(HttpRequest {
+    method: method,
+    path: path,
+    headers: headers,
+    body: body,
+  }))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+  haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn ok(body: &str) -> String {
+  format!("HTTP/1.1 200 OK\r\n\
+      CONTENT-TYPE: text/xml; charset=\"utf-8\"\r\n\
+      CONTENT-LENGTH: {}\r\n\
+      \r\n\
+      {}", body.len(), body)
+}
+
+fn handle_soap(shared: &Shared, request: &HttpRequest) -> String {
+  let action = request.header("SOAPACTION").unwrap_or("");
+
+  let (response_element, state) = if action.contains("#SetBinaryState") {
+    match parse_state(&request.body) {
+      Err(_) => { return "HTTP/1.1 500 Internal Server Error\r\n\
+          Content-Length: 0\r\n\r\n".to_string(); },
+      // Like real devices, refuse to set the state it's already in.
+      Ok(ref state) if *state == shared.state() => {
+        ("SetBinaryStateResponse", "Error".to_string())
+      },
+      Ok(state) => {
+        shared.set_state(state.clone());
+        ("SetBinaryStateResponse", state.to_i8().to_string())
+      },
+    }
+  } else {
+    ("GetBinaryStateResponse", shared.state().to_i8().to_string())
+  };
+
+  ok(&format!("\
+      <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+          s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+        <s:Body>\
+          <u:{} xmlns:u=\"urn:Belkin:service:basicevent:1\">\
+            <BinaryState>{}</BinaryState>\
+          </u:{}>\
+        </s:Body>\
+      </s:Envelope>",
+      response_element, state, response_element))
+}
+
+/// Answer the setup actions onboarding uses. The device joins the home
+/// network as soon as it's told to.
+fn handle_setup(shared: &Shared, request: &HttpRequest) -> String {
+  let action = request.header("SOAPACTION").unwrap_or("")
+      .trim_matches('"')
+      .rsplit('#')
+      .next()
+      .unwrap_or("")
+      .to_string();
+  let argument = |name: &str| {
+    find_tag_value(name, &request.body).unwrap_or("").to_string()
+  };
+
+  let (service, result) = match action.as_str() {
+    "GetMetaInfo" => ("metainfo", format!("<MetaInfo>EC1A59F0A1B2|{}|\
+        Plugin Device|WeMo_WW_2.00.11057.PVT-OWRT-SNS|WeMo_Smart_Socket|\
+        Socket</MetaInfo>", shared.serial_number)),
+    "GetApList" => ("WiFiSetup", "<ApList>Page:1/1/2$\n\
+        Home|6|100|WPA2PSK/AES,\n\
+        Neighbor|11|40|WPA2PSK/AES,\n</ApList>".to_string()),
+    "ConnectHomeNetwork" => {
+      *shared.home_network.lock().unwrap() =
+          Some((argument("ssid"), argument("password")));
+      ("WiFiSetup", "<PairingStatus>Connecting</PairingStatus>".to_string())
+    },
+    "GetNetworkStatus" => {
+      let joined = shared.home_network.lock().unwrap().is_some();
+      ("WiFiSetup", format!("<NetworkStatus>{}</NetworkStatus>",
+          if joined { 1 } else { 0 }))
+    },
+    "CloseSetup" => {
+      shared.unconfigured.store(false, Ordering::SeqCst);
+      ("WiFiSetup", "<status>success</status>".to_string())
+    },
+    _ => {
+      return "HTTP/1.1 500 Internal Server Error\r\n\
+          Content-Length: 0\r\n\r\n".to_string();
+    },
+  };
+
+  ok(&format!("\
+      <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+          s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+        <s:Body>\
+          <u:{}Response xmlns:u=\"urn:Belkin:service:{}:1\">{}</u:{}Response>\
+        </s:Body>\
+      </s:Envelope>",
+      action, service, result, action))
+}
+
+/// A subscription the device accepted.
+struct Subscriber {
+  sid: String,
+  callback: Url,
+  /// The `SEQ` of the next event.
+  seq: u32,
+}
+
+/// A `SUBSCRIBE` with a `CALLBACK` starts a new subscription, even for a
+/// callback that's already subscribed; one with only a `SID` renews it.
+fn handle_subscribe(shared: &Shared, request: &HttpRequest) -> String {
+  let callback = request.header("CALLBACK")
+      .map(|value| value.trim_matches(|c| c == '<' || c == '>'))
+      .and_then(|value| Url::parse(value).ok());
+
+  let mut subscribers = shared.subscribers.lock().unwrap();
+  let sid = match (callback, request.header("SID")) {
+    (Some(callback), _) => {
+      let sid = format!("uuid:{}-subscription-{}", shared.serial_number,
+          shared.next_sid.fetch_add(1, Ordering::SeqCst));
+      subscribers.push(Subscriber {
+        sid: sid.clone(),
+        callback: callback,
+        seq: 0,
+      });
+      sid
+    },
+    (None, Some(sid)) if subscribers.iter().any(|s| s.sid == sid) => {
+      sid.to_string()
+    },
+    _ => { return precondition_failed(); },
+  };
+
+  format!("HTTP/1.1 200 OK\r\n\
+      SID: {}\r\n\
+      TIMEOUT: Second-600\r\n\
+      Content-Length: 0\r\n\
+      \r\n", sid)
+}
+
+fn handle_unsubscribe(shared: &Shared, request: &HttpRequest) -> String {
+  let mut subscribers = shared.subscribers.lock().unwrap();
+  let count = subscribers.len();
+  subscribers.retain(|subscriber| {
+    request.header("SID") != Some(subscriber.sid.as_str())
+  });
+
+  if subscribers.len() == count {
+    return precondition_failed();
+  }
+  "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string()
+}
+
+fn precondition_failed() -> String {
+  "HTTP/1.1 412 Precondition Failed\r\nContent-Length: 0\r\n\r\n"
+      .to_string()
+}
+
+fn send_event(callback: &Url, sid: &str, seq: u32, state: &WemoState)
+    -> io::Result<()> {
+  let host = callback.host_str().unwrap_or("127.0.0.1");
+  let port = callback.port().unwrap_or(80);
+  let path = match callback.query() {
+    None => callback.path().to_string(),
+    Some(query) => format!("{}?{}", callback.path(), query),
+  };
+
+  let body = format!("\
+      <e:propertyset xmlns:e=\"urn:schemas-upnp-org:event-1-0\">\
+        <e:property>\
+          <BinaryState>{}</BinaryState>\
+        </e:property>\
+      </e:propertyset>", state.to_i8());
+
+  let mut stream = TcpStream::connect((host, port))?;
+  stream.set_read_timeout(Some(Duration::from_millis(CLIENT_TIMEOUT_MS)))?;
+  write!(stream, "\
+      NOTIFY {} HTTP/1.1\r\n\
+      HOST: {}:{}\r\n\
+      CONTENT-TYPE: text/xml; charset=\"utf-8\"\r\n\
+      NT: upnp:event\r\n\
+      NTS: upnp:propchange\r\n\
+      SID: {}\r\n\
+      SEQ: {}\r\n\
+      CONTENT-LENGTH: {}\r\n\
+      Connection: close\r\n\
+      \r\n\
+      {}", path, host, port, sid, seq, body.len(), body)?;
+
+  // Wait for the subscriber to handle the event.
+  let _r = stream.read(&mut [0; 1024]);
+  Ok(())
+}
+
+fn setup_xml(serial_number: &str, unconfigured: bool) -> String {
+  let setup_services = if unconfigured { SETUP_SERVICES_XML } else { "" };
+
+  format!("\
+      <?xml version=\"1.0\"?>\
+      <root xmlns=\"urn:Belkin:device-1-0\">\
+        <device>\
+          <deviceType>urn:Belkin:device:controllee:1</deviceType>\
+          <friendlyName>Fake Switch</friendlyName>\
+          <manufacturer>Belkin International Inc.</manufacturer>\
+          <modelName>Socket</modelName>\
+          <UDN>{}</UDN>\
+          <serialNumber>{}</serialNumber>\
+          <serviceList>\
+            <service>\
+              <serviceType>urn:Belkin:service:basicevent:1</serviceType>\
+              <serviceId>urn:Belkin:serviceId:basicevent1</serviceId>\
+              <controlURL>/upnp/control/basicevent1</controlURL>\
+              <eventSubURL>/upnp/event/basicevent1</eventSubURL>\
+              <SCPDURL>/eventservice.xml</SCPDURL>\
+            </service>{}\
+          </serviceList>\
+        </device>\
+      </root>", udn(serial_number), serial_number, setup_services)
+}
+
+const SETUP_SERVICES_XML: &'static str = "\
+    <service>\
+      <serviceType>urn:Belkin:service:metainfo:1</serviceType>\
+      <serviceId>urn:Belkin:serviceId:metainfo1</serviceId>\
+      <controlURL>/upnp/control/metainfo1</controlURL>\
+      <eventSubURL>/upnp/event/metainfo1</eventSubURL>\
+      <SCPDURL>/metainfoservice.xml</SCPDURL>\
+    </service>\
+    <service>\
+      <serviceType>urn:Belkin:service:WiFiSetup:1</serviceType>\
+      <serviceId>urn:Belkin:serviceId:WiFiSetup1</serviceId>\
+      <controlURL>/upnp/control/WiFiSetup1</controlURL>\
+      <eventSubURL>/upnp/event/WiFiSetup1</eventSubURL>\
+      <SCPDURL>/setupservice.xml</SCPDURL>\
+    </service>";
+
+const EVENT_SERVICE_XML: &'static str = "\
+    <?xml version=\"1.0\"?>\
+    <scpd xmlns=\"urn:Belkin:service-1-0\">\
+      <actionList>\
+        <action>\
+          <name>GetBinaryState</name>\
+          <argumentList>\
+            <argument>\
+              <name>BinaryState</name>\
+              <relatedStateVariable>BinaryState</relatedStateVariable>\
+              <direction>out</direction>\
+            </argument>\
+          </argumentList>\
+        </action>\
+        <action>\
+          <name>SetBinaryState</name>\
+          <argumentList>\
+            <argument>\
+              <name>BinaryState</name>\
+              <relatedStateVariable>BinaryState</relatedStateVariable>\
+              <direction>in</direction>\
+            </argument>\
+          </argumentList>\
+        </action>\
+      </actionList>\
+      <serviceStateTable>\
+        <stateVariable sendEvents=\"yes\">\
+          <name>BinaryState</name>\
+          <dataType>Boolean</dataType>\
+        </stateVariable>\
+      </serviceStateTable>\
+    </scpd>";
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_unique_serial_number() {
+    let first = FakeDevice::start_unique().unwrap();
+    let second = FakeDevice::start_unique().unwrap();
+    assert!(first.serial_number() != second.serial_number());
+    assert_eq!(14, first.serial_number().len());
+  }
+}
@@ -0,0 +1,131 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Spawning the library's background threads. Each is named, eg.
+//! `wemo-resubscribe`, so it shows up in debuggers and panic messages, and a
+//! panic is logged and reported to `WemoMetrics::on_thread_panic`, since
+//! otherwise a long-running process would just quietly stop, say, renewing
+//! its subscriptions.
+
+use metrics;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+/// Like `thread::spawn`, on a thread called `name`. The thread still panics
+/// after reporting, so joining it returns the panic as usual.
+pub fn spawn<F, T>(name: &str, f: F) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+  let thread_name = name.to_string();
+
+  thread::Builder::new()
+      .name(name.to_string())
+      .spawn(move || {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+          Ok(result) => result,
+          Err(cause) => {
+            report(&thread_name, &*cause);
+            panic::resume_unwind(cause)
+          },
+        }
+      })
+      .expect("failed to spawn thread") // As `thread::spawn` does.
+}
+
+/// Call `f` on every item from at most `limit` scoped threads, each taking
+/// the next unclaimed item until none are left, so a thousand devices don't
+/// mean a thousand threads. Results are in the order of `items`; an item
+/// whose call panicked has none.
+pub fn map_bounded<T, R, F>(items: &[T], limit: usize, f: F) -> Vec<Option<R>>
+    where T: Sync, R: Send, F: Fn(&T) -> R + Sync {
+  let next = AtomicUsize::new(0);
+  let (next, f) = (&next, &f);
+  let workers = limit.max(1).min(items.len());
+
+  thread::scope(|scope| {
+    let handles = (0..workers)
+        .map(|_| {
+          scope.spawn(move || {
+            let mut done = Vec::new();
+            loop {
+              let index = next.fetch_add(1, Ordering::SeqCst);
+              let item = match items.get(index) {
+                Some(item) => item,
+                None => break,
+              };
+              let result = panic::catch_unwind(AssertUnwindSafe(|| f(item)));
+              done.push((index, result.ok()));
+            }
+            done
+          })
+        })
+        .collect::<Vec<_>>();
+
+    let mut results = (0..items.len()).map(|_| None).collect::<Vec<_>>();
+    for handle in handles {
+      if let Ok(done) = handle.join() {
+        for (index, result) in done {
+          results[index] = result;
+        }
+      }
+    }
+    results
+  })
+}
+
+fn report(name: &str, cause: &(Any + Send)) {
+  let message = panic_message(cause);
+  error!(target: "wemo", thread = name; "{} thread died: {}", name, message);
+  metrics::report(|metrics| metrics.on_thread_panic(name, &message));
+}
+
+/// The message a thread panicked with, if it was a string.
+pub fn panic_message(cause: &(Any + Send)) -> String {
+  cause.downcast_ref::<&str>()
+      .map(|message| message.to_string())
+      .or_else(|| cause.downcast_ref::<String>().cloned())
+      .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_spawn() {
+    let handle = spawn("wemo-test", || {
+      thread::current().name().map(|name| name.to_string())
+    });
+    assert_eq!(Some("wemo-test".to_string()), handle.join().unwrap());
+
+    let handle = spawn("wemo-test-panic", || -> () {
+      panic!("renewal failed")
+    });
+    let cause = handle.join().unwrap_err();
+    assert_eq!("renewal failed", panic_message(&*cause));
+  }
+
+  #[test]
+  fn test_map_bounded() {
+    let running = AtomicUsize::new(0);
+    let most = AtomicUsize::new(0);
+    let items = (0..20).collect::<Vec<usize>>();
+
+    let results = map_bounded(&items, 3, |&item| {
+      let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+      most.fetch_max(now, Ordering::SeqCst);
+      thread::sleep(::std::time::Duration::from_millis(5));
+      running.fetch_sub(1, Ordering::SeqCst);
+      if item == 7 {
+        panic!("request failed");
+      }
+      item * 2
+    });
+
+    assert!(most.load(Ordering::SeqCst) <= 3);
+    assert_eq!(20, results.len());
+    assert_eq!(None, results[7]);
+    assert_eq!(Some(38), results[19]);
+    assert_eq!(19, results.iter().filter(|result| result.is_some()).count());
+  }
+}
@@ -0,0 +1,220 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Time types used throughout the API. These replace the re-exports of the
+//! `time` 0.1 crate, so depending on wemo doesn't pin an old version of it.
+//! They keep the methods callers used, eg. `Duration::seconds`, and convert
+//! to and from `std::time`. With the `time01` feature they also convert to
+//! and from `time` 0.1, for migrating code that still uses it.
+
+use std::error::Error;
+use std::fmt;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::time::{Duration as StdDuration, Instant};
+
+const NANOS_PER_MICRO: i64 = 1_000;
+const NANOS_PER_MILLI: i64 = 1_000_000;
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// A signed span of time with nanosecond precision, like `time::Duration`.
+/// Arithmetic saturates instead of overflowing, at about 292 years.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Duration {
+  nanos: i64,
+}
+
+impl Duration {
+  pub fn zero() -> Duration {
+    Duration { nanos: 0 }
+  }
+
+  pub fn hours(hours: i64) -> Duration {
+    Duration::seconds(hours.saturating_mul(3600))
+  }
+
+  pub fn minutes(minutes: i64) -> Duration {
+    Duration::seconds(minutes.saturating_mul(60))
+  }
+
+  pub fn seconds(seconds: i64) -> Duration {
+    Duration { nanos: seconds.saturating_mul(NANOS_PER_SEC) }
+  }
+
+  pub fn milliseconds(milliseconds: i64) -> Duration {
+    Duration { nanos: milliseconds.saturating_mul(NANOS_PER_MILLI) }
+  }
+
+  pub fn microseconds(microseconds: i64) -> Duration {
+    Duration { nanos: microseconds.saturating_mul(NANOS_PER_MICRO) }
+  }
+
+  pub fn nanoseconds(nanoseconds: i64) -> Duration {
+    Duration { nanos: nanoseconds }
+  }
+
+  pub fn num_seconds(&self) -> i64 {
+    self.nanos / NANOS_PER_SEC
+  }
+
+  pub fn num_milliseconds(&self) -> i64 {
+    self.nanos / NANOS_PER_MILLI
+  }
+
+  pub fn num_microseconds(&self) -> Option<i64> {
+    Some(self.nanos / NANOS_PER_MICRO)
+  }
+
+  pub fn num_nanoseconds(&self) -> Option<i64> {
+    Some(self.nanos)
+  }
+
+  /// Fails for negative durations, which `std::time::Duration` can't hold.
+  pub fn to_std(&self) -> Result<StdDuration, OutOfRangeError> {
+    if self.nanos < 0 {
+      return Err(OutOfRangeError(()));
+    }
+    Ok(StdDuration::new((self.nanos / NANOS_PER_SEC) as u64,
+        (self.nanos % NANOS_PER_SEC) as u32))
+  }
+
+  /// Fails for durations longer than about 292 years.
+  pub fn from_std(duration: StdDuration) -> Result<Duration, OutOfRangeError> {
+    let nanos = duration.as_nanos();
+    if nanos > i64::MAX as u128 {
+      return Err(OutOfRangeError(()));
+    }
+    Ok(Duration { nanos: nanos as i64 })
+  }
+}
+
+impl From<StdDuration> for Duration {
+  /// Durations too long to represent saturate.
+  fn from(duration: StdDuration) -> Duration {
+    Duration::from_std(duration)
+        .unwrap_or(Duration { nanos: i64::MAX })
+  }
+}
+
+#[cfg(feature = "time01")]
+impl From<::time01::Duration> for Duration {
+  fn from(duration: ::time01::Duration) -> Duration {
+    match duration.num_nanoseconds() {
+      Some(nanos) => Duration { nanos: nanos },
+      None if duration < ::time01::Duration::zero() => {
+        Duration { nanos: i64::MIN }
+      },
+      None => Duration { nanos: i64::MAX },
+    }
+  }
+}
+
+#[cfg(feature = "time01")]
+impl From<Duration> for ::time01::Duration {
+  fn from(duration: Duration) -> ::time01::Duration {
+    ::time01::Duration::nanoseconds(duration.nanos)
+  }
+}
+
+impl Add for Duration {
+  type Output = Duration;
+
+  fn add(self, other: Duration) -> Duration {
+    Duration { nanos: self.nanos.saturating_add(other.nanos) }
+  }
+}
+
+impl Sub for Duration {
+  type Output = Duration;
+
+  fn sub(self, other: Duration) -> Duration {
+    Duration { nanos: self.nanos.saturating_sub(other.nanos) }
+  }
+}
+
+impl Mul<i32> for Duration {
+  type Output = Duration;
+
+  fn mul(self, factor: i32) -> Duration {
+    Duration { nanos: self.nanos.saturating_mul(factor as i64) }
+  }
+}
+
+impl Div<i32> for Duration {
+  type Output = Duration;
+
+  fn div(self, divisor: i32) -> Duration {
+    Duration { nanos: self.nanos / divisor as i64 }
+  }
+}
+
+impl Neg for Duration {
+  type Output = Duration;
+
+  fn neg(self) -> Duration {
+    Duration { nanos: self.nanos.saturating_neg() }
+  }
+}
+
+/// A `Duration` couldn't be converted to or from `std::time::Duration`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OutOfRangeError(());
+
+impl fmt::Display for OutOfRangeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "duration out of range")
+  }
+}
+
+impl Error for OutOfRangeError {}
+
+/// A monotonic timestamp for measuring elapsed time, like
+/// `time::PreciseTime`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PreciseTime {
+  instant: Instant,
+}
+
+impl PreciseTime {
+  pub fn now() -> PreciseTime {
+    PreciseTime { instant: Instant::now() }
+  }
+
+  /// The time from this to `later`, negative if `later` is earlier.
+  pub fn to(&self, later: PreciseTime) -> Duration {
+    if later.instant >= self.instant {
+      Duration::from(later.instant - self.instant)
+    } else {
+      -Duration::from(self.instant - later.instant)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_duration() {
+    assert_eq!(Duration::seconds(90), Duration::minutes(1)
+        + Duration::milliseconds(30_000));
+    assert_eq!(1500, (Duration::seconds(3) / 2).num_milliseconds());
+    assert_eq!(-5, Duration::milliseconds(-5).num_milliseconds());
+    assert!(Duration::milliseconds(-5) < Duration::zero());
+
+    assert_eq!(Ok(StdDuration::from_millis(1500)),
+        Duration::milliseconds(1500).to_std());
+    assert!(Duration::milliseconds(-5).to_std().is_err());
+    assert_eq!(Duration::milliseconds(1500),
+        Duration::from(StdDuration::from_millis(1500)));
+    assert_eq!(i64::MAX,
+        Duration::from(StdDuration::from_secs(u64::MAX))
+            .num_nanoseconds().unwrap());
+  }
+
+  #[test]
+  fn test_precise_time() {
+    let start = PreciseTime::now();
+    let later = PreciseTime::now();
+    assert!(start.to(later) >= Duration::zero());
+    assert!(later.to(start) <= Duration::zero());
+  }
+}
@@ -0,0 +1,242 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! A parser for the small subset of TOML used by device files: arrays of
+//! tables (`[[devices]]`) holding strings, integers, booleans, and
+//! single-line arrays, with `#` comments.
+
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+  String(String),
+  Integer(i64),
+  Boolean(bool),
+  Array(Vec<Value>),
+}
+
+pub type Table = BTreeMap<String, Value>;
+
+/// Parse the input into its tables, in order, each with its header name.
+/// Errors name the offending line.
+pub fn parse(input: &str) -> Result<Vec<(String, Table)>, String> {
+  let mut tables: Vec<(String, Table)> = Vec::new();
+
+  for (i, line) in input.lines().enumerate() {
+    parse_line(line, &mut tables)
+        .map_err(|reason| format!("line {}: {}", i + 1, reason))?;
+  }
+
+  Ok(tables)
+}
+
+fn parse_line(line: &str, tables: &mut Vec<(String, Table)>)
+    -> Result<(), String> {
+  let line = line.trim();
+  if line.is_empty() || line.starts_with('#') {
+    return Ok(());
+  }
+
+  if line.starts_with("[[") {
+    let end = line.find("]]").ok_or("unterminated table header")?;
+    let name = line[2..end].trim();
+    if !is_bare_key(name) {
+      return Err(format!("invalid table name '{}'", name));
+    }
+    expect_end(&mut line[end + 2..].chars().peekable())?;
+    tables.push((name.to_string(), Table::new()));
+    return Ok(());
+  }
+
+  if line.starts_with('[') {
+    return Err("only arrays of tables ([[name]]) are supported".to_string());
+  }
+
+  let equals = line.find('=').ok_or("expected 'key = value'")?;
+  let key = line[..equals].trim();
+  if !is_bare_key(key) {
+    return Err(format!("invalid key '{}'", key));
+  }
+
+  let mut chars = line[equals + 1..].chars().peekable();
+  let value = parse_value(&mut chars)?;
+  expect_end(&mut chars)?;
+
+  let table = match tables.last_mut() {
+    None => { return Err(format!("key '{}' is outside a table", key)); },
+    Some(&mut (_, ref mut table)) => { table },
+  };
+
+  if table.insert(key.to_string(), value).is_some() {
+    return Err(format!("duplicate key '{}'", key));
+  }
+
+  Ok(())
+}
+
+fn is_bare_key(key: &str) -> bool {
+  !key.is_empty() && key.chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+  while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+    chars.next();
+  }
+}
+
+/// Only whitespace or a comment may follow.
+fn expect_end(chars: &mut Peekable<Chars>) -> Result<(), String> {
+  skip_whitespace(chars);
+  match chars.peek() {
+    None | Some(&'#') => Ok(()),
+    Some(c) => Err(format!("unexpected '{}'", c)),
+  }
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<Value, String> {
+  skip_whitespace(chars);
+
+  match chars.peek().cloned() {
+    Some('"') | Some('\'') => parse_string(chars).map(Value::String),
+    Some('[') => parse_array(chars),
+    Some(c) if c == '-' || c == '+' || c.is_ascii_digit() => {
+      parse_integer(chars)
+    },
+    Some(c) if c.is_ascii_alphabetic() => {
+      let mut word = String::new();
+      while chars.peek().map(|c| c.is_ascii_alphabetic()).unwrap_or(false) {
+        word.push(chars.next().unwrap_or_default());
+      }
+      match word.as_str() {
+        "true" => Ok(Value::Boolean(true)),
+        "false" => Ok(Value::Boolean(false)),
+        _ => Err(format!("unexpected '{}'", word)),
+      }
+    },
+    Some(c) => Err(format!("unexpected '{}'", c)),
+    None => Err("missing value".to_string()),
+  }
+}
+
+/// A basic ("...") string with escapes, or a literal ('...') one without.
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+  let quote = chars.next().unwrap_or_default();
+  let mut string = String::new();
+
+  loop {
+    match chars.next() {
+      None => { return Err("unterminated string".to_string()); },
+      Some(c) if c == quote => { return Ok(string); },
+      Some('\\') if quote == '"' => {
+        match chars.next() {
+          Some('"') => string.push('"'),
+          Some('\\') => string.push('\\'),
+          Some('n') => string.push('\n'),
+          Some('r') => string.push('\r'),
+          Some('t') => string.push('\t'),
+          Some(c) => { return Err(format!("unsupported escape '\\{}'", c)); },
+          None => { return Err("unterminated string".to_string()); },
+        }
+      },
+      Some(c) => string.push(c),
+    }
+  }
+}
+
+fn parse_integer(chars: &mut Peekable<Chars>) -> Result<Value, String> {
+  let mut digits = String::new();
+  if let Some(&sign) = chars.peek() {
+    if sign == '-' || sign == '+' {
+      digits.push(sign);
+      chars.next();
+    }
+  }
+
+  while let Some(&c) = chars.peek() {
+    if c.is_ascii_digit() {
+      digits.push(c);
+    } else if c != '_' {
+      break;
+    }
+    chars.next();
+  }
+
+  digits.parse()
+      .map(Value::Integer)
+      .map_err(|_| format!("invalid integer '{}'", digits))
+}
+
+fn parse_array(chars: &mut Peekable<Chars>) -> Result<Value, String> {
+  chars.next(); // '['
+  let mut values = Vec::new();
+
+  loop {
+    skip_whitespace(chars);
+    if chars.peek() == Some(&']') {
+      chars.next();
+      return Ok(Value::Array(values));
+    }
+
+    values.push(parse_value(chars)?);
+
+    skip_whitespace(chars);
+    match chars.next() {
+      Some(',') => {},
+      Some(']') => { return Ok(Value::Array(values)); },
+      Some(c) => { return Err(format!("unexpected '{}' in array", c)); },
+      None => { return Err("unterminated array".to_string()); },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let input = r#"
+      # Devices.
+      [[devices]]
+      name = "Porch \"front\" light" # Trailing comment.
+      port = 49_153
+      enabled = true
+      aliases = ["porch", 'front # door',]
+
+      [[devices]]
+      offset = -2
+      empty = []
+    "#;
+
+    let tables = parse(input).unwrap();
+    assert_eq!(2, tables.len());
+
+    let (ref name, ref first) = tables[0];
+    assert_eq!("devices", name);
+    assert_eq!(Some(&Value::String("Porch \"front\" light".to_string())),
+        first.get("name"));
+    assert_eq!(Some(&Value::Integer(49153)), first.get("port"));
+    assert_eq!(Some(&Value::Boolean(true)), first.get("enabled"));
+    assert_eq!(Some(&Value::Array(vec![
+        Value::String("porch".to_string()),
+        Value::String("front # door".to_string())])), first.get("aliases"));
+
+    let second = &tables[1].1;
+    assert_eq!(Some(&Value::Integer(-2)), second.get("offset"));
+    assert_eq!(Some(&Value::Array(Vec::new())), second.get("empty"));
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    assert_eq!(Err("line 1: key 'name' is outside a table".to_string()),
+        parse("name = \"porch\""));
+    assert_eq!(Err("line 3: duplicate key 'port'".to_string()),
+        parse("[[devices]]\nport = 1\nport = 2"));
+    assert!(parse("[[devices]]\nname = \"porch").is_err());
+    assert!(parse("[[devices]]\nname = porch").is_err());
+    assert!(parse("[[devices]]\nport = 1 2").is_err());
+    assert!(parse("[devices]").is_err());
+  }
+}
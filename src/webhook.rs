@@ -0,0 +1,306 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! Forwards subscription notifications to a webhook as JSON, for bridging
+//! WeMo events into other services without writing glue code. Bodies are
+//! encoded as described in the `json` module.
+
+use error::WemoError;
+use json;
+use net::http;
+use std::net::ToSocketAddrs;
+use std::sync::Mutex;
+use std::sync::mpsc::{Sender, channel};
+use std::thread::JoinHandle;
+use std::thread;
+use subscriptions::Notification;
+use threads;
+use time::Duration;
+use url_crate::Url;
+
+/// Where and how to POST notifications. Only plain `http://` URLs are
+/// supported.
+#[derive(Clone, Debug)]
+pub struct Webhook {
+  url: Url,
+  headers: Vec<(String, String)>,
+  retries: u32,
+  retry_delay: Duration,
+  timeout: Duration,
+}
+
+impl Webhook {
+  /// Webhook CTOR. Failed deliveries are retried twice, a second apart.
+  /// Fails with `WemoError::InvalidUrl` if `url` can't be parsed, or isn't a
+  /// plain `http://` URL with a host.
+  pub fn new(url: &str) -> Result<Webhook, WemoError> {
+    let url = Url::parse(url).map_err(|error| WemoError::InvalidUrl {
+      reason: format!("{}: {}", error, url),
+    })?;
+
+    if url.scheme() != "http" || url.host_str().is_none() {
+      return Err(WemoError::InvalidUrl {
+        reason: format!("Only http:// URLs are supported: {}", url),
+      });
+    }
+
+    Ok(Webhook {
+      url: url,
+      headers: Vec::new(),
+      retries: 2,
+      retry_delay: Duration::seconds(1),
+      timeout: Duration::seconds(5),
+    })
+  }
+
+  /// Send an extra HTTP header with every request, eg. for authorization.
+  /// Fails if the header can't be sent as given, eg. its value holds a line
+  /// break.
+  pub fn with_header(mut self, name: &str, value: &str)
+      -> Result<Webhook, WemoError> {
+    http::check_header(name, value)?;
+    self.headers.push((name.to_string(), value.to_string()));
+    Ok(self)
+  }
+
+  /// Retry a failed delivery this many times, waiting `delay` in between.
+  pub fn with_retries(mut self, retries: u32, delay: Duration) -> Webhook {
+    self.retries = retries;
+    self.retry_delay = delay;
+    self
+  }
+
+  /// Give up on each attempt after this long.
+  pub fn with_timeout(mut self, timeout: Duration) -> Webhook {
+    self.timeout = timeout;
+    self
+  }
+
+  /// POST the notification. Any 2xx status is success. Connection failures
+  /// and 5xx statuses are retried; other statuses, eg. a 404 for a mistyped
+  /// URL, won't succeed on a retry and fail straight away.
+  pub fn send(&self, notification: &Notification) -> Result<(), WemoError> {
+    let body = to_json(notification);
+    let mut result = self.post(&body);
+
+    for _ in 0..self.retries {
+      let retry = match result {
+        Ok(status) => status >= 500,
+        Err(WemoError::IoError { .. }) => true,
+        Err(WemoError::TimeoutError { .. }) => true,
+        Err(_) => false,
+      };
+      if !retry {
+        break;
+      }
+
+      warn!(target: "wemo", url:% = self.url,
+          subscription_key = notification.subscription_key.as_str();
+          "Webhook delivery failed, retrying");
+
+      if let Ok(delay) = self.retry_delay.to_std() {
+        thread::sleep(delay);
+      }
+      result = self.post(&body);
+    }
+
+    match result? {
+      200..=299 => Ok(()),
+      _ => Err(WemoError::BadResponseError),
+    }
+  }
+
+  /// Deliver notifications from a background thread from now on.
+  pub fn start(self) -> WebhookForwarder {
+    let (sender, receiver) = channel::<Notification>();
+
+    let handle = threads::spawn("wemo-webhook", move || {
+      for notification in receiver.iter() {
+        if let Err(e) = self.send(&notification) {
+          error!(target: "wemo", url:% = self.url,
+              subscription_key = notification.subscription_key.as_str();
+              "Webhook delivery failed: {:?}", e);
+        }
+      }
+    });
+
+    WebhookForwarder {
+      sender: Mutex::new(Some(sender)),
+      handle: Some(handle),
+    }
+  }
+
+  /// Returns the response's status code.
+  fn post(&self, body: &str) -> Result<u16, WemoError> {
+    let host = self.url.host_str().ok_or(WemoError::BadResponseError)?;
+    let port = self.url.port_or_known_default().unwrap_or(80);
+    let socket = (host, port).to_socket_addrs()?
+        .next()
+        .ok_or(WemoError::BadResponseError)?;
+
+    let path = match self.url.query() {
+      None => self.url.path().to_string(),
+      Some(query) => format!("{}?{}", self.url.path(), query),
+    };
+
+    let mut headers = vec![
+      ("Content-Type".to_string(), "application/json".to_string())];
+    headers.extend(self.headers.iter().cloned());
+
+    let host_header = match self.url.port() {
+      None => host.to_string(),
+      Some(port) => format!("{}:{}", host, port),
+    };
+
+    http::post(socket, &host_header, &path, &headers, body, self.timeout)
+  }
+}
+
+/// Forwards notifications to a `Webhook` in the order they're given, without
+/// blocking the caller. Stops once pending notifications are delivered when
+/// dropped.
+pub struct WebhookForwarder {
+  sender: Mutex<Option<Sender<Notification>>>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl WebhookForwarder {
+  /// Queue the notification for delivery. Suitable for use directly in a
+  /// `Subscriptions::subscribe` callback.
+  pub fn forward(&self, notification: Notification) {
+    match self.sender.lock() {
+      Err(_) => {}, // Ignore.
+      Ok(sender) => {
+        if let Some(ref sender) = *sender {
+          let _r = sender.send(notification);
+        }
+      },
+    }
+  }
+
+  /// Stop accepting notifications and wait for queued ones to be delivered.
+  pub fn stop(&mut self) {
+    match self.sender.lock() {
+      Err(_) => {}, // Ignore.
+      Ok(mut sender) => { *sender = None; },
+    }
+
+    if let Some(handle) = self.handle.take() {
+      let _r = handle.join();
+    }
+  }
+}
+
+impl Drop for WebhookForwarder {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
+
+/// The JSON body POSTed for a notification, eg.
+/// `{"received_at":1478113200000,"sequence":1,"state":"on",
+/// "subscription_key":"192.168.1.4:49153","type":"state","version":1}`.
+pub fn to_json(notification: &Notification) -> String {
+  json::notification(notification).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use device::state::WemoState;
+  use std::io::{Read, Write};
+  use subscriptions::NotificationType;
+  use std::net::TcpListener;
+  use std::sync::mpsc::channel;
+  use std::time::{Duration as StdDuration, UNIX_EPOCH};
+  use super::*;
+
+  fn notification() -> Notification {
+    Notification {
+      notification_type: NotificationType::State { state: WemoState::On },
+      subscription_key: "192.168.1.4:49153".to_string(),
+      received_at: UNIX_EPOCH + StdDuration::from_millis(1_478_113_200_250),
+      sequence: 3,
+      reconciled: false,
+    }
+  }
+
+  #[test]
+  fn test_to_json() {
+    assert_eq!(concat!(r#"{"received_at":1478113200250,"sequence":3,"#,
+        r#""state":"on","#,
+        r#""subscription_key":"192.168.1.4:49153","type":"state","#,
+        r#""version":1}"#),
+        to_json(&notification()));
+  }
+
+  #[test]
+  fn test_send_retries() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let (sender, receiver) = channel();
+
+    thread::spawn(move || {
+      // Fail the first delivery.
+      for status in ["500 Internal Server Error", "200 OK"].iter() {
+        let mut stream = listener.accept().unwrap().0;
+        let mut buf = [0; 1024];
+        let amount = stream.read(&mut buf).unwrap();
+        sender.send(String::from_utf8_lossy(&buf[..amount]).to_string())
+            .unwrap();
+
+        let response = format!("HTTP/1.1 {}\r\n\r\n", status);
+        stream.write_all(response.as_bytes()).unwrap();
+      }
+    });
+
+    let webhook = Webhook::new(&format!("http://{}/hook", address)).unwrap()
+        .with_retries(1, Duration::milliseconds(10));
+
+    webhook.send(&notification()).unwrap();
+
+    let request = receiver.recv().unwrap();
+    assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+    assert!(request.contains("Content-Type: application/json\r\n"));
+    assert!(receiver.recv().is_ok());
+  }
+
+  #[test]
+  fn test_send_client_error() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+      // Only a retry would see the success.
+      for status in ["404 Not Found", "200 OK"].iter() {
+        let mut stream = listener.accept().unwrap().0;
+        let mut buf = [0; 1024];
+        let _r = stream.read(&mut buf).unwrap();
+        let response = format!("HTTP/1.1 {}\r\n\r\n", status);
+        stream.write_all(response.as_bytes()).unwrap();
+      }
+    });
+
+    let webhook = Webhook::new(&format!("http://{}/hook", address)).unwrap()
+        .with_retries(1, Duration::milliseconds(10));
+
+    match webhook.send(&notification()) {
+      Err(WemoError::BadResponseError) => {},
+      other => panic!("expected the delivery to fail, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_invalid_webhook() {
+    for url in ["https://example.com/hook", "mailto:me@example.com"].iter() {
+      match Webhook::new(url) {
+        Err(WemoError::InvalidUrl { .. }) => {},
+        other => panic!("expected {} to be refused, got {:?}", url, other),
+      }
+    }
+
+    let webhook = Webhook::new("http://example.com/hook").unwrap();
+    match webhook.with_header("Authorization", "Bearer x\r\nHost: y") {
+      Err(WemoError::InvalidArgument { .. }) => {},
+      other => panic!("expected the header to be refused, got {:?}", other),
+    }
+  }
+}
@@ -0,0 +1,473 @@
+// Copyright (c) 2016 Brandon Thomas <bt@brand.io, echelon@gmail.com>
+
+//! A local WebSocket endpoint that streams device events as JSON text
+//! messages, encoded as described in the `json` module, eg. for a live
+//! dashboard. Feed it discoveries, SSDP
+//! announcements, and subscription notifications with the `publish_*`
+//! methods. Clients only listen; anything they send is ignored.
+//!
+//! Browsers' cross-origin connections are refused, so web pages the user
+//! opens can't read the stream, and at most `MAX_CLIENTS` are connected at
+//! once.
+
+use base64;
+use error::WemoError;
+use json;
+use net::notify::SsdpNotification;
+use net::ssdp::SsdpResponse;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "subscriptions")] use subscriptions::Notification;
+use threads;
+
+/// How often the accepting thread wakes to check whether it was stopped.
+const STOP_CHECK_MS: u64 = 100;
+
+/// Longest a client may take to send its handshake.
+const HANDSHAKE_TIMEOUT_MS: u64 = 2000;
+
+/// Longest a message may take to write to a client before it's disconnected,
+/// so one that stops reading can't hold up the others.
+const WRITE_TIMEOUT_MS: u64 = 500;
+
+/// The most clients connected or handshaking at once. Others are turned
+/// away.
+pub const MAX_CLIENTS: usize = 32;
+
+/// Appended to the client's key to form the accept key, per RFC 6455.
+const WEBSOCKET_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Serves a WebSocket event stream on a background thread, which stops when
+/// dropped. Any request path is accepted.
+pub struct EventStream {
+  local_addr: SocketAddr,
+  clients: Arc<Mutex<Vec<TcpStream>>>,
+  stopped: Arc<AtomicBool>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl EventStream {
+  /// Listen for WebSocket clients, eg. on `127.0.0.1:8090`.
+  pub fn bind(address: &str) -> Result<EventStream, WemoError> {
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let local_addr = listener.local_addr()?;
+
+    let clients = Arc::new(Mutex::new(Vec::new()));
+    let stopped = Arc::new(AtomicBool::new(false));
+
+    let accepted = clients.clone();
+    let stop = stopped.clone();
+    let handshaking = Arc::new(AtomicUsize::new(0));
+
+    let handle = threads::spawn("wemo-websocket", move || {
+      while !stop.load(Ordering::SeqCst) {
+        let mut stream = match listener.accept() {
+          Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+            thread::sleep(Duration::from_millis(STOP_CHECK_MS));
+            continue;
+          },
+          Err(_) => { continue; },
+          Ok((stream, _)) => { stream },
+        };
+
+        let connected = accepted.lock().map(|clients| clients.len())
+            .unwrap_or(0);
+        if connected + handshaking.load(Ordering::SeqCst) >= MAX_CLIENTS {
+          debug!(target: "wemo", "Too many WebSocket clients, refusing one");
+          let _r = refuse(&mut stream, "503 Service Unavailable");
+          continue;
+        }
+
+        // Handshake off the accepting thread, so a slow client doesn't
+        // hold up the others.
+        let accepted = accepted.clone();
+        let stop = stop.clone();
+        let handshaking = handshaking.clone();
+        handshaking.fetch_add(1, Ordering::SeqCst);
+        threads::spawn("wemo-websocket-handshake", move || {
+          match handshake(stream) {
+            Err(e) => {
+              debug!(target: "wemo", "WebSocket handshake failed: {:?}", e);
+            },
+            Ok(_) if stop.load(Ordering::SeqCst) => {}, // Stopped meanwhile.
+            Ok(stream) => {
+              match accepted.lock() {
+                Err(_) => {}, // Ignore.
+                Ok(mut clients) => { clients.push(stream); },
+              }
+            },
+          }
+          handshaking.fetch_sub(1, Ordering::SeqCst);
+        });
+      }
+    });
+
+    Ok(EventStream {
+      local_addr: local_addr,
+      clients: clients,
+      stopped: stopped,
+      handle: Some(handle),
+    })
+  }
+
+  /// The address being listened on.
+  pub fn local_addr(&self) -> SocketAddr {
+    self.local_addr
+  }
+
+  /// The number of connected clients.
+  pub fn client_count(&self) -> usize {
+    self.clients.lock().map(|clients| clients.len()).unwrap_or(0)
+  }
+
+  /// Stream a device found by a search.
+  pub fn publish_discovery(&self, response: &SsdpResponse) {
+    self.publish(&json::search_result(response).to_string());
+  }
+
+  /// Stream an SSDP announcement.
+  pub fn publish_ssdp(&self, notification: &SsdpNotification) {
+    self.publish(&json::ssdp_notification(notification).to_string());
+  }
+
+  /// Stream a subscription notification, eg. a state change.
+  #[cfg(feature = "subscriptions")]
+  pub fn publish_notification(&self, notification: &Notification) {
+    self.publish(&json::notification(notification).to_string());
+  }
+
+  /// Send a text message to every client. Clients that can't be written to
+  /// within half a second are disconnected.
+  pub fn publish(&self, message: &str) {
+    let frame = text_frame(message);
+
+    match self.clients.lock() {
+      Err(_) => {}, // Ignore.
+      Ok(mut clients) => {
+        clients.retain(|client| {
+          (&*client).write_all(&frame).is_ok()
+        });
+      },
+    }
+  }
+
+  /// Stop accepting clients and disconnect the current ones.
+  pub fn stop(&mut self) {
+    self.stopped.store(true, Ordering::SeqCst);
+    if let Some(handle) = self.handle.take() {
+      let _r = handle.join();
+    }
+
+    match self.clients.lock() {
+      Err(_) => {}, // Ignore.
+      Ok(mut clients) => { clients.clear(); },
+    }
+  }
+}
+
+impl Drop for EventStream {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
+
+/// Read the client's upgrade request and accept it, unless it comes from a
+/// web page on another origin.
+fn handshake(mut stream: TcpStream) -> Result<TcpStream, WemoError> {
+  stream.set_nonblocking(false)?;
+  stream.set_read_timeout(
+      Some(Duration::from_millis(HANDSHAKE_TIMEOUT_MS)))?;
+
+  let mut request = Vec::new();
+  let mut buf = [0; 1024];
+  while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+    let amount = stream.read(&mut buf)?;
+    if amount == 0 || request.len() > 16 * 1024 {
+      return Err(WemoError::BadResponseError);
+    }
+    request.extend_from_slice(&buf[..amount]);
+  }
+
+  let request = String::from_utf8_lossy(&request);
+  let key = header(&request, "Sec-WebSocket-Key")
+      .ok_or(WemoError::BadResponseError)?;
+
+  // Browsers send the page's origin, which only matches the host it
+  // connects to for our own pages.
+  if let Some(origin) = header(&request, "Origin") {
+    let host = header(&request, "Host").unwrap_or("");
+    if origin != format!("http://{}", host) {
+      let _r = refuse(&mut stream, "403 Forbidden");
+      return Err(WemoError::InvalidArgument {
+        reason: format!("cross-origin connection from {}", origin),
+      });
+    }
+  }
+
+  let response = format!("\
+      HTTP/1.1 101 Switching Protocols\r\n\
+      Upgrade: websocket\r\n\
+      Connection: Upgrade\r\n\
+      Sec-WebSocket-Accept: {}\r\n\
+      \r\n",
+      accept_key(&key));
+
+  stream.write_all(response.as_bytes())?;
+  stream.set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT_MS)))?;
+  Ok(stream)
+}
+
+/// The value of the request's header `name`, if sent.
+fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+  request.lines()
+      .filter_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+          (Some(found), Some(value))
+              if found.trim().eq_ignore_ascii_case(name) => {
+            Some(value.trim())
+          },
+          _ => None,
+        }
+      })
+      .next()
+}
+
+/// Turn a client away with `status`, eg. `403 Forbidden`.
+fn refuse(stream: &mut TcpStream, status: &str) -> Result<(), WemoError> {
+  let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+  stream.write_all(response.as_bytes())?;
+  Ok(())
+}
+
+/// The `Sec-WebSocket-Accept` value for a client's key.
+fn accept_key(key: &str) -> String {
+  let mut input = key.as_bytes().to_vec();
+  input.extend_from_slice(WEBSOCKET_GUID.as_bytes());
+  base64::encode(&sha1(&input))
+}
+
+/// An unmasked, unfragmented text frame.
+fn text_frame(message: &str) -> Vec<u8> {
+  let payload = message.as_bytes();
+  let mut frame = Vec::with_capacity(payload.len() + 10);
+  frame.push(0x81); // FIN, text.
+
+  if payload.len() < 126 {
+    frame.push(payload.len() as u8);
+  } else if payload.len() <= 0xffff {
+    frame.push(126);
+    frame.push((payload.len() >> 8) as u8);
+    frame.push(payload.len() as u8);
+  } else {
+    frame.push(127);
+    for shift in (0..8).rev() {
+      frame.push(((payload.len() as u64) >> (shift * 8)) as u8);
+    }
+  }
+
+  frame.extend_from_slice(payload);
+  frame
+}
+
+fn sha1(input: &[u8]) -> [u8; 20] {
+  let mut h: [u32; 5] =
+      [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+  let mut message = input.to_vec();
+  message.push(0x80);
+  while message.len() % 64 != 56 {
+    message.push(0);
+  }
+  let bit_length = (input.len() as u64).wrapping_mul(8);
+  for shift in (0..8).rev() {
+    message.push((bit_length >> (shift * 8)) as u8);
+  }
+
+  for chunk in message.chunks(64) {
+    let mut w = [0u32; 80];
+    for i in 0..16 {
+      w[i] = (chunk[i * 4] as u32) << 24
+          | (chunk[i * 4 + 1] as u32) << 16
+          | (chunk[i * 4 + 2] as u32) << 8
+          | chunk[i * 4 + 3] as u32;
+    }
+    for i in 16..80 {
+      w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+    for i in 0..80 {
+      let (f, k) = match i {
+        0..=19 => ((b & c) | (!b & d), 0x5A827999),
+        20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+        40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+        _ => (b ^ c ^ d, 0xCA62C1D6),
+      };
+      let temp = a.rotate_left(5)
+          .wrapping_add(f)
+          .wrapping_add(e)
+          .wrapping_add(k)
+          .wrapping_add(w[i]);
+      e = d;
+      d = c;
+      c = b.rotate_left(30);
+      b = a;
+      a = temp;
+    }
+
+    h[0] = h[0].wrapping_add(a);
+    h[1] = h[1].wrapping_add(b);
+    h[2] = h[2].wrapping_add(c);
+    h[3] = h[3].wrapping_add(d);
+    h[4] = h[4].wrapping_add(e);
+  }
+
+  let mut digest = [0u8; 20];
+  for (i, word) in h.iter().enumerate() {
+    digest[i * 4] = (word >> 24) as u8;
+    digest[i * 4 + 1] = (word >> 16) as u8;
+    digest[i * 4 + 2] = (word >> 8) as u8;
+    digest[i * 4 + 3] = *word as u8;
+  }
+  digest
+}
+
+#[cfg(test)]
+mod tests {
+  use net::notify::SsdpNotification;
+  use std::io::{Read, Write};
+  use std::net::TcpStream;
+  use std::thread;
+  use std::time::Duration;
+  use super::*;
+
+  #[test]
+  fn test_accept_key() {
+    // The example from RFC 6455.
+    assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+        accept_key("dGhlIHNhbXBsZSBub25jZQ=="));
+  }
+
+  #[test]
+  fn test_text_frame() {
+    assert_eq!(vec![0x81, 2, b'h', b'i'], text_frame("hi"));
+    assert_eq!(&[0x81, 126, 0, 200], &text_frame(&"x".repeat(200))[..4]);
+  }
+
+  fn connect(stream: &EventStream) -> TcpStream {
+    let mut client = TcpStream::connect(stream.local_addr()).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"GET /events HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        \r\n").unwrap();
+    client
+  }
+
+  #[test]
+  fn test_stream_events() {
+    let stream = EventStream::bind("127.0.0.1:0").unwrap();
+
+    // A client that never sends its handshake doesn't hold up the next one.
+    let _silent = TcpStream::connect(stream.local_addr()).unwrap();
+    let mut client = connect(&stream);
+
+    while stream.client_count() == 0 {
+      thread::sleep(Duration::from_millis(10));
+    }
+
+    stream.publish_ssdp(&SsdpNotification::ByeBye {
+      serial_number: "221517K0101769".to_string(),
+    });
+
+    let mut received = Vec::new();
+    let mut buf = [0; 1024];
+    while !received.ends_with(b"}") {
+      let amount = client.read(&mut buf).unwrap();
+      assert!(amount > 0);
+      received.extend_from_slice(&buf[..amount]);
+    }
+
+    let received = String::from_utf8_lossy(&received);
+    assert!(received.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(received.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    assert!(received.ends_with(concat!(
+        r#"{"serial_number":"221517K0101769","type":"departed","#,
+        r#""version":1}"#)));
+  }
+
+  fn connect_from(stream: &EventStream, origin: &str) -> TcpStream {
+    let mut client = TcpStream::connect(stream.local_addr()).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(format!("GET /events HTTP/1.1\r\n\
+        Host: localhost:8090\r\n\
+        Origin: {}\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        \r\n", origin).as_bytes()).unwrap();
+    client
+  }
+
+  fn response(client: &mut TcpStream) -> String {
+    let mut response = String::new();
+    let _r = client.read_to_string(&mut response);
+    response
+  }
+
+  #[test]
+  fn test_refuse_cross_origin() {
+    let stream = EventStream::bind("127.0.0.1:0").unwrap();
+
+    let mut client = connect_from(&stream, "http://evil.example");
+    assert!(response(&mut client).starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    assert_eq!(0, stream.client_count());
+
+    let _client = connect_from(&stream, "http://localhost:8090");
+    while stream.client_count() == 0 {
+      thread::sleep(Duration::from_millis(10));
+    }
+  }
+
+  #[test]
+  fn test_max_clients() {
+    let stream = EventStream::bind("127.0.0.1:0").unwrap();
+    let _clients = (0..MAX_CLIENTS)
+        .map(|_| connect(&stream))
+        .collect::<Vec<_>>();
+    while stream.client_count() < MAX_CLIENTS {
+      thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut client = connect(&stream);
+    assert!(response(&mut client)
+        .starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    assert_eq!(MAX_CLIENTS, stream.client_count());
+  }
+
+  #[test]
+  fn test_drop_stalled_client() {
+    let stream = EventStream::bind("127.0.0.1:0").unwrap();
+    let _client = connect(&stream);
+    while stream.client_count() == 0 {
+      thread::sleep(Duration::from_millis(10));
+    }
+
+    // The client never reads, so its buffers fill and the write times out.
+    let message = "x".repeat(64 * 1024);
+    while stream.client_count() > 0 {
+      stream.publish(&message);
+    }
+  }
+}
@@ -1,17 +1,71 @@
 // Copyright (c) 2015 Brandon Thomas <bt@brand.io>
 
-use regex::Regex;
+use regex::{self, Regex};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Tag names come from service descriptions as well as our own code, so
+/// the cache is bounded in case a device lists a great many.
+const MAX_CACHED_REGEXES: usize = 256;
+
+lazy_static! {
+  /// Compiled patterns by source, since the same few tags are looked up in
+  /// every response.
+  static ref REGEXES: RwLock<HashMap<String, Regex>> =
+      RwLock::new(HashMap::new());
+}
 
 /// Super lazy way to extract text between tags without real XML parsing.
 /// (Better hope for no duplicate tags, nested tags, or anything really...!)
 pub fn find_tag_value<'a>(tag_name: &str, xml: &'a str) -> Option<&'a str> {
   let reg = format!(r"(?im:<{}>(.*)</{}>)", tag_name, tag_name);
-  let re = Regex::new(reg.as_ref()).unwrap();
+  with_regex(&reg, |re| re.captures(xml).and_then(|capture| capture.at(1)))
+      .ok()
+      .and_then(|value| value)
+}
+
+/// Run `f` with `pattern` compiled, compiling it only the first time it's
+/// used.
+pub fn with_regex<T, F>(pattern: &str, f: F) -> Result<T, regex::Error>
+    where F: FnOnce(&Regex) -> T {
+  if let Ok(regexes) = REGEXES.read() {
+    if let Some(regex) = regexes.get(pattern) {
+      return Ok(f(regex));
+    }
+  }
+
+  let regex = Regex::new(pattern)?;
+  let result = f(&regex);
+
+  if let Ok(mut regexes) = REGEXES.write() {
+    if regexes.len() < MAX_CACHED_REGEXES {
+      regexes.insert(pattern.to_string(), regex);
+    }
+  }
+  Ok(result)
+}
 
-  for capture in re.captures_iter(xml) {
-    return capture.at(1);
+/// Escape text for use as an element's content, appending it to `out`.
+pub fn escape_into(text: &str, out: &mut String) {
+  for c in text.chars() {
+    match c {
+      '&' => out.push_str("&amp;"),
+      '<' => out.push_str("&lt;"),
+      '>' => out.push_str("&gt;"),
+      '"' => out.push_str("&quot;"),
+      '\'' => out.push_str("&apos;"),
+      c => out.push(c),
+    }
   }
-  None
+}
+
+/// Undo `escape_into`, along with the other predefined entities.
+pub fn unescape(text: &str) -> String {
+  text.replace("&lt;", "<")
+      .replace("&gt;", ">")
+      .replace("&quot;", "\"")
+      .replace("&apos;", "'")
+      .replace("&amp;", "&")
 }
 
 #[cfg(test)]
@@ -45,4 +99,28 @@ mod tests {
     assert_eq!(None,
       find_tag_value("futuramaCharacter", "<pokemon>Pikachu</pokemon>"));
   }
+
+  #[test]
+  fn test_with_regex() {
+    let pattern = r"(?im:<Pokedex>(.*)</Pokedex>)";
+    let first = with_regex(pattern, |re| re.is_match("<Pokedex>25</Pokedex>"));
+    assert!(first.unwrap());
+    assert!(REGEXES.read().unwrap().contains_key(pattern));
+    assert!(!with_regex(pattern, |re| re.is_match("Pikachu")).unwrap());
+
+    assert!(with_regex("(unclosed", |_| ()).is_err());
+  }
+
+  #[test]
+  fn test_escape() {
+    let escape = |text: &str| {
+      let mut escaped = String::new();
+      escape_into(text, &mut escaped);
+      escaped
+    };
+    let text = "Tom & Jerry's <\"den\">";
+    assert_eq!("Tom &amp; Jerry&apos;s &lt;&quot;den&quot;&gt;", escape(text));
+    assert_eq!(text, unescape(&escape(text)));
+    assert_eq!("&lt;", unescape("&amp;lt;"));
+  }
 }